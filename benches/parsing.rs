@@ -0,0 +1,249 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Benchmarks of the parsers on synthetic index data, and of a whole crawl
+//! served from memory. Run with `cargo bench`.
+use cc_host_mapper::*;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+const INDEX_ID: &str = "CC-MAIN-2020-50";
+const WARC_FILE: &str = "crawl-data/CC-MAIN-2020-50/segments/1/warc/bench.warc.gz";
+const HOSTS: usize = 1000;
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn surt(i: usize) -> String {
+    format!("com,example{},www)/index.html", i)
+}
+
+/// A WARC response record header as found in Common Crawl, with the IP
+/// address after a typical set of headers.
+fn warc_header(i: usize) -> String {
+    format!(
+        "WARC/1.0\r\n\
+         WARC-Type: response\r\n\
+         WARC-Date: 2020-11-26T20:11:42Z\r\n\
+         WARC-Record-ID: <urn:uuid:00000000-0000-0000-0000-{:012}>\r\n\
+         Content-Length: 12345\r\n\
+         Content-Type: application/http; msgtype=response\r\n\
+         WARC-Warcinfo-ID: <urn:uuid:11111111-1111-1111-1111-111111111111>\r\n\
+         WARC-Concurrent-To: <urn:uuid:22222222-2222-2222-2222-222222222222>\r\n\
+         WARC-IP-Address: 10.0.{}.{}\r\n\
+         WARC-Target-URI: https://www.example{}.com/index.html\r\n\
+         WARC-Payload-Digest: sha1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\r\n\
+         WARC-Block-Digest: sha1:BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\r\n\
+         WARC-Identified-Payload-Type: text/html\r\n\r\n\
+         HTTP/1.1 200 OK\r\n",
+        i,
+        i / 256 % 256,
+        i % 256,
+        i
+    )
+}
+
+/// An index of [HOSTS] hosts with one capture each, all in one cdx block
+/// and one WARC file.
+struct Fixture {
+    idx_lines: Vec<String>,
+    cdx_lines: Vec<String>,
+    warc_records: Vec<Vec<u8>>,
+    cluster_idx: Vec<u8>,
+    cdx: Vec<u8>,
+    warc: Vec<u8>,
+}
+
+fn fixture() -> Fixture {
+    let mut warc = vec![];
+    let mut warc_records = vec![];
+    let mut cdx_lines = vec![];
+    for i in 0..HOSTS {
+        let record = gzip(warc_header(i).as_bytes());
+        let json = serde_json::json!({
+            "url": format!("https://www.example{}.com/index.html", i),
+            "mime": "text/html",
+            "mime-detected": "text/html",
+            "status": "200",
+            "digest": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            "length": record.len().to_string(),
+            "offset": warc.len().to_string(),
+            "filename": WARC_FILE,
+            "charset": "UTF-8",
+            "languages": "eng",
+        });
+        cdx_lines.push(format!("{} 20201126201142 {}", surt(i), json));
+        warc.extend(&record);
+        warc_records.push(record);
+    }
+    let cdx = gzip((cdx_lines.join("\n") + "\n").as_bytes());
+    let idx_lines: Vec<String> = (0..HOSTS)
+        .map(|i| {
+            format!(
+                "{} 20201126201142\tcdx-00000.gz\t0\t{}\t1",
+                surt(i),
+                cdx.len()
+            )
+        })
+        .collect();
+    let cluster_idx = (idx_lines.join("\n") + "\n").into_bytes();
+    Fixture {
+        idx_lines,
+        cdx_lines,
+        warc_records,
+        cluster_idx,
+        cdx,
+        warc,
+    }
+}
+
+/// [Fetcher] serving the fixture files from memory.
+struct MemoryFetcher {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryFetcher {
+    fn new(fixture: &Fixture) -> MemoryFetcher {
+        let indexes = format!("cc-index/collections/{}/indexes", INDEX_ID);
+        let mut files = HashMap::new();
+        files.insert(format!("{}/cluster.idx", indexes), fixture.cluster_idx.clone());
+        files.insert(format!("{}/cdx-00000.gz", indexes), fixture.cdx.clone());
+        files.insert(WARC_FILE.to_owned(), fixture.warc.clone());
+        MemoryFetcher { files }
+    }
+
+    fn file(&self, url: &str) -> Option<&Vec<u8>> {
+        let path = url.splitn(4, '/').nth(3)?;
+        self.files.get(path)
+    }
+}
+
+impl Fetcher for MemoryFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        let body = self.file(url).cloned().unwrap_or_default();
+        Ok(FetchResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body,
+        })
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        let file = self.file(url).unwrap();
+        let last = (end as usize).min(file.len() - 1);
+        let mut headers = HeaderMap::new();
+        let range = format!("bytes {}-{}/{}", start, last, file.len());
+        headers.insert(CONTENT_RANGE, HeaderValue::from_str(&range).unwrap());
+        Ok(FetchResponse {
+            status: StatusCode::PARTIAL_CONTENT,
+            headers,
+            body: file[start as usize..=last].to_vec(),
+        })
+    }
+}
+
+fn parsers(c: &mut Criterion) {
+    let fixture = fixture();
+    let surts: Vec<String> = (0..HOSTS).map(surt).collect();
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(HOSTS as u64));
+    group.bench_function("surt_host", |b| {
+        b.iter(|| {
+            for surt in &surts {
+                parse_surt_host(surt).unwrap();
+            }
+        })
+    });
+    group.bench_function("idx_line", |b| {
+        b.iter(|| {
+            for line in &fixture.idx_lines {
+                parse_idx_line(DEFAULT_BASE_URL, INDEX_ID, line).unwrap();
+            }
+        })
+    });
+    group.bench_function("cdx_line", |b| {
+        b.iter(|| {
+            for line in &fixture.cdx_lines {
+                parse_cdx_line(line).unwrap();
+            }
+        })
+    });
+    group.bench_function("warc_ip", |b| {
+        b.iter(|| {
+            for record in &fixture.warc_records {
+                parse_warc_ip(record).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let fixture = fixture();
+    let fetcher: Arc<dyn Fetcher> = Arc::new(MemoryFetcher::new(&fixture));
+    let options = CrawlOptions {
+        progress: ProgressMode::Hidden,
+        fetcher: Some(fetcher),
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(HOSTS as u64));
+    group.sample_size(10);
+    group.bench_function("crawl", |b| {
+        b.iter_batched(
+            Vec::<MappingEntry>::new,
+            |mut mappings| {
+                crawl_to_sink(INDEX_ID, &mut mappings, &options);
+                assert_eq!(mappings.len(), HOSTS);
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsers, pipeline);
+criterion_main!(benches);
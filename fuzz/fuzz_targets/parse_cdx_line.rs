@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        if let Ok(line) = cc_host_mapper::parse_cdx_line(line) {
+            let _ = line.day();
+        }
+    }
+});
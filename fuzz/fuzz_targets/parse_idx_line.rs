@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        let _ = cc_host_mapper::parse_idx_line(
+            "https://data.commoncrawl.org",
+            "CC-MAIN-2020-50",
+            line,
+        );
+    }
+});
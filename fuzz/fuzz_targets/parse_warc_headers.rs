@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(headers) = cc_host_mapper::parse_warc_headers(data) {
+        let _ = headers.ip_address();
+    }
+});
@@ -0,0 +1,284 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Circuit breaker pausing the whole crawl during sustained failures.
+//!
+//! When Common Crawl throttles us or has an outage, every request fails and
+//! continuing would only burn through the pointer list. The breaker trips
+//! after too many consecutive failures, or too high a failure ratio over a
+//! sliding window of requests. All workers then pause for a cooldown, after
+//! which a single probe request decides whether to resume or to pause again.
+//! If the probes keep failing, the breaker aborts the crawl.
+//!
+//! A run of 403 responses means Common Crawl blocks us for a while rather
+//! than being down, which typically lasts much longer than an outage. With
+//! [BreakerPolicy::block_cooldown] set, such a block pauses the crawl for
+//! that longer cooldown instead, and the crawl resumes by itself once a
+//! probe gets through.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often waiting workers check whether the probe request is done.
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Thresholds of the [CircuitBreaker].
+#[derive(Debug, Clone)]
+pub struct BreakerPolicy {
+    /// Trip after this many consecutive failed requests
+    pub consecutive_failures: u32,
+    /// Number of most recent requests considered for the failure ratio
+    pub window: usize,
+    /// Trip when this fraction of the requests in a full window failed
+    pub max_failure_ratio: f64,
+    /// How long all workers pause after the breaker trips
+    pub cooldown: Duration,
+    /// Abort the crawl after this many failed probes in a row
+    pub max_probes: u32,
+    /// Consider the crawl blocked after this many consecutive 403 responses
+    pub block_failures: u32,
+    /// How long all workers pause once the crawl is blocked, e.g. 45
+    /// minutes. Blocks are handled like other failures if `None`.
+    pub block_cooldown: Option<Duration>,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        BreakerPolicy {
+            consecutive_failures: 50,
+            window: 500,
+            max_failure_ratio: 0.8,
+            cooldown: Duration::from_secs(120),
+            max_probes: 5,
+            block_failures: 20,
+            block_cooldown: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Requests flow normally
+    Closed,
+    /// All workers pause until the given time
+    Open(Instant),
+    /// One worker probes, the others wait for its outcome
+    Probing,
+    /// The outage persisted, no more requests are sent
+    Aborted,
+}
+
+struct Inner {
+    state: State,
+    consecutive: u32,
+    consecutive_forbidden: u32,
+    window: VecDeque<bool>,
+    window_failures: usize,
+    failed_probes: u32,
+    /// Whether the current pause is due to a block
+    blocked: bool,
+    /// Number of times the breaker paused the crawl
+    pauses: u64,
+}
+
+/// Permission to send one request, handed out by [CircuitBreaker::wait_ready]
+/// and given back with the outcome to [CircuitBreaker::record].
+#[derive(Debug, Clone, Copy)]
+pub struct Permit {
+    probe: bool,
+}
+
+/// Error returned to workers once the breaker gave up on the crawl.
+#[derive(Debug, Clone, Copy)]
+pub struct Aborted;
+
+/// Circuit breaker shared by all workers of a crawl.
+pub struct CircuitBreaker {
+    policy: BreakerPolicy,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: BreakerPolicy) -> CircuitBreaker {
+        CircuitBreaker {
+            policy,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                consecutive: 0,
+                consecutive_forbidden: 0,
+                window: VecDeque::new(),
+                window_failures: 0,
+                failed_probes: 0,
+                blocked: false,
+                pauses: 0,
+            }),
+        }
+    }
+
+    /// Block until the calling worker may send a request. While the breaker
+    /// is open this waits for the cooldown, and afterwards only one worker
+    /// is let through to probe.
+    pub fn wait_ready(&self) -> Result<Permit, Aborted> {
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.state {
+                    State::Closed => return Ok(Permit { probe: false }),
+                    State::Aborted => return Err(Aborted),
+                    State::Probing => PROBE_POLL_INTERVAL,
+                    State::Open(until) => {
+                        let now = Instant::now();
+                        if now >= until {
+                            // this worker sends the probe
+                            inner.state = State::Probing;
+                            return Ok(Permit { probe: true });
+                        }
+                        until - now
+                    }
+                }
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    /// Record the outcome of the request sent with the given permit.
+    pub fn record(&self, permit: Permit, success: bool) {
+        self.record_outcome(permit, success, false);
+    }
+
+    /// Record a 403 response to the request sent with the given permit, a
+    /// failure that counts towards a block.
+    pub fn record_forbidden(&self, permit: Permit) {
+        self.record_outcome(permit, false, true);
+    }
+
+    fn record_outcome(&self, permit: Permit, success: bool, forbidden: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if permit.probe {
+            if success {
+                inner.state = State::Closed;
+                inner.failed_probes = 0;
+                inner.consecutive = 0;
+                inner.consecutive_forbidden = 0;
+                inner.window.clear();
+                inner.window_failures = 0;
+                inner.blocked = false;
+                eprintln!("probe request succeeded, resuming crawl");
+            } else {
+                inner.failed_probes += 1;
+                inner.blocked =
+                    self.policy.block_cooldown.is_some() && (inner.blocked || forbidden);
+                if inner.failed_probes >= self.policy.max_probes {
+                    inner.state = State::Aborted;
+                    eprintln!(
+                        "{} probe requests failed, aborting crawl: Common Crawl appears to be throttling us or down",
+                        inner.failed_probes
+                    );
+                } else {
+                    let cooldown = self.cooldown(&inner);
+                    inner.state = State::Open(Instant::now() + cooldown);
+                    inner.pauses += 1;
+                    eprintln!(
+                        "probe request failed, pausing for another {}s",
+                        cooldown.as_secs()
+                    );
+                }
+            }
+            return;
+        }
+        if inner.state != State::Closed {
+            // late results of requests sent before the breaker tripped
+            return;
+        }
+
+        inner.consecutive = if success { 0 } else { inner.consecutive + 1 };
+        inner.consecutive_forbidden = if forbidden { inner.consecutive_forbidden + 1 } else { 0 };
+        inner.window.push_back(success);
+        if !success {
+            inner.window_failures += 1;
+        }
+        if inner.window.len() > self.policy.window {
+            if let Some(false) = inner.window.pop_front() {
+                inner.window_failures -= 1;
+            }
+        }
+
+        let window_full = inner.window.len() >= self.policy.window;
+        let ratio = inner.window_failures as f64 / inner.window.len() as f64;
+        if self.policy.block_cooldown.is_some()
+            && inner.consecutive_forbidden >= self.policy.block_failures
+        {
+            inner.blocked = true;
+            let cooldown = self.cooldown(&inner);
+            inner.state = State::Open(Instant::now() + cooldown);
+            inner.pauses += 1;
+            eprintln!(
+                "{} consecutive 403 responses, Common Crawl appears to block us: \
+                 pausing all workers for {}s",
+                inner.consecutive_forbidden,
+                cooldown.as_secs()
+            );
+        } else if inner.consecutive >= self.policy.consecutive_failures
+            || (window_full && ratio >= self.policy.max_failure_ratio)
+        {
+            inner.state = State::Open(Instant::now() + self.policy.cooldown);
+            inner.pauses += 1;
+            eprintln!(
+                "too many failed requests, pausing all workers for {}s",
+                self.policy.cooldown.as_secs()
+            );
+        }
+    }
+
+    /// The cooldown of the current pause.
+    fn cooldown(&self, inner: &Inner) -> Duration {
+        match self.policy.block_cooldown {
+            Some(cooldown) if inner.blocked => cooldown,
+            _ => self.policy.cooldown,
+        }
+    }
+
+    /// Number of times the breaker paused the crawl, including the pauses
+    /// after failed probes.
+    pub fn pauses(&self) -> u64 {
+        self.inner.lock().unwrap().pauses
+    }
+
+    /// Whether the breaker gave up on the crawl.
+    pub fn is_aborted(&self) -> bool {
+        self.inner.lock().unwrap().state == State::Aborted
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! On-disk cache of downloaded data, so that re-runs and resumed runs do not
+//! download identical bytes again.
+//!
+//! Entries are keyed by URL and byte range and stored as one file each in the
+//! cache directory, named after a hash of the key. The key is written on the
+//! first line of the file and checked on reading, so that colliding keys
+//! miss rather than return the wrong body. When the total size exceeds the
+//! cap, the least recently written entries are evicted.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Default size cap of the cache.
+pub const DEFAULT_CACHE_SIZE: u64 = 10_000_000_000;
+
+/// Fraction of the size cap to shrink the cache to when evicting, so that
+/// not every insertion triggers an eviction.
+const EVICT_TO: f64 = 0.9;
+
+/// Cache of response bodies in a directory, shared by all workers.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    size: Mutex<u64>,
+    /// Number of entries written so far, to name their temporary files
+    written: AtomicU64,
+}
+
+impl DiskCache {
+    /// Open the cache in `dir`, creating the directory if needed. The total
+    /// size of the entries is kept under `max_bytes`.
+    pub fn open(dir: PathBuf, max_bytes: u64) -> io::Result<DiskCache> {
+        fs::create_dir_all(&dir)?;
+        let size = entries(&dir)?.iter().map(|(_, len, _)| len).sum();
+        Ok(DiskCache {
+            dir,
+            max_bytes,
+            size: Mutex::new(size),
+            written: AtomicU64::new(0),
+        })
+    }
+
+    /// Cached body for `url`, restricted to the byte range `start..=end` if
+    /// given.
+    pub fn get(&self, url: &str, range: Option<(i64, i64)>) -> Option<Vec<u8>> {
+        let key = cache_key(url, range);
+        let mut entry = fs::read(self.path(&key)).ok()?;
+        let newline = entry.iter().position(|byte| *byte == b'\n')?;
+        if entry[..newline] != *key.as_bytes() {
+            return None;
+        }
+        entry.drain(..=newline);
+        Some(entry)
+    }
+
+    /// Store the body for `url` and byte range. Failing to write the cache
+    /// is not an error for the crawl, so it is silently ignored.
+    pub fn put(&self, url: &str, range: Option<(i64, i64)>, bytes: &[u8]) {
+        let key = cache_key(url, range);
+        let path = self.path(&key);
+        let tmp_path = path.with_extension(format!(
+            "tmp{}-{}",
+            std::process::id(),
+            self.written.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut entry = Vec::with_capacity(key.len() + 1 + bytes.len());
+        entry.extend_from_slice(key.as_bytes());
+        entry.push(b'\n');
+        entry.extend_from_slice(bytes);
+        if fs::write(&tmp_path, &entry).is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return;
+        }
+        let mut size = self.size.lock().unwrap();
+        // an overwritten entry no longer takes space
+        let replaced = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if fs::rename(&tmp_path, &path).is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return;
+        }
+        *size = size.saturating_sub(replaced) + entry.len() as u64;
+        if *size > self.max_bytes {
+            *size = self.evict().unwrap_or(*size);
+        }
+    }
+
+    /// Remove the oldest entries until the cache is below [EVICT_TO] of its
+    /// cap, returning the new total size.
+    fn evict(&self) -> io::Result<u64> {
+        let mut entries = entries(&self.dir)?;
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        let target = (self.max_bytes as f64 * EVICT_TO) as u64;
+        for (path, len, _) in entries {
+            if size <= target {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= len;
+            }
+        }
+        Ok(size)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+}
+
+/// Key of the entry for `url` and byte range, written on the first line of
+/// its file.
+fn cache_key(url: &str, range: Option<(i64, i64)>) -> String {
+    match range {
+        Some((start, end)) => format!("{} {}-{}", url, start, end),
+        None => url.to_owned(),
+    }
+}
+
+/// Cache entries in `dir` with their size and modification time.
+fn entries(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((entry.path(), metadata.len(), modified));
+        }
+    }
+    Ok(entries)
+}
+
+/// 64-bit FNV-1a hash, used for cache file names since it is stable across
+/// Rust releases, unlike the standard library hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
@@ -0,0 +1,280 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Recording and replaying the HTTP traffic of a crawl, so that a failing
+//! crawl can be reproduced offline and turned into a regression test.
+//!
+//! A cassette is a directory holding `interactions.jsonl`, one line per
+//! request in the order they were made, and one file per response body.
+use crate::fetcher::{FetchError, FetchResponse, Fetcher};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const INTERACTIONS_FILE: &str = "interactions.jsonl";
+
+/// A request and its outcome as stored in a cassette.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    url: String,
+    range: Option<(i64, i64)>,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Outcome {
+    Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        /// Name of the file holding the body, relative to the cassette
+        body: String,
+    },
+    Connect(String),
+    Timeout(String),
+    Body(String),
+}
+
+/// Key under which an interaction is replayed: the path and query of the
+/// URL, so a cassette can be replayed against any base URL, and the range.
+type RequestKey = (String, Option<(i64, i64)>);
+
+fn request_key(url: &str, range: Option<(i64, i64)>) -> RequestKey {
+    let path = match Url::parse(url) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        },
+        Err(_) => url.to_owned(),
+    };
+    (path, range)
+}
+
+struct Recorder {
+    interactions: File,
+    next_body: u64,
+}
+
+/// [Fetcher] passing all requests to another fetcher and recording them
+/// with their responses to a cassette directory.
+///
+/// Every interaction is written out as soon as it completes, so the
+/// cassette of a crawl that panicked covers all requests up to the panic.
+/// Data served from the [crate::DiskCache] never reaches the fetcher and is
+/// not recorded.
+pub struct RecordingFetcher {
+    inner: Arc<dyn Fetcher>,
+    dir: PathBuf,
+    recorder: Mutex<Recorder>,
+}
+
+impl RecordingFetcher {
+    /// Record the requests made through `inner` to the cassette in `dir`,
+    /// replacing any interactions recorded there before.
+    pub fn create<P: Into<PathBuf>>(
+        dir: P,
+        inner: Arc<dyn Fetcher>,
+    ) -> io::Result<RecordingFetcher> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let interactions = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(dir.join(INTERACTIONS_FILE))?;
+        Ok(RecordingFetcher {
+            inner,
+            dir,
+            recorder: Mutex::new(Recorder {
+                interactions,
+                next_body: 0,
+            }),
+        })
+    }
+
+    fn record(
+        &self,
+        url: &str,
+        range: Option<(i64, i64)>,
+        result: &Result<FetchResponse, FetchError>,
+    ) -> io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+        let outcome = match result {
+            Ok(rsp) => {
+                let body = format!("{:06}.bin", recorder.next_body);
+                recorder.next_body += 1;
+                fs::write(self.dir.join(&body), &rsp.body)?;
+                let headers = rsp
+                    .headers
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                    })
+                    .collect();
+                Outcome::Response {
+                    status: rsp.status.as_u16(),
+                    headers,
+                    body,
+                }
+            }
+            Err(FetchError::Connect(e)) => Outcome::Connect(e.clone()),
+            Err(FetchError::Timeout(e)) => Outcome::Timeout(e.clone()),
+            Err(FetchError::Body(e)) => Outcome::Body(e.clone()),
+        };
+        let interaction = Interaction {
+            url: url.to_owned(),
+            range,
+            outcome,
+        };
+        let line = serde_json::to_string(&interaction)?;
+        writeln!(recorder.interactions, "{}", line)?;
+        recorder.interactions.flush()
+    }
+
+    fn recorded(
+        &self,
+        url: &str,
+        range: Option<(i64, i64)>,
+        result: Result<FetchResponse, FetchError>,
+    ) -> Result<FetchResponse, FetchError> {
+        if let Err(e) = self.record(url, range, &result) {
+            eprintln!("failed to record {} to {}: {}", url, self.dir.display(), e);
+        }
+        result
+    }
+}
+
+impl Fetcher for RecordingFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        self.recorded(url, None, self.inner.get(url))
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        self.recorded(url, Some((start, end)), self.inner.get_range(url, start, end))
+    }
+}
+
+/// [Fetcher] answering requests from a cassette written by a
+/// [RecordingFetcher], without any network access.
+///
+/// Requests are matched by URL path and byte range, whatever the host.
+/// Repeated requests, e.g. retries, get the recorded responses in their
+/// original order; once those run out the last one is repeated. Requests
+/// missing from the cassette fail with [FetchError::Connect].
+pub struct ReplayFetcher {
+    interactions: HashMap<RequestKey, Vec<Result<FetchResponse, FetchError>>>,
+    replayed: Mutex<HashMap<RequestKey, usize>>,
+}
+
+impl ReplayFetcher {
+    /// Load the cassette in `dir`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<ReplayFetcher> {
+        let dir = dir.as_ref();
+        let file = File::open(dir.join(INTERACTIONS_FILE))?;
+        let mut interactions: HashMap<RequestKey, Vec<_>> = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let interaction: Interaction = serde_json::from_str(&line?)?;
+            let result = match interaction.outcome {
+                Outcome::Response {
+                    status,
+                    headers,
+                    body,
+                } => Ok(FetchResponse {
+                    status: StatusCode::from_u16(status)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                    headers: header_map(&headers),
+                    body: fs::read(dir.join(body))?,
+                }),
+                Outcome::Connect(e) => Err(FetchError::Connect(e)),
+                Outcome::Timeout(e) => Err(FetchError::Timeout(e)),
+                Outcome::Body(e) => Err(FetchError::Body(e)),
+            };
+            interactions
+                .entry(request_key(&interaction.url, interaction.range))
+                .or_default()
+                .push(result);
+        }
+        Ok(ReplayFetcher {
+            interactions,
+            replayed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn replay(&self, url: &str, range: Option<(i64, i64)>) -> Result<FetchResponse, FetchError> {
+        let key = request_key(url, range);
+        let results = match self.interactions.get(&key) {
+            Some(results) => results,
+            None => {
+                return Err(FetchError::Connect(format!(
+                    "no recorded response for {}",
+                    url
+                )))
+            }
+        };
+        let mut replayed = self.replayed.lock().unwrap();
+        let count = replayed.entry(key).or_insert(0);
+        let result = &results[(*count).min(results.len() - 1)];
+        *count += 1;
+        result.clone()
+    }
+}
+
+/// Header map of recorded headers, skipping any that are no longer valid.
+fn header_map(headers: &[(String, String)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            map.append(name, value);
+        }
+    }
+    map
+}
+
+impl Fetcher for ReplayFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        self.replay(url, None)
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        self.replay(url, Some((start, end)))
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Checkpoint of the host pointers a crawl completed, for resuming it.
+//!
+//! The checkpoint is a text file with the index id on its first line,
+//! `# index CC-MAIN-2020-50`, followed by every completed pointer, one per
+//! line, as the name of its cdx file and the start of its range, e.g.
+//! `cdx-00000.gz 186457`. Pointers rather than hosts are recorded, as the
+//! captures of a large host span several consecutive pointers. A crawl
+//! given a checkpoint skips the pointers listed in it, so that a crawl
+//! interrupted during a long pause can be resumed into a new output file,
+//! and the outputs combined afterwards.
+use crate::IndexHostPointer;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Prefix of the first line of a checkpoint file.
+const INDEX_PREFIX: &str = "# index ";
+
+/// Hosts of the pointers completed while crawling one index, saved to a
+/// file when the crawl pauses and when it ends.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    index_id: String,
+    done: Mutex<HashSet<String>>,
+    resumed: usize,
+}
+
+impl Checkpoint {
+    /// Checkpoint of crawling `index_id`, saved to `path`. If the file
+    /// exists, the pointers listed in it are considered done already; it is
+    /// an error if it belongs to another index.
+    pub fn open(path: impl AsRef<Path>, index_id: &str) -> io::Result<Checkpoint> {
+        let path = path.as_ref().to_owned();
+        let mut done = HashSet::new();
+        if path.exists() {
+            let mut lines = BufReader::new(File::open(&path)?).lines();
+            let header = lines.next().transpose()?.unwrap_or_default();
+            match header.strip_prefix(INDEX_PREFIX) {
+                Some(id) if id == index_id => {}
+                Some(id) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("checkpoint of index {}, not {}", id, index_id),
+                    ))
+                }
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint"))
+                }
+            }
+            for line in lines {
+                let line = line?;
+                if !line.is_empty() {
+                    done.insert(line);
+                }
+            }
+        }
+        Ok(Checkpoint {
+            path,
+            index_id: index_id.to_owned(),
+            resumed: done.len(),
+            done: Mutex::new(done),
+        })
+    }
+
+    /// Whether this pointer was completed.
+    pub fn contains(&self, pointer: &IndexHostPointer) -> bool {
+        self.done.lock().unwrap().contains(&pointer_key(pointer))
+    }
+
+    /// Number of completed pointers.
+    pub fn len(&self) -> usize {
+        self.done.lock().unwrap().len()
+    }
+
+    /// Whether no pointer was completed yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of completed pointers read from the file by
+    /// [Checkpoint::open].
+    pub fn resumed(&self) -> usize {
+        self.resumed
+    }
+
+    /// Mark this pointer as completed.
+    pub(crate) fn insert(&self, pointer: &IndexHostPointer) {
+        self.done.lock().unwrap().insert(pointer_key(pointer));
+    }
+
+    /// The lines of the pointers completed so far.
+    pub(crate) fn snapshot(&self) -> Vec<String> {
+        let mut pointers: Vec<String> = self.done.lock().unwrap().iter().cloned().collect();
+        pointers.sort_unstable();
+        pointers
+    }
+
+    /// Save all completed pointers to the file.
+    pub fn save(&self) -> io::Result<()> {
+        self.save_pointers(&self.snapshot())
+    }
+
+    /// Replace the file with these pointer lines, through a temporary file
+    /// so that an interrupted save keeps the previous checkpoint.
+    pub(crate) fn save_pointers(&self, pointers: &[String]) -> io::Result<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writeln!(writer, "{}{}", INDEX_PREFIX, self.index_id)?;
+        for pointer in pointers {
+            writeln!(writer, "{}", pointer)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+/// Line of a pointer in the checkpoint file.
+fn pointer_key(pointer: &IndexHostPointer) -> String {
+    let file = pointer.index_file_name.rsplit('/').next().unwrap_or_default();
+    format!("{} {}", file, pointer.range_start)
+}
@@ -0,0 +1,88 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! SHA-256 checksums of finished outputs, written next to them in the
+//! format of `sha256sum` so that they can be checked with `sha256sum -c`.
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extension of the checksum sidecar files.
+pub const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// Line of a checksum sidecar: the hex digest and the file name.
+pub(crate) fn checksum_line(digest: &str, name: &str) -> String {
+    format!("{}  {}\n", digest, name)
+}
+
+/// Hex SHA-256 digest of a file.
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Write a `<file>.sha256` sidecar for a file, or for every file of a
+/// directory tree such as a partitioned output, and return the digests of
+/// the files.
+pub fn write_checksums(path: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut checksums = vec![];
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.extension().is_some_and(|ext| ext == CHECKSUM_EXTENSION) {
+                continue;
+            }
+            checksums.extend(write_checksums(&entry)?);
+        }
+        return Ok(checksums);
+    }
+    let digest = file_sha256(path)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(format!(".{}", CHECKSUM_EXTENSION));
+    fs::write(sidecar, checksum_line(&digest, &name))?;
+    checksums.push((path.to_owned(), digest));
+    Ok(checksums)
+}
@@ -0,0 +1,519 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Parquet output of the mappings, which DuckDB, Spark or Athena query in
+//! place.
+use crate::object_store::UploadSink;
+use crate::{
+    CsvColumns, MappingEntry, MappingSink, ObjectStore, ObjectUrl, PartitionKey, PrefixOutput,
+};
+use chrono::NaiveDate;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Mappings buffered in memory before they are written as a row group.
+const ROW_GROUP_ROWS: usize = 100_000;
+
+/// Physical and logical type of the values of a column.
+#[derive(Clone, Copy)]
+enum ValueType {
+    String,
+    Int32,
+    Int64,
+    Date,
+}
+
+impl ValueType {
+    fn schema(self) -> &'static str {
+        match self {
+            ValueType::String => "binary {} (STRING)",
+            ValueType::Int32 => "int32 {}",
+            ValueType::Int64 => "int64 {}",
+            ValueType::Date => "int32 {} (DATE)",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Repetition {
+    Required,
+    Optional,
+    /// Optional list of required values
+    List,
+}
+
+enum Value {
+    Bytes(ByteArray),
+    Int32(i32),
+    Int64(i64),
+}
+
+fn string(value: Option<&str>) -> Option<Vec<Value>> {
+    value.map(|value| vec![Value::Bytes(value.into())])
+}
+
+fn strings(values: Option<&Vec<String>>) -> Option<Vec<Value>> {
+    values.map(|values| values.iter().map(|v| Value::Bytes(v.as_str().into())).collect())
+}
+
+fn int32(value: Option<u16>) -> Option<Vec<Value>> {
+    value.map(|value| vec![Value::Int32(value as i32)])
+}
+
+/// Values of a mapping in a column: `None` if null, else one value, or the
+/// elements of a list.
+type Extract = fn(&MappingEntry, CsvColumns) -> Option<Vec<Value>>;
+
+/// A column of the output with the values of the buffered mappings, and
+/// their Dremel definition and repetition levels.
+struct Column {
+    name: &'static str,
+    value_type: ValueType,
+    repetition: Repetition,
+    extract: Extract,
+    values: Vec<Value>,
+    def_levels: Vec<i16>,
+    rep_levels: Vec<i16>,
+}
+
+impl Column {
+    fn new(
+        name: &'static str,
+        value_type: ValueType,
+        repetition: Repetition,
+        extract: Extract,
+    ) -> Column {
+        Column {
+            name,
+            value_type,
+            repetition,
+            extract,
+            values: vec![],
+            def_levels: vec![],
+            rep_levels: vec![],
+        }
+    }
+
+    fn schema(&self) -> String {
+        let field = self.value_type.schema().replace("{}", self.name);
+        match self.repetition {
+            Repetition::Required => format!("required {};", field),
+            Repetition::Optional => format!("optional {};", field),
+            Repetition::List => format!(
+                "optional group {} (LIST) {{ repeated group list {{ required {}; }} }}",
+                self.name,
+                self.value_type.schema().replace("{}", "element")
+            ),
+        }
+    }
+
+    /// Buffer the values of a mapping, which must not be `None` for a
+    /// required column.
+    fn push(&mut self, values: Option<Vec<Value>>) {
+        match (self.repetition, values) {
+            (Repetition::Required, values) => self.values.extend(values.unwrap_or_default()),
+            (Repetition::Optional, Some(values)) => {
+                self.values.extend(values);
+                self.def_levels.push(1);
+            }
+            (_, None) => {
+                self.def_levels.push(0);
+                self.rep_levels.push(0);
+            }
+            (Repetition::List, Some(values)) if values.is_empty() => {
+                self.def_levels.push(1);
+                self.rep_levels.push(0);
+            }
+            (Repetition::List, Some(values)) => {
+                for i in 0..values.len() {
+                    self.def_levels.push(2);
+                    self.rep_levels.push(if i == 0 { 0 } else { 1 });
+                }
+                self.values.extend(values);
+            }
+        }
+    }
+}
+
+/// Sink writing the mappings to a Parquet file, with a column for `host`,
+/// `date` and `ip`, and for each of the optional [CsvColumns]. The columns
+/// are named after the CSV columns in lowercase; `date` is a DATE, `port`
+/// and `status` are integers, and `protocols`, `asn` and `org` are lists.
+/// Mappings are written in row groups of 100,000, compressed with Snappy.
+pub struct ParquetSink {
+    writer: Option<SerializedFileWriter<Box<dyn Write + Send>>>,
+    columns: CsvColumns,
+    buffer: Vec<Column>,
+    rows: usize,
+}
+
+impl ParquetSink {
+    /// Create a sink writing the given optional columns to `filename`.
+    pub fn create(filename: &str, columns: CsvColumns) -> io::Result<ParquetSink> {
+        ParquetSink::new(Box::new(File::create(filename)?), columns)
+    }
+
+    /// Create a sink writing the given optional columns to any writer.
+    pub fn new(writer: Box<dyn Write + Send>, columns: CsvColumns) -> io::Result<ParquetSink> {
+        ParquetSink::without(writer, columns, &[])
+    }
+
+    /// Create a sink leaving out the columns of the given names, e.g. the
+    /// partition columns of a [PartitionedParquetSink].
+    fn without(
+        writer: Box<dyn Write + Send>,
+        columns: CsvColumns,
+        excluded: &[&str],
+    ) -> io::Result<ParquetSink> {
+        use Repetition::*;
+        use ValueType::*;
+        let mut buffer = vec![
+            Column::new("host", String, Required, |e, _| string(Some(&e.host))),
+            Column::new("date", Date, Required, |e, _| {
+                let date = NaiveDate::parse_from_str(&e.timestr, "%Y-%m-%d").ok()?;
+                let epoch = NaiveDate::from_ymd_opt(1970, 1, 1)?;
+                Some(vec![Value::Int32((date - epoch).num_days() as i32)])
+            }),
+            Column::new("ip", String, Required, |e, columns| {
+                match (columns.prefix, e.prefix) {
+                    (PrefixOutput::Instead, Some(prefix)) => string(Some(&prefix.to_string())),
+                    _ => string(Some(&e.ip.to_string())),
+                }
+            }),
+        ];
+        if columns.server {
+            buffer.push(Column::new("server", String, Optional, |e, _| {
+                string(e.server.as_ref()?.server.as_deref())
+            }));
+            buffer.push(Column::new("powered_by", String, Optional, |e, _| {
+                string(e.server.as_ref()?.powered_by.as_deref())
+            }));
+        }
+        if columns.protocol {
+            buffer.push(Column::new("protocols", String, List, |e, _| {
+                strings(e.protocol.as_ref().map(|p| &p.protocols))
+            }));
+            buffer.push(Column::new("cipher", String, Optional, |e, _| {
+                string(e.protocol.as_ref()?.cipher_suite.as_deref())
+            }));
+        }
+        if columns.port {
+            buffer.push(Column::new("port", Int32, Optional, |e, _| int32(e.port)));
+        }
+        if columns.url {
+            buffer.push(Column::new("url", String, Optional, |e, _| string(e.url.as_deref())));
+        }
+        if columns.status {
+            buffer.push(Column::new("status", Int32, Optional, |e, _| int32(e.status)));
+        }
+        if columns.record {
+            buffer.push(Column::new("languages", String, List, |e, _| {
+                strings(e.record.as_ref().map(|r| &r.languages))
+            }));
+            buffer.push(Column::new("charset", String, Optional, |e, _| {
+                string(e.record.as_ref()?.charset.as_deref())
+            }));
+            buffer.push(Column::new("truncated", String, Optional, |e, _| {
+                string(e.record.as_ref()?.truncated.as_deref())
+            }));
+            buffer.push(Column::new("redirect", String, Optional, |e, _| {
+                string(e.record.as_ref()?.redirect.as_deref())
+            }));
+        }
+        if columns.host_unicode {
+            buffer.push(Column::new("host_unicode", String, Optional, |e, _| {
+                string(e.host_unicode.as_deref())
+            }));
+        }
+        if columns.prefix == PrefixOutput::Alongside {
+            buffer.push(Column::new("prefix", String, Optional, |e, _| {
+                string(Some(&e.prefix?.to_string()))
+            }));
+        }
+        if columns.asn {
+            buffer.push(Column::new("asn", Int64, List, |e, _| {
+                let asns = e.asn.as_ref()?;
+                Some(asns.iter().map(|asn| Value::Int64(*asn as i64)).collect())
+            }));
+        }
+        if columns.rpki {
+            buffer.push(Column::new("rpki", String, Optional, |e, _| {
+                string(Some(&e.rpki?.to_string()))
+            }));
+        }
+        if columns.provider {
+            buffer.push(Column::new("provider", String, Optional, |e, _| {
+                string(e.provider.as_deref())
+            }));
+        }
+        if columns.org {
+            buffer.push(Column::new("org", String, List, |e, _| strings(e.org.as_ref())));
+        }
+        if columns.country {
+            buffer.push(Column::new("country", String, Optional, |e, _| {
+                string(e.country.as_deref())
+            }));
+        }
+        buffer.retain(|column| !excluded.contains(&column.name));
+        let fields: Vec<std::string::String> = buffer.iter().map(Column::schema).collect();
+        let schema = parse_message_type(&format!("message mapping {{ {} }}", fields.join(" ")))
+            .map_err(parquet_error)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = SerializedFileWriter::new(writer, Arc::new(schema), Arc::new(properties))
+            .map_err(parquet_error)?;
+        Ok(ParquetSink {
+            writer: Some(writer),
+            columns,
+            buffer,
+            rows: 0,
+        })
+    }
+
+    /// Write the buffered mappings as a row group.
+    fn write_row_group(&mut self) -> io::Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Err(io::Error::other("parquet file closed")),
+        };
+        let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+        for column in &mut self.buffer {
+            let mut column_writer = match row_group.next_column().map_err(parquet_error)? {
+                Some(column_writer) => column_writer,
+                None => break,
+            };
+            let values = std::mem::take(&mut column.values);
+            let def_levels = match column.repetition {
+                Repetition::Required => None,
+                _ => Some(column.def_levels.as_slice()),
+            };
+            let rep_levels = match column.repetition {
+                Repetition::List => Some(column.rep_levels.as_slice()),
+                _ => None,
+            };
+            match column.value_type {
+                ValueType::String => {
+                    let values: Vec<ByteArray> = values
+                        .into_iter()
+                        .filter_map(|v| if let Value::Bytes(v) = v { Some(v) } else { None })
+                        .collect();
+                    column_writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, def_levels, rep_levels)
+                }
+                ValueType::Int32 | ValueType::Date => {
+                    let values: Vec<i32> = values
+                        .into_iter()
+                        .filter_map(|v| if let Value::Int32(v) = v { Some(v) } else { None })
+                        .collect();
+                    column_writer
+                        .typed::<Int32Type>()
+                        .write_batch(&values, def_levels, rep_levels)
+                }
+                ValueType::Int64 => {
+                    let values: Vec<i64> = values
+                        .into_iter()
+                        .filter_map(|v| if let Value::Int64(v) = v { Some(v) } else { None })
+                        .collect();
+                    column_writer
+                        .typed::<Int64Type>()
+                        .write_batch(&values, def_levels, rep_levels)
+                }
+            }
+            .map_err(parquet_error)?;
+            column_writer.close().map_err(parquet_error)?;
+            column.def_levels.clear();
+            column.rep_levels.clear();
+        }
+        row_group.close().map_err(parquet_error)?;
+        self.rows = 0;
+        Ok(())
+    }
+}
+
+impl MappingSink for ParquetSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        let row: Vec<Option<Vec<Value>>> = self
+            .buffer
+            .iter()
+            .map(|column| (column.extract)(&entry, self.columns))
+            .collect();
+        for (column, values) in self.buffer.iter().zip(&row) {
+            if column.repetition == Repetition::Required && values.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {} for {}", column.name, entry.host),
+                ));
+            }
+        }
+        for (column, values) in self.buffer.iter_mut().zip(row) {
+            column.push(values);
+        }
+        self.rows += 1;
+        if self.rows >= ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Write the last row group and the footer; the sink cannot be written
+    /// to afterwards.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.rows > 0 {
+            self.write_row_group()?;
+        }
+        match self.writer.take() {
+            Some(writer) => writer.close().map(|_| ()).map_err(parquet_error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sink writing the mappings into a Hive-style directory tree of Parquet
+/// files, e.g. `index_id=CC-MAIN-2020-50/date=2020-11-26/part-cc-main-2020-50.parquet`,
+/// that Athena, Spark and DuckDB (`hive_partitioning`) query in place. The
+/// files have the columns of a [ParquetSink], without the `date` and
+/// `country` columns when they are partition keys. Files are named after
+/// the index, so that crawls of several indexes can share a tree.
+///
+/// A file is kept open, with its buffered row group, for every partition
+/// written to, so keys with many values, such as `tld`, use more memory.
+pub struct PartitionedParquetSink {
+    dir: PathBuf,
+    /// Store and URL of the directory if the files are uploaded
+    store: Option<(Arc<ObjectStore>, ObjectUrl)>,
+    index_id: String,
+    keys: Vec<PartitionKey>,
+    columns: CsvColumns,
+    partitions: HashMap<String, Box<dyn MappingSink>>,
+}
+
+impl PartitionedParquetSink {
+    /// Create a sink writing the mappings of a crawl of `index_id` under
+    /// the directory `dir`, partitioned by the given keys.
+    pub fn create(
+        dir: &str,
+        index_id: &str,
+        keys: Vec<PartitionKey>,
+        columns: CsvColumns,
+    ) -> io::Result<PartitionedParquetSink> {
+        fs::create_dir_all(dir)?;
+        Ok(PartitionedParquetSink {
+            dir: PathBuf::from(dir),
+            store: None,
+            index_id: index_id.to_owned(),
+            keys,
+            columns,
+            partitions: HashMap::new(),
+        })
+    }
+
+    /// Create a sink uploading the files under the directory `url` of an
+    /// object store instead.
+    pub fn in_object_store(
+        store: Arc<ObjectStore>,
+        url: ObjectUrl,
+        index_id: &str,
+        keys: Vec<PartitionKey>,
+        columns: CsvColumns,
+    ) -> PartitionedParquetSink {
+        PartitionedParquetSink {
+            dir: PathBuf::new(),
+            store: Some((store, url)),
+            index_id: index_id.to_owned(),
+            keys,
+            columns,
+            partitions: HashMap::new(),
+        }
+    }
+
+    /// Create the file of a partition directory.
+    fn create_partition(&self, directory: &str) -> io::Result<Box<dyn MappingSink>> {
+        let name = format!("part-{}.parquet", self.index_id.to_lowercase());
+        let excluded: Vec<&str> = self.keys.iter().map(|key| key.name()).collect();
+        let columns = self.columns;
+        match &self.store {
+            Some((store, url)) => {
+                let url = url.join(&format!("{}/{}", directory, name));
+                Ok(Box::new(UploadSink::create(store, &url, |writer| {
+                    Ok(Box::new(ParquetSink::without(writer, columns, &excluded)?))
+                })?))
+            }
+            None => {
+                let path = self.dir.join(directory);
+                fs::create_dir_all(&path)?;
+                let file = File::create(path.join(name))?;
+                Ok(Box::new(ParquetSink::without(Box::new(file), columns, &excluded)?))
+            }
+        }
+    }
+}
+
+impl MappingSink for PartitionedParquetSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        let directory = PartitionKey::directory(&self.keys, &entry, &self.index_id);
+        if !self.partitions.contains_key(&directory) {
+            let sink = self.create_partition(&directory)?;
+            self.partitions.insert(directory.clone(), sink);
+        }
+        self.partitions.get_mut(&directory).unwrap().write(entry)
+    }
+
+    /// Close the files of all partitions.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.partitions.values_mut() {
+            let flushed = sink.flush();
+            if result.is_ok() {
+                result = flushed;
+            }
+        }
+        result
+    }
+}
+
+fn parquet_error(e: parquet::errors::ParquetError) -> io::Error {
+    io::Error::other(e)
+}
@@ -0,0 +1,249 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Builder-style API for configuring and running a crawl.
+use crate::{
+    crawl_to_sink, crawl_with_options, get_newest_index_with, BreakerPolicy, CancelToken,
+    CrawlOptions, CrawlStats, Fetcher, HostFilter, IpFamily, MappingSink, ProgressMode,
+    ProgressObserver, RampUp, RecordFilter, RetryPolicy,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A configured crawl of one Common Crawl index, created with
+/// [Crawler::builder].
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let stats = Crawler::builder()
+///     .index_id("CC-MAIN-2020-50")
+///     .output("mapping.csv.gz")
+///     .num_threads(16)
+///     .max_requests_per_sec(50.0)
+///     .build()
+///     .run();
+/// println!("{}", stats);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Crawler {
+    index_id: Option<String>,
+    output: Option<String>,
+    options: CrawlOptions,
+}
+
+impl Crawler {
+    /// Start configuring a crawl. Without further settings, it crawls the
+    /// newest index into `mapping-INDEX_ID.csv.gz`.
+    pub fn builder() -> CrawlerBuilder {
+        CrawlerBuilder::default()
+    }
+
+    /// The options the crawl runs with.
+    pub fn options(&self) -> &CrawlOptions {
+        &self.options
+    }
+
+    /// Run the crawl to completion and return its statistics.
+    pub fn run(&self) -> CrawlStats {
+        let index_id = self.index_id();
+        let output = match &self.output {
+            Some(output) => output.clone(),
+            None => format!("mapping-{}.csv.gz", index_id.to_lowercase()),
+        };
+        crawl_with_options(index_id, output, &self.options)
+    }
+
+    /// Run the crawl to completion, writing the mappings to `sink` instead
+    /// of the output file.
+    pub fn run_to_sink(&self, sink: &mut dyn MappingSink) -> CrawlStats {
+        crawl_to_sink(&self.index_id(), sink, &self.options)
+    }
+
+    fn index_id(&self) -> String {
+        match &self.index_id {
+            Some(id) => id.clone(),
+            None => get_newest_index_with(&self.options).id,
+        }
+    }
+}
+
+/// Builder for a [Crawler]. Every setting is optional.
+#[derive(Debug, Clone, Default)]
+pub struct CrawlerBuilder {
+    index_id: Option<String>,
+    output: Option<String>,
+    options: CrawlOptions,
+}
+
+impl CrawlerBuilder {
+    /// Index to crawl, e.g. `CC-MAIN-2020-50`. Defaults to the newest index.
+    pub fn index_id(mut self, index_id: &str) -> Self {
+        self.index_id = Some(index_id.to_owned());
+        self
+    }
+
+    /// Output file, gzip-compressed if it ends with `.gz`.
+    pub fn output(mut self, output: &str) -> Self {
+        self.output = Some(output.to_owned());
+        self
+    }
+
+    /// Replace all crawl options at once, e.g. to start from options shared
+    /// between several crawls.
+    pub fn options(mut self, options: CrawlOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Number of crawling threads.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.options.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Only crawl hosts passing the filter.
+    pub fn host_filter(mut self, filter: HostFilter) -> Self {
+        self.options.host_filter = filter;
+        self
+    }
+
+    /// Only use index records passing the filter.
+    pub fn record_filter(mut self, filter: RecordFilter) -> Self {
+        self.options.record_filter = filter;
+        self
+    }
+
+    /// Only keep IP addresses of the given family.
+    pub fn ip_family(mut self, family: IpFamily) -> Self {
+        self.options.ip_family = Some(family);
+        self
+    }
+
+    /// Crawl at most `limit` host pointers.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.options.limit = Some(limit);
+        self
+    }
+
+    /// Crawl a random sample of `size` host pointers, reproducible if a seed
+    /// is given.
+    pub fn sample(mut self, size: usize, seed: Option<u64>) -> Self {
+        self.options.sample = Some(size);
+        self.options.seed = seed;
+        self
+    }
+
+    /// Crawl the host pointers in random order, reproducible with the seed
+    /// given to [CrawlerBuilder::sample], to spread the requests over the
+    /// cdx files.
+    pub fn shuffle(mut self) -> Self {
+        self.options.shuffle = true;
+        self
+    }
+
+    /// How failed requests are retried.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// When to pause or abort the crawl on sustained failures.
+    pub fn breaker(mut self, breaker: BreakerPolicy) -> Self {
+        self.options.breaker = breaker;
+        self
+    }
+
+    /// Maximum number of requests per second across all threads.
+    pub fn max_requests_per_sec(mut self, rate: f64) -> Self {
+        self.options.max_requests_per_sec = Some(rate);
+        self
+    }
+
+    /// Start at `initial_rate` requests per second and ramp up to the
+    /// maximum rate over `duration`, see [RampUp].
+    pub fn ramp_up(mut self, initial_rate: f64, duration: Duration) -> Self {
+        self.options.ramp_up = Some(RampUp {
+            initial_rate,
+            duration,
+        });
+        self
+    }
+
+    /// Maximum download bandwidth in bytes per second across all threads.
+    pub fn max_bytes_per_sec(mut self, rate: u64) -> Self {
+        self.options.max_bytes_per_sec = Some(rate);
+        self
+    }
+
+    /// Maximum number of concurrent requests to the same cdx or WARC file
+    /// across all threads.
+    pub fn max_requests_per_file(mut self, max: usize) -> Self {
+        self.options.max_requests_per_file = Some(max);
+        self
+    }
+
+    /// How progress is reported while crawling.
+    pub fn progress(mut self, progress: ProgressMode) -> Self {
+        self.options.progress = progress;
+        self
+    }
+
+    /// Callbacks following the progress of the crawl.
+    pub fn observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.options.observer = Some(observer);
+        self
+    }
+
+    /// Source of all downloads, e.g. canned responses in tests.
+    pub fn fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.options.fetcher = Some(fetcher);
+        self
+    }
+
+    /// Token to stop the crawl early from another thread.
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.options.cancel = token;
+        self
+    }
+
+    /// Finish configuring the crawl.
+    pub fn build(self) -> Crawler {
+        Crawler {
+            index_id: self.index_id,
+            output: self.output,
+            options: self.options,
+        }
+    }
+}
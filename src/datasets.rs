@@ -0,0 +1,268 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Download and caching of the enrichment datasets, so that the latest
+//! files do not have to be staged by hand.
+//!
+//! Each dataset is kept in a cache directory together with a manifest
+//! recording where it was downloaded from, when, and its SHA-256 digest.
+//! A cached file is used as long as it is younger than the refresh
+//! interval and still matches its digest; otherwise the latest file is
+//! looked up and downloaded again. Downloads are only accepted once their
+//! gzip checksums have been verified by decompressing them completely.
+use crate::{FetchResponse, Fetcher};
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PFX2AS_V4_URL: &str = "https://publicdata.caida.org/datasets/routing/routeviews-prefix2as/";
+const PFX2AS_V6_URL: &str = "https://publicdata.caida.org/datasets/routing/routeviews6-prefix2as/";
+const AS2ORG_URL: &str = "https://publicdata.caida.org/datasets/as-organizations/";
+
+/// Name of the manifest file in the cache directory.
+const MANIFEST: &str = "datasets.json";
+
+/// A dataset the [DatasetManager] can download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dataset {
+    /// CAIDA Routeviews prefix-to-AS of IPv4 prefixes
+    Pfx2AsV4,
+    /// CAIDA Routeviews prefix-to-AS of IPv6 prefixes
+    Pfx2AsV6,
+    /// CAIDA AS-to-organization
+    As2Org,
+}
+
+impl Dataset {
+    /// Name of the dataset in the manifest and in file names.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dataset::Pfx2AsV4 => "pfx2as-v4",
+            Dataset::Pfx2AsV6 => "pfx2as-v6",
+            Dataset::As2Org => "as2org",
+        }
+    }
+}
+
+impl fmt::Display for Dataset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A downloaded dataset, as recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    url: String,
+    file: String,
+    sha256: String,
+    /// Unix time of the download
+    fetched_at: u64,
+}
+
+/// Keeps the latest enrichment datasets in a cache directory.
+pub struct DatasetManager {
+    dir: PathBuf,
+    fetcher: Arc<dyn Fetcher>,
+    max_age: Duration,
+}
+
+impl DatasetManager {
+    /// Manage the datasets in `dir`, downloading with `fetcher` and
+    /// refreshing them when older than `max_age`.
+    pub fn new(dir: impl Into<PathBuf>, fetcher: Arc<dyn Fetcher>, max_age: Duration) -> DatasetManager {
+        DatasetManager {
+            dir: dir.into(),
+            fetcher,
+            max_age,
+        }
+    }
+
+    /// Path of an up-to-date copy of a dataset, downloading it if the cached
+    /// copy is missing, too old or corrupted. If the latest file cannot be
+    /// downloaded, a valid cached copy is used however old it is.
+    pub fn path(&self, dataset: Dataset) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let mut manifest = self.read_manifest();
+        let cached = manifest
+            .get(dataset.name())
+            .filter(|entry| self.verify(entry))
+            .cloned();
+        if let Some(entry) = &cached {
+            if age(entry) < self.max_age {
+                return Ok(self.dir.join(&entry.file));
+            }
+        }
+        match self.download(dataset) {
+            Ok(entry) => {
+                if let Some(old) = &cached {
+                    if old.file != entry.file {
+                        let _ = fs::remove_file(self.dir.join(&old.file));
+                    }
+                }
+                let path = self.dir.join(&entry.file);
+                manifest.insert(dataset.name().to_owned(), entry);
+                self.write_manifest(&manifest)?;
+                Ok(path)
+            }
+            Err(e) => match cached {
+                Some(entry) => {
+                    eprintln!("cannot refresh {}, using the cached copy: {}", dataset, e);
+                    Ok(self.dir.join(&entry.file))
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    fn read_manifest(&self) -> BTreeMap<String, ManifestEntry> {
+        fs::read(self.dir.join(MANIFEST))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_manifest(&self, manifest: &BTreeMap<String, ManifestEntry>) -> io::Result<()> {
+        let tmp = self.dir.join(format!(".{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_vec_pretty(manifest).map_err(io::Error::from)?)?;
+        fs::rename(tmp, self.dir.join(MANIFEST))
+    }
+
+    /// Check that a cached file still has the digest it was downloaded with.
+    fn verify(&self, entry: &ManifestEntry) -> bool {
+        match fs::read(self.dir.join(&entry.file)) {
+            Ok(content) => sha256_hex(&content) == entry.sha256,
+            Err(_) => false,
+        }
+    }
+
+    /// Download the latest file of a dataset into the cache directory.
+    fn download(&self, dataset: Dataset) -> io::Result<ManifestEntry> {
+        let url = self.latest_url(dataset)?;
+        let body = self.get(&url)?.body;
+        // reading the whole stream checks the CRC-32 of every gzip member
+        let mut decoder = MultiGzDecoder::new(&body[..]);
+        io::copy(&mut decoder, &mut io::sink())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", url, e)))?;
+        let file = format!("{}-{}", dataset.name(), url.rsplit('/').next().unwrap_or_default());
+        let tmp = self.dir.join(format!(".{}.tmp", file));
+        fs::write(&tmp, &body)?;
+        fs::rename(&tmp, self.dir.join(&file))?;
+        Ok(ManifestEntry {
+            url,
+            file,
+            sha256: sha256_hex(&body),
+            fetched_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+    }
+
+    /// Find the URL of the latest file of a dataset.
+    fn latest_url(&self, dataset: Dataset) -> io::Result<String> {
+        match dataset {
+            Dataset::Pfx2AsV4 | Dataset::Pfx2AsV6 => {
+                let base = match dataset {
+                    Dataset::Pfx2AsV4 => PFX2AS_V4_URL,
+                    _ => PFX2AS_V6_URL,
+                };
+                // `SEQNUM TIMESTAMP PATH` lines, oldest first
+                let log = self.get(&format!("{}pfx2as-creation.log", base))?;
+                let log = String::from_utf8_lossy(&log.body);
+                let path = log
+                    .lines()
+                    .rev()
+                    .filter(|line| !line.starts_with('#'))
+                    .find_map(|line| line.split_whitespace().nth(2))
+                    .ok_or_else(|| not_found(dataset))?;
+                Ok(format!("{}{}", base, path))
+            }
+            Dataset::As2Org => {
+                let listing = self.get(AS2ORG_URL)?;
+                let listing = String::from_utf8_lossy(&listing.body);
+                let pattern = Regex::new(r#"href="(\d{8}\.as-org2info\.(?:jsonl|txt)\.gz)""#).unwrap();
+                let file = pattern
+                    .captures_iter(&listing)
+                    .map(|captures| captures[1].to_owned())
+                    // newest date, preferring JSON lines to the older format
+                    .max_by_key(|file| (file[..8].to_owned(), file.contains(".jsonl.")))
+                    .ok_or_else(|| not_found(dataset))?;
+                Ok(format!("{}{}", AS2ORG_URL, file))
+            }
+        }
+    }
+
+    fn get(&self, url: &str) -> io::Result<FetchResponse> {
+        let response = self
+            .fetcher
+            .get(url)
+            .map_err(|e| io::Error::other(format!("{}: {}", url, e)))?;
+        if !response.status.is_success() {
+            return Err(io::Error::other(format!("{}: HTTP {}", url, response.status)));
+        }
+        Ok(response)
+    }
+}
+
+impl fmt::Debug for DatasetManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DatasetManager({})", self.dir.display())
+    }
+}
+
+fn not_found(dataset: Dataset) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no {} file published", dataset))
+}
+
+fn age(entry: &ManifestEntry) -> Duration {
+    let fetched_at = UNIX_EPOCH + Duration::from_secs(entry.fetched_at);
+    SystemTime::now().duration_since(fetched_at).unwrap_or_default()
+}
+
+pub(crate) fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
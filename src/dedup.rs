@@ -0,0 +1,258 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Dropping duplicate mappings before they reach the sink.
+use crate::MappingEntry;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Magic number and version of the files written by [BloomDedup::save].
+const BLOOM_MAGIC: &[u8; 5] = b"CCBF1";
+
+/// Largest value of the 4-bit counters; saturated counters are never
+/// decremented.
+const COUNTER_MAX: u8 = 15;
+
+/// Fingerprints of the last mappings written, for dropping duplicates
+/// within a bounded amount of memory: 16 bytes or so per mapping
+/// remembered, plus hash set overhead. Duplicates from retries and
+/// overlapping cdx ranges arrive close together, so forgetting the oldest
+/// fingerprints loses little.
+pub(crate) struct DedupWindow {
+    capacity: usize,
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl DedupWindow {
+    pub(crate) fn new(capacity: usize) -> DedupWindow {
+        DedupWindow {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember a mapping and check whether it is new. Mappings are keyed by
+    /// host, IP and date; rows differing only in an optional column count as
+    /// duplicates.
+    pub(crate) fn insert(&mut self, entry: &MappingEntry) -> bool {
+        let mut hasher = DefaultHasher::new();
+        entry.host.hash(&mut hasher);
+        entry.ip.hash(&mut hasher);
+        entry.timestr.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        if self.capacity == 0 || !self.seen.insert(fingerprint) {
+            return self.capacity == 0;
+        }
+        self.order.push_back(fingerprint);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Counting Bloom filter of the mappings written, for dropping exact
+/// duplicates over outputs too large to remember, e.g. across the shards or
+/// indexes of a merged pipeline. It takes 4 bits per counter, about
+/// `-ln(p) / ln(2)^2 / 2` bytes per expected mapping for a false-positive
+/// rate `p`: 0.6 bytes at 1%, 0.9 bytes at 0.1%. A false positive drops a
+/// mapping that was not a duplicate.
+///
+/// The counters allow removing mappings again, and the filter can be saved
+/// and loaded so that separate runs share it. Mappings are hashed with
+/// SHA-256, so saved filters stay valid across builds.
+#[derive(Clone)]
+pub struct BloomDedup {
+    counters: Vec<u8>,
+    slots: u64,
+    hashes: u32,
+    items: u64,
+}
+
+impl fmt::Debug for BloomDedup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BloomDedup")
+            .field("slots", &self.slots)
+            .field("hashes", &self.hashes)
+            .field("items", &self.items)
+            .finish()
+    }
+}
+
+impl BloomDedup {
+    /// Filter sized for `expected` distinct mappings at the given
+    /// false-positive rate, between 0 and 1.
+    pub fn new(expected: u64, false_positive_rate: f64) -> BloomDedup {
+        let rate = false_positive_rate.clamp(1e-12, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let slots = ((expected.max(1) as f64) * -rate.ln() / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let hashes = ((slots as f64 / expected.max(1) as f64) * ln2).round().clamp(1.0, 30.0);
+        BloomDedup {
+            counters: vec![0; slots.div_ceil(2) as usize],
+            slots,
+            hashes: hashes as u32,
+            items: 0,
+        }
+    }
+
+    /// Number of mappings inserted and not removed.
+    pub fn len(&self) -> u64 {
+        self.items
+    }
+
+    /// Whether no mapping was inserted.
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    /// Size of the counters in bytes.
+    pub fn size_bytes(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Counters of a key, by double hashing of its SHA-256 digest.
+    fn slots_of(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let digest = Sha256::digest(key);
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let slots = self.slots;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % slots)
+    }
+
+    fn counter(&self, slot: u64) -> u8 {
+        (self.counters[(slot / 2) as usize] >> ((slot % 2) * 4)) & 0x0f
+    }
+
+    fn set_counter(&mut self, slot: u64, value: u8) {
+        let shift = (slot % 2) * 4;
+        let byte = &mut self.counters[(slot / 2) as usize];
+        *byte = (*byte & !(0x0f << shift)) | (value << shift);
+    }
+
+    /// Check whether a key was probably inserted.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.slots_of(key).all(|slot| self.counter(slot) > 0)
+    }
+
+    /// Insert a key and check whether it is new. A key that was probably
+    /// inserted already is not counted again.
+    pub fn insert(&mut self, key: &[u8]) -> bool {
+        if self.contains(key) {
+            return false;
+        }
+        let slots: Vec<u64> = self.slots_of(key).collect();
+        for slot in slots {
+            let value = self.counter(slot);
+            self.set_counter(slot, value.saturating_add(1).min(COUNTER_MAX));
+        }
+        self.items += 1;
+        true
+    }
+
+    /// Remove a key inserted before, so that it is new again, and check
+    /// whether it was there.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        if !self.contains(key) {
+            return false;
+        }
+        let slots: Vec<u64> = self.slots_of(key).collect();
+        for slot in slots {
+            let value = self.counter(slot);
+            if value < COUNTER_MAX {
+                self.set_counter(slot, value - 1);
+            }
+        }
+        self.items = self.items.saturating_sub(1);
+        true
+    }
+
+    /// Insert a mapping and check whether it is new. All fields are
+    /// compared.
+    pub(crate) fn insert_mapping(&mut self, entry: &MappingEntry) -> bool {
+        self.insert(entry.to_csv().as_bytes())
+    }
+
+    /// Write the filter to a file, to be loaded by a later run.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(BLOOM_MAGIC)?;
+        writer.write_all(&self.hashes.to_le_bytes())?;
+        writer.write_all(&self.slots.to_le_bytes())?;
+        writer.write_all(&self.items.to_le_bytes())?;
+        writer.write_all(&self.counters)?;
+        writer.flush()
+    }
+
+    /// Read a filter written by [BloomDedup::save].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<BloomDedup> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 5];
+        reader.read_exact(&mut magic)?;
+        if &magic != BLOOM_MAGIC {
+            return Err(invalid("not a dedup filter"));
+        }
+        let mut word = [0; 4];
+        reader.read_exact(&mut word)?;
+        let hashes = u32::from_le_bytes(word);
+        let mut long = [0; 8];
+        reader.read_exact(&mut long)?;
+        let slots = u64::from_le_bytes(long);
+        reader.read_exact(&mut long)?;
+        let items = u64::from_le_bytes(long);
+        let mut counters = vec![];
+        reader.read_to_end(&mut counters)?;
+        if hashes == 0 || slots == 0 || counters.len() as u64 != slots.div_ceil(2) {
+            return Err(invalid("truncated dedup filter"));
+        }
+        Ok(BloomDedup {
+            counters,
+            slots,
+            hashes,
+            items,
+        })
+    }
+}
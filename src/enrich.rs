@@ -0,0 +1,536 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Annotation of mappings with routing data about their IPs, loaded from
+//! dataset files given by the user.
+use crate::{IpPrefix, MappingEntry};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Values keyed by IP prefix, with longest-prefix lookups.
+pub(crate) struct PrefixTable<T> {
+    /// Prefixes by length, keyed by their network address
+    v4: Vec<HashMap<u32, T>>,
+    v6: Vec<HashMap<u128, T>>,
+    len: usize,
+}
+
+impl<T> PrefixTable<T> {
+    pub(crate) fn new() -> PrefixTable<T> {
+        PrefixTable {
+            v4: (0..=32).map(|_| HashMap::new()).collect(),
+            v6: (0..=128).map(|_| HashMap::new()).collect(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The value of a prefix, inserted with `default` if missing.
+    pub(crate) fn entry_or_insert_with(&mut self, prefix: IpPrefix, default: impl FnOnce() -> T) -> &mut T {
+        let len = &mut self.len;
+        let insert = || {
+            *len += 1;
+            default()
+        };
+        match prefix.network {
+            IpAddr::V4(network) => self.v4[prefix.length as usize]
+                .entry(u32::from(network))
+                .or_insert_with(insert),
+            IpAddr::V6(network) => self.v6[prefix.length as usize]
+                .entry(u128::from(network))
+                .or_insert_with(insert),
+        }
+    }
+
+    pub(crate) fn get(&self, prefix: IpPrefix) -> Option<&T> {
+        match prefix.network {
+            IpAddr::V4(network) => self.v4[prefix.length as usize].get(&u32::from(network)),
+            IpAddr::V6(network) => self.v6[prefix.length as usize].get(&u128::from(network)),
+        }
+    }
+
+    /// The most specific prefix containing an IP, and its value.
+    pub(crate) fn longest_match(&self, ip: IpAddr) -> Option<(IpPrefix, &T)> {
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        self.covering(IpPrefix::of(ip, max)).last()
+    }
+
+    /// The prefixes containing a prefix, including itself, from the least
+    /// to the most specific, with their values.
+    pub(crate) fn covering(&self, prefix: IpPrefix) -> impl Iterator<Item = (IpPrefix, &T)> + '_ {
+        (0..=prefix.length).filter_map(move |length| {
+            let covering = IpPrefix::of(prefix.network, length);
+            self.get(covering).map(|value| (covering, value))
+        })
+    }
+}
+
+impl<T> Default for PrefixTable<T> {
+    fn default() -> Self {
+        PrefixTable::new()
+    }
+}
+
+fn invalid_data(path: &Path, number: usize, message: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: line {}: {}", path.display(), number + 1, message),
+    )
+}
+
+/// Origin ASes of routed prefixes, from a CAIDA Routeviews prefix-to-AS
+/// (pfx2as) file: `PREFIX<TAB>LENGTH<TAB>ASNS` lines, where multi-origin
+/// prefixes list their ASes separated by `_` and AS sets by `,`.
+pub struct Pfx2As {
+    table: PrefixTable<Vec<u32>>,
+}
+
+impl Pfx2As {
+    /// Load a pfx2as file, gzip-compressed if its name ends with `.gz`.
+    pub fn load(path: &Path) -> io::Result<Pfx2As> {
+        Pfx2As::load_all(&[path])
+    }
+
+    /// Load several pfx2as files into one table, e.g. the IPv4 and the IPv6
+    /// one.
+    pub fn load_all<P: AsRef<Path>>(paths: &[P]) -> io::Result<Pfx2As> {
+        let mut table = PrefixTable::new();
+        for path in paths {
+            Pfx2As::load_into(&mut table, path.as_ref())?;
+        }
+        Ok(Pfx2As { table })
+    }
+
+    fn load_into(table: &mut PrefixTable<Vec<u32>>, path: &Path) -> io::Result<()> {
+        let reader = crate::get_reader(&path.to_string_lossy())?;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (network, length, asns) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(network), Some(length), Some(asns)) => (network, length, asns),
+                _ => return Err(invalid_data(path, number, "expected PREFIX LENGTH ASNS")),
+            };
+            let prefix: IpPrefix = format!("{}/{}", network, length)
+                .parse()
+                .map_err(|e| invalid_data(path, number, e))?;
+            let origins: &mut Vec<u32> = table.entry_or_insert_with(prefix, Vec::new);
+            for asn in asns.split(['_', ',']) {
+                let asn = asn.parse().map_err(|e| invalid_data(path, number, e))?;
+                if !origins.contains(&asn) {
+                    origins.push(asn);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The most specific routed prefix containing an IP, and its origin
+    /// ASes.
+    pub fn lookup(&self, ip: IpAddr) -> Option<(IpPrefix, &[u32])> {
+        self.table
+            .longest_match(ip)
+            .map(|(prefix, origins)| (prefix, origins.as_slice()))
+    }
+}
+
+impl fmt::Debug for Pfx2As {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pfx2As({} prefixes)", self.table.len())
+    }
+}
+
+/// Route origin validation state of a route, RFC 6811.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RpkiValidity {
+    /// A VRP covers the route with its origin AS and a large enough
+    /// maximum length
+    Valid,
+    /// VRPs cover the route, but none matches
+    Invalid,
+    /// No VRP covers the route
+    NotFound,
+}
+
+impl fmt::Display for RpkiValidity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RpkiValidity::Valid => "valid",
+            RpkiValidity::Invalid => "invalid",
+            RpkiValidity::NotFound => "not-found",
+        })
+    }
+}
+
+impl std::str::FromStr for RpkiValidity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "valid" => Ok(RpkiValidity::Valid),
+            "invalid" => Ok(RpkiValidity::Invalid),
+            "not-found" => Ok(RpkiValidity::NotFound),
+            _ => Err(format!("expected valid, invalid or not-found, found {:?}", s)),
+        }
+    }
+}
+
+/// A validated ROA payload: an AS allowed to originate a prefix and its
+/// more specifics up to a maximum length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Vrp {
+    asn: u32,
+    max_length: u8,
+}
+
+/// Validated ROA payloads, from the CSV or JSON output of a relying party
+/// such as Routinator or rpki-client.
+pub struct VrpTable {
+    table: PrefixTable<Vec<Vrp>>,
+}
+
+impl VrpTable {
+    /// Load VRPs from `ASN,IP Prefix,Max Length[,Trust Anchor]` CSV lines,
+    /// e.g. `AS13335,1.0.0.0/24,24,apnic`, or from a JSON document with a
+    /// `roas` array of `asn`, `prefix` and `maxLength` objects.
+    pub fn load(path: &Path) -> io::Result<VrpTable> {
+        let content = fs::read_to_string(path)?;
+        let mut table = PrefixTable::new();
+        let mut insert = |prefix: IpPrefix, vrp: Vrp| {
+            let vrps: &mut Vec<Vrp> = table.entry_or_insert_with(prefix, Vec::new);
+            if !vrps.contains(&vrp) {
+                vrps.push(vrp);
+            }
+        };
+        if content.trim_start().starts_with('{') {
+            let document: Value = serde_json::from_str(&content).map_err(io::Error::from)?;
+            let roas = document["roas"].as_array().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "no roas array in VRP JSON")
+            })?;
+            for (number, roa) in roas.iter().enumerate() {
+                let parsed = (|| {
+                    let asn = match &roa["asn"] {
+                        Value::String(asn) => parse_asn(asn)?,
+                        asn => asn.as_u64().and_then(|asn| u32::try_from(asn).ok())?,
+                    };
+                    let prefix: IpPrefix = roa["prefix"].as_str()?.parse().ok()?;
+                    let max_length = match roa["maxLength"].as_u64() {
+                        Some(max_length) => u8::try_from(max_length).ok()?,
+                        None => prefix.length,
+                    };
+                    Some((prefix, Vrp { asn, max_length }))
+                })();
+                match parsed {
+                    Some((prefix, vrp)) => insert(prefix, vrp),
+                    None => return Err(invalid_data(path, number, format!("invalid ROA {}", roa))),
+                }
+            }
+        } else {
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with("ASN") || line.starts_with('#') {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                let parsed = (|| {
+                    let asn = parse_asn(fields.first()?)?;
+                    let prefix: IpPrefix = fields.get(1)?.parse().ok()?;
+                    let max_length = fields.get(2)?.parse().ok()?;
+                    Some((prefix, Vrp { asn, max_length }))
+                })();
+                match parsed {
+                    Some((prefix, vrp)) => insert(prefix, vrp),
+                    None => return Err(invalid_data(path, number, "expected ASN,PREFIX,MAX_LENGTH")),
+                }
+            }
+        }
+        Ok(VrpTable { table })
+    }
+
+    /// Validate a route, i.e. a prefix and its origin AS.
+    pub fn validate(&self, route: IpPrefix, origin: u32) -> RpkiValidity {
+        let mut covered = false;
+        for (_, vrps) in self.table.covering(route) {
+            covered = true;
+            if vrps
+                .iter()
+                .any(|vrp| vrp.asn != 0 && vrp.asn == origin && route.length <= vrp.max_length)
+            {
+                return RpkiValidity::Valid;
+            }
+        }
+        match covered {
+            true => RpkiValidity::Invalid,
+            false => RpkiValidity::NotFound,
+        }
+    }
+}
+
+impl fmt::Debug for VrpTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VrpTable({} prefixes)", self.table.len())
+    }
+}
+
+/// Parse an AS number written as `13335` or `AS13335`.
+fn parse_asn(asn: &str) -> Option<u32> {
+    let asn = asn.trim();
+    let digits = match asn.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("as") => &asn[2..],
+        _ => asn,
+    };
+    digits.parse().ok()
+}
+
+/// Organizations of ASes, from CAIDA's AS-to-organization dataset
+/// (as2org), in its JSON lines or older `|`-separated format.
+pub struct As2Org {
+    names: HashMap<u32, String>,
+}
+
+impl As2Org {
+    /// Load an as2org file, gzip-compressed if its name ends with `.gz`.
+    pub fn load(path: &Path) -> io::Result<As2Org> {
+        // ASes refer to organizations by id, listed before or after them
+        let mut org_names: HashMap<String, String> = HashMap::new();
+        let mut as_orgs: Vec<(u32, String)> = vec![];
+        let mut section = "";
+        let reader = crate::get_reader(&path.to_string_lossy())?;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with('{') {
+                let record: Value = serde_json::from_str(&line).map_err(|e| invalid_data(path, number, e))?;
+                let org_id = record["organizationId"].as_str().unwrap_or_default().to_owned();
+                match record["type"].as_str() {
+                    Some("Organization") => {
+                        let name = record["name"].as_str().unwrap_or_default();
+                        org_names.insert(org_id, name.to_owned());
+                    }
+                    Some("ASN") => {
+                        let asn = match &record["asn"] {
+                            Value::String(asn) => parse_asn(asn),
+                            asn => asn.as_u64().and_then(|asn| u32::try_from(asn).ok()),
+                        };
+                        let asn = asn.ok_or_else(|| invalid_data(path, number, "invalid asn"))?;
+                        as_orgs.push((asn, org_id));
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if let Some(format) = line.strip_prefix("# format:") {
+                section = if format.starts_with("aut|") { "aut" } else { "org" };
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('|').collect();
+            match (section, fields.as_slice()) {
+                ("org", [org_id, _, name, ..]) => {
+                    org_names.insert(org_id.to_string(), name.to_string());
+                }
+                ("aut", [asn, _, _, org_id, ..]) => {
+                    let asn = parse_asn(asn).ok_or_else(|| invalid_data(path, number, "invalid asn"))?;
+                    as_orgs.push((asn, org_id.to_string()));
+                }
+                _ => return Err(invalid_data(path, number, "expected an organization or AS record")),
+            }
+        }
+        let names = as_orgs
+            .into_iter()
+            .filter_map(|(asn, org_id)| Some((asn, org_names.get(&org_id)?.clone())))
+            .collect();
+        Ok(As2Org { names })
+    }
+
+    /// Name of the organization of an AS.
+    pub fn lookup(&self, asn: u32) -> Option<&str> {
+        self.names.get(&asn).map(String::as_str)
+    }
+}
+
+impl fmt::Debug for As2Org {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "As2Org({} ASes)", self.names.len())
+    }
+}
+
+/// IP ranges published by cloud and CDN providers, e.g. AWS `ip-ranges.json`,
+/// GCP `cloud.json`, Azure service tags, the Cloudflare `ips-v4` list or the
+/// Fastly `public-ip-list`.
+#[derive(Default)]
+pub struct ProviderRanges {
+    /// Index in `names` of the provider of each range
+    table: PrefixTable<usize>,
+    names: Vec<String>,
+}
+
+impl ProviderRanges {
+    pub fn new() -> ProviderRanges {
+        ProviderRanges::default()
+    }
+
+    /// Load the ranges of a provider. Every string of a JSON file that is a
+    /// prefix in CIDR notation is taken as a range, whatever the layout of
+    /// the file; other files list one range per line. Ranges of providers
+    /// loaded earlier are overwritten.
+    pub fn load(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        let mut ranges = vec![];
+        if content.trim_start().starts_with(['{', '[']) {
+            let document: Value = serde_json::from_str(&content).map_err(io::Error::from)?;
+            collect_prefixes(&document, &mut ranges);
+        } else {
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                ranges.push(line.parse().map_err(|e| invalid_data(path, number, e))?);
+            }
+        }
+        if ranges.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no IP ranges found", path.display()),
+            ));
+        }
+        let index = match self.names.iter().position(|known| known == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name.to_owned());
+                self.names.len() - 1
+            }
+        };
+        for range in ranges {
+            *self.table.entry_or_insert_with(range, || index) = index;
+        }
+        Ok(())
+    }
+
+    /// The provider of the most specific range containing an IP.
+    pub fn lookup(&self, ip: IpAddr) -> Option<&str> {
+        let (_, index) = self.table.longest_match(ip)?;
+        Some(&self.names[*index])
+    }
+}
+
+impl fmt::Debug for ProviderRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProviderRanges({} ranges of {:?})", self.table.len(), self.names)
+    }
+}
+
+/// Collect the strings of a JSON document that are IP prefixes.
+fn collect_prefixes(value: &Value, prefixes: &mut Vec<IpPrefix>) {
+    match value {
+        Value::String(s) => prefixes.extend(s.parse::<IpPrefix>().ok()),
+        Value::Array(values) => values.iter().for_each(|v| collect_prefixes(v, prefixes)),
+        Value::Object(map) => map.values().for_each(|v| collect_prefixes(v, prefixes)),
+        _ => {}
+    }
+}
+
+/// Datasets the mappings of a crawl are annotated with, see
+/// [crate::CrawlOptions::enricher].
+#[derive(Debug, Default)]
+pub struct Enricher {
+    /// Sets [MappingEntry::asn] to the origin ASes of the IP's route
+    pub pfx2as: Option<Pfx2As>,
+    /// Sets [MappingEntry::rpki] to the validity of the IP's route, found
+    /// in `pfx2as`
+    pub vrps: Option<VrpTable>,
+    /// Sets [MappingEntry::provider] to the cloud or CDN provider of the IP
+    pub providers: Option<ProviderRanges>,
+    /// Sets [MappingEntry::org] to the organizations of the ASes found in
+    /// `pfx2as`
+    pub as2org: Option<As2Org>,
+    /// Sets [MappingEntry::country] to the country of the IP
+    #[cfg(feature = "geoip")]
+    pub geoip: Option<crate::GeoIp>,
+}
+
+impl Enricher {
+    /// Annotate a mapping with the datasets that are loaded. IPs that are
+    /// not routed get no AS and no validity.
+    pub fn annotate(&self, entry: &mut MappingEntry) {
+        if let Some(providers) = &self.providers {
+            entry.provider = providers.lookup(entry.ip).map(str::to_owned);
+        }
+        #[cfg(feature = "geoip")]
+        if let Some(geoip) = &self.geoip {
+            entry.country = geoip.country(entry.ip);
+        }
+        let route = self.pfx2as.as_ref().and_then(|pfx2as| pfx2as.lookup(entry.ip));
+        if self.pfx2as.is_some() {
+            entry.asn = route.map(|(_, origins)| origins.to_vec());
+        }
+        if let (Some(as2org), Some((_, origins))) = (&self.as2org, route) {
+            let mut orgs: Vec<String> = vec![];
+            for org in origins.iter().filter_map(|origin| as2org.lookup(*origin)) {
+                if !orgs.iter().any(|known| known == org) {
+                    orgs.push(org.to_owned());
+                }
+            }
+            entry.org = Some(orgs).filter(|orgs| !orgs.is_empty());
+        }
+        if let (Some(vrps), Some((prefix, origins))) = (&self.vrps, route) {
+            // a route originated by several ASes is valid if one of them is
+            let validities = origins.iter().map(|origin| vrps.validate(prefix, *origin));
+            entry.rpki = validities
+                .min_by_key(|validity| match validity {
+                    RpkiValidity::Valid => 0,
+                    RpkiValidity::Invalid => 1,
+                    RpkiValidity::NotFound => 2,
+                });
+        }
+    }
+}
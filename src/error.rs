@@ -0,0 +1,132 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Errors reported for individual parts of a crawl.
+use crate::http::is_retryable;
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+
+/// Failure to crawl part of an index, or to download one of its files.
+/// Failures of single hosts are counted in the [crate::CrawlStats] and do
+/// not stop the crawl.
+///
+/// Every error is either retryable or fatal, see [CrawlError::is_retryable].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrawlError {
+    /// The index records of a host pointer could not be fetched
+    HostFailed { host: String, cause: Box<CrawlError> },
+    /// No response was received, e.g. the connection failed or timed out
+    Request { url: String, reason: String },
+    /// The server answered with an error status
+    Status { url: String, status: u16 },
+    /// The response was incomplete: a truncated body, a different range
+    /// than requested, or a gzip stream that does not terminate cleanly
+    Truncated { url: String },
+    /// Downloaded data could not be parsed
+    Parse(ParseError),
+    /// The request was not sent because the crawl was aborted by the
+    /// circuit breaker
+    Aborted,
+}
+
+impl CrawlError {
+    /// Whether the failure may go away when the request is repeated:
+    /// connection failures and timeouts, throttling (429), server errors and
+    /// incomplete responses. Other errors, such as a 404 for a missing index
+    /// or malformed data, are fatal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CrawlError::HostFailed { cause, .. } => cause.is_retryable(),
+            CrawlError::Request { .. } | CrawlError::Truncated { .. } => true,
+            CrawlError::Status { status, .. } => {
+                StatusCode::from_u16(*status).is_ok_and(is_retryable)
+            }
+            CrawlError::Parse(_) | CrawlError::Aborted => false,
+        }
+    }
+}
+
+impl fmt::Display for CrawlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrawlError::HostFailed { host, cause } => {
+                write!(f, "failed to fetch index records for {}: {}", host, cause)
+            }
+            CrawlError::Request { url, reason } => {
+                write!(f, "request for {} failed: {}", url, reason)
+            }
+            CrawlError::Status { url, status } => {
+                write!(f, "request for {} failed with status {}", url, status)
+            }
+            CrawlError::Truncated { url } => {
+                write!(f, "incomplete response for {}", url)
+            }
+            CrawlError::Parse(e) => e.fmt(f),
+            CrawlError::Aborted => write!(f, "crawl aborted"),
+        }
+    }
+}
+
+impl Error for CrawlError {}
+
+impl From<ParseError> for CrawlError {
+    fn from(e: ParseError) -> CrawlError {
+        CrawlError::Parse(e)
+    }
+}
+
+/// A malformed line in cluster.idx or a cdx file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What is wrong with the line
+    pub reason: String,
+}
+
+impl ParseError {
+    pub(crate) fn new<S: Into<String>>(reason: S) -> ParseError {
+        ParseError {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed line: {}", self.reason)
+    }
+}
+
+impl Error for ParseError {}
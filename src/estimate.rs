@@ -0,0 +1,128 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Dry-run cost estimation of a crawl.
+//!
+//! The estimate only downloads cluster.idx. The cdx range requests are known
+//! exactly from the selected pointers, while the number of WARC requests
+//! depends on how many distinct capture days each host has, which is only
+//! known after fetching the cdx ranges; it is approximated with
+//! [WARC_FETCHES_PER_POINTER].
+use crate::{select_host_pointers, CrawlOptions};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+/// Assumed average number of WARC record fetches triggered by one pointer.
+pub const WARC_FETCHES_PER_POINTER: f64 = 3.0;
+/// Assumed average latency of a single range request.
+pub const REQUEST_LATENCY: Duration = Duration::from_millis(400);
+
+/// Projected cost of crawling an index with a given set of [CrawlOptions].
+#[derive(Debug, Clone)]
+pub struct CrawlEstimate {
+    /// Host pointers selected for crawling
+    pub pointers: usize,
+    /// Distinct hosts among the selected pointers
+    pub hosts: usize,
+    /// Ranged requests to cdx index files, one per pointer
+    pub cdx_requests: u64,
+    /// Estimated ranged requests to WARC files
+    pub warc_requests: u64,
+    /// Estimated bytes to download
+    pub bytes: u64,
+    /// Number of threads the crawl would run with
+    pub threads: usize,
+    /// Projected wall-clock time of the crawl
+    pub duration: Duration,
+}
+
+impl CrawlEstimate {
+    /// Total number of data requests, cdx and WARC combined.
+    pub fn total_requests(&self) -> u64 {
+        self.cdx_requests + self.warc_requests
+    }
+}
+
+impl fmt::Display for CrawlEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pointers to crawl:     {}", self.pointers)?;
+        writeln!(f, "distinct hosts:        {}", self.hosts)?;
+        writeln!(
+            f,
+            "estimated requests:    {} ({} cdx + ~{} WARC)",
+            self.total_requests(),
+            self.cdx_requests,
+            self.warc_requests
+        )?;
+        writeln!(f, "estimated download:    {:.2} GB", self.bytes as f64 / 1e9)?;
+        write!(
+            f,
+            "estimated time:        {:.1} hours with {} threads",
+            self.duration.as_secs_f64() / 3600.0,
+            self.threads
+        )
+    }
+}
+
+/// Estimate the cost of a crawl without issuing any data requests.
+///
+/// Only cluster.idx is fetched, and all pointer selection options (filters,
+/// slice, sample, limit) are applied exactly as the crawl would.
+pub fn estimate_crawl(index_id: &str, options: &CrawlOptions) -> CrawlEstimate {
+    let (pointers, _) = select_host_pointers(index_id, options);
+
+    // shuffled or sampled pointers are not grouped by host
+    let hosts: HashSet<&str> = pointers.iter().map(|p| p.host.as_str()).collect();
+
+    let cdx_requests = pointers.len() as u64;
+    let cdx_bytes: u64 = pointers.iter().map(|p| p.range_length.max(0) as u64).sum();
+    let warc_requests = (cdx_requests as f64 * WARC_FETCHES_PER_POINTER).round() as u64;
+    let threads = options
+        .num_threads
+        .unwrap_or_else(rayon::current_num_threads)
+        .max(1);
+    let duration = REQUEST_LATENCY.mul_f64((cdx_requests + warc_requests) as f64 / threads as f64);
+
+    CrawlEstimate {
+        pointers: pointers.len(),
+        hosts: hosts.len(),
+        cdx_requests,
+        warc_requests,
+        bytes: cdx_bytes + warc_requests * options.warc_read_bytes() as u64,
+        threads,
+        duration,
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Abstraction of the HTTP requests made by a crawl, so that tests can serve
+//! canned responses and other backends (local files, object stores) can be
+//! plugged in.
+use crate::http::{build_client, BandwidthLimiter, ThrottledReader};
+use crate::CrawlOptions;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderValue, RANGE};
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+/// A response returned by a [Fetcher].
+#[derive(Debug, Clone)]
+pub struct FetchResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// Failure to get a complete response from a [Fetcher].
+#[derive(Debug, Clone)]
+pub enum FetchError {
+    /// No response was received, e.g. the connection failed
+    Connect(String),
+    /// The request or the response body timed out
+    Timeout(String),
+    /// The response body could not be read completely
+    Body(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Connect(e) => write!(f, "request failed: {}", e),
+            FetchError::Timeout(e) => write!(f, "request timed out: {}", e),
+            FetchError::Body(e) => write!(f, "failed to read response body: {}", e),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+/// Source of the data a crawl downloads. Retries, rate limits and the
+/// circuit breaker are applied on top of it, so an implementation only
+/// issues single requests.
+///
+/// Set a custom fetcher with [CrawlOptions::fetcher]; by default a
+/// [ReqwestFetcher] is used.
+pub trait Fetcher: Send + Sync {
+    /// Get a whole resource.
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError>;
+
+    /// Get the inclusive byte range `start..=end` of a resource. A complete
+    /// response has status 206 and, optionally, a `Content-Range` header.
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError>;
+}
+
+impl fmt::Debug for dyn Fetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Fetcher")
+    }
+}
+
+/// [Fetcher] issuing HTTP requests with a reqwest client.
+pub struct ReqwestFetcher {
+    client: Client,
+    /// Limit on the bandwidth of all the requests of the fetcher
+    bandwidth: BandwidthLimiter,
+}
+
+impl ReqwestFetcher {
+    /// Create a fetcher using the given client, without bandwidth limit.
+    pub fn new(client: Client) -> ReqwestFetcher {
+        ReqwestFetcher {
+            client,
+            bandwidth: BandwidthLimiter::new(None),
+        }
+    }
+
+    /// Create a fetcher with a client configured from the [CrawlOptions],
+    /// see [build_client], reading the response bodies no faster than
+    /// [CrawlOptions::max_bytes_per_sec] over all its requests.
+    pub fn from_options(options: &CrawlOptions) -> reqwest::Result<ReqwestFetcher> {
+        Ok(ReqwestFetcher {
+            client: build_client(options)?,
+            bandwidth: BandwidthLimiter::new(options.max_bytes_per_sec),
+        })
+    }
+
+    fn send(&self, url: &str, range: Option<HeaderValue>) -> Result<FetchResponse, FetchError> {
+        let mut request = self.client.get(url);
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let rsp = request.send().map_err(|e| match e.is_timeout() {
+            true => FetchError::Timeout(e.to_string()),
+            false => FetchError::Connect(e.to_string()),
+        })?;
+        let status = rsp.status();
+        let headers = rsp.headers().clone();
+        let mut body = Vec::with_capacity(rsp.content_length().unwrap_or(0) as usize);
+        ThrottledReader::new(rsp, &self.bandwidth)
+            .read_to_end(&mut body)
+            .map_err(body_error)?;
+        Ok(FetchResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Classify an error reading a response body, which reqwest wraps in an
+/// [io::Error].
+fn body_error(e: io::Error) -> FetchError {
+    let timed_out = e.kind() == io::ErrorKind::TimedOut
+        || e.get_ref()
+            .and_then(|e| e.downcast_ref::<reqwest::Error>())
+            .is_some_and(reqwest::Error::is_timeout);
+    match timed_out {
+        true => FetchError::Timeout(e.to_string()),
+        false => FetchError::Body(e.to_string()),
+    }
+}
+
+impl Fetcher for ReqwestFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        self.send(url, None)
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        let range = HeaderValue::from_str(&format!("bytes={}-{}", start, end)).unwrap();
+        self.send(url, Some(range))
+    }
+}
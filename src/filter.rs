@@ -0,0 +1,300 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Filters deciding which hosts and records make it into the crawl.
+use crate::{CdxLine, IpPrefix};
+use chrono::NaiveDate;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Hostname include/exclude rules, applied to the hostnames reconstructed
+/// from cluster.idx entries.
+///
+/// A host passes the filter if it matches `include` (when set) and does not
+/// match `exclude` (when set).
+#[derive(Debug, Clone, Default)]
+pub struct HostFilter {
+    /// Only keep hosts matching this pattern, e.g. `^mail\.`
+    pub include: Option<Regex>,
+    /// Drop hosts matching this pattern, e.g. `\.blogspot\.`
+    pub exclude: Option<Regex>,
+}
+
+impl HostFilter {
+    /// Check whether the given hostname passes the filter.
+    pub fn matches(&self, host: &str) -> bool {
+        if let Some(include) = &self.include {
+            if !include.is_match(host) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(host) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Rules applied to the index records found for a host, deciding which
+/// captures get their IP resolved.
+#[derive(Debug, Clone, Default)]
+pub struct RecordFilter {
+    /// Only resolve records with one of these MIME types, e.g. `text/html`.
+    /// Empty means no restriction.
+    pub mime_include: Vec<String>,
+    /// Never resolve records with one of these MIME types
+    pub mime_exclude: Vec<String>,
+    /// Only resolve records fetched from one of these ports, e.g. 443.
+    /// Empty means no restriction.
+    pub ports: Vec<u16>,
+    /// Whether captures of robots.txt files are resolved
+    pub robots: RobotsMode,
+    /// Only resolve records captured on or after this day
+    pub from: Option<NaiveDate>,
+    /// Only resolve records captured on or before this day
+    pub to: Option<NaiveDate>,
+    /// Resolve at most this many captures per host, chosen by
+    /// `capture_selection`. `None` means no limit.
+    pub max_captures: Option<usize>,
+    /// Which captures are kept when a host has more than `max_captures`
+    pub capture_selection: CaptureSelection,
+}
+
+/// Choice of the captures resolved for a host with more captures than
+/// [RecordFilter::max_captures].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureSelection {
+    /// The earliest captures
+    #[default]
+    First,
+    /// The latest captures
+    Last,
+    /// Captures spread evenly over the months of the crawl, earliest first
+    /// within each month
+    Stratified,
+}
+
+impl std::str::FromStr for CaptureSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(CaptureSelection::First),
+            "last" => Ok(CaptureSelection::Last),
+            "stratified" => Ok(CaptureSelection::Stratified),
+            _ => Err(format!("expected first, last or stratified, found {:?}", s)),
+        }
+    }
+}
+
+/// Treatment of captures of `/robots.txt`, which the crawler fetches for
+/// every host and which are sometimes served by other front-ends than the
+/// content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RobotsMode {
+    /// Resolve robots.txt captures like any other
+    #[default]
+    Include,
+    /// Skip robots.txt captures
+    Exclude,
+    /// Only resolve robots.txt captures
+    Only,
+}
+
+/// Check whether a captured URL is the robots.txt file of its host.
+pub fn is_robots_url(url: &str) -> bool {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+        None => url,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    path.eq_ignore_ascii_case("/robots.txt")
+}
+
+impl RecordFilter {
+    /// Check a record's MIME types against the filter. Both the declared
+    /// `mime` and the `mime-detected` fields of the record are considered.
+    pub fn matches_mime(&self, mime: &str, mime_detected: Option<&str>) -> bool {
+        let is_listed = |list: &[String]| {
+            list.iter()
+                .any(|m| m == mime || Some(m.as_str()) == mime_detected)
+        };
+        if !self.mime_include.is_empty() && !is_listed(&self.mime_include) {
+            return false;
+        }
+        !is_listed(&self.mime_exclude)
+    }
+
+    /// Check a record's URL against the [RobotsMode] of the filter.
+    pub fn matches_url(&self, url: &str) -> bool {
+        match self.robots {
+            RobotsMode::Include => true,
+            RobotsMode::Exclude => !is_robots_url(url),
+            RobotsMode::Only => is_robots_url(url),
+        }
+    }
+
+    /// Apply [RecordFilter::max_captures] to the records selected for a
+    /// host. The records kept are returned in capture order.
+    pub fn limit_captures(&self, mut records: Vec<CdxLine>) -> Vec<CdxLine> {
+        let max = match self.max_captures {
+            Some(max) if records.len() > max => max,
+            _ => return records,
+        };
+        records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        match self.capture_selection {
+            CaptureSelection::First => records.truncate(max),
+            CaptureSelection::Last => {
+                records.drain(..records.len() - max);
+            }
+            CaptureSelection::Stratified => {
+                let mut months: BTreeMap<String, Vec<CdxLine>> = BTreeMap::new();
+                for record in records.drain(..).rev() {
+                    months.entry(record.timestamp[..6].to_string()).or_default().push(record);
+                }
+                // take the earliest remaining capture of each month in
+                // turn, the vectors being in reverse order
+                while records.len() < max {
+                    for month in months.values_mut() {
+                        if records.len() < max {
+                            records.extend(month.pop());
+                        }
+                    }
+                }
+                records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            }
+        }
+        records
+    }
+
+    /// Check a record's capture time, as `YYYYMMDDhhmmss`, against the
+    /// `from` and `to` days of the filter.
+    pub fn matches_date(&self, timestamp: &str) -> bool {
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        let day = match timestamp.get(..8).map(|day| NaiveDate::parse_from_str(day, "%Y%m%d")) {
+            Some(Ok(day)) => day,
+            _ => return false,
+        };
+        self.from.is_none_or(|from| day >= from) && self.to.is_none_or(|to| day <= to)
+    }
+
+    /// Check a record's port, see [crate::CdxLine::port], against the
+    /// filter.
+    pub fn matches_port(&self, port: Option<u16>) -> bool {
+        match port {
+            _ if self.ports.is_empty() => true,
+            Some(port) => self.ports.contains(&port),
+            None => false,
+        }
+    }
+}
+
+/// IP address family used to restrict the output mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// Check whether the address belongs to this family.
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => ip.is_ipv4(),
+            IpFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// IPv4 special-purpose ranges (RFC 6890 and successors): private,
+/// loopback, link-local, shared, documentation, benchmarking, multicast
+/// and reserved space.
+const BOGONS_V4: [([u8; 4], u8); 15] = [
+    ([0, 0, 0, 0], 8),
+    ([10, 0, 0, 0], 8),
+    ([100, 64, 0, 0], 10),
+    ([127, 0, 0, 0], 8),
+    ([169, 254, 0, 0], 16),
+    ([172, 16, 0, 0], 12),
+    ([192, 0, 0, 0], 24),
+    ([192, 0, 2, 0], 24),
+    ([192, 88, 99, 0], 24),
+    ([192, 168, 0, 0], 16),
+    ([198, 18, 0, 0], 15),
+    ([198, 51, 100, 0], 24),
+    ([203, 0, 113, 0], 24),
+    ([224, 0, 0, 0], 4),
+    ([240, 0, 0, 0], 4),
+];
+
+/// IPv6 special-purpose ranges: unspecified, loopback, IPv4-mapped,
+/// local-use NAT64, discard, benchmarking, documentation, unique local,
+/// link-local, site-local and multicast space.
+const BOGONS_V6: [([u16; 8], u8); 12] = [
+    ([0, 0, 0, 0, 0, 0, 0, 0], 128),
+    ([0, 0, 0, 0, 0, 0, 0, 1], 128),
+    ([0, 0, 0, 0, 0, 0xffff, 0, 0], 96),
+    ([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48),
+    ([0x100, 0, 0, 0, 0, 0, 0, 0], 64),
+    ([0x2001, 2, 0, 0, 0, 0, 0, 0], 48),
+    ([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32),
+    ([0x3fff, 0, 0, 0, 0, 0, 0, 0], 20),
+    ([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+    ([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    ([0xfec0, 0, 0, 0, 0, 0, 0, 0], 10),
+    ([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+];
+
+/// Check whether an IP is in a private, reserved, documentation or other
+/// special-purpose range, i.e. cannot be the address of a public web
+/// server. Such IPs show up in WARC records of misconfigured origins.
+pub fn is_bogon(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => BOGONS_V4.iter().any(|(network, length)| {
+            IpPrefix::of(ip, *length).network == IpAddr::V4(Ipv4Addr::from(*network))
+        }),
+        IpAddr::V6(_) => BOGONS_V6.iter().any(|(network, length)| {
+            IpPrefix::of(ip, *length).network == IpAddr::V6(Ipv6Addr::from(*network))
+        }),
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Export of the bipartite graph between the hosts and IPs of a mapping
+//! file, for graph tools such as networkx or graph-tool.
+use crate::MappingEntry;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+/// File format of an exported [HostIpGraph].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// One `HOST IP` edge per line, followed by its weight if weighted, as
+    /// read by networkx `read_edgelist` and `read_weighted_edgelist`
+    Edges,
+    /// GraphML, with a `kind` attribute telling host and IP nodes apart and
+    /// an edge `weight` attribute if weighted
+    GraphMl,
+    /// One line per host followed by its IPs, as read by networkx
+    /// `read_adjlist`; always unweighted
+    Adjacency,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edges" => Ok(GraphFormat::Edges),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            "adjacency" => Ok(GraphFormat::Adjacency),
+            _ => Err(format!("expected edges, graphml or adjacency, found {:?}", s)),
+        }
+    }
+}
+
+/// Bipartite graph between hosts and the IPs they were mapped to, each
+/// edge weighted by the number of mappings, i.e. of captures unless the
+/// mapping file was deduplicated.
+#[derive(Debug, Clone, Default)]
+pub struct HostIpGraph {
+    edges: BTreeMap<String, BTreeMap<IpAddr, u64>>,
+}
+
+impl HostIpGraph {
+    /// Build the graph of mappings, e.g. from [crate::read_mapping_file].
+    /// The edges are held in memory.
+    pub fn from_mappings<I>(mappings: I) -> io::Result<HostIpGraph>
+    where
+        I: IntoIterator<Item = io::Result<MappingEntry>>,
+    {
+        let mut graph = HostIpGraph::default();
+        for mapping in mappings {
+            let mapping = mapping?;
+            let ips = graph.edges.entry(mapping.host).or_default();
+            *ips.entry(mapping.ip).or_insert(0) += 1;
+        }
+        Ok(graph)
+    }
+
+    /// Write the graph in the given format, with the edge weights if
+    /// `weighted` and the format has them.
+    pub fn write(
+        &self,
+        writer: &mut dyn Write,
+        format: GraphFormat,
+        weighted: bool,
+    ) -> io::Result<()> {
+        match format {
+            GraphFormat::Edges => self.write_edges(writer, weighted),
+            GraphFormat::GraphMl => self.write_graphml(writer, weighted),
+            GraphFormat::Adjacency => self.write_adjacency(writer),
+        }
+    }
+
+    fn write_edges(&self, writer: &mut dyn Write, weighted: bool) -> io::Result<()> {
+        for (host, ips) in &self.edges {
+            for (ip, weight) in ips {
+                match weighted {
+                    true => writeln!(writer, "{} {} {}", host, ip, weight)?,
+                    false => writeln!(writer, "{} {}", host, ip)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_adjacency(&self, writer: &mut dyn Write) -> io::Result<()> {
+        for (host, ips) in &self.edges {
+            write!(writer, "{}", host)?;
+            for ip in ips.keys() {
+                write!(writer, " {}", ip)?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Host nodes get the ids `h0`, `h1`... and IP nodes `i0`, `i1`..., so
+    /// that hosts written as IP addresses do not clash with IPs.
+    fn write_graphml(&self, writer: &mut dyn Write, weighted: bool) -> io::Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(writer, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+        writeln!(
+            writer,
+            r#"  <key id="name" for="node" attr.name="name" attr.type="string"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="kind" for="node" attr.name="kind" attr.type="string"/>"#
+        )?;
+        if weighted {
+            writeln!(
+                writer,
+                r#"  <key id="weight" for="edge" attr.name="weight" attr.type="long"/>"#
+            )?;
+        }
+        writeln!(writer, r#"  <graph id="hosts-ips" edgedefault="undirected">"#)?;
+        let mut ip_ids: BTreeMap<IpAddr, usize> = BTreeMap::new();
+        for ip in self.edges.values().flat_map(BTreeMap::keys) {
+            let id = ip_ids.len();
+            ip_ids.entry(*ip).or_insert(id);
+        }
+        for (id, host) in self.edges.keys().enumerate() {
+            write_node(writer, &format!("h{}", id), &xml_escape(host), "host")?;
+        }
+        for (ip, id) in &ip_ids {
+            write_node(writer, &format!("i{}", id), &ip.to_string(), "ip")?;
+        }
+        for (host_id, ips) in self.edges.values().enumerate() {
+            for (ip, weight) in ips {
+                let (source, target) = (format!("h{}", host_id), format!("i{}", ip_ids[ip]));
+                match weighted {
+                    true => writeln!(
+                        writer,
+                        r#"    <edge source="{}" target="{}"><data key="weight">{}</data></edge>"#,
+                        source, target, weight
+                    )?,
+                    false => writeln!(
+                        writer,
+                        r#"    <edge source="{}" target="{}"/>"#,
+                        source, target
+                    )?,
+                }
+            }
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")
+    }
+}
+
+fn write_node(writer: &mut dyn Write, id: &str, name: &str, kind: &str) -> io::Result<()> {
+    writeln!(
+        writer,
+        r#"    <node id="{}"><data key="name">{}</data><data key="kind">{}</data></node>"#,
+        id, name, kind
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
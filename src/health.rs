@@ -0,0 +1,272 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Health check of the Common Crawl endpoints a crawl depends on.
+//!
+//! One small request is sent to each of collinfo.json, cluster.idx, a cdx
+//! file and a WARC file, so that throttling by Common Crawl can be told
+//! apart from a broken network or proxy before starting a long crawl.
+use crate::{
+    build_client, cluster_idx_url, parse_cdx_line, warc_range, CrawlOptions, Index,
+    INDEX_SERVER_URL,
+};
+use flate2::read::MultiGzDecoder;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::RANGE;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+/// Bytes requested from the start of the first cdx file, enough for a few
+/// complete lines of its first block.
+const CDX_PROBE_BYTES: i64 = 16 * 1024;
+/// Bytes requested from the WARC record found in the cdx probe.
+const WARC_PROBE_BYTES: i64 = 1024;
+
+/// Outcome of one probe request.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    /// What was probed, e.g. `cluster.idx`
+    pub name: &'static str,
+    /// URL requested, empty if the probe was skipped
+    pub url: String,
+    /// HTTP status of the response, if one was received
+    pub status: Option<u16>,
+    /// Time until the response body was read
+    pub latency: Duration,
+    /// Why the probe failed or was skipped, if it did
+    pub error: Option<String>,
+}
+
+impl Probe {
+    /// A probe that could not be sent.
+    fn not_sent(name: &'static str, error: String) -> Probe {
+        Probe {
+            name,
+            url: String::new(),
+            status: None,
+            latency: Duration::from_secs(0),
+            error: Some(error),
+        }
+    }
+
+    /// Whether the probe got a successful response.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Whether the response has a status commonly used for throttling:
+    /// 403, 429 or 503.
+    pub fn is_throttled(&self) -> bool {
+        matches!(self.status, Some(403) | Some(429) | Some(503))
+    }
+
+    /// Whether no response was received at all, e.g. because of a DNS,
+    /// connection, proxy or TLS failure.
+    pub fn is_unreachable(&self) -> bool {
+        self.status.is_none() && !self.url.is_empty()
+    }
+}
+
+/// Outcome of all probes of [healthcheck].
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    /// Index the cluster.idx, cdx and WARC probes were sent for
+    pub index_id: Option<String>,
+    pub probes: Vec<Probe>,
+}
+
+impl HealthReport {
+    /// Whether all probes succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.probes.iter().all(Probe::is_ok)
+    }
+
+    /// Whether any probe was answered with a throttling status.
+    pub fn is_throttled(&self) -> bool {
+        self.probes.iter().any(Probe::is_throttled)
+    }
+
+    /// Whether no probe sent got a response.
+    pub fn is_unreachable(&self) -> bool {
+        self.probes
+            .iter()
+            .filter(|probe| !probe.url.is_empty())
+            .all(Probe::is_unreachable)
+    }
+
+    /// One-line conclusion drawn from the probes.
+    pub fn diagnosis(&self) -> &'static str {
+        if self.is_healthy() {
+            "all endpoints reachable"
+        } else if self.is_throttled() {
+            "Common Crawl is throttling or blocking requests, wait before crawling"
+        } else if self.is_unreachable() {
+            "no endpoint reachable, check the network connection and proxy settings"
+        } else {
+            "some endpoints failed, see above"
+        }
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(index_id) = &self.index_id {
+            writeln!(f, "index: {}", index_id)?;
+        }
+        for probe in &self.probes {
+            let status = probe
+                .status
+                .map_or_else(|| "-".to_owned(), |status| status.to_string());
+            write!(
+                f,
+                "{:<13} {:>3} {:>8.0}ms",
+                probe.name,
+                status,
+                probe.latency.as_secs_f64() * 1000.0
+            )?;
+            match &probe.error {
+                Some(error) => writeln!(f, "  {}", error)?,
+                None => writeln!(f, "  {}", probe.url)?,
+            }
+        }
+        write!(f, "{}", self.diagnosis())
+    }
+}
+
+/// Send the request and time it until the body is read.
+fn probe(name: &'static str, url: String, request: RequestBuilder) -> (Probe, Vec<u8>) {
+    let start = Instant::now();
+    let (status, result) = match request.send() {
+        Ok(rsp) => {
+            let status = rsp.status();
+            let body = rsp.bytes().map(|bytes| bytes.to_vec());
+            let result = match body {
+                Ok(_) if !status.is_success() => Err(format!("HTTP status {}", status)),
+                Ok(body) => Ok(body),
+                Err(e) => Err(format!("incomplete response: {}", e)),
+            };
+            (Some(status.as_u16()), result)
+        }
+        Err(e) => (None, Err(e.to_string())),
+    };
+    let latency = start.elapsed();
+    let (error, body) = match result {
+        Ok(body) => (None, body),
+        Err(error) => (Some(error), vec![]),
+    };
+    let probe = Probe {
+        name,
+        url,
+        status,
+        latency,
+        error,
+    };
+    (probe, body)
+}
+
+fn ranged(client: &Client, url: &str, start: i64, end: i64) -> RequestBuilder {
+    client
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", start, end))
+}
+
+/// Probe collinfo.json, then `HEAD` the cluster.idx of `index_id`, or of
+/// the newest index if `None`, and fetch the first bytes of its first cdx
+/// file and of the WARC record of the first capture listed there. Probes
+/// that depend on a failed one are skipped. The requests are sent with the
+/// connection settings of the [CrawlOptions], without retries.
+pub fn healthcheck(index_id: Option<&str>, options: &CrawlOptions) -> HealthReport {
+    let mut report = HealthReport {
+        index_id: index_id.map(str::to_owned),
+        probes: vec![],
+    };
+    let client = match build_client(options) {
+        Ok(client) => client,
+        Err(e) => {
+            let error = format!("invalid connection settings: {}", e);
+            report.probes.push(Probe::not_sent("client", error));
+            return report;
+        }
+    };
+
+    let url = format!("{}/collinfo.json", INDEX_SERVER_URL);
+    let (collinfo, body) = probe("collinfo.json", url.clone(), client.get(&url));
+    report.probes.push(collinfo);
+    if report.index_id.is_none() {
+        let mut indices: Vec<Index> = serde_json::from_slice(&body).unwrap_or_default();
+        indices.sort();
+        report.index_id = indices.into_iter().next().map(|index| index.id);
+    }
+    let index_id = match report.index_id.clone() {
+        Some(index_id) => index_id,
+        None => {
+            for name in &["cluster.idx", "cdx", "warc"] {
+                report.probes.push(Probe::not_sent(name, "skipped: no index".to_owned()));
+            }
+            return report;
+        }
+    };
+
+    let url = cluster_idx_url(&index_id, options);
+    report.probes.push(probe("cluster.idx", url.clone(), client.head(&url)).0);
+
+    let url = format!(
+        "{}/cc-index/collections/{}/indexes/cdx-00000.gz",
+        options.base_url(),
+        index_id
+    );
+    let (cdx, body) = probe("cdx", url.clone(), ranged(&client, &url, 0, CDX_PROBE_BYTES - 1));
+    report.probes.push(cdx);
+    // the range cuts the gzip member short, so read the lines before the cut
+    let record = BufReader::new(MultiGzDecoder::new(&*body))
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|line| parse_cdx_line(&line).ok())
+        .and_then(|line| warc_range(&line.record, options).ok());
+    match record {
+        Some((url, offset, length)) => {
+            let end = offset + length.min(WARC_PROBE_BYTES) - 1;
+            let request = ranged(&client, &url, offset, end);
+            report.probes.push(probe("warc", url, request).0);
+        }
+        None => {
+            let error = "skipped: no capture read from cdx".to_owned();
+            report.probes.push(Probe::not_sent("warc", error));
+        }
+    }
+    report
+}
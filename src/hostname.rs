@@ -0,0 +1,132 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Forms and canonicalization of the hostnames written to the output.
+/// Which form internationalized hostnames are written in. The index holds
+/// them as punycode A-labels, e.g. `xn--bcher-kva.example`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdnForm {
+    /// Keep the A-labels of the index
+    #[default]
+    Ascii,
+    /// Decode to Unicode U-labels, e.g. `bücher.example`
+    Unicode,
+    /// Keep the A-labels and add the Unicode form in
+    /// [crate::MappingEntry::host_unicode]
+    Both,
+}
+
+impl std::str::FromStr for IdnForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(IdnForm::Ascii),
+            "unicode" => Ok(IdnForm::Unicode),
+            "both" => Ok(IdnForm::Both),
+            _ => Err(format!("expected ascii, unicode or both, found {:?}", s)),
+        }
+    }
+}
+
+/// Unicode form of a hostname, or `None` if it has no valid punycode labels
+/// to decode.
+pub fn host_to_unicode(host: &str) -> Option<String> {
+    if !host.split('.').any(|label| label.len() > 4 && label[..4].eq_ignore_ascii_case("xn--")) {
+        return None;
+    }
+    match idna::domain_to_unicode(host) {
+        (unicode, Ok(())) => Some(unicode),
+        (_, Err(_)) => None,
+    }
+}
+
+/// Canonicalization applied to hostnames before they are resolved and
+/// written out. Nothing is changed by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HostNormalization {
+    /// Lowercase the hostname
+    pub lowercase: bool,
+    /// Strip trailing dots, e.g. `example.com.`
+    pub strip_trailing_dot: bool,
+    /// Strip a leading `www.` label, so that `www.example.com` and
+    /// `example.com` end up as the same host
+    pub fold_www: bool,
+    /// Drop syntactically invalid hostnames, see [is_valid_hostname]
+    pub reject_invalid: bool,
+}
+
+impl HostNormalization {
+    /// The canonical form of a hostname, or `None` if it is rejected.
+    pub fn apply(&self, host: &str) -> Option<String> {
+        let mut host = host.to_owned();
+        if self.lowercase {
+            host.make_ascii_lowercase();
+        }
+        if self.strip_trailing_dot {
+            host.truncate(host.trim_end_matches('.').len());
+        }
+        if self.fold_www {
+            let www = host.len() > 4 && host[..4].eq_ignore_ascii_case("www.");
+            if www && host[4..].contains('.') {
+                host.drain(..4);
+            }
+        }
+        if self.reject_invalid && !is_valid_hostname(&host) {
+            return None;
+        }
+        Some(host)
+    }
+}
+
+/// Check the syntax of a hostname: at most 253 characters, made of labels
+/// of 1 to 63 letters, digits, hyphens and underscores that do not start or
+/// end with a hyphen. Underscores are not allowed by RFC 952 but common in
+/// practice. A single trailing dot is accepted.
+pub fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() || host.len() > 253 {
+        return false;
+    }
+    host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
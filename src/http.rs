@@ -0,0 +1,839 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! HTTP access to Common Crawl: the shared client, ranged fetches with
+//! retries, and the rate limiter shared by all workers.
+use crate::fetcher::{FetchError, FetchResponse};
+use crate::{CrawlContext, CrawlCounters, CrawlError, CrawlOptions, Endpoint, FailureKind};
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use rand::Rng;
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE, RETRY_AFTER};
+use reqwest::{Proxy, StatusCode};
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Upper bound on a server-requested `Retry-After` pause.
+pub(crate) const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// How failed requests are retried.
+///
+/// Connection errors and retryable statuses (429 and 5xx) are retried up to
+/// `max_retries` times with exponential backoff starting at `base_delay`. If
+/// the server sends a `Retry-After` header, that delay is used instead and
+/// applied to all workers through the [RateLimiter].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries per request
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub base_delay: Duration,
+    /// Upper bound of the backoff delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff delay before the given retry, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.min(16));
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// Gradual start of a crawl: the request rate grows from `initial_rate` to
+/// the maximum rate over `duration`, instead of all workers firing at once.
+///
+/// Without a maximum rate, the ramp goes up to 100 times the initial rate,
+/// and the rate is unlimited once it is over. Throttling and server errors
+/// during the ramp set it back by a quarter of its duration.
+#[derive(Debug, Clone)]
+pub struct RampUp {
+    /// Requests per second at the start of the crawl
+    pub initial_rate: f64,
+    /// Time to reach the maximum rate without errors
+    pub duration: Duration,
+}
+
+/// Ratio of the final to the initial rate of a [RampUp] without maximum
+/// rate.
+const RAMP_UNLIMITED_FACTOR: f64 = 100.0;
+
+struct LimiterState {
+    next_slot: Instant,
+    paused_until: Option<Instant>,
+    /// Start of the ramp-up, moved forward by errors
+    ramp_start: Instant,
+}
+
+/// Rate limiter shared by all workers of a crawl.
+///
+/// It spaces requests to stay under an optional maximum request rate, and
+/// lets any worker pause all workers, e.g. when the server asks to back off
+/// with `Retry-After`.
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    ramp_up: Option<RampUp>,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing at most `max_per_sec` requests per second,
+    /// or an unlimited rate if `None`.
+    pub fn new(max_per_sec: Option<f64>) -> RateLimiter {
+        RateLimiter {
+            interval: max_per_sec
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            ramp_up: None,
+            state: Mutex::new(LimiterState {
+                next_slot: Instant::now(),
+                paused_until: None,
+                ramp_start: Instant::now(),
+            }),
+        }
+    }
+
+    /// Create the limiter of the rate settings of the [CrawlOptions].
+    pub fn from_options(options: &CrawlOptions) -> RateLimiter {
+        let limiter = RateLimiter::new(options.max_requests_per_sec);
+        match &options.ramp_up {
+            Some(ramp_up) => limiter.with_ramp_up(ramp_up.clone()),
+            None => limiter,
+        }
+    }
+
+    /// Start at a lower rate and ramp up, see [RampUp].
+    pub fn with_ramp_up(mut self, ramp_up: RampUp) -> RateLimiter {
+        if ramp_up.initial_rate > 0.0 && !ramp_up.duration.is_zero() {
+            self.ramp_up = Some(ramp_up);
+            self.state.get_mut().unwrap().ramp_start = Instant::now();
+        }
+        self
+    }
+
+    /// Interval between requests at `now`: the one of the ramp-up while it
+    /// lasts, else the one of the maximum rate.
+    fn interval_at(&self, state: &LimiterState, now: Instant) -> Option<Duration> {
+        let ramp_up = match &self.ramp_up {
+            Some(ramp_up) => ramp_up,
+            None => return self.interval,
+        };
+        let progress = now.saturating_duration_since(state.ramp_start).as_secs_f64()
+            / ramp_up.duration.as_secs_f64();
+        if progress >= 1.0 {
+            return self.interval;
+        }
+        let target = match self.interval {
+            Some(interval) => 1.0 / interval.as_secs_f64(),
+            None => ramp_up.initial_rate * RAMP_UNLIMITED_FACTOR,
+        };
+        if target <= ramp_up.initial_rate {
+            return self.interval;
+        }
+        // geometric growth, doubling the rate at regular steps
+        let rate = ramp_up.initial_rate * (target / ramp_up.initial_rate).powf(progress);
+        Some(Duration::from_secs_f64(1.0 / rate))
+    }
+
+    /// Record the outcome of a request: throttling and server errors set the
+    /// ramp-up back, if it is not over.
+    pub fn record(&self, success: bool) {
+        let ramp_up = match &self.ramp_up {
+            Some(ramp_up) if !success => ramp_up,
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.ramp_start) < ramp_up.duration {
+            state.ramp_start = (state.ramp_start + ramp_up.duration / 4).min(now);
+        }
+    }
+
+    /// Block until the calling worker may issue its next request.
+    pub fn acquire(&self) {
+        let wait = self.reserve();
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Reserve the next request slot and return how long the caller has to
+    /// wait before using it, for callers that cannot block the thread.
+    pub fn reserve(&self) -> Duration {
+        let now = Instant::now();
+        let at = {
+            let mut state = self.state.lock().unwrap();
+            let mut at = now;
+            if let Some(paused_until) = state.paused_until {
+                at = at.max(paused_until);
+            }
+            if let Some(interval) = self.interval_at(&state, now) {
+                at = at.max(state.next_slot);
+                state.next_slot = at + interval;
+            }
+            at
+        };
+        at.saturating_duration_since(now)
+    }
+
+    /// Pause all workers for the given duration. Overlapping pauses are not
+    /// added up, the later end wins.
+    pub fn pause_for(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut state = self.state.lock().unwrap();
+        state.paused_until = Some(match state.paused_until {
+            Some(current) => current.max(until),
+            None => until,
+        });
+    }
+}
+
+/// Bandwidth limit shared by all workers of a crawl.
+///
+/// Every chunk of a response body read through a [ThrottledReader] reserves
+/// its share of the allowed bandwidth, and the receiving worker sleeps until
+/// the reservation is over, so that it reads no faster than allowed.
+pub struct BandwidthLimiter {
+    bytes_per_sec: Option<f64>,
+    next_free: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    /// Create a limiter allowing at most `max_bytes_per_sec` bytes per second
+    /// across all workers, or unlimited bandwidth if `None`.
+    pub fn new(max_bytes_per_sec: Option<u64>) -> BandwidthLimiter {
+        BandwidthLimiter {
+            bytes_per_sec: max_bytes_per_sec.filter(|rate| *rate > 0).map(|rate| rate as f64),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `bytes` just received, blocking as long as needed to stay
+    /// under the limit.
+    pub fn consume(&self, bytes: usize) {
+        let rate = match self.bytes_per_sec {
+            Some(rate) if bytes > 0 => rate,
+            _ => return,
+        };
+        let now = Instant::now();
+        let until = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let until = (*next_free).max(now) + Duration::from_secs_f64(bytes as f64 / rate);
+            *next_free = until;
+            until
+        };
+        thread::sleep(until - now);
+    }
+}
+
+/// Reader accounting for every chunk read from `inner` in a
+/// [BandwidthLimiter].
+pub(crate) struct ThrottledReader<'a, R> {
+    inner: R,
+    limiter: &'a BandwidthLimiter,
+}
+
+impl<'a, R: Read> ThrottledReader<'a, R> {
+    pub(crate) fn new(inner: R, limiter: &'a BandwidthLimiter) -> ThrottledReader<'a, R> {
+        ThrottledReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.limiter.consume(read);
+        Ok(read)
+    }
+}
+
+/// Cap on the concurrent requests to the same file, shared by all workers
+/// of a crawl.
+///
+/// Many concurrent ranged requests to one cdx or WARC file get throttled
+/// sooner than the same requests spread over several files, so workers
+/// wait for a request to the file to finish rather than pile onto it.
+pub struct FileLimiter {
+    max_per_file: Option<usize>,
+    active: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+impl FileLimiter {
+    /// Create a limiter allowing at most `max_per_file` concurrent requests
+    /// to the same URL, or any number if `None`.
+    pub fn new(max_per_file: Option<usize>) -> FileLimiter {
+        FileLimiter {
+            max_per_file: max_per_file.filter(|max| *max > 0),
+            active: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a request to `url` may be sent; it counts as in flight
+    /// until the returned permit is dropped.
+    pub fn acquire(&self, url: &str) -> FilePermit<'_> {
+        let max = match self.max_per_file {
+            Some(max) => max,
+            None => return FilePermit { limiter: self, url: None },
+        };
+        let mut active = self.active.lock().unwrap();
+        while active.get(url).is_some_and(|count| *count >= max) {
+            active = self.released.wait(active).unwrap();
+        }
+        *active.entry(url.to_owned()).or_insert(0) += 1;
+        FilePermit {
+            limiter: self,
+            url: Some(url.to_owned()),
+        }
+    }
+}
+
+/// Request in flight to a file, see [FileLimiter::acquire].
+pub struct FilePermit<'a> {
+    limiter: &'a FileLimiter,
+    url: Option<String>,
+}
+
+impl Drop for FilePermit<'_> {
+    fn drop(&mut self) {
+        let url = match self.url.take() {
+            Some(url) => url,
+            None => return,
+        };
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&url) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&url);
+            }
+        }
+        self.limiter.released.notify_all();
+    }
+}
+
+/// User-Agent sent when none is configured. Common Crawl asks heavy users to
+/// identify themselves, so please set a contact in your own User-Agent for
+/// large crawls.
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/CAIDA/commoncrawl-host-ip-mapper)"
+);
+
+/// Client builder with the settings common to all requests.
+///
+/// Without an explicit proxy, reqwest picks up `HTTPS_PROXY`/`HTTP_PROXY`
+/// from the environment.
+fn client_builder(options: &CrawlOptions) -> reqwest::Result<ClientBuilder> {
+    let user_agent = options.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+    let mut builder = Client::builder().user_agent(user_agent);
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    Ok(builder)
+}
+
+/// Build the HTTP client shared by all workers of a crawl, configured with
+/// the connection settings of the [CrawlOptions].
+pub fn build_client(options: &CrawlOptions) -> reqwest::Result<Client> {
+    let mut builder = client_builder(options)?;
+    if let Some(timeout) = options.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder.build()
+}
+
+/// Client for downloading whole files such as collinfo.json or cluster.idx.
+/// Unlike [build_client] there is no overall request timeout, since these
+/// downloads can be large.
+pub(crate) fn bulk_client(options: &CrawlOptions) -> Client {
+    client_builder(options)
+        .expect("invalid proxy URL")
+        .timeout(None)
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+/// Parse a `Retry-After` header value, given either in seconds or as an
+/// HTTP date.
+pub(crate) fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Statuses counted as failures by the circuit breaker: throttling (403,
+/// 429) and server errors.
+pub(crate) fn is_outage(status: StatusCode) -> bool {
+    status == StatusCode::FORBIDDEN || is_retryable(status)
+}
+
+pub(crate) fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// The configured per-request delay plus a random jitter, so the requests
+/// of different workers do not line up into bursts.
+pub(crate) fn pace_delay(options: &CrawlOptions) -> Duration {
+    let mut delay = options.request_delay;
+    if options.request_jitter > Duration::from_secs(0) {
+        delay += options
+            .request_jitter
+            .mul_f64(rand::thread_rng().gen_range(0.0..=1.0));
+    }
+    delay
+}
+
+/// Sleep for the [pace_delay].
+fn pace(options: &CrawlOptions) {
+    let delay = pace_delay(options);
+    if delay > Duration::from_secs(0) {
+        thread::sleep(delay);
+    }
+}
+
+/// Send a GET request, for the inclusive byte range `start..=end` if `range`
+/// is given, retrying retryable failures according to the crawl's
+/// [RetryPolicy]. Every attempt is recorded in the crawl counters. Returns
+/// the first successful response, or the last error.
+fn send_with_retries(
+    url: &str,
+    range: Option<(i64, i64)>,
+    ctx: &CrawlContext,
+) -> Result<FetchResponse, CrawlError> {
+    let id = ctx.counters.next_fetch_id();
+    #[cfg(feature = "otel")]
+    let cx = crate::otel::fetch_context(id, url, range);
+    #[cfg(feature = "otel")]
+    let _attached = cx.clone().attach();
+    let result = send_attempts(id, url, range, ctx);
+    #[cfg(feature = "otel")]
+    crate::otel::finish(&cx, &result);
+    result
+}
+
+/// The attempts of [send_with_retries] for the fetch with the given ID.
+fn send_attempts(
+    id: u64,
+    url: &str,
+    range: Option<(i64, i64)>,
+    ctx: &CrawlContext,
+) -> Result<FetchResponse, CrawlError> {
+    let mut attempts = Attempts::new(url, ctx.options, ctx.counters, ctx.limiter);
+    loop {
+        pace(ctx.options);
+        let permit = ctx.breaker.wait_ready().map_err(|_| CrawlError::Aborted)?;
+        let file_permit = ctx.files.acquire(url);
+        ctx.limiter.acquire();
+        let sent = attempts.send();
+        let result = match range {
+            Some((start, end)) => ctx.fetcher.get_range(url, start, end),
+            None => ctx.fetcher.get(url),
+        };
+        drop(file_permit);
+        let outcome: &dyn fmt::Display = match &result {
+            Ok(rsp) => &rsp.status,
+            Err(e) => e,
+        };
+        attempts.log_slow(id, range, sent, outcome);
+        let error = match result {
+            Ok(rsp) => {
+                let status = rsp.status;
+                attempts.response(status, sent);
+                if status == StatusCode::FORBIDDEN {
+                    ctx.breaker.record_forbidden(permit);
+                } else {
+                    ctx.breaker.record(permit, !is_outage(status));
+                }
+                if status.is_success() {
+                    attempts.downloaded(rsp.body.len());
+                    return Ok(rsp);
+                }
+                attempts.status_error(status, &rsp.headers)
+            }
+            Err(FetchError::Body(_)) => {
+                ctx.breaker.record(permit, true);
+                attempts.truncated()
+            }
+            Err(FetchError::Connect(reason)) => {
+                ctx.breaker.record(permit, false);
+                attempts.request_error(reason, FailureKind::Connect)
+            }
+            Err(FetchError::Timeout(reason)) => {
+                ctx.breaker.record(permit, false);
+                attempts.request_error(reason, FailureKind::Timeout)
+            }
+        };
+        thread::sleep(attempts.backoff(error)?);
+    }
+}
+
+/// What went wrong in an attempt of a request.
+#[derive(Debug, Clone, Copy)]
+enum AttemptFailure {
+    Status,
+    Body,
+    NoResponse(FailureKind),
+}
+
+/// Bookkeeping of the attempts of one request, shared by the blocking and
+/// the async retry loops: records the outcome of each attempt in the crawl
+/// counters and decides whether, and after which delay, to try again.
+///
+/// Failures are only counted once the request fails for good, so that a
+/// request that succeeds on a retry counts as a retry but not as a failure.
+pub(crate) struct Attempts<'a> {
+    url: &'a str,
+    endpoint: Endpoint,
+    options: &'a CrawlOptions,
+    counters: &'a CrawlCounters,
+    limiter: &'a RateLimiter,
+    retry: u32,
+    retry_after: Option<Duration>,
+    failure: Option<AttemptFailure>,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(
+        url: &'a str,
+        options: &'a CrawlOptions,
+        counters: &'a CrawlCounters,
+        limiter: &'a RateLimiter,
+    ) -> Attempts<'a> {
+        Attempts {
+            url,
+            endpoint: Endpoint::of(url),
+            options,
+            counters,
+            limiter,
+            retry: 0,
+            retry_after: None,
+            failure: None,
+        }
+    }
+
+    /// Count a request about to be sent, returning when it was sent.
+    pub(crate) fn send(&self) -> Instant {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    /// Log the attempt sent at `sent` if it was slow, see
+    /// [log_slow_request].
+    pub(crate) fn log_slow(
+        &self,
+        id: u64,
+        range: Option<(i64, i64)>,
+        sent: Instant,
+        outcome: &dyn fmt::Display,
+    ) {
+        log_slow_request(self.options, id, self.url, range, sent.elapsed(), outcome, self.retry);
+    }
+
+    /// Record a response received to the attempt sent at `sent`.
+    pub(crate) fn response(&self, status: StatusCode, sent: Instant) {
+        self.counters.record_status(status.as_u16());
+        self.counters.record_response(self.endpoint, status.as_u16());
+        self.counters.record_latency(self.endpoint, sent.elapsed());
+        self.limiter.record(!is_outage(status));
+    }
+
+    /// Count the bytes of a successful response.
+    pub(crate) fn downloaded(&self, bytes: usize) {
+        self.counters.bytes_downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The error of a response with an error status, keeping its
+    /// `Retry-After` for [Attempts::backoff].
+    pub(crate) fn status_error(&mut self, status: StatusCode, headers: &HeaderMap) -> CrawlError {
+        self.failure = Some(AttemptFailure::Status);
+        self.retry_after = headers.get(RETRY_AFTER).and_then(parse_retry_after);
+        CrawlError::Status {
+            url: self.url.to_owned(),
+            status: status.as_u16(),
+        }
+    }
+
+    /// The error of a response whose body is incomplete.
+    pub(crate) fn truncated(&mut self) -> CrawlError {
+        self.failure = Some(AttemptFailure::Body);
+        CrawlError::Truncated {
+            url: self.url.to_owned(),
+        }
+    }
+
+    /// The error of a request that got no response, a
+    /// [FailureKind::Connect] or [FailureKind::Timeout].
+    pub(crate) fn request_error(&mut self, reason: String, kind: FailureKind) -> CrawlError {
+        self.limiter.record(false);
+        self.counters.record_no_response(self.endpoint);
+        self.failure = Some(AttemptFailure::NoResponse(kind));
+        CrawlError::Request {
+            url: self.url.to_owned(),
+            reason,
+        }
+    }
+
+    /// The delay before retrying after this error, or the error if it is
+    /// final, counting the failure then.
+    pub(crate) fn backoff(&mut self, error: CrawlError) -> Result<Duration, CrawlError> {
+        let policy = &self.options.retry;
+        if !error.is_retryable() || self.retry >= policy.max_retries {
+            self.count_failure();
+            return Err(error);
+        }
+        let mut delay = policy.backoff(self.retry);
+        if let Some(retry_after) = self.retry_after.take() {
+            // the server is overloaded for everyone, so hold back all workers
+            self.limiter.pause_for(retry_after.min(MAX_RETRY_AFTER));
+            delay = Duration::from_secs(0);
+        }
+        self.counters.retries.fetch_add(1, Ordering::Relaxed);
+        self.retry += 1;
+        #[cfg(feature = "otel")]
+        crate::otel::record_retry(self.retry, &error);
+        Ok(delay)
+    }
+
+    /// Count the failure of the last attempt as the failure of the request.
+    fn count_failure(&self) {
+        let counters = self.counters;
+        match self.failure {
+            Some(AttemptFailure::Status) => {
+                counters.status_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(AttemptFailure::Body) => {
+                counters.body_errors.fetch_add(1, Ordering::Relaxed);
+                counters.record_failure(self.endpoint, FailureKind::Truncated);
+            }
+            Some(AttemptFailure::NoResponse(kind)) => {
+                counters.connect_errors.fetch_add(1, Ordering::Relaxed);
+                counters.record_failure(self.endpoint, kind);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Log a request that took longer than [CrawlOptions::slow_request] at
+/// debug level, to find the stragglers holding up the end of a crawl.
+pub(crate) fn log_slow_request(
+    options: &CrawlOptions,
+    id: u64,
+    url: &str,
+    range: Option<(i64, i64)>,
+    elapsed: Duration,
+    outcome: &dyn fmt::Display,
+    retry: u32,
+) {
+    if options.slow_request.is_none_or(|threshold| elapsed < threshold) {
+        return;
+    }
+    let range = range
+        .map(|(start, end)| format!(" bytes {}-{}", start, end))
+        .unwrap_or_default();
+    log::debug!(
+        "slow request #{}: {}{} took {:.1}s, {} (retry {})",
+        id,
+        url,
+        range,
+        elapsed.as_secs_f64(),
+        outcome,
+        retry
+    );
+}
+
+/// Issue a GET request for a whole (small) resource and return the response
+/// body, recording the outcome in the crawl counters.
+pub(crate) fn fetch(url: &str, ctx: &CrawlContext) -> Result<Vec<u8>, CrawlError> {
+    send_with_retries(url, None, ctx).map(|rsp| rsp.body)
+}
+
+/// Range of the response body as announced by a `Content-Range: bytes
+/// first-last/total` header.
+fn content_range(headers: &HeaderMap) -> Option<(i64, i64, Option<i64>)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((
+        first.parse().ok()?,
+        last.parse().ok()?,
+        total.parse().ok(),
+    ))
+}
+
+/// Expected body length of a response to a request for the byte range
+/// `start..=end`, or `None` if the response does not cover exactly that
+/// range (clamped to the end of the file).
+pub(crate) fn expected_range_len(
+    status: StatusCode,
+    headers: &HeaderMap,
+    start: i64,
+    end: i64,
+) -> Option<i64> {
+    if status != StatusCode::PARTIAL_CONTENT {
+        // the server ignored the range and sent the whole file
+        return None;
+    }
+    match content_range(headers) {
+        Some((first, last, total)) => {
+            let end = total.map_or(end, |total| end.min(total - 1));
+            if first != start || last != end {
+                return None;
+            }
+            Some(last - first + 1)
+        }
+        None => Some(end - start + 1),
+    }
+}
+
+/// Body of a ranged response, if it covers exactly the requested range
+/// `start..=end` (clamped to the end of the file). Returns `None` if the
+/// response is truncated or misplaced.
+fn check_range(rsp: FetchResponse, start: i64, end: i64) -> Option<Vec<u8>> {
+    let expected = expected_range_len(rsp.status, &rsp.headers, start, end)?;
+    if rsp.body.len() as i64 != expected {
+        return None;
+    }
+    Some(rsp.body)
+}
+
+/// Issue a ranged GET request for the inclusive byte range `start..=end` and
+/// return the body after passing it through `check`, recording the outcome
+/// in the crawl counters, bodies rejected by `check` as `check_failure`.
+/// Truncated bodies and bodies rejected by `check` are retried like other
+/// retryable failures, and only counted if the last attempt fails. Returns
+/// the last error if all attempts fail.
+///
+/// Bodies are served from and stored in the crawl's cache, if any.
+fn fetch_range_checked<T>(
+    url: &str,
+    start: i64,
+    end: i64,
+    ctx: &CrawlContext,
+    check_failure: FailureKind,
+    check: impl Fn(&[u8]) -> Option<T>,
+) -> Result<T, CrawlError> {
+    let key = Some((start, end));
+    if let Some(body) = ctx.cache.and_then(|cache| cache.get(url, key)) {
+        if let Some(checked) = check(&body) {
+            ctx.counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(checked);
+        }
+    }
+
+    let policy = &ctx.options.retry;
+    let mut retry = 0;
+    loop {
+        let rsp = send_with_retries(url, Some((start, end)), ctx)?;
+        let failure = match check_range(rsp, start, end) {
+            Some(body) => match check(&body) {
+                Some(checked) => {
+                    if let Some(cache) = ctx.cache {
+                        cache.put(url, key, &body);
+                    }
+                    return Ok(checked);
+                }
+                None => check_failure,
+            },
+            None => FailureKind::Truncated,
+        };
+        if retry >= policy.max_retries {
+            ctx.counters.body_errors.fetch_add(1, Ordering::Relaxed);
+            ctx.counters.record_failure(Endpoint::of(url), failure);
+            return Err(CrawlError::Truncated { url: url.to_owned() });
+        }
+        ctx.counters.retries.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(policy.backoff(retry));
+        retry += 1;
+    }
+}
+
+/// Issue a ranged GET request and return the response body. Returns the last
+/// error if the request still fails, or the server does not answer with the
+/// complete range, after all retries.
+pub(crate) fn fetch_range(
+    url: &str,
+    start: i64,
+    end: i64,
+    ctx: &CrawlContext,
+) -> Result<Vec<u8>, CrawlError> {
+    fetch_range_checked(url, start, end, ctx, FailureKind::Truncated, |bytes| {
+        Some(bytes.to_vec())
+    })
+}
+
+/// Same as [fetch_range], but for a range holding complete gzip members,
+/// which are returned decompressed and concatenated. A gzip stream that
+/// does not terminate cleanly counts as a truncated response.
+pub(crate) fn fetch_gzip_range(
+    url: &str,
+    start: i64,
+    end: i64,
+    ctx: &CrawlContext,
+) -> Result<Vec<u8>, CrawlError> {
+    fetch_range_checked(url, start, end, ctx, FailureKind::Gzip, |bytes| {
+        let mut decoded = vec![];
+        MultiGzDecoder::new(bytes).read_to_end(&mut decoded).ok()?;
+        Some(decoded)
+    })
+}
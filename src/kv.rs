@@ -0,0 +1,180 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Embedded key-value store output: host -> sorted distinct `(date, ip)`
+//! pairs in a sled database, for services that need fast lookups without a
+//! database server.
+//!
+//! Values are `DATE,IP` lines. Mappings are grouped by host in memory and
+//! merged into the store in batches; the merge operator of the store keeps
+//! each value sorted and deduplicated, so crawls of several indexes can be
+//! written into the same store.
+use crate::{MappingEntry, MappingSink};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+
+/// Number of mappings grouped in memory before they are merged into the
+/// store.
+const KV_BATCH_MAPPINGS: usize = 200_000;
+
+/// Merge operator of the store: the sorted union of the `DATE,IP` lines of
+/// the old and new values.
+fn merge_pairs(_key: &[u8], old: Option<&[u8]>, new: &[u8]) -> Option<Vec<u8>> {
+    let mut lines: BTreeSet<&[u8]> = old.into_iter().flat_map(value_lines).collect();
+    lines.extend(value_lines(new));
+    Some(join_lines(lines))
+}
+
+fn value_lines(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value.split(|byte| *byte == b'\n').filter(|line| !line.is_empty())
+}
+
+fn join_lines<'a>(lines: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut value = vec![];
+    for line in lines {
+        value.extend_from_slice(line);
+        value.push(b'\n');
+    }
+    value
+}
+
+/// Open a store, creating it if needed.
+fn open_store(path: &Path) -> io::Result<sled::Db> {
+    let db = sled::open(path).map_err(io::Error::other)?;
+    db.set_merge_operator(merge_pairs);
+    Ok(db)
+}
+
+/// Sink writing host -> `(date, ip)` pairs into a sled store.
+pub struct KvSink {
+    db: sled::Db,
+    batch: HashMap<String, BTreeSet<String>>,
+    pending: usize,
+}
+
+impl KvSink {
+    /// Write into the store at `path`, creating it if needed. Existing
+    /// mappings are kept.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<KvSink> {
+        Ok(KvSink {
+            db: open_store(path.as_ref())?,
+            batch: HashMap::new(),
+            pending: 0,
+        })
+    }
+
+    /// Merge the mappings grouped in memory into the store.
+    fn write_batch(&mut self) -> io::Result<()> {
+        for (host, pairs) in self.batch.drain() {
+            let value = join_lines(pairs.iter().map(|pair| pair.as_bytes()));
+            self.db.merge(host.as_bytes(), value).map_err(io::Error::other)?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+impl MappingSink for KvSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        let pair = format!("{},{}", entry.timestr, entry.ip);
+        if self.batch.entry(entry.host).or_default().insert(pair) {
+            self.pending += 1;
+        }
+        if self.pending >= KV_BATCH_MAPPINGS {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Merge the last batch and write the store to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_batch()?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Read-only access to a store written by a [KvSink].
+pub struct KvStore {
+    db: sled::Db,
+}
+
+impl KvStore {
+    /// Open the store at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<KvStore> {
+        let path = path.as_ref();
+        if !is_kv_store(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no key-value store in {}", path.display()),
+            ));
+        }
+        Ok(KvStore {
+            db: open_store(path)?,
+        })
+    }
+
+    /// The `(date, ip)` pairs of a host, sorted, as written to the store.
+    pub fn get(&self, host: &str) -> io::Result<Vec<(String, String)>> {
+        let value = self.db.get(host.as_bytes()).map_err(io::Error::other)?;
+        Ok(value.as_deref().map(pairs).unwrap_or_default())
+    }
+
+    /// All hosts with their `(date, ip)` pairs, sorted by host.
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<(String, Vec<(String, String)>)>> {
+        self.db.iter().map(|item| {
+            let (host, value) = item.map_err(io::Error::other)?;
+            Ok((String::from_utf8_lossy(&host).into_owned(), pairs(&value)))
+        })
+    }
+}
+
+/// Pairs of a value of the store.
+fn pairs(value: &[u8]) -> Vec<(String, String)> {
+    value_lines(value)
+        .filter_map(|line| {
+            let line = String::from_utf8_lossy(line);
+            let (date, ip) = line.split_once(',')?;
+            Some((date.to_owned(), ip.to_owned()))
+        })
+        .collect()
+}
+
+/// Check whether a directory holds a sled store.
+pub fn is_kv_store(path: &Path) -> bool {
+    path.join("conf").is_file() && path.join("db").is_file()
+}
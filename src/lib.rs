@@ -43,29 +43,169 @@
 //! It also comes with very straightforward commandline user interface and
 //! progress bar on the current crawling process.
 use chrono::prelude::*;
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use rayon::prelude::*;
-use reqwest::{
-    self,
-    header::{HeaderValue, RANGE},
-};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::fs::File;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{
-    collections::HashSet,
-    io::{BufRead, BufReader, BufWriter, Write},
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, BufWriter, IntoInnerError, Write},
     net::IpAddr,
     thread,
 };
-use indicatif::{ProgressBar,ProgressStyle};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+mod breaker;
+mod cache;
+mod cancel;
+mod cassette;
+mod checkpoint;
+mod checksum;
+#[cfg(feature = "parquet")]
+mod columnar;
+mod crawler;
+mod datasets;
+mod dedup;
+mod enrich;
+mod error;
+mod estimate;
+mod fetcher;
+mod filter;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod graph;
+mod health;
+mod hostname;
+#[cfg(feature = "kv")]
+mod kv;
+mod http;
+mod local;
+mod lookup;
+mod manifest;
+mod metrics;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+mod object_store;
+mod observer;
+#[cfg(feature = "otel")]
+mod otel;
+mod parse;
+mod prefix;
+mod preflight;
+mod progress;
+mod report;
+mod seekable;
+mod select;
+mod sink;
+mod sort;
+mod stats;
+mod watch;
+#[cfg(feature = "tui")]
+mod tui;
+pub use breaker::BreakerPolicy;
+use breaker::CircuitBreaker;
+pub use dedup::BloomDedup;
+use dedup::DedupWindow;
+pub use cache::{DiskCache, DEFAULT_CACHE_SIZE};
+pub use cancel::CancelToken;
+pub use cassette::{RecordingFetcher, ReplayFetcher};
+#[cfg(feature = "parquet")]
+pub use columnar::{ParquetSink, PartitionedParquetSink};
+pub use checkpoint::Checkpoint;
+pub use checksum::{file_sha256, write_checksums, CHECKSUM_EXTENSION};
+pub use crawler::{Crawler, CrawlerBuilder};
+pub use datasets::{Dataset, DatasetManager};
+pub use enrich::{As2Org, Enricher, Pfx2As, ProviderRanges, RpkiValidity, VrpTable};
+pub use error::{CrawlError, ParseError};
+pub use estimate::{estimate_crawl, CrawlEstimate};
+pub use fetcher::{FetchError, FetchResponse, Fetcher, ReqwestFetcher};
+pub use filter::{
+    is_bogon, is_robots_url, CaptureSelection, HostFilter, IpFamily, RecordFilter, RobotsMode,
+};
+#[cfg(feature = "geoip")]
+pub use geoip::GeoIp;
+pub use graph::{GraphFormat, HostIpGraph};
+pub use health::{healthcheck, HealthReport, Probe};
+pub use hostname::{host_to_unicode, is_valid_hostname, HostNormalization, IdnForm};
+use http::{bulk_client, fetch, fetch_gzip_range, fetch_range};
+pub use http::{
+    build_client, BandwidthLimiter, FileLimiter, FilePermit, RampUp, RateLimiter, RetryPolicy,
+    DEFAULT_USER_AGENT,
+};
+#[cfg(feature = "kv")]
+pub use kv::{is_kv_store, KvSink, KvStore};
+pub use local::{local_indices, LocalFetcher};
+pub use lookup::{lookup_rows, LookupKey};
+pub use manifest::{Manifest, ManifestFilters, ManifestShard, MANIFEST_NAME};
+pub use metrics::{prometheus_metrics, write_prometheus_metrics};
+#[cfg(feature = "otel")]
+pub use otel::{install_otlp_exporter, OtlpExporter};
+pub use object_store::{
+    is_object_url, Credentials, ObjectStore, ObjectUrl, ObjectWriter, DEFAULT_PART_SIZE,
+};
+pub use observer::ProgressObserver;
+pub use parse::{
+    parse_cdx_line, parse_idx_line, parse_surt_authority, parse_surt_host, parse_warc_headers,
+    parse_warc_ip, parse_warc_response, CdxLine, HttpHeaders, WarcHeaders,
+};
+use observer::ObserverHook;
+use parse::is_ip_host;
+pub use prefix::{IpPrefix, PrefixLengths, PrefixOutput};
+pub use preflight::{
+    preflight, PreflightError, PreflightReport, IDX_BYTES_PER_POINTER, OUTPUT_BYTES_PER_MAPPING,
+};
+pub use progress::ProgressMode;
+pub use report::{
+    churn_report, churn_report_with, concentration_report, host_tld, multi_ip_hosts,
+    read_mapping_file, tld_report, tld_report_with, write_group_report, AsHosts, ChurnReport,
+    ConcentrationReport, GroupCounter, GroupStats, HistogramBucket, HostSpread, SharedIp,
+    TldStats,
+};
+use progress::spawn_progress;
+pub use seekable::{SeekableFormat, SeekableWriter, INDEX_EXTENSION};
+pub use select::{sample_pointers, shuffle_pointers, slice_pointers};
+pub use sink::{CsvSink, MappingSink, OutputFormat, PartitionKey};
+use sink::{csv_field, split_csv_line};
+pub use sort::{
+    merge_files, sort_dedup_file, sort_dedup_file_with, SortOptions, DEFAULT_SORT_MEMORY,
+};
+pub use stats::{
+    CrawlCounters, CrawlStats, Endpoint, EndpointStats, FailureCounts, FailureKind, LatencyStats,
+};
+use stats::DistinctCounter;
+pub use watch::WatchState;
+
+/// Where Common Crawl data is served from, unless [CrawlOptions::base_url]
+/// points at a mirror.
+pub const DEFAULT_BASE_URL: &str = "https://data.commoncrawl.org";
+
+/// Number of recent mappings remembered for dropping duplicates, about
+/// 40MB of memory.
+pub const DEFAULT_DEDUP_WINDOW: usize = 1_000_000;
+
+/// Bytes read from the start of a WARC record to find its headers, unless
+/// set with [CrawlOptions::warc_read_bytes].
+pub const DEFAULT_WARC_READ_BYTES: u64 = 901;
+
+/// Default number of results buffered between the crawling threads and the
+/// output writer.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 10_000;
+
+/// How often the output writer checks whether the crawl paused and the
+/// checkpoint needs saving.
+const CHECKPOINT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The Common Crawl index server, serving collinfo.json and the CDX API.
+const INDEX_SERVER_URL: &str = "https://index.commoncrawl.org";
 
-const BASE_URL: &str = "https://data.commoncrawl.org";
 
 /// An index is a set of [IndexFiles] that logs the locations of the WARC
 /// records for the hosts Common Crawl crawled for that period
@@ -89,36 +229,79 @@ impl PartialEq for Index {
 }
 impl Eq for Index {}
 
+impl Index {
+    /// Index for an id of the form `CC-MAIN-YYYY-WW`, with the URLs of the
+    /// index server filled in. The name is set to the id, as the published
+    /// name cannot be derived from it.
+    pub fn from_id(id: &str) -> Option<Index> {
+        let (year, week) = parse_index_id(id)?;
+        // reject ids without a week, or with a week that does not exist
+        NaiveDate::from_isoywd_opt(year, week?, Weekday::Mon)?;
+        Some(Index {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            timegate: format!("{}/{}/", INDEX_SERVER_URL, id),
+            cdx_api: format!("{}/{}-index", INDEX_SERVER_URL, id),
+        })
+    }
+
+    /// Year of the crawl, taken from the id.
+    pub fn year(&self) -> Option<i32> {
+        parse_index_id(&self.id).map(|(year, _)| year)
+    }
+
+    /// ISO week of the crawl, taken from the id. The oldest crawls (before
+    /// `CC-MAIN-2013-20`) have no week in their id.
+    pub fn week(&self) -> Option<u32> {
+        parse_index_id(&self.id).and_then(|(_, week)| week)
+    }
+
+    /// Monday of the ISO week in the id, for ids with a week.
+    pub fn crawl_date(&self) -> Option<NaiveDate> {
+        NaiveDate::from_isoywd_opt(self.year()?, self.week()?, Weekday::Mon)
+    }
+}
+
+/// Year and, if present, ISO week of an index id such as `CC-MAIN-2020-50`.
+/// Older ids are of the form `CC-MAIN-2012` or `CC-MAIN-2009-2010`.
+fn parse_index_id(id: &str) -> Option<(i32, Option<u32>)> {
+    let mut parts = id.strip_prefix("CC-MAIN-")?.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let week = match parts.next() {
+        Some(week) => week.parse().ok().filter(|week| (1..=53).contains(week)),
+        None => None,
+    };
+    Some((year, week))
+}
+
+/// Indices are sorted most recent first, by the year and week in their id.
 impl Ord for Index {
     fn cmp(&self, other: &Self) -> Ordering {
-        let d1 = NaiveDate::parse_from_str(&self.name, "%B %Y Index").unwrap();
-        let d2 = NaiveDate::parse_from_str(&other.name, "%B %Y Index").unwrap();
-        d1.cmp(&d2)
+        let key = |index: &Index| (index.year().unwrap_or(0), index.week().unwrap_or(0));
+        key(other).cmp(&key(self)).then_with(|| other.id.cmp(&self.id))
     }
 }
 
 impl PartialOrd for Index {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let d1 = match NaiveDate::parse_from_str(&self.name, "%B %Y Index") {
-            Ok(d) => d,
-            Err(_) => return None,
-        };
-        let d2 = NaiveDate::parse_from_str(&other.name, "%B %Y Index").unwrap();
-        Some(d1.cmp(&d2))
+        Some(self.cmp(other))
     }
 }
 
 /// Each [Index] contains a list of actual index files
 #[derive(Debug)]
 pub struct IndexFiles {
+    /// URLs of the `cdx-NNNNN.gz` files
     pub cdx_files: Vec<String>,
+    /// URL of cluster.idx
     pub cdx_cluster: String,
+    /// URL of metadata.yaml
     pub metadata: String,
 }
 
 /// A line in cluster.idx file that points to a record on one index file for the
 /// host in question.
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IndexHostPointer {
     pub host: String,
     pub timestamp: i64,
@@ -131,45 +314,506 @@ impl IndexHostPointer {
     pub fn to_csv(&self) -> String {
         format!("{},{},{},{},{}", self.host, self.timestamp, self.index_file_name, self.range_start, self.range_length)
     }
+
+    /// Parse a line written by [IndexHostPointer::to_csv], e.g. in a
+    /// cluster.idx dump.
+    pub fn from_csv(line: &str) -> Option<IndexHostPointer> {
+        let parts: Vec<&str> = line.trim_end().split(',').collect();
+        if parts.len() != 5 {
+            return None;
+        }
+        Some(IndexHostPointer {
+            host: parts[0].to_owned(),
+            timestamp: parts[1].parse().ok()?,
+            index_file_name: parts[2].to_owned(),
+            range_start: parts[3].parse().ok()?,
+            range_length: parts[4].parse().ok()?,
+        })
+    }
+
+    /// ID of the index this pointer belongs to, taken from the index file URL.
+    pub fn index_id(&self) -> Option<&str> {
+        let (_, rest) = self.index_file_name.split_once("/collections/")?;
+        rest.split('/').next()
+    }
 }
 
-/// A record in an index file.
-#[allow(dead_code)]
+/// A record in an index file: the JSON part of a cdx line.
 #[derive(Deserialize, Debug, Clone)]
-struct IndexRecord {
-    url: String,
-    mime: String,
+pub struct IndexRecord {
+    /// Captured URL
+    pub url: String,
+    /// MIME type announced by the server
+    pub mime: String,
+    /// MIME type detected by Common Crawl
     #[serde(rename(deserialize = "mime-detected"))]
-    mime_detected: Option<String>,
-    status: String,
-    digest: Option<String>,
-    length: String,
-    offset: String,
-    filename: String,
+    pub mime_detected: Option<String>,
+    /// HTTP status of the capture
+    pub status: String,
+    /// Digest of the payload
+    pub digest: Option<String>,
+    /// Length of the compressed WARC record
+    pub length: String,
+    /// Offset of the WARC record in its file
+    pub offset: String,
+    /// Path of the WARC file, relative to the base URL
+    pub filename: String,
+    /// Languages of the content detected by Common Crawl, ISO 639-3 codes
+    /// separated by commas, e.g. `eng,deu`
+    pub languages: Option<String>,
+    /// Character set of the content detected by Common Crawl
+    pub charset: Option<String>,
+    /// Why the capture was cut short, e.g. `length` or `disconnect`
+    pub truncated: Option<String>,
+    /// Target of a redirect captured as such
+    pub redirect: Option<String>,
 }
 
 /// Host to IP mapping entry. This is the final product.
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MappingEntry {
     pub host: String,
     pub timestr: String,
     pub ip: IpAddr,
+    /// Software headers of the HTTP response, set when the crawl captures
+    /// them, see [CrawlOptions::capture_server]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerInfo>,
+    /// Protocol and TLS metadata of the connection, set when the crawl
+    /// captures them, see [CrawlOptions::capture_protocol]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ProtocolInfo>,
+    /// Port the capture was fetched from, set when the crawl keeps ports,
+    /// see [CrawlOptions::with_ports]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// URL of the capture the IP was read from, set with
+    /// [CrawlOptions::with_url]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// HTTP status of the capture the IP was read from, set with
+    /// [CrawlOptions::with_status]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Index metadata of the capture the IP was read from, set with
+    /// [CrawlOptions::with_record_fields]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<RecordInfo>,
+    /// Unicode form of an internationalized host, set with [IdnForm::Both]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_unicode: Option<String>,
+    /// Prefix containing the IP, set with [CrawlOptions::prefixes]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<IpPrefix>,
+    /// Origin ASes of the route of the IP, set with [Enricher::pfx2as]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<Vec<u32>>,
+    /// RPKI validity of the route of the IP, set with [Enricher::vrps]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpki: Option<RpkiValidity>,
+    /// Cloud or CDN provider of the IP, set with [Enricher::providers]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Organizations of the origin ASes in [MappingEntry::asn], set with
+    /// [Enricher::as2org]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org: Option<Vec<String>>,
+    /// ISO 3166-1 code of the country of the IP, set with a GeoIP database
+    /// (`geoip` feature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+/// Software announced by the origin server in its HTTP response.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The `Server` header
+    pub server: Option<String>,
+    /// The `X-Powered-By` header
+    pub powered_by: Option<String>,
+}
+
+impl ServerInfo {
+    /// Read the software headers of an HTTP response.
+    pub fn from_headers(headers: &HttpHeaders) -> ServerInfo {
+        ServerInfo {
+            server: headers.get("Server").map(str::to_owned),
+            powered_by: headers.get("X-Powered-By").map(str::to_owned),
+        }
+    }
+}
+
+/// Protocol metadata the crawler recorded for the connection, from WARC/1.1
+/// records of newer crawls.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    /// The `WARC-Protocol` fields, e.g. `h2` and `tls/1.3`
+    pub protocols: Vec<String>,
+    /// The `WARC-Cipher-Suite` field, e.g. `TLS_AES_256_GCM_SHA384`
+    pub cipher_suite: Option<String>,
 }
 
-#[allow(dead_code)]
-fn parse_index(index_id: &str) -> IndexFiles {
+impl ProtocolInfo {
+    /// Read the protocol fields of a WARC header block.
+    pub fn from_headers(headers: &WarcHeaders) -> ProtocolInfo {
+        ProtocolInfo {
+            protocols: headers
+                .fields
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("WARC-Protocol"))
+                .flat_map(|(_, value)| value.split(','))
+                .map(|protocol| protocol.trim().to_owned())
+                .filter(|protocol| !protocol.is_empty())
+                .collect(),
+            cipher_suite: headers.get("WARC-Cipher-Suite").map(str::to_owned),
+        }
+    }
+}
+
+/// Metadata Common Crawl recorded in the index for a capture, beyond what
+/// the crawl needs to read it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordInfo {
+    /// Detected languages of the content, e.g. `eng`
+    pub languages: Vec<String>,
+    /// Detected character set of the content, e.g. `UTF-8`
+    pub charset: Option<String>,
+    /// Why the capture was truncated, see [IndexRecord::truncated]
+    pub truncated: Option<String>,
+    /// Target of a redirect, see [IndexRecord::redirect]
+    pub redirect: Option<String>,
+}
+
+impl RecordInfo {
+    /// Read the metadata fields of an index record.
+    pub fn from_record(record: &IndexRecord) -> RecordInfo {
+        RecordInfo {
+            languages: record
+                .languages
+                .iter()
+                .flat_map(|languages| languages.split(','))
+                .map(|language| language.trim().to_owned())
+                .filter(|language| !language.is_empty())
+                .collect(),
+            charset: record.charset.clone(),
+            truncated: record.truncated.clone(),
+            redirect: record.redirect.clone(),
+        }
+    }
+}
+
+/// Optional column groups of an output file, written after `HOST,DATE,IP`
+/// in this order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CsvColumns {
+    /// `SERVER,POWERED_BY`, see [ServerInfo]
+    pub server: bool,
+    /// `PROTOCOLS,CIPHER_SUITE`, see [ProtocolInfo]
+    pub protocol: bool,
+    /// `PORT`, see [MappingEntry::port]
+    pub port: bool,
+    /// `URL`, see [MappingEntry::url]
+    pub url: bool,
+    /// `STATUS`, see [MappingEntry::status]
+    pub status: bool,
+    /// `LANGUAGES,CHARSET,TRUNCATED,REDIRECT`, see [RecordInfo]
+    pub record: bool,
+    /// `HOST_UNICODE`, see [MappingEntry::host_unicode]
+    pub host_unicode: bool,
+    /// `PREFIX`, or the prefix in place of the IP, see
+    /// [MappingEntry::prefix]
+    pub prefix: PrefixOutput,
+    /// `ASN`, see [MappingEntry::asn]
+    pub asn: bool,
+    /// `RPKI`, see [MappingEntry::rpki]
+    pub rpki: bool,
+    /// `PROVIDER`, see [MappingEntry::provider]
+    pub provider: bool,
+    /// `ORG`, see [MappingEntry::org]
+    pub org: bool,
+    /// `COUNTRY`, see [MappingEntry::country]
+    pub country: bool,
+}
+
+impl CsvColumns {
+    /// The columns written by a crawl with the given options.
+    pub fn of(options: &CrawlOptions) -> CsvColumns {
+        CsvColumns {
+            server: options.capture_server,
+            protocol: options.capture_protocol,
+            port: options.with_ports,
+            url: options.with_url,
+            status: options.with_status,
+            record: options.with_record_fields,
+            host_unicode: options.idn == IdnForm::Both,
+            prefix: options.prefixes,
+            asn: options.enricher.as_ref().is_some_and(|e| e.pfx2as.is_some()),
+            rpki: options.enricher.as_ref().is_some_and(|e| e.vrps.is_some()),
+            provider: options.enricher.as_ref().is_some_and(|e| e.providers.is_some()),
+            org: options.enricher.as_ref().is_some_and(|e| e.as2org.is_some()),
+            #[cfg(feature = "geoip")]
+            country: options.enricher.as_ref().is_some_and(|e| e.geoip.is_some()),
+            #[cfg(not(feature = "geoip"))]
+            country: false,
+        }
+    }
+}
+
+impl MappingEntry {
+    /// Format as a `HOST,DATE,IP` line of the output file, followed by
+    /// `SERVER,POWERED_BY` if the server headers were captured,
+    /// `PROTOCOLS,CIPHER_SUITE` if the protocol metadata was, with multiple
+    /// protocols separated by `;`, `PORT`, `URL` and `STATUS` if the port,
+    /// URL and status are set, `LANGUAGES,CHARSET,TRUNCATED,REDIRECT` if the
+    /// index metadata is, with multiple languages separated by `;`,
+    /// `HOST_UNICODE` if the Unicode form is,
+    /// `PREFIX` if the prefix is, and `ASN`, with multiple origins separated
+    /// by `;`, and `RPKI` if the route annotations are, `PROVIDER` if the
+    /// provider is, `ORG`, with multiple organizations separated by `;`, if
+    /// the organizations are, and `COUNTRY` if the country is.
+    pub fn to_csv(&self) -> String {
+        self.to_csv_with(CsvColumns {
+            server: self.server.is_some(),
+            protocol: self.protocol.is_some(),
+            port: self.port.is_some(),
+            url: self.url.is_some(),
+            status: self.status.is_some(),
+            record: self.record.is_some(),
+            host_unicode: self.host_unicode.is_some(),
+            prefix: match self.prefix {
+                Some(_) => PrefixOutput::Alongside,
+                None => PrefixOutput::Off,
+            },
+            asn: self.asn.is_some(),
+            rpki: self.rpki.is_some(),
+            provider: self.provider.is_some(),
+            org: self.org.is_some(),
+            country: self.country.is_some(),
+        })
+    }
+
+    /// Format as a line of the output file with the given optional columns,
+    /// left empty where the entry has no value.
+    pub fn to_csv_with(&self, columns: CsvColumns) -> String {
+        let ip = match (columns.prefix, self.prefix) {
+            (PrefixOutput::Instead, Some(prefix)) => prefix.to_string(),
+            _ => self.ip.to_string(),
+        };
+        let mut line = format!("{},{},{}", self.host, self.timestr, ip);
+        let mut push = |value: &str| {
+            line.push(',');
+            line.push_str(&csv_field(value));
+        };
+        if columns.server {
+            let server = self.server.clone().unwrap_or_default();
+            push(server.server.as_deref().unwrap_or_default());
+            push(server.powered_by.as_deref().unwrap_or_default());
+        }
+        if columns.protocol {
+            let protocol = self.protocol.clone().unwrap_or_default();
+            push(&protocol.protocols.join(";"));
+            push(protocol.cipher_suite.as_deref().unwrap_or_default());
+        }
+        if columns.port {
+            push(&self.port.map(|port| port.to_string()).unwrap_or_default());
+        }
+        if columns.url {
+            push(self.url.as_deref().unwrap_or_default());
+        }
+        if columns.status {
+            push(&self.status.map(|status| status.to_string()).unwrap_or_default());
+        }
+        if columns.record {
+            let record = self.record.clone().unwrap_or_default();
+            push(&record.languages.join(";"));
+            push(record.charset.as_deref().unwrap_or_default());
+            push(record.truncated.as_deref().unwrap_or_default());
+            push(record.redirect.as_deref().unwrap_or_default());
+        }
+        if columns.host_unicode {
+            push(self.host_unicode.as_deref().unwrap_or_default());
+        }
+        if columns.prefix == PrefixOutput::Alongside {
+            push(&self.prefix.map(|prefix| prefix.to_string()).unwrap_or_default());
+        }
+        if columns.asn {
+            let asn = self.asn.iter().flatten().map(|asn| asn.to_string());
+            push(&asn.collect::<Vec<_>>().join(";"));
+        }
+        if columns.rpki {
+            push(&self.rpki.map(|rpki| rpki.to_string()).unwrap_or_default());
+        }
+        if columns.provider {
+            push(self.provider.as_deref().unwrap_or_default());
+        }
+        if columns.org {
+            push(&self.org.clone().unwrap_or_default().join(";"));
+        }
+        if columns.country {
+            push(self.country.as_deref().unwrap_or_default());
+        }
+        line
+    }
+
+    /// Parse a `HOST,DATE,IP` line without optional columns; use
+    /// [MappingEntry::from_csv_with] for lines that have some, since the
+    /// columns cannot be told apart by their number.
+    pub fn from_csv(line: &str) -> Option<MappingEntry> {
+        MappingEntry::from_csv_with(line, CsvColumns::default())
+    }
+
+    /// Parse a line written by [MappingEntry::to_csv] with the given
+    /// optional columns.
+    pub fn from_csv_with(line: &str, columns: CsvColumns) -> Option<MappingEntry> {
+        let parts = split_csv_line(line.trim_end())?;
+        let expected = 3
+            + 2 * (columns.server as usize + columns.protocol as usize)
+            + columns.port as usize
+            + columns.url as usize
+            + columns.status as usize
+            + 4 * columns.record as usize
+            + columns.host_unicode as usize
+            + (columns.prefix == PrefixOutput::Alongside) as usize
+            + columns.asn as usize
+            + columns.rpki as usize
+            + columns.provider as usize
+            + columns.org as usize
+            + columns.country as usize;
+        if parts.len() != expected {
+            return None;
+        }
+        let optional = |value: &String| Some(value.clone()).filter(|v| !v.is_empty());
+        let mut rest = parts[3..].iter();
+        let server = match columns.server {
+            true => Some(ServerInfo {
+                server: optional(rest.next()?),
+                powered_by: optional(rest.next()?),
+            }),
+            false => None,
+        };
+        let protocol = match columns.protocol {
+            true => Some(ProtocolInfo {
+                protocols: rest
+                    .next()?
+                    .split(';')
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                cipher_suite: optional(rest.next()?),
+            }),
+            false => None,
+        };
+        let port = match columns.port {
+            true => match rest.next()?.as_str() {
+                "" => None,
+                port => Some(port.parse().ok()?),
+            },
+            false => None,
+        };
+        let url = match columns.url {
+            true => optional(rest.next()?),
+            false => None,
+        };
+        let status = match columns.status {
+            true => match rest.next()?.as_str() {
+                "" => None,
+                status => Some(status.parse().ok()?),
+            },
+            false => None,
+        };
+        let record = match columns.record {
+            true => Some(RecordInfo {
+                languages: rest
+                    .next()?
+                    .split(';')
+                    .filter(|l| !l.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                charset: optional(rest.next()?),
+                truncated: optional(rest.next()?),
+                redirect: optional(rest.next()?),
+            }),
+            false => None,
+        };
+        let host_unicode = match columns.host_unicode {
+            true => optional(rest.next()?),
+            false => None,
+        };
+        let (ip, prefix) = match columns.prefix {
+            PrefixOutput::Off => (parts[2].parse().ok()?, None),
+            PrefixOutput::Alongside => match rest.next()?.as_str() {
+                "" => (parts[2].parse().ok()?, None),
+                prefix => (parts[2].parse().ok()?, Some(prefix.parse().ok()?)),
+            },
+            PrefixOutput::Instead => {
+                let prefix: IpPrefix = parts[2].parse().ok()?;
+                (prefix.network, Some(prefix))
+            }
+        };
+        let asn = match columns.asn {
+            true => match rest.next()?.as_str() {
+                "" => None,
+                asn => Some(asn.split(';').map(str::parse).collect::<Result<_, _>>().ok()?),
+            },
+            false => None,
+        };
+        let rpki = match columns.rpki {
+            true => match rest.next()?.as_str() {
+                "" => None,
+                rpki => Some(rpki.parse().ok()?),
+            },
+            false => None,
+        };
+        let provider = match columns.provider {
+            true => optional(rest.next()?),
+            false => None,
+        };
+        let org = match columns.org {
+            true => optional(rest.next()?).map(|orgs| orgs.split(';').map(str::to_owned).collect()),
+            false => None,
+        };
+        let country = match columns.country {
+            true => optional(rest.next()?),
+            false => None,
+        };
+        Some(MappingEntry {
+            host: parts[0].clone(),
+            timestr: parts[1].clone(),
+            ip,
+            prefix,
+            asn,
+            rpki,
+            provider,
+            org,
+            country,
+            server,
+            protocol,
+            port,
+            url,
+            status,
+            record,
+            host_unicode,
+        })
+    }
+}
+
+/// Download and parse the `cc-index.paths.gz` listing of an index, see
+/// [parse_index_with].
+pub fn parse_index(index_id: &str) -> Result<IndexFiles, CrawlError> {
+    parse_index_with(index_id, &CrawlOptions::default())
+}
+
+/// Download and parse the `cc-index.paths.gz` listing of an index, with the
+/// connection settings of the given [CrawlOptions]. The returned URLs point
+/// to [CrawlOptions::base_url].
+pub fn parse_index_with(index_id: &str, options: &CrawlOptions) -> Result<IndexFiles, CrawlError> {
     let path_file = format!(
         "{}/crawl-data/{}/cc-index.paths.gz",
-        BASE_URL,index_id
+        options.base_url(),
+        index_id
     );
 
-    let bytes: Vec<u8> = reqwest::blocking::get(&path_file)
-        .unwrap()
-        .bytes()
-        .unwrap()
-        .to_vec();
-
-    // NOTE: needs both of the following imports BufRead, BufReader;
+    let bytes = try_download(&path_file, options, None)?;
     let reader = BufReader::new(GzDecoder::new(&*bytes));
 
     let mut idx = IndexFiles {
@@ -179,19 +823,20 @@ fn parse_index(index_id: &str) -> IndexFiles {
     };
 
     for line in reader.lines() {
-        let temp_line = line.unwrap();
-        let line_string = format!("{}/{}", BASE_URL, temp_line);
-        match temp_line.split("/").last() {
-            Some(name) => match name {
+        let temp_line = line.map_err(|_| CrawlError::Truncated {
+            url: path_file.clone(),
+        })?;
+        let line_string = format!("{}/{}", options.base_url(), temp_line);
+        if let Some(name) = temp_line.split('/').next_back() {
+            match name {
                 "cluster.idx" => idx.cdx_cluster = line_string,
                 "metadata.yaml" => idx.metadata = line_string,
                 _ => idx.cdx_files.push(line_string),
-            },
-            None => {}
+            }
         }
     }
 
-    idx
+    Ok(idx)
 }
 
 /// Retrieve a number of indices using commoncrawl's
@@ -205,66 +850,80 @@ fn parse_index(index_id: &str) -> IndexFiles {
 ///
 /// Retrieve all indices and sort by most-recent-first order.
 /// ```no_run
+/// # use cc_host_mapper::*;
 /// let mut index_list: Vec<Index> = retrieve_indices();
 /// index_list.sort();
 /// // index_list.reverse();
 /// ```
 pub fn retrieve_indices() -> Vec<Index> {
-    let rsp = match reqwest::blocking::get("https://index.commoncrawl.org/collinfo.json") {
-        Ok(a) => a,
-        _ => unreachable!(),
-    };
-    match rsp.json::<Vec<Index>>() {
+    match try_retrieve_indices() {
         Ok(lst) => lst,
-        Err(_) => panic!("cannot parse returned json to struct"),
+        Err(e) => panic!("cannot retrieve index list: {}", e),
     }
 }
 
-/// Retrives all indicis using [retrieve_indices] functions, sorts the indicis
-/// using the names, and return the most recent index.
+/// Same as [retrieve_indices], but returns an error instead of panicking
+/// when collinfo.json cannot be reached or parsed.
+pub fn try_retrieve_indices() -> Result<Vec<Index>, reqwest::Error> {
+    try_retrieve_indices_with_options(&CrawlOptions::default())
+}
+
+/// Same as [try_retrieve_indices], with the connection settings and index
+/// server of the given [CrawlOptions].
+pub fn try_retrieve_indices_with_options(
+    options: &CrawlOptions,
+) -> Result<Vec<Index>, reqwest::Error> {
+    bulk_client(options)
+        .get(&format!("{}/collinfo.json", options.index_server_url()))
+        .send()?
+        .error_for_status()?
+        .json::<Vec<Index>>()
+}
+
+/// Same as [try_retrieve_indices], but sends the request with the given
+/// client, e.g. one configured with [build_client] to use a proxy,
+/// User-Agent or timeouts.
+///
+/// # Example
 ///
-/// The sorting is done by parsing Index names (e.g. `November 2020 Index`) to
-/// [NaiveDate] and compare the dates.
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let options = CrawlOptions {
+///     user_agent: Some("my-crawler (mailto:me@example.org)".to_owned()),
+///     ..Default::default()
+/// };
+/// let client = build_client(&options).unwrap();
+/// match retrieve_indices_with(&client) {
+///     Ok(indices) => println!("{} indices", indices.len()),
+///     Err(e) => eprintln!("index server unavailable: {}", e),
+/// }
+/// ```
+pub fn retrieve_indices_with(client: &reqwest::blocking::Client) -> Result<Vec<Index>, reqwest::Error> {
+    client
+        .get(&format!("{}/collinfo.json", INDEX_SERVER_URL))
+        .send()?
+        .error_for_status()?
+        .json::<Vec<Index>>()
+}
+
+/// Retrives all indicis using [retrieve_indices] functions, sorts the indicis
+/// by the year and week in their ids, and return the most recent index.
 pub fn get_newest_index() -> Index {
-    let mut indices = retrieve_indices();
-    indices.sort();
-    indices[0].clone()
+    get_newest_index_with(&CrawlOptions::default())
 }
 
-/// Parse one line in cluster.idx file and return a [IndexHostPointer]
-///
-/// Example line:
-/// 0,102,126,13:7037)/robots.txt 20201126201142\tcdx-00000.gz\t0\t205505\t1
-fn parse_idx_entry(index_id: &str, line: String) -> Option<IndexHostPointer> {
-    let parts: Vec<&str> = line.split("\t").collect::<Vec<&str>>();
-    assert_eq!(parts.len(), 5);
-    let url_time = parts[0].split(" ").collect::<Vec<&str>>();
-    let timestamp = url_time[1].parse::<i64>().unwrap();
-    let mut host_vec = url_time[0].split(")").collect::<Vec<&str>>()[0]
-        .split(",")
-        .collect::<Vec<&str>>();
-    if host_vec[0].chars().all(char::is_numeric) {
-        // it is a IP address, not a host name
-        return None;
-    }
-
-    host_vec.reverse();
-    let host = host_vec.join(".");
-
-    let file_name = format!(
-        "{}/cc-index/collections/{}/indexes/{}",
-        BASE_URL,
-        index_id,
-        parts[1].parse::<String>().unwrap()
-    );
+/// Same as [get_newest_index], with the connection settings and index
+/// server of the given [CrawlOptions].
+pub fn get_newest_index_with(options: &CrawlOptions) -> Index {
+    let indices = try_retrieve_indices_with_options(options)
+        .unwrap_or_else(|e| panic!("cannot retrieve index list: {}", e));
+    newest_index(&indices).expect("no index published").clone()
+}
 
-    Some(IndexHostPointer {
-        host,
-        timestamp,
-        index_file_name: file_name,
-        range_start: parts[2].parse::<i64>().unwrap(),
-        range_length: parts[3].parse::<i64>().unwrap(),
-    })
+/// The most recent of the given indices, by the year and week in their ids,
+/// or `None` if there are none.
+pub fn newest_index(indices: &[Index]) -> Option<&Index> {
+    indices.iter().min()
 }
 
 /// Read the cluster.idx file to get a vector of HostPointers each of each
@@ -277,26 +936,143 @@ fn parse_idx_entry(index_id: &str, line: String) -> Option<IndexHostPointer> {
 /// Essentially, these pointers will lead us to the location of the WARC records
 /// for each host
 pub fn read_cluster_idx(index_id: &str) -> Vec<IndexHostPointer> {
-    let url = format!(
+    read_cluster_idx_filtered(index_id, &HostFilter::default())
+}
+
+/// Same as [read_cluster_idx], but only keeps pointers whose reconstructed
+/// hostname passes the given [HostFilter].
+pub fn read_cluster_idx_filtered(index_id: &str, filter: &HostFilter) -> Vec<IndexHostPointer> {
+    let options = CrawlOptions {
+        host_filter: filter.clone(),
+        ..Default::default()
+    };
+    read_cluster_idx_with(index_id, &options)
+}
+
+/// Read the cluster.idx file with the connection, cache and host filter
+/// settings of the given [CrawlOptions].
+pub fn read_cluster_idx_with(index_id: &str, options: &CrawlOptions) -> Vec<IndexHostPointer> {
+    try_read_cluster_idx_with(index_id, options).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Same as [read_cluster_idx_with], but returns an error instead of
+/// panicking when cluster.idx cannot be downloaded. A missing index results
+/// in a fatal error, see [CrawlError::is_retryable].
+pub fn try_read_cluster_idx_with(
+    index_id: &str,
+    options: &CrawlOptions,
+) -> Result<Vec<IndexHostPointer>, CrawlError> {
+    read_cluster_idx_in(index_id, options, None)
+}
+
+/// Same as [try_read_cluster_idx_with], recording the download in the crawl
+/// counters if given.
+fn read_cluster_idx_in(
+    index_id: &str,
+    options: &CrawlOptions,
+    counters: Option<&CrawlCounters>,
+) -> Result<Vec<IndexHostPointer>, CrawlError> {
+    let url = cluster_idx_url(index_id, options);
+    let cache = open_cache(options);
+    let cached = cache.as_ref().and_then(|cache| cache.get(&url, None));
+    let stream = match cached {
+        Some(bytes) => bytes,
+        None => {
+            let bytes = try_download(&url, options, counters)?;
+            if let Some(cache) = &cache {
+                cache.put(&url, None, &bytes);
+            }
+            bytes
+        }
+    };
+    let (pointers, malformed) = parse_cluster_idx(&stream, index_id, options);
+    if let Some(counters) = counters {
+        counters.malformed_lines.fetch_add(malformed, AtomicOrdering::Relaxed);
+    }
+    Ok(pointers)
+}
+
+/// Download a whole file with the fetcher of the [CrawlOptions], or a client
+/// without overall timeout, recording the outcome in the crawl counters if
+/// given.
+fn try_download(
+    url: &str,
+    options: &CrawlOptions,
+    counters: Option<&CrawlCounters>,
+) -> Result<Vec<u8>, CrawlError> {
+    let fetcher: Arc<dyn Fetcher> = match &options.fetcher {
+        Some(fetcher) => fetcher.clone(),
+        None => Arc::new(ReqwestFetcher::new(bulk_client(options))),
+    };
+    let sent = Instant::now();
+    let result = fetcher.get(url);
+    if let Some(counters) = counters {
+        let endpoint = Endpoint::of(url);
+        match &result {
+            Ok(rsp) => {
+                counters.record_response(endpoint, rsp.status.as_u16());
+                counters.record_latency(endpoint, sent.elapsed());
+            }
+            Err(FetchError::Connect(_)) => {
+                counters.record_no_response(endpoint);
+                counters.record_failure(endpoint, FailureKind::Connect);
+            }
+            Err(FetchError::Timeout(_)) => {
+                counters.record_no_response(endpoint);
+                counters.record_failure(endpoint, FailureKind::Timeout);
+            }
+            Err(FetchError::Body(_)) => counters.record_failure(endpoint, FailureKind::Truncated),
+        }
+    }
+    let rsp = result.map_err(|e| match e {
+        FetchError::Connect(reason) | FetchError::Timeout(reason) => CrawlError::Request {
+            url: url.to_owned(),
+            reason,
+        },
+        FetchError::Body(_) => CrawlError::Truncated { url: url.to_owned() },
+    })?;
+    if !rsp.status.is_success() {
+        return Err(CrawlError::Status {
+            url: url.to_owned(),
+            status: rsp.status.as_u16(),
+        });
+    }
+    Ok(rsp.body)
+}
+
+fn cluster_idx_url(index_id: &str, options: &CrawlOptions) -> String {
+    format!(
         "{}/cc-index/collections/{}/indexes/cluster.idx",
-        BASE_URL,
+        options.base_url(),
         index_id
-    );
-    let stream = reqwest::blocking::get(&url.to_owned())
-        .unwrap()
-        .bytes()
-        .unwrap()
-        .to_vec();
-    let reader = BufReader::new(&*stream);
+    )
+}
+
+/// Parse a downloaded cluster.idx file, keeping the pointers that pass the
+/// host filter of the [CrawlOptions]. Malformed lines are skipped, and
+/// returned as a count along with the pointers.
+fn parse_cluster_idx(
+    bytes: &[u8],
+    index_id: &str,
+    options: &CrawlOptions,
+) -> (Vec<IndexHostPointer>, u64) {
+    let text = String::from_utf8_lossy(bytes);
 
     let mut pointers = vec![];
+    let mut malformed = 0;
 
-    for line in reader.lines() {
-        if let Some(host_pointer) = parse_idx_entry(index_id, line.unwrap()) {
-            pointers.push(host_pointer);
+    for line in text.lines() {
+        match parse_idx_line(options.base_url(), index_id, line) {
+            Ok(Some(host_pointer)) => {
+                if options.host_filter.matches(&host_pointer.host) {
+                    pointers.push(host_pointer);
+                }
+            }
+            Ok(None) => {}
+            Err(_) => malformed += 1,
         }
     }
-    pointers
+    (pointers, malformed)
 }
 
 /// Query host IP using a [IndexHostPointer]. The pointer points to a location
@@ -304,136 +1080,604 @@ pub fn read_cluster_idx(index_id: &str) -> Vec<IndexHostPointer> {
 /// file to get the pointer to a WARC record and then crawl the WARC record to
 /// get the actual IP.
 pub fn query_host(pointer: IndexHostPointer) -> Vec<Option<MappingEntry>> {
-    // TODO: should return Err and retry.
+    query_host_with_options(pointer, &CrawlOptions::default())
+}
+
+/// Same as [query_host], but applies the record-level settings of the given
+/// [CrawlOptions], such as the [RecordFilter].
+pub fn query_host_with_options(
+    pointer: IndexHostPointer,
+    options: &CrawlOptions,
+) -> Vec<Option<MappingEntry>> {
+    let counters = CrawlCounters::default();
+    let resources = CrawlResources::new(options);
+    let ctx = resources.context(options, &counters);
+    query_host_in(pointer, &ctx).unwrap_or_default()
+}
+
+/// Resolve the IPs of one host pointer: fetch its cdx block, pick one
+/// capture per day, and read the IP from the beginning of each WARC record.
+///
+/// Unlike [query_host], a pointer whose cdx block cannot be fetched is
+/// reported as an error, and captures whose IP cannot be read are left out.
+///
+/// # Example
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let pointers = read_cluster_idx("CC-MAIN-2020-50");
+/// for entry in resolve_pointer(&pointers[0]).unwrap() {
+///     println!("{}", entry.to_csv());
+/// }
+/// ```
+pub fn resolve_pointer(pointer: &IndexHostPointer) -> Result<Vec<MappingEntry>, CrawlError> {
+    resolve_pointer_with(pointer, &CrawlOptions::default())
+}
+
+/// Same as [resolve_pointer], with the connection and record-level settings
+/// of the given [CrawlOptions].
+pub fn resolve_pointer_with(
+    pointer: &IndexHostPointer,
+    options: &CrawlOptions,
+) -> Result<Vec<MappingEntry>, CrawlError> {
+    let counters = CrawlCounters::default();
+    let resources = CrawlResources::new(options);
+    let ctx = resources.context(options, &counters);
+    let mappings = query_host_in(pointer.clone(), &ctx)?;
+    Ok(mappings.into_iter().flatten().collect())
+}
+
+/// Read the IP address the crawler connected to for one index record from the
+/// `WARC-IP-Address` header of its WARC record. Returns `Ok(None)` if the
+/// record has no such header, e.g. for DNS records.
+pub fn resolve_record(record: &IndexRecord) -> Result<Option<IpAddr>, CrawlError> {
+    resolve_record_with(record, &CrawlOptions::default())
+}
+
+/// Same as [resolve_record], with the connection settings of the given
+/// [CrawlOptions].
+pub fn resolve_record_with(
+    record: &IndexRecord,
+    options: &CrawlOptions,
+) -> Result<Option<IpAddr>, CrawlError> {
+    let counters = CrawlCounters::default();
+    let resources = CrawlResources::new(options);
+    let ctx = resources.context(options, &counters);
+    Ok(fetch_warc_capture(record, &ctx)?.map(|capture| capture.ip))
+}
+
+fn query_host_in(
+    pointer: IndexHostPointer,
+    ctx: &CrawlContext,
+) -> Result<Vec<Option<MappingEntry>>, CrawlError> {
+    #[cfg(feature = "otel")]
+    let cx = otel::pointer_context(&pointer);
+    #[cfg(feature = "otel")]
+    let _attached = cx.clone().attach();
+    let result = query_pointer(pointer, ctx);
+    #[cfg(feature = "otel")]
+    otel::finish_pointer(&cx, &result);
+    result
+}
+
+fn query_pointer(
+    pointer: IndexHostPointer,
+    ctx: &CrawlContext,
+) -> Result<Vec<Option<MappingEntry>>, CrawlError> {
+    let options = ctx.options;
     let url = &pointer.index_file_name;
-    let start = &pointer.range_start;
-    let end = start + &pointer.range_length;
-    let client = reqwest::blocking::Client::new();
-
-    let range_str = format!("bytes={}-{}", start, end);
-    let range = HeaderValue::from_str(&range_str).unwrap();
-    let rsp = match client.get(url).header(RANGE, range).send() {
-        Ok(res) => res,
-        Err(_) => return vec![],
+    let start = pointer.range_start;
+    let end = start + pointer.range_length - 1;
+    let lines = match fetch_gzip_range(url, start, end, ctx) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).lines().map(str::to_owned).collect(),
+        Err(cause) => {
+            let fallback = if options.cdx_api_fallback {
+                query_cdx_api(&pointer, ctx)
+            } else {
+                None
+            };
+            match fallback {
+                Some(lines) => lines,
+                None => {
+                    ctx.counters.record_failed_host(&pointer.host, &cause);
+                    return Err(CrawlError::HostFailed {
+                        host: pointer.host,
+                        cause: Box::new(cause),
+                    });
+                }
+            }
+        }
     };
-    let bytes = rsp.bytes().unwrap();
-    drop(client);
 
-    // NOTE: needs both of the following imports BufRead, BufReader;
-    let reader = BufReader::new(GzDecoder::new(&*bytes));
+    Ok(select_records(&pointer.host, lines, options, ctx.counters, ctx.pointer_hosts)
+        .into_iter()
+        .map(|line| retrieve_ip(line, ctx))
+        .collect())
+}
+
+/// Pick the index records to look up from the cdx lines of a host pointer,
+/// see [select_host_records], for the pointer's host and, with
+/// [CrawlOptions::bonus_hosts], for the other hosts in the lines that pass
+/// the host filter and are not in `pointer_hosts`, the hosts of the
+/// pointers that crawl them themselves.
+fn select_records(
+    pointer_host: &str,
+    lines: Vec<String>,
+    options: &CrawlOptions,
+    counters: &CrawlCounters,
+    pointer_hosts: Option<&HashSet<String>>,
+) -> Vec<CdxLine> {
+    let is_bonus_host = |host: &str| {
+        options.bonus_hosts
+            && !is_ip_host(host)
+            && options.host_filter.matches(host)
+            && !pointer_hosts.is_some_and(|hosts| hosts.contains(host))
+    };
+    // the lines of each host kept, by host, `None` for the hosts skipped
+    let mut groups: Vec<(String, Vec<CdxLine>)> = vec![];
+    let mut group_of: HashMap<String, Option<usize>> = HashMap::new();
+    for record_str in lines {
+        let line = match parse_cdx_line(&record_str) {
+            Ok(line) => line,
+            Err(_) => {
+                counters.malformed_lines.fetch_add(1, AtomicOrdering::Relaxed);
+                counters.record_failure(Endpoint::Cdx, FailureKind::Parse);
+                continue;
+            }
+        };
+        // the range also holds records of other hosts, ignored unless they
+        // are bonus hosts
+        let group = *group_of.entry(line.host.clone()).or_insert_with(|| {
+            if line.host != pointer_host && !is_bonus_host(&line.host) {
+                return None;
+            }
+            groups.push((line.host.clone(), vec![]));
+            Some(groups.len() - 1)
+        });
+        if let Some(group) = group {
+            groups[group].1.push(line);
+        }
+    }
+
+    let mut records = vec![];
+    for (host, lines) in groups {
+        let selected = select_host_records(&host, lines, options, counters);
+        if host != pointer_host && !selected.is_empty() {
+            counters.bonus_hosts.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        records.extend(selected);
+    }
+    records
+}
+
+/// Pick the index records to look up from the cdx lines of one host: the
+/// first record per day, or per day and port with
+/// [CrawlOptions::with_ports], that passes the [RecordFilter].
+fn select_host_records(
+    raw_host: &str,
+    lines: Vec<CdxLine>,
+    options: &CrawlOptions,
+    counters: &CrawlCounters,
+) -> Vec<CdxLine> {
+    let host = match options.host_normalization.apply(raw_host) {
+        Some(host) => host,
+        None => {
+            counters.rejected_hosts.fetch_add(1, AtomicOrdering::Relaxed);
+            return vec![];
+        }
+    };
+    if host != raw_host {
+        counters.normalized_hosts.fetch_add(1, AtomicOrdering::Relaxed);
+    }
     let mut records = vec![];
     let mut futures_times = HashSet::new();
-    let mut mappings = vec![];
 
-    for line in reader.lines() {
-        let record_str = line.unwrap();
-        let fields = record_str.split(" ").collect::<Vec<&str>>();
-
-        // get host
-        let mut host_vec = fields[0].split(")").collect::<Vec<&str>>()[0]
-            .split(":")
-            .collect::<Vec<&str>>()[0]
-            .split(",")
-            .collect::<Vec<&str>>();
-        host_vec.reverse();
-        let host = host_vec.join(".");
-
-        // it's possible that the range provided contains records for other hosts, in this case, ignore
-        if pointer.host != host {
-            continue;
-        }
-
-        // get timestamp
-        let date = parse_time_string(fields[1]);
-        let timestamp_str = date.format("%Y-%m-%d").to_string();
-
-        if !futures_times.contains(&timestamp_str) {
-            let json_str = fields[2..].join(" ");
-            if let Ok(entry) = serde_json::from_str::<IndexRecord>(json_str.as_str()) {
-                mappings.push(retrieve_ip(
-                    host.clone(),
-                    timestamp_str.clone(),
-                    entry.clone(),
-                ));
-                futures_times.insert(timestamp_str);
-                records.push(entry);
-            };
+    for line in lines {
+        let key = match options.with_ports {
+            true => (line.day(), line.port()),
+            false => (line.day(), None),
+        };
+
+        if !futures_times.contains(&key) {
+            let entry = &line.record;
+            if !options
+                .record_filter
+                .matches_mime(&entry.mime, entry.mime_detected.as_deref())
+                || !options.record_filter.matches_port(line.port())
+                || !options.record_filter.matches_url(&entry.url)
+                || !options.record_filter.matches_date(&line.timestamp)
+            {
+                continue;
+            }
+            futures_times.insert(key);
+            records.push(CdxLine {
+                host: host.clone(),
+                ..line
+            });
         }
     }
 
-    mappings
+    let selected = records.len();
+    let records = options.record_filter.limit_captures(records);
+    counters
+        .capped_captures
+        .fetch_add((selected - records.len()) as u64, AtomicOrdering::Relaxed);
+    records
 }
 
-fn parse_time_string(time_str: &str) -> chrono::DateTime<chrono::Utc> {
-    let year = time_str[0..=3].parse::<i32>().unwrap();
-    let month = time_str[4..=5].parse::<u32>().unwrap();
-    let day = time_str[6..=7].parse::<u32>().unwrap();
-    Utc.ymd(year, month, day).and_hms(0, 0, 0)
+/// Look up the captures of the pointer's host through the CDX API of the
+/// index server, for when its cdx block cannot be fetched. Returns the
+/// captures as cdx lines, or `None` if the API request fails too.
+///
+/// The API is queried for all captures of the host, following its pages, so
+/// only the first pointer of a host that fails queries it. If that query
+/// succeeds the others get no lines, otherwise they get `None` too and their
+/// host fails, without querying the API again.
+fn query_cdx_api(pointer: &IndexHostPointer, ctx: &CrawlContext) -> Option<Vec<String>> {
+    let lookup = ctx
+        .cdx_api_hosts
+        .lock()
+        .unwrap()
+        .entry(pointer.host.clone())
+        .or_default()
+        .clone();
+    // held during the query, so other pointers of the host wait for its outcome
+    let mut queried = lookup.lock().unwrap();
+    match *queried {
+        Some(true) => return Some(vec![]),
+        Some(false) => return None,
+        None => {}
+    }
+    let lines = fetch_cdx_api(pointer, ctx);
+    *queried = Some(lines.is_some());
+    lines
+}
+
+/// Query the CDX API for all captures of the pointer's host, see
+/// [query_cdx_api].
+fn fetch_cdx_api(pointer: &IndexHostPointer, ctx: &CrawlContext) -> Option<Vec<String>> {
+    let api = format!("{}/{}-index", ctx.options.index_server_url(), pointer.index_id()?);
+    let query = [
+        ("url", pointer.host.as_str()),
+        ("matchType", "host"),
+        ("output", "json"),
+    ];
+    let api_url = |extra: (&str, &str)| {
+        reqwest::Url::parse_with_params(&api, query.iter().chain(std::iter::once(&extra))).ok()
+    };
+    let num_pages = fetch(api_url(("showNumPages", "true"))?.as_str(), ctx).ok()?;
+    let num_pages = serde_json::from_slice::<serde_json::Value>(&num_pages).ok()?;
+    let mut lines = vec![];
+    for page in 0..num_pages.get("pages")?.as_u64()? {
+        let bytes = fetch(api_url(("page", &page.to_string()))?.as_str(), ctx).ok()?;
+        lines.extend(BufReader::new(&*bytes).lines().filter_map(|line| {
+            let line = line.ok()?;
+            let capture = serde_json::from_str::<serde_json::Value>(&line).ok()?;
+            let urlkey = capture.get("urlkey")?.as_str()?;
+            let timestamp = capture.get("timestamp")?.as_str()?;
+            Some(format!("{} {} {}", urlkey, timestamp, line))
+        }));
+    }
+    ctx.counters.cdx_api_fallbacks.fetch_add(1, AtomicOrdering::Relaxed);
+    Some(lines)
 }
 
 /// retrieve IP address of a crawl result from the WARC file specified in the index record
-fn retrieve_ip(
-    host: String,
-    timestamp_str: String,
-    index_record: IndexRecord,
-) -> Option<MappingEntry> {
+fn retrieve_ip(line: CdxLine, ctx: &CrawlContext) -> Option<MappingEntry> {
+    let (url, offset, length) = match warc_range(&line.record, ctx.options) {
+        Ok(range) => range,
+        Err(_) => {
+            ctx.counters.malformed_lines.fetch_add(1, AtomicOrdering::Relaxed);
+            ctx.counters.record_failure(Endpoint::Cdx, FailureKind::Parse);
+            return None;
+        }
+    };
+    // WARC records that cannot be parsed are counted by next_warc_window
+    let capture = match read_warc_capture(&url, offset, length, ctx) {
+        Ok(capture) => capture,
+        Err(_) => return None,
+    };
+    match capture {
+        Some(capture) if keep_ip(capture.ip, ctx.options, ctx.counters) => {
+            Some(mapping_entry(line, capture, ctx.options))
+        }
+        Some(_) => None,
+        None => {
+            ctx.counters.missing_ip.fetch_add(1, AtomicOrdering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Check a resolved IP against the [CrawlOptions::ip_family] and
+/// [CrawlOptions::drop_bogons] options, counting bogons.
+fn keep_ip(ip: IpAddr, options: &CrawlOptions, counters: &CrawlCounters) -> bool {
+    if is_bogon(ip) {
+        counters.bogon_ips.fetch_add(1, AtomicOrdering::Relaxed);
+        if options.drop_bogons {
+            return false;
+        }
+    }
+    options.ip_family.is_none_or(|family| family.matches(&ip))
+}
+
+/// The mapping of a capture whose WARC record has been read.
+fn mapping_entry(line: CdxLine, capture: WarcCapture, options: &CrawlOptions) -> MappingEntry {
+    let unicode = match options.idn {
+        IdnForm::Ascii => None,
+        IdnForm::Unicode | IdnForm::Both => host_to_unicode(&line.host),
+    };
+    let timestr = line.day();
+    let port = line.port().filter(|_| options.with_ports);
+    let record = Some(&line.record)
+        .filter(|_| options.with_record_fields)
+        .map(RecordInfo::from_record);
+    let (host, host_unicode) = match options.idn {
+        IdnForm::Unicode => (unicode.unwrap_or(line.host), None),
+        _ => (line.host, unicode),
+    };
+    let mut entry = MappingEntry {
+        timestr,
+        port,
+        status: line.record.status.parse().ok().filter(|_| options.with_status),
+        record,
+        url: Some(line.record.url).filter(|_| options.with_url),
+        host,
+        host_unicode,
+        ip: capture.ip,
+        prefix: match options.prefixes {
+            PrefixOutput::Off => None,
+            _ => Some(options.prefix_lengths.prefix_of(capture.ip)),
+        },
+        asn: None,
+        rpki: None,
+        provider: None,
+        org: None,
+        country: None,
+        server: capture.server,
+        protocol: capture.protocol,
+    };
+    if let Some(enricher) = &options.enricher {
+        enricher.annotate(&mut entry);
+    }
+    entry
+}
+
+/// What is read from the beginning of a WARC record.
+struct WarcCapture {
+    ip: IpAddr,
+    /// Set if [CrawlOptions::capture_server] is
+    server: Option<ServerInfo>,
+    /// Set if [CrawlOptions::capture_protocol] is
+    protocol: Option<ProtocolInfo>,
+}
+
+/// Outcome of reading one window of a WARC record, see [next_warc_window].
+enum WarcWindow {
+    /// The headers needed were complete, `None` if the record has no IP
+    /// address
+    Capture(Option<WarcCapture>),
+    /// The headers were cut off, read this larger window
+    Widen(i64),
+}
+
+/// Read the `WARC-IP-Address` header of the WARC record of an index record,
+/// widening the read window until the headers needed are complete.
+fn fetch_warc_capture(
+    index_record: &IndexRecord,
+    ctx: &CrawlContext,
+) -> Result<Option<WarcCapture>, CrawlError> {
+    let (url, offset, length) = warc_range(index_record, ctx.options)?;
+    read_warc_capture(&url, offset, length, ctx)
+}
+
+/// Same as [fetch_warc_capture], for the WARC record of `length` bytes at
+/// `offset` of `url`.
+fn read_warc_capture(
+    url: &str,
+    offset: i64,
+    length: i64,
+    ctx: &CrawlContext,
+) -> Result<Option<WarcCapture>, CrawlError> {
+    let mut window = ctx.options.warc_read_bytes();
+    loop {
+        let bytes = fetch_range(url, offset, offset + window.min(length) - 1, ctx)?;
+        match next_warc_window(&bytes, window, length, ctx.options, ctx.counters)? {
+            WarcWindow::Capture(capture) => return Ok(capture),
+            WarcWindow::Widen(next) => {
+                ctx.counters.warc_refetches.fetch_add(1, AtomicOrdering::Relaxed);
+                window = next;
+            }
+        }
+    }
+}
+
+/// Look for the IP address, and the server headers if wanted, in the first
+/// `window` bytes of a WARC record of `length` bytes. Returns them, or
+/// `None` if the record has no IP address, or the next, larger window to
+/// read if the headers were cut off. Records that cannot be parsed are
+/// counted in the crawl counters and returned as an error.
+fn next_warc_window(
+    bytes: &[u8],
+    window: i64,
+    length: i64,
+    options: &CrawlOptions,
+    counters: &CrawlCounters,
+) -> Result<WarcWindow, ParseError> {
+    let (headers, http) = match parse_warc_response(bytes) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            counters.record_failure(Endpoint::Warc, FailureKind::Parse);
+            return Err(e);
+        }
+    };
+    let cut_off = window < length;
+    let widen = Ok(WarcWindow::Widen(window.saturating_mul(2)));
+    let ip = match headers.ip_address() {
+        Some(ip) => ip,
+        None if cut_off && !headers.complete => return widen,
+        None => return Ok(WarcWindow::Capture(None)),
+    };
+    // the protocol fields may follow the IP address
+    if options.capture_protocol && cut_off && !headers.complete {
+        return widen;
+    }
+    let server = match http {
+        _ if !options.capture_server => None,
+        Some(http) if http.complete || !cut_off => Some(ServerInfo::from_headers(&http)),
+        None if headers.complete || !cut_off => Some(ServerInfo::default()),
+        _ => return widen,
+    };
+    let protocol = match options.capture_protocol {
+        true => Some(ProtocolInfo::from_headers(&headers)),
+        false => None,
+    };
+    Ok(WarcWindow::Capture(Some(WarcCapture {
+        ip,
+        server,
+        protocol,
+    })))
+}
+
+/// URL, offset and length of the WARC record of an index record.
+fn warc_range(
+    index_record: &IndexRecord,
+    options: &CrawlOptions,
+) -> Result<(String, i64, i64), ParseError> {
     let url = format!(
         "{}/{}",
-        BASE_URL,
+        options.base_url(),
         index_record.filename
     );
-    let start: i64 = index_record.offset.parse::<i64>().unwrap();
-    let mut length: i64 = index_record.length.parse::<i64>().unwrap();
-    if length > 901 {
-        length = 901;
-    }
-    let end: i64 = start + length;
-
-    let range_str = format!("bytes={}-{}", start, end);
-    let range = HeaderValue::from_str(&range_str).unwrap();
-    let client = reqwest::blocking::Client::new();
-    let rsp = match client.get(&url).header(RANGE, range).send() {
-        Ok(res) => res,
-        Err(_) => return None,
+    let number = |field: &str| {
+        field
+            .parse::<i64>()
+            .ok()
+            .filter(|n| *n >= 0)
+            .ok_or_else(|| ParseError::new(format!("invalid offset or length {:?}", field)))
     };
-    let bytes = rsp.bytes().unwrap();
-    let reader = BufReader::new(GzDecoder::new(&*bytes));
-    // let reader = BufReader::new(&*bytes);
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                if line.starts_with("WARC-IP-Address") {
-                    if let Ok(addr) = line.split(": ").collect::<Vec<&str>>()[1].parse::<IpAddr>() {
-                        drop(client);
-                        return Some(MappingEntry {
-                            host: host.to_owned(),
-                            timestr: timestamp_str,
-                            ip: addr,
-                        });
-                    }
-                }
+    let offset: i64 = number(&index_record.offset)?;
+    let length: i64 = number(&index_record.length)?;
+    Ok((url, offset, length))
+}
+
+pub fn get_writer(filename: &str) -> Box<dyn Write> {
+    match try_get_writer(filename) {
+        Err(why) => panic!("couldn't open {}: {}", filename, why),
+        Ok(writer) => writer,
+    }
+}
+
+/// Same as [get_writer], but returns an error instead of panicking when the
+/// file cannot be created.
+///
+/// Seekable `.bgz` and `.zst` files get a host index in
+/// `<filename>.idx`, see [SeekableWriter].
+pub fn try_get_writer(filename: &str) -> std::io::Result<Box<dyn Write>> {
+    Ok(Box::new(FileWriter::create(filename)?))
+}
+
+/// Writer of a local file, compressed according to its extension like with
+/// [try_get_writer]. Dropping it finishes compressed files silently,
+/// [FileWriter::finish] returns the errors instead.
+pub(crate) enum FileWriter {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+    Seekable(BufWriter<SeekableWriter<File>>),
+}
+
+impl FileWriter {
+    pub(crate) fn create(filename: &str) -> std::io::Result<FileWriter> {
+        if let Some(format) = SeekableFormat::of(filename) {
+            let index = format!("{}.{}", filename, INDEX_EXTENSION);
+            let index = BufWriter::new(File::create(index)?);
+            let writer =
+                SeekableWriter::new(File::create(filename)?, format, Some(Box::new(index)));
+            return Ok(FileWriter::Seekable(BufWriter::with_capacity(128 * 1024, writer)));
+        }
+        let file = File::create(filename)?;
+        if Path::new(filename).extension() == Some(OsStr::new("gz")) {
+            let encoder = GzEncoder::new(file, Compression::default());
+            Ok(FileWriter::Gzip(BufWriter::with_capacity(128 * 1024, encoder)))
+        } else {
+            Ok(FileWriter::Plain(BufWriter::with_capacity(128 * 1024, file)))
+        }
+    }
+
+    /// Flush the buffered lines and write the end of a compressed file.
+    pub(crate) fn finish(self) -> std::io::Result<()> {
+        match self {
+            FileWriter::Plain(mut writer) => writer.flush(),
+            FileWriter::Gzip(writer) => {
+                let encoder = writer.into_inner().map_err(IntoInnerError::into_error)?;
+                encoder.finish().map(drop)
+            }
+            FileWriter::Seekable(writer) => {
+                writer.into_inner().map_err(IntoInnerError::into_error)?.finish()
             }
-            Err(_) => break,
         }
     }
-    drop(client);
-    None
 }
 
-pub fn get_writer(filename: &str) -> Box<dyn Write> {
-    let path = Path::new(filename);
-    let file = match File::create(&path) {
-        Err(why) => panic!("couldn't open {}: {}", path.display(), why.to_string()),
-        Ok(file) => file,
-    };
-    if path.extension() == Some(OsStr::new("gz")) {
+impl Write for FileWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            FileWriter::Plain(writer) => writer.write(data),
+            FileWriter::Gzip(writer) => writer.write(data),
+            FileWriter::Seekable(writer) => writer.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            FileWriter::Plain(writer) => writer.flush(),
+            FileWriter::Gzip(writer) => writer.flush(),
+            FileWriter::Seekable(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Buffer writes to `inner`, gzip-compressing them if `filename` ends with
+/// `.gz`, or into a seekable format without host index, see
+/// [SeekableFormat::of].
+pub(crate) fn encoded_writer<W: Write + Send + 'static>(
+    filename: &str,
+    inner: W,
+) -> Box<dyn Write + Send> {
+    if let Some(format) = SeekableFormat::of(filename) {
+        Box::new(BufWriter::with_capacity(
+            128 * 1024,
+            SeekableWriter::new(inner, format, None),
+        ))
+    } else if Path::new(filename).extension() == Some(OsStr::new("gz")) {
         // Error is here: Created file isn't gzip-compressed
         Box::new(BufWriter::with_capacity(
             128 * 1024,
-            GzEncoder::new(file, Compression::default()),
+            GzEncoder::new(inner, Compression::default()),
         ))
     } else {
-        Box::new(BufWriter::with_capacity(128 * 1024, file))
+        Box::new(BufWriter::with_capacity(128 * 1024, inner))
+    }
+}
+
+/// Open a file for line-based reading, transparently decompressing it if the
+/// file name ends with `.gz` or `.bgz`, or `.zst` with the zstd feature.
+/// Counterpart of [get_writer].
+pub fn get_reader(filename: &str) -> std::io::Result<Box<dyn BufRead>> {
+    let path = Path::new(filename);
+    let file = File::open(path)?;
+    match path.extension().and_then(OsStr::to_str) {
+        // BGZF and concatenated gzip files have several members
+        Some("gz") | Some("bgz") => Ok(Box::new(BufReader::with_capacity(
+            128 * 1024,
+            MultiGzDecoder::new(file),
+        ))),
+        #[cfg(feature = "zstd")]
+        Some("zst") => Ok(Box::new(BufReader::with_capacity(
+            128 * 1024,
+            zstd::stream::read::Decoder::new(file)?,
+        ))),
+        _ => Ok(Box::new(BufReader::with_capacity(128 * 1024, file))),
     }
 }
 
@@ -446,6 +1690,7 @@ pub fn get_writer(filename: &str) -> Box<dyn Write> {
 /// results to `mapping.csv`.
 ///
 /// ```no_run
+/// # use cc_host_mapper::*;
 /// let newest_index = get_newest_index();
 /// crawl_host_ip_mapping(newest_index.id.to_owned(), "mapping.csv".to_owned(), None);
 /// ```
@@ -453,59 +1698,813 @@ pub fn get_writer(filename: &str) -> Box<dyn Write> {
 /// You can also specify the number of threads you want. For example, run crawling with 16 threads:
 ///
 /// ```no_run
+/// # use cc_host_mapper::*;
 /// let newest_index = get_newest_index();
 /// crawl_host_ip_mapping(newest_index.id.to_owned(), "mapping.csv".to_owned(), Some(16));
 /// ```
+///
+/// The returned [CrawlStats] tell whether the crawl succeeded, e.g. to
+/// retry it or to publish the output:
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let stats = crawl_host_ip_mapping("CC-MAIN-2020-50".to_owned(), "mapping.csv".to_owned(), None);
+/// if stats.index_error_retryable || stats.failure_rate() > 0.05 {
+///     eprintln!("crawl incomplete, retry later:\n{}", stats);
+/// } else if stats.output_error.is_none() {
+///     println!("wrote {:?}", stats.output_files);
+/// }
+/// ```
+///
+/// For any further configuration, use [Crawler::builder].
 pub fn crawl_host_ip_mapping(
     index_id: String,
     output_file_name: String,
     num_threads: Option<usize>,
-) {
-    let host_pointers = read_cluster_idx(&index_id);
-    let total_hosts = host_pointers.len().clone() as u64;
+) -> CrawlStats {
+    let options = CrawlOptions {
+        num_threads,
+        ..Default::default()
+    };
+    crawl_with_options(index_id, output_file_name, &options)
+}
+
+/// Options controlling which host pointers are crawled and how. Start from
+/// [CrawlOptions::default] and override the fields of interest.
+#[derive(Debug, Clone, Default)]
+pub struct CrawlOptions {
+    /// Number of threads used for crawling, defaults to the number of CPUs
+    pub num_threads: Option<usize>,
+    /// Skip this many host pointers at the start of cluster.idx
+    pub idx_offset: usize,
+    /// Process at most this many host pointers after `idx_offset`
+    pub idx_count: Option<usize>,
+    /// Crawl only a uniform random sample of this many host pointers
+    pub sample: Option<usize>,
+    /// Seed for the random sample and `shuffle`, a random seed is used if
+    /// not set
+    pub seed: Option<u64>,
+    /// Process the selected pointers in random order rather than in
+    /// cluster.idx order, which concentrates the requests on a few cdx files
+    /// at a time, see [shuffle_pointers]
+    pub shuffle: bool,
+    /// Stop dispatching after this many host pointers
+    pub limit: Option<usize>,
+    /// Include/exclude rules applied to hostnames from cluster.idx
+    pub host_filter: HostFilter,
+    /// Rules applied to the index records of each host
+    pub record_filter: RecordFilter,
+    /// Only output mappings of this IP address family
+    pub ip_family: Option<IpFamily>,
+    /// How crawl progress is displayed
+    pub progress: ProgressMode,
+    /// Retries of failed requests
+    pub retry: RetryPolicy,
+    /// Maximum number of requests per second across all workers
+    pub max_requests_per_sec: Option<f64>,
+    /// Start at a lower request rate and ramp up to `max_requests_per_sec`
+    pub ramp_up: Option<RampUp>,
+    /// Delay each thread waits before every request
+    pub request_delay: Duration,
+    /// Upper bound of a random delay added to `request_delay`
+    pub request_jitter: Duration,
+    /// Maximum download bandwidth in bytes per second across all threads,
+    /// enforced while reading the response bodies by the [ReqwestFetcher]
+    /// built from these options
+    pub max_bytes_per_sec: Option<u64>,
+    /// Maximum number of concurrent requests to the same cdx or WARC file
+    /// across all threads, see [FileLimiter]
+    pub max_requests_per_file: Option<usize>,
+    /// When to pause or abort the crawl on sustained failures
+    pub breaker: BreakerPolicy,
+    /// Skip the pointers this checkpoint lists as completed, and add the
+    /// pointers completed by the crawl to it. It is saved whenever the
+    /// circuit breaker pauses the crawl, if the sink supports
+    /// [MappingSink::sync], and when the crawl ends.
+    pub checkpoint: Option<Arc<Checkpoint>>,
+    /// User-Agent sent with every request, defaults to [DEFAULT_USER_AGENT]
+    pub user_agent: Option<String>,
+    /// Proxy URL for all requests, overriding `HTTPS_PROXY`/`HTTP_PROXY`.
+    /// Both HTTP(S) and SOCKS5 (`socks5://`, or `socks5h://` to resolve
+    /// hostnames through the proxy) proxies are supported.
+    pub proxy: Option<String>,
+    /// Look up hosts through the CDX API of the
+    /// [CrawlOptions::index_server_url] when their cdx block cannot be
+    /// fetched after all retries
+    pub cdx_api_fallback: bool,
+    /// Also map the other hosts whose records are in the fetched cdx block
+    /// of a pointer, and that start no cdx block of cluster.idx themselves,
+    /// instead of only the pointer's host. Their records are downloaded
+    /// anyway, and most hosts start no cdx block.
+    pub bonus_hosts: bool,
+    /// Directory caching cluster.idx, cdx blocks and WARC snippets across
+    /// runs, disabled if `None`
+    pub cache_dir: Option<PathBuf>,
+    /// Size cap of the cache in bytes, defaults to [DEFAULT_CACHE_SIZE]
+    pub cache_max_bytes: Option<u64>,
+    /// Number of results buffered between the crawling threads and the
+    /// output writer, defaults to [DEFAULT_CHANNEL_CAPACITY]
+    pub channel_capacity: Option<usize>,
+    /// Token to stop the crawl early
+    pub cancel: CancelToken,
+    /// Callbacks following the progress of the crawl
+    pub observer: Option<Arc<dyn ProgressObserver>>,
+    /// Source of all downloads, a [ReqwestFetcher] built from these options
+    /// if not set
+    pub fetcher: Option<Arc<dyn Fetcher>>,
+    /// Timeout for establishing a connection
+    pub connect_timeout: Option<Duration>,
+    /// Timeout for a whole request, from connecting until the response body
+    /// is read
+    pub request_timeout: Option<Duration>,
+    /// Log the requests that take longer than this at debug level, through
+    /// the `log` crate, with the ID of their fetch, their URL, range,
+    /// status and retry count
+    pub slow_request: Option<Duration>,
+    /// Where to fetch cluster.idx, index and WARC files from, e.g. a regional
+    /// mirror or caching proxy. Defaults to [DEFAULT_BASE_URL].
+    pub base_url: Option<String>,
+    /// Where to query the CDX API for [CrawlOptions::cdx_api_fallback],
+    /// e.g. a mirror of the index server. Defaults to
+    /// `https://index.commoncrawl.org`.
+    pub index_server_url: Option<String>,
+    /// Bytes read from the start of each WARC record to find its headers,
+    /// defaults to [DEFAULT_WARC_READ_BYTES]. Records whose header block
+    /// does not end within this window are re-fetched with a larger one.
+    pub warc_read_bytes: Option<u64>,
+    /// Also read the `Server` and `X-Powered-By` headers of the HTTP
+    /// responses into [MappingEntry::server]
+    pub capture_server: bool,
+    /// Also read the `WARC-Protocol` and `WARC-Cipher-Suite` fields of the
+    /// WARC records into [MappingEntry::protocol]
+    pub capture_protocol: bool,
+    /// Keep the port of each capture: captures on different ports of a host
+    /// are resolved separately and the port is set in [MappingEntry::port]
+    pub with_ports: bool,
+    /// Set [MappingEntry::url] to the URL of the capture each IP was read
+    /// from. One capture per day is still resolved.
+    pub with_url: bool,
+    /// Set [MappingEntry::status] to the HTTP status of the capture each IP
+    /// was read from, e.g. to tell mappings from redirects apart
+    pub with_status: bool,
+    /// Set [MappingEntry::record] to the languages, charset, truncation and
+    /// redirect target the index recorded for the capture each IP was read
+    /// from
+    pub with_record_fields: bool,
+    /// Form of internationalized hostnames in the output
+    pub idn: IdnForm,
+    /// Canonicalization of hostnames, applied before IDN decoding
+    pub host_normalization: HostNormalization,
+    /// Drop mappings with the host, IP and date of one of the last this many
+    /// written, see [DEFAULT_DEDUP_WINDOW]. Disabled if `None`.
+    pub dedup_window: Option<usize>,
+    /// Drop the mappings already in this Bloom filter, exact duplicates
+    /// but for its false positives, and add the others. Share it between
+    /// crawls, or save it, to drop the duplicates across indexes or shards.
+    pub dedup_bloom: Option<Arc<Mutex<BloomDedup>>>,
+    /// Whether the prefixes of the IPs are written, alongside the IPs or
+    /// in their place
+    pub prefixes: PrefixOutput,
+    /// Lengths of the prefixes written with [CrawlOptions::prefixes]
+    pub prefix_lengths: PrefixLengths,
+    /// Routing datasets the mappings are annotated with
+    pub enricher: Option<Arc<Enricher>>,
+    /// Skip mappings to private, reserved and other special-purpose IPs,
+    /// see [is_bogon]. They are counted in [CrawlStats::bogon_ips] either
+    /// way.
+    pub drop_bogons: bool,
+    /// File the distinct hosts and IPs per [MappingEntry::country] are
+    /// written to at the end of the crawl, as JSON if it ends with `.json`
+    /// and else as CSV, see [write_group_report]
+    pub country_report: Option<String>,
+    /// File the distinct hosts and IPs per origin AS, see
+    /// [MappingEntry::asn], are written to at the end of the crawl, like
+    /// `country_report`. Hosts of multi-origin prefixes count for each
+    /// origin, those of unrouted IPs under an empty ASN.
+    pub as_report: Option<String>,
+    /// Number of origin ASes with the most hosts kept in
+    /// [CrawlStats::top_ases] with `as_report`
+    pub as_report_top: usize,
+    /// Format of the output file of [crawl_with_options]
+    pub output_format: OutputFormat,
+    /// With Parquet output, write the mappings into a Hive-style directory
+    /// tree under the output path, one level per key, e.g.
+    /// `index_id=CC-MAIN-2020-50/date=2020-11-26/part-cc-main-2020-50.parquet`,
+    /// that Athena, Spark and DuckDB can query in place. Ignored for CSV.
+    pub partition_by: Vec<PartitionKey>,
+    /// Store the output of [crawl_with_options] is uploaded to when its
+    /// path is an `s3://` or `gs://` URL, see [is_object_url]
+    pub object_store: Option<Arc<ObjectStore>>,
+    /// With object store output, finish the object after this many
+    /// mappings and continue in a new one, numbered before its extensions,
+    /// e.g. `mapping-00000.csv.gz`, `mapping-00001.csv.gz`..., so that a
+    /// failure only loses the mappings of the last object. Ignored for
+    /// partitioned output.
+    pub upload_chunk_mappings: Option<u64>,
+}
+
+impl CrawlOptions {
+    /// The data endpoint in use, without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+    }
+
+    /// The index server in use for the CDX API, without a trailing slash.
+    pub fn index_server_url(&self) -> &str {
+        self.index_server_url
+            .as_deref()
+            .unwrap_or(INDEX_SERVER_URL)
+            .trim_end_matches('/')
+    }
 
-    let (sender, receiver) = channel::<MappingEntry>();
-    let (sender_pb, receiver_pb) = channel::<String>();
+    /// The initial WARC read window in use, at least one byte.
+    pub fn warc_read_bytes(&self) -> i64 {
+        self.warc_read_bytes.unwrap_or(DEFAULT_WARC_READ_BYTES).max(1) as i64
+    }
+}
+
+/// Open the cache configured in the [CrawlOptions], if any.
+fn open_cache(options: &CrawlOptions) -> Option<DiskCache> {
+    let dir = options.cache_dir.clone()?;
+    let max_bytes = options.cache_max_bytes.unwrap_or(DEFAULT_CACHE_SIZE);
+    Some(DiskCache::open(dir, max_bytes).expect("failed to open cache directory"))
+}
 
-    // dedicated thread for handling output of results
-    let writer_thread = thread::spawn(move || {
-        let mut writer = get_writer(&output_file_name.as_str());
-        for item in receiver.iter() {
-            writeln!(writer, "{},{},{}", item.host, item.timestr, item.ip).unwrap();
+/// Outcome of the CDX API lookup of each host, `None` while it is running,
+/// see [query_cdx_api].
+type CdxApiLookups = Mutex<HashMap<String, Arc<Mutex<Option<bool>>>>>;
+
+/// Fetcher, limiters, cache and circuit breaker of a crawl, set up from its
+/// [CrawlOptions].
+struct CrawlResources {
+    fetcher: Arc<dyn Fetcher>,
+    limiter: RateLimiter,
+    files: FileLimiter,
+    cache: Option<DiskCache>,
+    breaker: CircuitBreaker,
+    cdx_api_hosts: CdxApiLookups,
+}
+
+impl CrawlResources {
+    fn new(options: &CrawlOptions) -> CrawlResources {
+        CrawlResources {
+            fetcher: match &options.fetcher {
+                Some(fetcher) => fetcher.clone(),
+                None => Arc::new(
+                    ReqwestFetcher::from_options(options).expect("failed to build HTTP client"),
+                ),
+            },
+            limiter: RateLimiter::from_options(options),
+            files: FileLimiter::new(options.max_requests_per_file),
+            cache: open_cache(options),
+            breaker: CircuitBreaker::new(options.breaker.clone()),
+            cdx_api_hosts: Mutex::new(HashMap::new()),
         }
-    });
+    }
 
-    // dedicated thread for showing progress of the parsing
-    thread::spawn(move || {
-        let sty = ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-            .progress_chars("##-");
-        let pb = ProgressBar::new(total_hosts);
-        pb.set_style(sty);
-        for host in receiver_pb.iter() {
-            pb.set_message(&host);
-            pb.inc(1);
+    fn context<'a>(
+        &'a self,
+        options: &'a CrawlOptions,
+        counters: &'a CrawlCounters,
+    ) -> CrawlContext<'a> {
+        CrawlContext {
+            options,
+            fetcher: self.fetcher.as_ref(),
+            counters,
+            limiter: &self.limiter,
+            files: &self.files,
+            cache: self.cache.as_ref(),
+            breaker: &self.breaker,
+            pointer_hosts: None,
+            cdx_api_hosts: &self.cdx_api_hosts,
+        }
+    }
+}
+
+/// Shared state of a running crawl, handed to every worker.
+struct CrawlContext<'a> {
+    options: &'a CrawlOptions,
+    fetcher: &'a dyn Fetcher,
+    counters: &'a CrawlCounters,
+    limiter: &'a RateLimiter,
+    files: &'a FileLimiter,
+    cache: Option<&'a DiskCache>,
+    breaker: &'a CircuitBreaker,
+    /// Hosts of the pointers of the crawl, see [select_records]
+    pointer_hosts: Option<&'a HashSet<String>>,
+    /// Hosts already looked up through the CDX API, see [query_cdx_api]
+    cdx_api_hosts: &'a CdxApiLookups,
+}
+
+/// Read the cluster.idx of an index and apply the pointer selection settings
+/// of the [CrawlOptions]: host filter, slice, sample, limit, and shuffle, in
+/// that order.
+///
+/// Returns the selected pointers along with the number of pointers that were
+/// available before the limit was applied.
+pub fn select_host_pointers(
+    index_id: &str,
+    options: &CrawlOptions,
+) -> (Vec<IndexHostPointer>, usize) {
+    narrow_pointers(read_cluster_idx_with(index_id, options), options)
+}
+
+/// Same as [select_host_pointers], but returns an error if cluster.idx
+/// cannot be downloaded, and records its download in the crawl counters.
+/// Also returns the [pointer_hosts] of all pointers before the selection.
+#[allow(clippy::type_complexity)]
+fn try_select_host_pointers(
+    index_id: &str,
+    options: &CrawlOptions,
+    counters: &CrawlCounters,
+) -> Result<(Vec<IndexHostPointer>, usize, Option<HashSet<String>>), CrawlError> {
+    let pointers = read_cluster_idx_in(index_id, options, Some(counters))?;
+    let hosts = pointer_hosts(&pointers, options);
+    let (pointers, available) = narrow_pointers(pointers, options);
+    Ok((pointers, available, hosts))
+}
+
+/// The hosts of the pointers of cluster.idx with
+/// [CrawlOptions::bonus_hosts]. Their records are only selected by their
+/// own pointers, even when the pointer is not selected by this crawl, so
+/// that each host is mapped once across the shards of an index.
+pub(crate) fn pointer_hosts(
+    pointers: &[IndexHostPointer],
+    options: &CrawlOptions,
+) -> Option<HashSet<String>> {
+    options
+        .bonus_hosts
+        .then(|| pointers.iter().map(|pointer| pointer.host.clone()).collect())
+}
+
+/// Apply slice, sample, limit, and shuffle of the [CrawlOptions] to the
+/// pointers.
+fn narrow_pointers(
+    mut host_pointers: Vec<IndexHostPointer>,
+    options: &CrawlOptions,
+) -> (Vec<IndexHostPointer>, usize) {
+    if options.idx_offset > 0 || options.idx_count.is_some() {
+        host_pointers = slice_pointers(host_pointers, options.idx_offset, options.idx_count);
+    }
+    if let Some(n) = options.sample {
+        host_pointers = sample_pointers(host_pointers, n, options.seed);
+    }
+    let available_hosts = host_pointers.len();
+    if let Some(n) = options.limit {
+        host_pointers.truncate(n);
+    }
+    if options.shuffle {
+        host_pointers = shuffle_pointers(host_pointers, options.seed);
+    }
+    (host_pointers, available_hosts)
+}
+
+/// Same as [crawl_host_ip_mapping], but with the crawl configured by a
+/// [CrawlOptions]. Returns a [CrawlStats] summary of the run.
+///
+/// # Example
+///
+/// Crawl a random sample of 10,000 hosts with a fixed seed:
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let options = CrawlOptions {
+///     sample: Some(10_000),
+///     seed: Some(42),
+///     ..Default::default()
+/// };
+/// crawl_with_options("CC-MAIN-2020-50".to_owned(), "mapping.csv".to_owned(), &options);
+/// ```
+pub fn crawl_with_options(
+    index_id: String,
+    output_file_name: String,
+    options: &CrawlOptions,
+) -> CrawlStats {
+    let sink = options
+        .output_format
+        .create_sink(&output_file_name, &index_id, options);
+    let mut sink = match sink {
+        Ok(sink) => sink,
+        Err(e) => {
+            return CrawlStats {
+                output_error: Some(format!("{}: {}", output_file_name, e)),
+                ..Default::default()
+            }
+        }
+    };
+    let uploaded = options.object_store.as_ref().map_or(0, |store| store.written().len());
+    let mut stats = crawl_into(&index_id, sink.as_mut(), options, &output_file_name);
+    stats.output_error = stats
+        .output_error
+        .map(|e| format!("{}: {}", output_file_name, e));
+    let outputs = match &options.object_store {
+        Some(store) if is_object_url(&output_file_name) => store.written()[uploaded..]
+            .iter()
+            .map(|(url, _)| url.to_string())
+            .collect(),
+        _ => vec![output_file_name],
+    };
+    stats.output_files.splice(0..0, outputs);
+    stats
+}
+
+/// Crawl the index like [crawl_with_options], but write the mappings to the
+/// given [MappingSink] instead of a file.
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// let mut mappings: Vec<MappingEntry> = vec![];
+/// let options = CrawlOptions {
+///     limit: Some(100),
+///     ..Default::default()
+/// };
+/// crawl_to_sink("CC-MAIN-2020-50", &mut mappings, &options);
+/// ```
+pub fn crawl_to_sink(
+    index_id: &str,
+    sink: &mut dyn MappingSink,
+    options: &CrawlOptions,
+) -> CrawlStats {
+    crawl_into(index_id, sink, options, "sink")
+}
+
+/// Crawl the index into `sink`. `output_name` is only used for display.
+fn crawl_into(
+    index_id: &str,
+    sink: &mut dyn MappingSink,
+    options: &CrawlOptions,
+    output_name: &str,
+) -> CrawlStats {
+    let start_time = Instant::now();
+    let counters = Arc::new(CrawlCounters::default());
+    let selected = try_select_host_pointers(index_id, options, &counters);
+    let (mut host_pointers, available_hosts, pointer_hosts) = match selected {
+        Ok(selected) => selected,
+        Err(e) => {
+            let stats = CrawlStats {
+                index_error: Some(e.to_string()),
+                index_error_retryable: e.is_retryable(),
+                elapsed_secs: start_time.elapsed().as_secs_f64(),
+                ..counters.to_stats()
+            };
+            if let Some(observer) = &options.observer {
+                observer.on_error(&e);
+                observer.on_stats(&stats);
+            }
+            return stats;
         }
+    };
+    let mut pointers_resumed = 0;
+    if let Some(checkpoint) = &options.checkpoint {
+        let selected = host_pointers.len();
+        host_pointers.retain(|pointer| !checkpoint.contains(pointer));
+        pointers_resumed = (selected - host_pointers.len()) as u64;
+    }
+    let num_threads = options.num_threads;
+    let total_hosts = host_pointers.len() as u64;
+
+    // bounded, so that workers block instead of piling up results in memory
+    // when the writer falls behind
+    let capacity = options.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY);
+    let (sender, receiver) = sync_channel::<MappingEntry>(capacity);
+    let (sender_pb, receiver_pb) = sync_channel::<String>(capacity);
+
+    // dedicated thread for showing progress of the parsing
+    let progress_thread = spawn_progress(
+        options.progress,
+        counters.clone(),
+        total_hosts,
+        receiver_pb,
+        output_name.to_owned(),
+        options.cancel.clone(),
+    );
+
+    // a pool of our own, so that repeated crawls and the embedder's global
+    // pool do not get in the way of each other
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads.unwrap_or(0))
+        .build()
+        .expect("Failed to initialize rayon threadpool.");
+
+    println!("Will run in {} threads", pool.current_num_threads());
+
+    let resources = CrawlResources::new(options);
+    let breaker = &resources.breaker;
+    let ctx = CrawlContext {
+        pointer_hosts: pointer_hosts.as_ref(),
+        ..resources.context(options, &counters)
+    };
+    let hook = ObserverHook::new(options.observer.as_deref());
+    let shows_activity = options.progress.shows_activity();
+
+    let output = thread::scope(|scope| {
+        // start the actual crawling
+        let hook = &hook;
+        let pool = &pool;
+        scope.spawn(move || {
+            pool.install(|| {
+                host_pointers.par_iter().for_each_with((sender, sender_pb), |(s1,s2), x| {
+                    if breaker.is_aborted() || ctx.options.cancel.is_cancelled() {
+                        return;
+                    }
+                    if shows_activity {
+                        ctx.counters.set_activity(&x.host);
+                    }
+                    let pauses = breaker.pauses();
+                    let result = query_host_in(x.clone(), &ctx);
+                    hook.pointer_done(&x.host, &result, ctx.counters);
+                    // captures failing during a pause are lost, so such
+                    // pointers are crawled again when resuming
+                    let completed =
+                        result.is_ok() && breaker.pauses() == pauses && !breaker.is_aborted();
+                    for mapping in result.unwrap_or_default().into_iter().flatten() {
+                        s1.send(mapping).unwrap()
+                    }
+                    // only after its mappings were sent, see write_mappings
+                    if let (true, Some(checkpoint)) = (completed, &ctx.options.checkpoint) {
+                        checkpoint.insert(x);
+                    }
+                    ctx.counters.hosts_attempted.fetch_add(1, AtomicOrdering::Relaxed);
+                    s2.send(x.host.to_owned()).unwrap();
+                });
+            });
+        });
+
+        // handle the output of results on this thread, so that the sink
+        // does not need to be sent across threads
+        write_mappings(receiver, sink, options, breaker)
     });
+    progress_thread.join().unwrap();
 
-    // update number of threads to use if specified
-    if let Some(num_t) = num_threads {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(num_t)
-            .build_global()
-            .expect("Failed to initialize rayon threadpool.");
+    let mut stats = counters.to_stats();
+    output.fill_stats(&mut stats);
+    if breaker.is_aborted() {
+        stats.aborted = Some("circuit breaker tripped: sustained request failures".to_owned());
+    }
+    stats.cancelled = options.cancel.is_cancelled() && stats.hosts_attempted < total_hosts;
+    stats.hosts_available = available_hosts as u64;
+    stats.pointers_resumed = pointers_resumed;
+    stats.elapsed_secs = start_time.elapsed().as_secs_f64();
+    hook.finish(&stats);
+    stats
+}
+
+/// Write all mappings received from the workers to the sink, see
+/// [OutputTally::finish] for the result, and save the
+/// [CrawlOptions::checkpoint] whenever the breaker pauses the crawl and at
+/// the end.
+fn write_mappings(
+    receiver: Receiver<MappingEntry>,
+    sink: &mut dyn MappingSink,
+    options: &CrawlOptions,
+    breaker: &CircuitBreaker,
+) -> OutputTally {
+    let mut output = OutputTally::new(options);
+    let mut pauses = breaker.pauses();
+    loop {
+        match receiver.recv_timeout(CHECKPOINT_POLL_INTERVAL) {
+            Ok(item) => output.write(sink, item),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(checkpoint) = &options.checkpoint {
+            if breaker.pauses() != pauses {
+                pauses = breaker.pauses();
+                output.checkpoint(sink, &receiver, checkpoint, options.observer.as_deref());
+            }
+        }
+    }
+    let mut output = output.finish(sink);
+    if let (None, Some(checkpoint)) = (&output.error, &options.checkpoint) {
+        if let Err(e) = checkpoint.save() {
+            output.error = Some(format!("cannot save checkpoint: {}", e));
+        }
+    }
+    output
+}
+
+/// Bookkeeping of the mappings written to a sink.
+struct OutputTally {
+    written: u64,
+    duplicates: u64,
+    hosts: DistinctCounter,
+    ips: DistinctCounter,
+    dedup: Option<DedupWindow>,
+    bloom: Option<Arc<Mutex<BloomDedup>>>,
+    /// Hosts and IPs per country, and the file to write them to
+    countries: Option<(String, GroupCounter)>,
+    /// Hosts and IPs per origin AS, and the file to write them to
+    ases: Option<(String, GroupCounter)>,
+    /// Number of origin ASes with the most hosts kept in the stats
+    top_ases: usize,
+    /// Reports written when finished
+    reports: Vec<String>,
+    error: Option<String>,
+}
+
+impl OutputTally {
+    fn new(options: &CrawlOptions) -> OutputTally {
+        OutputTally {
+            written: 0,
+            duplicates: 0,
+            hosts: DistinctCounter::new(),
+            ips: DistinctCounter::new(),
+            dedup: options.dedup_window.map(DedupWindow::new),
+            bloom: options.dedup_bloom.clone(),
+            countries: options
+                .country_report
+                .clone()
+                .map(|filename| (filename, GroupCounter::new())),
+            ases: options
+                .as_report
+                .clone()
+                .map(|filename| (filename, GroupCounter::new())),
+            top_ases: options.as_report_top,
+            reports: vec![],
+            error: None,
+        }
+    }
+
+    fn write(&mut self, sink: &mut dyn MappingSink, item: MappingEntry) {
+        // after an I/O error keep draining the results so that workers
+        // are not blocked, but stop writing
+        if self.error.is_some() {
+            return;
+        }
+        if let Some(dedup) = &mut self.dedup {
+            if !dedup.insert(&item) {
+                self.duplicates += 1;
+                return;
+            }
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.lock().unwrap().insert_mapping(&item) {
+                self.duplicates += 1;
+                return;
+            }
+        }
+        if let Some((_, countries)) = &mut self.countries {
+            countries.add(item.country.as_deref().unwrap_or_default(), &item.host, item.ip);
+        }
+        if let Some((_, ases)) = &mut self.ases {
+            match &item.asn {
+                Some(asns) => {
+                    for asn in asns {
+                        ases.add(&asn.to_string(), &item.host, item.ip);
+                    }
+                }
+                None => ases.add("", &item.host, item.ip),
+            }
+        }
+        let host = item.host.clone();
+        let ip = item.ip;
+        if let Err(e) = sink.write(item) {
+            self.error = Some(e.to_string());
+            return;
+        }
+        self.written += 1;
+        self.hosts.insert(&host);
+        self.ips.insert(&ip);
+    }
+
+    /// Save the checkpoint mid-crawl, reporting the outcome to the observer.
+    /// Every pointer in the snapshot sent its mappings before it was marked
+    /// as completed, so they are all written once the channel is drained and
+    /// the sink synced.
+    fn checkpoint(
+        &mut self,
+        sink: &mut dyn MappingSink,
+        receiver: &Receiver<MappingEntry>,
+        checkpoint: &Checkpoint,
+        observer: Option<&dyn ProgressObserver>,
+    ) {
+        let pointers = checkpoint.snapshot();
+        while let Ok(item) = receiver.try_recv() {
+            self.write(sink, item);
+        }
+        if self.error.is_some() {
+            return;
+        }
+        match sink.sync() {
+            Ok(true) => {
+                let saved = checkpoint.save_pointers(&pointers).map(|()| pointers.len());
+                if let Some(observer) = observer {
+                    observer.on_checkpoint(&saved);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.error = Some(e.to_string()),
+        }
     }
 
-    println!("Will run in {} threads", rayon::current_num_threads());
+    /// Flush the sink and write the reports, recording an error in the
+    /// tally.
+    fn finish(mut self, sink: &mut dyn MappingSink) -> OutputTally {
+        if self.error.is_none() {
+            if let Err(e) = sink.flush() {
+                self.error = Some(e.to_string());
+            }
+        }
+        let reports = [("COUNTRY", &self.countries), ("ASN", &self.ases)];
+        for (key_header, report) in reports.iter() {
+            if let (None, Some((filename, groups))) = (&self.error, report) {
+                match write_group_report(filename, key_header, &groups.stats()) {
+                    Ok(()) => self.reports.push(filename.clone()),
+                    Err(e) => self.error = Some(format!("{}: {}", filename, e)),
+                }
+            }
+        }
+        self
+    }
 
-    // start the actual crawling
-    host_pointers.par_iter().for_each_with((sender, sender_pb), |(s1,s2), x| {
-        for mapping in query_host(x.clone()).into_iter().filter_map(|x| x) {
-            s1.send(mapping.clone()).unwrap()
+    /// Set the output statistics: the number of mappings written, of
+    /// distinct hosts and IPs among them, of duplicates dropped, the reports
+    /// written, and the error that stopped writing, if any.
+    fn fill_stats(self, stats: &mut CrawlStats) {
+        stats.output_files.extend(self.reports);
+        stats.mappings_written = self.written;
+        stats.duplicates_dropped = self.duplicates;
+        stats.unique_hosts = self.hosts.estimate();
+        stats.unique_ips = self.ips.estimate();
+        if let Some((_, ases)) = &self.ases {
+            stats.top_ases = ases.stats();
+            stats.top_ases.truncate(self.top_ases);
         }
-        s2.send(x.host.to_owned()).unwrap();
+        stats.output_error = self.error;
+    }
+}
+
+/// Crawl the index like [crawl_with_options], but hand the mappings to the
+/// caller as they are found instead of writing them to a file.
+///
+/// The crawl runs on a background thread pool of `options.num_threads`
+/// threads and yields an error for every host pointer whose index records
+/// could not be fetched. Dropping the iterator stops the crawl after the
+/// in-flight pointers. Output and progress options are ignored.
+///
+/// ```no_run
+/// # use cc_host_mapper::*;
+/// for mapping in crawl_iter("CC-MAIN-2020-50", &CrawlOptions::default()) {
+///     match mapping {
+///         Ok(m) => println!("{}", m.to_csv()),
+///         Err(e) => eprintln!("{}", e),
+///     }
+/// }
+/// ```
+pub fn crawl_iter(
+    index_id: &str,
+    options: &CrawlOptions,
+) -> impl Iterator<Item = Result<MappingEntry, CrawlError>> {
+    let index_id = index_id.to_owned();
+    let options = options.clone();
+    let capacity = options.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY);
+    let (sender, receiver) = sync_channel(capacity);
+
+    thread::spawn(move || {
+        let counters = CrawlCounters::default();
+        let (host_pointers, pointer_hosts) =
+            match try_select_host_pointers(&index_id, &options, &counters) {
+                Ok((host_pointers, _, pointer_hosts)) => (host_pointers, pointer_hosts),
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+        let resources = CrawlResources::new(&options);
+        let ctx = CrawlContext {
+            pointer_hosts: pointer_hosts.as_ref(),
+            ..resources.context(&options, &counters)
+        };
+        let stopped = AtomicBool::new(false);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.num_threads.unwrap_or(0))
+            .build()
+            .expect("Failed to initialize rayon threadpool.");
+        pool.install(|| {
+            host_pointers.par_iter().for_each_with(sender, |s, x| {
+                if stopped.load(AtomicOrdering::Relaxed)
+                    || resources.breaker.is_aborted()
+                    || options.cancel.is_cancelled()
+                {
+                    return;
+                }
+                let results = match query_host_in(x.clone(), &ctx) {
+                    Ok(mappings) => mappings.into_iter().flatten().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                for result in results {
+                    if s.send(result).is_err() {
+                        // the iterator was dropped
+                        stopped.store(true, AtomicOrdering::Relaxed);
+                        return;
+                    }
+                }
+            });
+        });
     });
 
-    // wait for the output thread to stop
-    writer_thread.join().unwrap();
+    receiver.into_iter()
 }
@@ -0,0 +1,155 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Offline crawling from a local copy of the Common Crawl data, e.g. synced
+//! from the `s3://commoncrawl/` bucket.
+//!
+//! The local directory has the same layout as the bucket: the path of every
+//! requested URL is looked up relative to it, whatever the host of the URL.
+use crate::fetcher::{FetchError, FetchResponse, Fetcher};
+use crate::Index;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE};
+use reqwest::{StatusCode, Url};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// [Fetcher] reading files from a local copy of the Common Crawl bucket
+/// instead of downloading them.
+#[derive(Debug, Clone)]
+pub struct LocalFetcher {
+    root: PathBuf,
+}
+
+impl LocalFetcher {
+    /// Create a fetcher for the copy of the bucket in `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> LocalFetcher {
+        LocalFetcher { root: root.into() }
+    }
+
+    /// Local file of a URL, or `None` if it is not a valid URL.
+    fn path(&self, url: &str) -> Option<PathBuf> {
+        let url = Url::parse(url).ok()?;
+        Some(self.root.join(url.path().trim_start_matches('/')))
+    }
+
+    fn open(&self, url: &str) -> Result<Option<File>, FetchError> {
+        let path = match self.path(url) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match File::open(&path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(FetchError::Connect(format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+/// Response with an empty body and the given status.
+fn empty_response(status: StatusCode) -> FetchResponse {
+    FetchResponse {
+        status,
+        headers: HeaderMap::new(),
+        body: vec![],
+    }
+}
+
+impl Fetcher for LocalFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        let mut file = match self.open(url)? {
+            Some(file) => file,
+            None => return Ok(empty_response(StatusCode::NOT_FOUND)),
+        };
+        let mut body = vec![];
+        file.read_to_end(&mut body)
+            .map_err(|e| FetchError::Body(e.to_string()))?;
+        Ok(FetchResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body,
+        })
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        let mut file = match self.open(url)? {
+            Some(file) => file,
+            None => return Ok(empty_response(StatusCode::NOT_FOUND)),
+        };
+        let total = file
+            .metadata()
+            .map_err(|e| FetchError::Body(e.to_string()))?
+            .len() as i64;
+        if start < 0 || end < start || start >= total {
+            return Ok(empty_response(StatusCode::RANGE_NOT_SATISFIABLE));
+        }
+        let last = end.min(total - 1);
+        let mut body = vec![0; (last - start + 1) as usize];
+        file.seek(SeekFrom::Start(start as u64))
+            .and_then(|_| file.read_exact(&mut body))
+            .map_err(|e| FetchError::Body(e.to_string()))?;
+        let mut headers = HeaderMap::new();
+        let range = format!("bytes {}-{}/{}", start, last, total);
+        headers.insert(CONTENT_RANGE, HeaderValue::from_str(&range).unwrap());
+        Ok(FetchResponse {
+            status: StatusCode::PARTIAL_CONTENT,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Indices available in a local copy of the bucket, i.e. the collections
+/// under `cc-index/collections/`, newest first.
+pub fn local_indices<P: AsRef<Path>>(root: P) -> io::Result<Vec<Index>> {
+    let mut ids = vec![];
+    for entry in fs::read_dir(root.as_ref().join("cc-index/collections"))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            ids.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    let mut indices: Vec<Index> = ids
+        .into_iter()
+        .map(|id| Index {
+            name: format!("{} (local)", id),
+            id,
+            timegate: String::new(),
+            cdx_api: String::new(),
+        })
+        .collect();
+    indices.sort();
+    Ok(indices)
+}
@@ -0,0 +1,255 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Retrieval of the rows of one host or IP from a finished mapping file:
+//! through the host index of seekable files for a host, see
+//! [crate::SeekableWriter], and by scanning CSV and Parquet files otherwise.
+use crate::{get_reader, SeekableFormat, INDEX_EXTENSION};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Host or IP looked up in a mapping file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupKey {
+    /// Rows of a hostname, compared case-insensitively
+    Host(String),
+    /// Rows of an IP address
+    Ip(IpAddr),
+}
+
+impl std::str::FromStr for LookupKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("expected a hostname or an IP address".to_owned());
+        }
+        Ok(match s.parse() {
+            Ok(ip) => LookupKey::Ip(ip),
+            Err(_) => LookupKey::Host(s.trim_end_matches('.').to_lowercase()),
+        })
+    }
+}
+
+impl LookupKey {
+    /// Check whether a row with the given host and IP columns matches.
+    fn matches(&self, host: &str, ip: &str) -> bool {
+        match self {
+            LookupKey::Host(key) => host.eq_ignore_ascii_case(key),
+            LookupKey::Ip(key) => ip.parse::<IpAddr>().is_ok_and(|ip| ip == *key),
+        }
+    }
+
+    /// Check whether a CSV output line matches.
+    fn matches_line(&self, line: &str) -> bool {
+        let mut fields = line.splitn(4, ',');
+        let host = fields.next().unwrap_or_default();
+        let ip = fields.nth(1).unwrap_or_default();
+        self.matches(host, ip)
+    }
+}
+
+/// Write the rows of a mapping file matching `key` to `out` and return
+/// their number. Hosts of seekable `.bgz` and `.zst` files are read through
+/// their `<file>.idx` host index when there is one; other lookups scan the
+/// file. Parquet files, and directories of partitioned Parquet files, are
+/// read with the parquet feature and their rows written like the CSV
+/// output. Key-value stores, see [crate::KvStore], are read with the kv
+/// feature, as `HOST,DATE,IP` rows.
+pub fn lookup_rows(path: &str, key: &LookupKey, out: &mut dyn Write) -> io::Result<u64> {
+    #[cfg(feature = "kv")]
+    if crate::is_kv_store(Path::new(path)) {
+        return lookup_kv(path, key, out);
+    }
+    if path.ends_with(".parquet") || Path::new(path).is_dir() {
+        #[cfg(feature = "parquet")]
+        return parquet_rows::lookup(Path::new(path), key, out);
+        #[cfg(not(feature = "parquet"))]
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "reading Parquet files needs the parquet feature",
+        ));
+    }
+    let index = format!("{}.{}", path, INDEX_EXTENSION);
+    if let (Some(format), LookupKey::Host(host)) = (SeekableFormat::of(path), key) {
+        if Path::new(&index).exists() {
+            return lookup_indexed(path, format, &index, host, key, out);
+        }
+    }
+    let mut found = 0;
+    for line in get_reader(path)?.lines() {
+        let line = line?;
+        if key.matches_line(&line) {
+            writeln!(out, "{}", line)?;
+            found += 1;
+        }
+    }
+    Ok(found)
+}
+
+/// Read the rows of a host from the blocks listed in the host index of a
+/// seekable file.
+fn lookup_indexed(
+    path: &str,
+    format: SeekableFormat,
+    index: &str,
+    host: &str,
+    key: &LookupKey,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut found = 0;
+    for line in get_reader(index)?.lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        if !fields.next().is_some_and(|name| name.eq_ignore_ascii_case(host)) {
+            continue;
+        }
+        let mut number = || {
+            fields.next().and_then(|field| field.parse::<u64>().ok()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("invalid index line {:?}", line))
+            })
+        };
+        let (offset, length) = (number()?, number()?);
+        let mut block = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut block)?;
+        let data = format.decompress(&block)?;
+        for row in String::from_utf8_lossy(&data).lines() {
+            if key.matches_line(row) {
+                writeln!(out, "{}", row)?;
+                found += 1;
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Read the rows of a host from a key-value store, or scan it for an IP.
+#[cfg(feature = "kv")]
+fn lookup_kv(path: &str, key: &LookupKey, out: &mut dyn Write) -> io::Result<u64> {
+    let store = crate::KvStore::open(path)?;
+    let mut found = 0;
+    let mut write = |host: &str, pairs: Vec<(String, String)>| -> io::Result<()> {
+        for (date, ip) in pairs {
+            if key.matches(host, &ip) {
+                writeln!(out, "{},{},{}", host, date, ip)?;
+                found += 1;
+            }
+        }
+        Ok(())
+    };
+    match key {
+        LookupKey::Host(host) => write(host, store.get(host)?)?,
+        LookupKey::Ip(_) => {
+            for item in store.iter() {
+                let (host, pairs) = item?;
+                write(&host, pairs)?;
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_rows {
+    use super::LookupKey;
+    use crate::sink::csv_field;
+    use chrono::{Duration, NaiveDate};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, Row};
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+
+    /// Look up the rows of a Parquet file, or of all Parquet files under a
+    /// directory.
+    pub(super) fn lookup(path: &Path, key: &LookupKey, out: &mut dyn Write) -> io::Result<u64> {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<_>>()?;
+            entries.sort();
+            let mut found = 0;
+            for entry in entries {
+                if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "parquet") {
+                    found += lookup(&entry, key, out)?;
+                }
+            }
+            return Ok(found);
+        }
+        let reader = SerializedFileReader::new(File::open(path)?).map_err(io::Error::other)?;
+        let mut found = 0;
+        for row in reader.get_row_iter(None).map_err(io::Error::other)? {
+            let row = row.map_err(io::Error::other)?;
+            if key.matches(&column(&row, "host"), &column(&row, "ip")) {
+                let fields: Vec<String> = row
+                    .get_column_iter()
+                    .map(|(_, field)| csv_field(&field_text(field)))
+                    .collect();
+                writeln!(out, "{}", fields.join(","))?;
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Text of a column of a row, empty if it is missing.
+    fn column(row: &Row, name: &str) -> String {
+        row.get_column_iter()
+            .find(|(column, _)| column.as_str() == name)
+            .map(|(_, field)| field_text(field))
+            .unwrap_or_default()
+    }
+
+    /// Text of a value as in the CSV output: lists joined with `;`.
+    fn field_text(field: &Field) -> String {
+        match field {
+            Field::Null => String::new(),
+            Field::Str(s) => s.clone(),
+            Field::Date(days) => (NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+                + Duration::days(*days as i64))
+            .to_string(),
+            Field::ListInternal(list) => {
+                list.elements().iter().map(field_text).collect::<Vec<_>>().join(";")
+            }
+            other => other.to_string(),
+        }
+    }
+}
@@ -34,18 +34,59 @@
  */
 
 use cc_host_mapper::*;
+use chrono::{NaiveDate, Utc};
 use clap::Clap;
 use dialoguer::{
     Confirm,
-    Input,
+    FuzzySelect,
 };
+use regex::Regex;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Clap)]
 struct Opts {
-    /// Output file name
+    /// Output file name, or s3://bucket/key or gs://bucket/key to upload
+    /// the output while crawling, with the credentials of the
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables
     #[clap(short, long)]
     output: Option<String>,
 
+    /// Size of the parts uploaded to S3 or GCS, at least 5MB
+    #[clap(long, default_value = "64MB", parse(try_from_str = parse_size))]
+    upload_part_size: u64,
+
+    /// Buffer the part being uploaded to S3 or GCS in this directory instead
+    /// of in memory
+    #[clap(long)]
+    spill_dir: Option<PathBuf>,
+
+    /// Split the output uploaded to S3 or GCS into objects of this many
+    /// mappings, each finished during the crawl: mapping-00000.csv.gz,
+    /// mapping-00001.csv.gz...
+    #[clap(long)]
+    upload_chunk_mappings: Option<u64>,
+
+    /// Write a .sha256 checksum next to each output file, report and
+    /// uploaded object, to be checked with sha256sum -c
+    #[clap(long)]
+    checksums: bool,
+
+    /// Output format: csv, parquet (built with the parquet feature), e.g.
+    /// to query the mappings with DuckDB, or kv, a directory holding an
+    /// embedded host -> dates and IPs store (built with the kv feature)
+    #[clap(long, default_value = "csv")]
+    format: OutputFormat,
+
+    /// With --format parquet, write the output into a directory tree
+    /// partitioned by these comma-separated keys, e.g. index_id,date: one
+    /// of index_id, date, month, tld or country. The output defaults to the
+    /// mappings directory
+    #[clap(long)]
+    partition_by: Option<String>,
+
     /// Number of threads to be used for crawling
     #[clap(short, long)]
     threads: Option<usize>,
@@ -54,29 +95,1595 @@ struct Opts {
     #[clap(short, long)]
     index_id: Option<String>,
 
+    /// Crawl the newest index without asking for confirmation
+    #[clap(long, conflicts_with = "index-id")]
+    latest: bool,
+
     /// Number of threads to be used for crawling
     #[clap(short, long)]
     dump_cluster_idx: bool,
+
+    /// Sort and deduplicate the output file after crawling finishes, see
+    /// --sort-memory
+    #[clap(long)]
+    sort_output: bool,
+
+    /// Memory taken by the lines held by --sort-output and merge before
+    /// they are spilled to sorted temporary files
+    #[clap(long, default_value = "256MB", parse(try_from_str = parse_size))]
+    sort_memory: u64,
+
+    /// Directory of the temporary files of --sort-output and merge, the one
+    /// of the output by default
+    #[clap(long)]
+    sort_temp_dir: Option<PathBuf>,
+
+    /// Skip the first N host entries of cluster.idx
+    #[clap(long, default_value = "0")]
+    idx_offset: usize,
+
+    /// Process only N host entries of cluster.idx, starting at --idx-offset
+    #[clap(long)]
+    idx_count: Option<usize>,
+
+    /// Only crawl hosts matching this regular expression
+    #[clap(long, parse(try_from_str = parse_regex))]
+    include_regex: Option<Regex>,
+
+    /// Do not crawl hosts matching this regular expression
+    #[clap(long, parse(try_from_str = parse_regex))]
+    exclude_regex: Option<Regex>,
+
+    /// Only resolve captures with these MIME types (comma-separated)
+    #[clap(long)]
+    mime: Option<String>,
+
+    /// Skip captures with these MIME types (comma-separated)
+    #[clap(long)]
+    exclude_mime: Option<String>,
+
+    /// Skip captures of robots.txt files
+    #[clap(long, conflicts_with = "only-robots")]
+    exclude_robots: bool,
+
+    /// Only resolve captures of robots.txt files
+    #[clap(long)]
+    only_robots: bool,
+
+    /// Only resolve captures fetched from these ports (comma-separated),
+    /// e.g. 443 for HTTPS on the default port
+    #[clap(long, validator = parse_ports)]
+    port: Option<String>,
+
+    /// Only resolve captures made on or after this day (YYYY-MM-DD)
+    #[clap(long)]
+    from: Option<NaiveDate>,
+
+    /// Only resolve captures made on or before this day (YYYY-MM-DD)
+    #[clap(long)]
+    to: Option<NaiveDate>,
+
+    /// Resolve at most this many captures (distinct days) per host
+    #[clap(long)]
+    max_captures_per_host: Option<usize>,
+
+    /// Captures kept by --max-captures-per-host: first, last, or
+    /// stratified to spread them over the months of the crawl
+    #[clap(long, default_value = "first")]
+    capture_selection: CaptureSelection,
+
+    /// Resolve captures on different ports of a host separately and add
+    /// the port as an extra output column
+    #[clap(long)]
+    with_ports: bool,
+
+    /// Add the URL of the capture each IP was read from as an extra output
+    /// column
+    #[clap(long)]
+    with_url: bool,
+
+    /// Add the HTTP status of the capture each IP was read from as an
+    /// extra output column
+    #[clap(long)]
+    with_status: bool,
+
+    /// Add the languages, charset, truncation and redirect target the index
+    /// recorded for the capture each IP was read from as extra output
+    /// columns
+    #[clap(long)]
+    with_record_fields: bool,
+
+    /// Form of internationalized hostnames: ascii keeps the punycode of the
+    /// index, unicode decodes it, both adds the Unicode form as an extra
+    /// output column
+    #[clap(long, default_value = "ascii")]
+    idn: IdnForm,
+
+    /// Write the prefixes containing the IPs (see --prefix-v4-length and
+    /// --prefix-v6-length): off, alongside the IPs in an extra column, or
+    /// instead of the IPs
+    #[clap(long, default_value = "off")]
+    prefixes: PrefixOutput,
+
+    /// Length of the IPv4 prefixes written with --prefixes
+    #[clap(long, default_value = "24")]
+    prefix_v4_length: u8,
+
+    /// Length of the IPv6 prefixes written with --prefixes
+    #[clap(long, default_value = "48")]
+    prefix_v6_length: u8,
+
+    /// Skip mappings to private, reserved, documentation and other
+    /// special-purpose IPs; they are counted in the summary either way
+    #[clap(long)]
+    drop_bogons: bool,
+
+    /// CAIDA prefix-to-AS file (pfx2as): add the origin ASes of the route of
+    /// each IP as an extra output column
+    #[clap(long)]
+    pfx2as: Option<PathBuf>,
+
+    /// VRPs exported by an RPKI relying party (Routinator or rpki-client
+    /// CSV or JSON): add the RPKI validity of the route of each IP, found
+    /// with --pfx2as, as an extra output column
+    #[clap(long)]
+    rpki_vrps: Option<PathBuf>,
+
+    /// CAIDA AS-to-organization file (as2org): add the organizations of the
+    /// ASes found with --pfx2as as an extra output column
+    #[clap(long)]
+    as2org: Option<PathBuf>,
+
+    /// Write the distinct hosts and IPs per origin AS, found with --pfx2as,
+    /// to this file at the end of the crawl: JSON if it ends with .json, CSV
+    /// otherwise
+    #[clap(long)]
+    as_report: Option<String>,
+
+    /// Number of origin ASes with the most hosts shown in the summary with
+    /// --as-report
+    #[clap(long, default_value = "10")]
+    as_report_top: usize,
+
+    /// MaxMind GeoLite2 or GeoIP2 Country or City database (.mmdb): add the
+    /// country of each IP as an extra output column
+    #[cfg(feature = "geoip")]
+    #[clap(long)]
+    geoip: Option<PathBuf>,
+
+    /// Write the distinct hosts and IPs per country, found with --geoip, to
+    /// this file at the end of the crawl: JSON if it ends with .json, CSV
+    /// otherwise
+    #[cfg(feature = "geoip")]
+    #[clap(long, requires = "geoip")]
+    country_report: Option<String>,
+
+    /// Download the latest CAIDA datasets (comma-separated: pfx2as,
+    /// as2org) into --dataset-dir and use them unless --pfx2as or --as2org
+    /// is given
+    #[clap(long)]
+    fetch_datasets: Option<String>,
+
+    /// Directory caching the datasets downloaded with --fetch-datasets
+    #[clap(long, default_value = "cc-host-mapper-datasets")]
+    dataset_dir: PathBuf,
+
+    /// Days after which the datasets of --fetch-datasets are downloaded
+    /// again
+    #[clap(long, default_value = "7")]
+    dataset_max_age: u64,
+
+    /// IP ranges published by cloud and CDN providers, as comma-separated
+    /// NAME=FILE pairs, e.g. aws=ip-ranges.json,fastly=public-ip-list.json:
+    /// add the provider of each IP as an extra output column
+    #[clap(long)]
+    provider_ranges: Option<String>,
+
+    /// Lowercase hostnames and strip trailing dots before output
+    #[clap(long)]
+    normalize_hosts: bool,
+
+    /// Strip a leading www. from hostnames, merging them with the bare
+    /// domain
+    #[clap(long)]
+    fold_www: bool,
+
+    /// Skip syntactically invalid hostnames
+    #[clap(long)]
+    reject_invalid_hosts: bool,
+
+    /// Drop duplicate mappings while writing, remembering the last
+    /// --dedup-window ones
+    #[clap(long)]
+    dedup: bool,
+
+    /// Number of recent mappings remembered by --dedup, about 40 bytes each
+    #[clap(long, default_value = "1000000")]
+    dedup_window: usize,
+
+    /// Drop all exact duplicate mappings with a counting Bloom filter sized
+    /// for this many distinct mappings, 0.9 bytes each at the default
+    /// --dedup-fpr; some mappings are dropped as false positives
+    #[clap(long)]
+    dedup_bloom: Option<u64>,
+
+    /// False-positive rate of --dedup-bloom, the fraction of new mappings
+    /// that may be dropped
+    #[clap(long, default_value = "0.001")]
+    dedup_fpr: f64,
+
+    /// Load the --dedup-bloom filter from this file if it exists, and save
+    /// it there after the crawl, to drop the duplicates of earlier shards
+    /// or indexes
+    #[clap(long, requires = "dedup-bloom")]
+    dedup_state: Option<PathBuf>,
+
+    /// Only output IPv4 mappings
+    #[clap(long, conflicts_with = "only-ipv6")]
+    only_ipv4: bool,
+
+    /// Only output IPv6 mappings
+    #[clap(long)]
+    only_ipv6: bool,
+
+    /// Only report how much work the crawl would take, without crawling
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Start crawling without first checking that the output is writable,
+    /// that there is enough disk space, and that the index can be fetched
+    #[clap(long)]
+    no_preflight: bool,
+
+    /// Also write the end-of-run summary as JSON to this file
+    #[clap(long)]
+    summary_json: Option<String>,
+
+    /// Also write the end-of-run summary, with the request latency
+    /// percentiles by endpoint, as Prometheus metrics to this file, e.g. in
+    /// the directory of the node_exporter textfile collector
+    #[clap(long)]
+    metrics: Option<String>,
+
+    /// Export a trace span per host pointer and per request, and the request
+    /// metrics, to this OTLP/HTTP collector, e.g. http://localhost:4318
+    #[cfg(feature = "otel")]
+    #[clap(long, validator = parse_otlp_endpoint)]
+    otlp_endpoint: Option<String>,
+
+    /// Write a manifest.json next to the output with the version, command
+    /// line, index, filters, shard, start and end times and summary of the
+    /// crawl, e.g. to publish the mappings as a dataset
+    #[clap(long)]
+    manifest: bool,
+
+    /// Do not show the progress bar, e.g. when redirecting output to a log
+    #[clap(short, long, alias = "no-progress")]
+    quiet: bool,
+
+    /// Print debug messages, such as slow requests, to stderr. RUST_LOG
+    /// selects other messages, as for env_logger
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Show a full-screen dashboard instead of the progress bar
+    #[cfg(feature = "tui")]
+    #[clap(long)]
+    tui: bool,
+
+    /// Exit with a partial-failure status if more than this fraction of
+    /// requests still failed after their retries
+    #[clap(long, default_value = "0.05")]
+    max_failure_rate: f64,
+
+    /// Maximum number of retries for a failed request
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+
+    /// Maximum number of requests per second across all threads
+    #[clap(long)]
+    max_requests_per_sec: Option<f64>,
+
+    /// Seconds over which the request rate ramps up from --ramp-up-rate to
+    /// --max-requests-per-sec, set back by throttling and server errors; 0
+    /// to start at full rate
+    #[clap(long, default_value = "180")]
+    ramp_up: u64,
+
+    /// Requests per second across all threads at the start of the ramp-up
+    #[clap(long, default_value = "5")]
+    ramp_up_rate: f64,
+
+    /// Milliseconds each thread waits before every request
+    #[clap(long, default_value = "0")]
+    delay_ms: u64,
+
+    /// Add a random delay of up to this many milliseconds before every request
+    #[clap(long, default_value = "0")]
+    jitter_ms: u64,
+
+    /// Maximum download bandwidth across all threads, e.g. 50MB/s or 500KB/s
+    #[clap(long, parse(try_from_str = parse_bandwidth))]
+    max_bandwidth: Option<u64>,
+
+    /// Maximum number of concurrent requests to the same cdx or WARC file
+    /// across all threads, since hammering one file gets throttled sooner
+    #[clap(long)]
+    max_requests_per_file: Option<usize>,
+
+    /// Pause all threads after this many consecutive failed requests
+    #[clap(long, default_value = "50")]
+    breaker_failures: u32,
+
+    /// Seconds to pause all threads when too many requests fail
+    #[clap(long, default_value = "120")]
+    breaker_cooldown: u64,
+
+    /// Consider the crawl temporarily blocked after this many consecutive
+    /// 403 responses
+    #[clap(long, default_value = "20")]
+    block_failures: u32,
+
+    /// Minutes to pause all threads when the crawl is blocked, before a
+    /// probe request decides whether to resume; 0 treats blocks like other
+    /// failures
+    #[clap(long, default_value = "45")]
+    block_cooldown: u64,
+
+    /// Skip the host pointers listed as completed in this file, and list
+    /// the completed ones there whenever the crawl pauses and when it ends,
+    /// so that an interrupted crawl can be resumed into another output file
+    /// and combined with the `merge` subcommand; single crawls only
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// User-Agent sent with every request; please include contact details
+    /// for large crawls
+    #[clap(long)]
+    user_agent: Option<String>,
+
+    /// Proxy URL for all requests (e.g. http://proxy.example.org:3128, or
+    /// socks5h://localhost:1080 for an SSH tunnel); defaults to the
+    /// HTTPS_PROXY/HTTP_PROXY environment variables
+    #[clap(long, validator = parse_proxy)]
+    proxy: Option<String>,
+
+    /// Fetch Common Crawl data from this URL instead of
+    /// https://data.commoncrawl.org, e.g. a mirror or a local copy
+    #[clap(long)]
+    base_url: Option<String>,
+
+    /// Query the CDX API of --cdx-api-fallback at this URL instead of
+    /// https://index.commoncrawl.org
+    #[clap(long)]
+    index_server_url: Option<String>,
+
+    /// Bytes read from the start of each WARC record to find the
+    /// WARC-IP-Address header; records with longer headers are re-fetched
+    #[clap(long, default_value = "901")]
+    warc_read_bytes: u64,
+
+    /// Read all Common Crawl data from this local copy of the bucket instead
+    /// of downloading it
+    #[clap(long)]
+    local_dir: Option<String>,
+
+    /// Record all requests of the crawl and their responses to this
+    /// directory, to be replayed with --replay
+    #[clap(long)]
+    record: Option<String>,
+
+    /// Answer all requests from a directory written with --record instead
+    /// of downloading anything
+    #[clap(long, requires = "index-id", conflicts_with_all = &["record", "local-dir"])]
+    replay: Option<String>,
+
+    /// Look up hosts through the index.commoncrawl.org CDX API when their
+    /// cdx block cannot be fetched
+    #[clap(long)]
+    cdx_api_fallback: bool,
+
+    /// Also map the other hosts in each fetched cdx block that start no
+    /// block of cluster.idx, instead of only the first host of the block
+    #[clap(long)]
+    bonus_hosts: bool,
+
+    /// Add the Server and X-Powered-By headers of each HTTP response as two
+    /// extra output columns
+    #[clap(long)]
+    capture_server: bool,
+
+    /// Add the WARC-Protocol fields (e.g. h2;tls/1.3) and the TLS cipher
+    /// suite recorded by newer crawls as two extra output columns
+    #[clap(long)]
+    capture_protocol: bool,
+
+    /// Cache downloaded index data and WARC snippets in this directory
+    #[clap(long)]
+    cache_dir: Option<String>,
+
+    /// Size cap of the cache, e.g. 20GB
+    #[clap(long, default_value = "10GB", parse(try_from_str = parse_size))]
+    cache_size: u64,
+
+    /// Number of results buffered in memory before crawling threads wait
+    /// for the output writer
+    #[clap(long, default_value = "10000")]
+    channel_capacity: usize,
+
+    /// Seconds to wait for a connection to be established
+    #[clap(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// Seconds to wait for a whole request to complete
+    #[clap(long, default_value = "60")]
+    request_timeout: u64,
+
+    /// Log the requests that take longer than this many seconds with
+    /// --verbose, with their URL, range, status and retry count; 0 disables
+    #[clap(long, default_value = "10")]
+    slow_request: u64,
+
+    /// Crawl only a uniform random sample of N hosts
+    #[clap(long)]
+    sample: Option<usize>,
+
+    /// Random seed used with --sample and --shuffle, for reproducible
+    /// samples and orders
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Crawl the host pointers in random order instead of cluster.idx
+    /// order, to spread the concurrent requests over all cdx files
+    #[clap(long)]
+    shuffle: bool,
+
+    /// Crawl at most N hosts, useful for smoke tests
+    #[clap(long)]
+    limit: Option<usize>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Keep running and crawl every newly published index
+    Watch(WatchOpts),
+    /// List the URLs of the cdx files, cluster.idx and metadata of an index
+    Files(FilesOpts),
+    /// Aggregate a mapping file by TLD: hosts, IPs and their IPv4/IPv6
+    /// split, and the top origin ASes with --pfx2as
+    Stats(StatsOpts),
+    /// Report how concentrated the hosting of a mapping file is: hosts per
+    /// IP and IPs per host, and the most shared IPs
+    Concentration(ConcentrationOpts),
+    /// List the hosts of a mapping file seen on several IPs or /24 (IPv4)
+    /// and /48 (IPv6) prefixes, a hint of anycast or CDN hosting
+    MultiIp(MultiIpOpts),
+    /// Report how the IPs of hosts changed over the mapping files of
+    /// several crawls
+    Churn(ChurnOpts),
+    /// Export the bipartite graph between the hosts and IPs of a mapping
+    /// file for graph tools
+    Graph(GraphOpts),
+    /// Print the rows of a host or IP from a mapping file, through the host
+    /// index of a seekable .bgz or .zst file when there is one
+    Lookup(LookupOpts),
+    /// Sort, deduplicate and merge mapping files, e.g. the shards of a
+    /// crawl, into one, see --sort-memory
+    Merge(MergeOpts),
+    /// Probe collinfo.json, cluster.idx, a cdx file and a WARC file of the
+    /// index given with -i, or of the newest one, and report the status and
+    /// latency of each, to tell throttling from network or proxy problems
+    Healthcheck,
+}
+
+#[derive(Clap)]
+struct GraphOpts {
+    /// Mapping file to export, gzip-compressed if ending with .gz
+    input: String,
+
+    /// Graph format: edges (edge list), graphml or adjacency (adjacency
+    /// list)
+    #[clap(long, default_value = "edges")]
+    format: GraphFormat,
+
+    /// Weight the edges by their number of mappings (captures); not
+    /// supported by the adjacency format
+    #[clap(long)]
+    weights: bool,
+
+    /// File to write the graph to instead of stdout, gzip-compressed if
+    /// ending with .gz
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Clap)]
+struct MergeOpts {
+    /// Mapping files to merge, compressed if ending with .gz, .bgz or .zst
+    #[clap(required = true, min_values = 1)]
+    inputs: Vec<String>,
+
+    /// Merged mapping file, compressed according to its extension
+    #[clap(short, long)]
+    output: String,
+}
+
+#[derive(Clap)]
+struct LookupOpts {
+    /// Mapping file: CSV, compressed if ending with .gz, .bgz or .zst, or
+    /// Parquet file or partitioned directory with the parquet feature
+    input: String,
+
+    /// Hostname or IP address to print the rows of
+    query: LookupKey,
+}
+
+#[derive(Clap)]
+struct ChurnOpts {
+    /// Mapping files of the crawls to compare, oldest first
+    #[clap(required = true, min_values = 2)]
+    inputs: Vec<String>,
+
+    /// CAIDA prefix-to-AS file (pfx2as): also count the changes of the
+    /// origin ASes of the routes of the IPs of hosts
+    #[clap(long)]
+    pfx2as: Option<PathBuf>,
+
+    /// Report format: csv or json
+    #[clap(long, default_value = "csv")]
+    format: ReportFormat,
+
+    /// File to write the report to instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Clap)]
+struct MultiIpOpts {
+    /// Mapping file to analyze, gzip-compressed if ending with .gz
+    input: String,
+
+    /// List the hosts seen on more than this many IPs or prefixes
+    #[clap(long, default_value = "1")]
+    threshold: u64,
+
+    /// Report format: csv or json
+    #[clap(long, default_value = "csv")]
+    format: ReportFormat,
+
+    /// File to write the report to instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Clap)]
+struct ConcentrationOpts {
+    /// Mapping file to analyze, gzip-compressed if ending with .gz
+    input: String,
+
+    /// Number of most shared IPs to list
+    #[clap(long, default_value = "20")]
+    top: usize,
+
+    /// Report format: csv or json
+    #[clap(long, default_value = "csv")]
+    format: ReportFormat,
+
+    /// File to write the report to instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Clap)]
+struct StatsOpts {
+    /// Mapping file to aggregate, gzip-compressed if ending with .gz
+    input: String,
+
+    /// CAIDA prefix-to-AS file (pfx2as): also list the origin ASes of the
+    /// most hosts of each TLD
+    #[clap(long)]
+    pfx2as: Option<PathBuf>,
+
+    /// Number of origin ASes to list per TLD with --pfx2as
+    #[clap(long, default_value = "5")]
+    top_ases: usize,
+
+    /// Report format: csv or json
+    #[clap(long, default_value = "csv")]
+    format: ReportFormat,
+
+    /// File to write the report to instead of stdout
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+/// Output format of the reports computed from mapping files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("expected csv or json, found {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clap)]
+struct FilesOpts {
+    /// Index to list the files of, e.g. CC-MAIN-2020-50
+    index_id: String,
+}
+
+#[derive(Clap)]
+struct WatchOpts {
+    /// File recording the indices that have already been crawled
+    #[clap(long, default_value = "cc-host-mapper-watch.json")]
+    state_file: String,
+
+    /// Directory where the per-index mapping files are written
+    #[clap(long, default_value = ".")]
+    output_dir: String,
+
+    /// Minutes to wait between two checks for new indices
+    #[clap(long, default_value = "360")]
+    interval: u64,
+
+    /// On first start, also crawl the indices that are already published
+    #[clap(long)]
+    crawl_existing: bool,
+}
+
+/// Process exit statuses, so that orchestration scripts can react to the
+/// outcome of a run without parsing stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitStatus {
+    /// Crawl completed
+    Success = 0,
+    /// Crawl completed, but more requests failed than allowed
+    PartialFailure = 2,
+    /// Crawl aborted after sustained failures, or completed but most
+    /// failures indicate throttling by Common Crawl
+    RateLimited = 3,
+    /// The requested index id does not exist, or its cluster.idx is missing
+    InvalidIndex = 4,
+    /// Common Crawl, or the local copy given with --local-dir, could not be
+    /// reached
+    NetworkUnreachable = 5,
+    /// The output file could not be written
+    OutputError = 6,
+    /// An enrichment dataset could not be read
+    DatasetError = 7,
+    /// Crawl cancelled by the user from the dashboard
+    Cancelled = 130,
+}
+
+impl ExitStatus {
+    fn from_stats(stats: &CrawlStats, max_failure_rate: f64) -> ExitStatus {
+        if stats.output_error.is_some() {
+            return ExitStatus::OutputError;
+        }
+        if stats.cancelled {
+            return ExitStatus::Cancelled;
+        }
+        if stats.index_error.is_some() {
+            // a missing index is fatal, anything else may work on a re-run
+            return if stats.index_error_retryable {
+                ExitStatus::NetworkUnreachable
+            } else {
+                ExitStatus::InvalidIndex
+            };
+        }
+        if stats.aborted.is_some() {
+            return ExitStatus::RateLimited;
+        }
+        let requests = stats.requests_without_retries();
+        if requests > 0 && stats.failures.connect == requests {
+            return ExitStatus::NetworkUnreachable;
+        }
+        if stats.failure_rate() <= max_failure_rate {
+            return ExitStatus::Success;
+        }
+        let failed = stats.failures.connect + stats.failures.http_status;
+        if stats.throttled_responses() * 2 > failed {
+            ExitStatus::RateLimited
+        } else {
+            ExitStatus::PartialFailure
+        }
+    }
+}
+
+fn exit(status: ExitStatus) -> ! {
+    std::process::exit(status as i32)
+}
+
+fn parse_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse().map_err(|e| format!("invalid port {}: {}", port, e))
+}
+
+/// Check that an OTLP collector endpoint is an HTTP(S) URL.
+#[cfg(feature = "otel")]
+fn parse_otlp_endpoint(endpoint: &str) -> Result<reqwest::Url, String> {
+    let url = reqwest::Url::parse(endpoint).map_err(|e| e.to_string())?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!("unsupported scheme {}, expected http or https", scheme)),
+    }
+}
+
+/// Check that a proxy URL is accepted by the HTTP client.
+fn parse_proxy(proxy: &str) -> Result<reqwest::Proxy, String> {
+    reqwest::Proxy::all(proxy).map_err(|e| e.to_string())
+}
+
+/// Parse a comma-separated list of ports, see [split_list].
+fn parse_ports(list: &str) -> Result<Vec<u16>, String> {
+    split_list(Some(list)).iter().map(|port| parse_port(port)).collect()
+}
+
+/// Parse a size such as `20GB`, `500K` or `1.5MB` into bytes. Units are
+/// decimal, a plain number means bytes.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let lower = lower.strip_suffix('b').unwrap_or(&lower);
+    let (number, factor) = match lower.char_indices().last() {
+        Some((i, 'k')) => (&lower[..i], 1e3),
+        Some((i, 'm')) => (&lower[..i], 1e6),
+        Some((i, 'g')) => (&lower[..i], 1e9),
+        _ => (lower, 1.0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok((n * factor) as u64),
+        _ => Err(format!("invalid size {}, expected e.g. 20GB", value)),
+    }
+}
+
+/// Parse a bandwidth such as `50MB/s` into bytes per second, see [parse_size].
+fn parse_bandwidth(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    parse_size(trimmed.strip_suffix("/s").unwrap_or(trimmed))
+        .map_err(|_| format!("invalid bandwidth {}, expected e.g. 50MB/s", value))
+}
+
+fn split_list(list: Option<&str>) -> Vec<String> {
+    match list {
+        Some(l) => l.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect(),
+        None => vec![],
+    }
+}
+
+/// Indices available for crawling, newest first: the published ones, or the
+/// ones in the local copy given with --local-dir. When replaying, only the
+/// index given with --index-id is available.
+fn list_indices(opts: &Opts) -> Result<Vec<Index>, String> {
+    if opts.replay.is_some() {
+        let id = opts.index_id.as_deref().unwrap_or_default();
+        return Index::from_id(id)
+            .map(|index| vec![index])
+            .ok_or_else(|| format!("invalid index id {}", id));
+    }
+    match &opts.local_dir {
+        Some(dir) => local_indices(dir).map_err(|e| format!("{}: {}", dir, e)),
+        None => {
+            let mut indices = try_retrieve_indices_with_options(&client_options(opts))
+                .map_err(|e| e.to_string())?;
+            indices.sort();
+            Ok(indices)
+        }
+    }
+}
+
+/// The newest of the available indices, see [list_indices], or exit if
+/// there are none.
+fn newest_or_exit(indices: &[Index]) -> Index {
+    match newest_index(indices) {
+        Some(index) => index.clone(),
+        None => {
+            eprintln!("no index available");
+            exit(ExitStatus::InvalidIndex);
+        }
+    }
+}
+
+/// Poll collinfo.json forever and crawl each index not recorded in the
+/// state file yet, writing one mapping file per index.
+fn run_watch(opts: &Opts, watch_opts: &WatchOpts) -> ! {
+    let options = crawl_options(opts);
+
+    let mut state = match WatchState::load(&watch_opts.state_file) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("cannot read state file {}: {}", watch_opts.state_file, e);
+            exit(ExitStatus::OutputError);
+        }
+    };
+    let interval = Duration::from_secs(watch_opts.interval * 60);
+
+    loop {
+        match list_indices(opts) {
+            Ok(indices) => {
+                if state.is_new() && !watch_opts.crawl_existing {
+                    println!("recording {} existing indices as already crawled", indices.len());
+                    for index in &indices {
+                        state.mark_crawled(&index.id);
+                    }
+                    save_watch_state(&mut state, watch_opts);
+                }
+                for index in state.uncrawled(&indices) {
+                    let output_file_name = std::path::Path::new(&watch_opts.output_dir)
+                        .join(format!(
+                            "mapping-{}.{}",
+                            index.id.to_lowercase(),
+                            options.output_format.extension()
+                        ));
+                    let output_file_name = output_file_name.to_string_lossy().to_string();
+                    println!("new index {}, crawling to {}", index.id, output_file_name);
+                    let stats = crawl_with_options(index.id.clone(), output_file_name, &options);
+                    println!("{}", stats);
+                    save_dedup_state(opts, &options);
+                    match ExitStatus::from_stats(&stats, opts.max_failure_rate) {
+                        ExitStatus::Success => state.mark_crawled(&index.id),
+                        ExitStatus::OutputError => exit(ExitStatus::OutputError),
+                        // aborted, failed or incomplete: try again at the next check
+                        _ => continue,
+                    }
+                    save_watch_state(&mut state, watch_opts);
+                }
+            }
+            Err(e) => eprintln!("cannot retrieve the list of indices: {}", e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Save the state of --watch, after each crawled index so that it is not
+/// crawled again if a later one fails.
+fn save_watch_state(state: &mut WatchState, watch_opts: &WatchOpts) {
+    if let Err(e) = state.save(&watch_opts.state_file) {
+        eprintln!("cannot write state file {}: {}", watch_opts.state_file, e);
+        exit(ExitStatus::OutputError);
+    }
+}
+
+/// Print the URLs of all files of an index, one per line.
+fn print_index_files(opts: &Opts, files_opts: &FilesOpts) -> ! {
+    match parse_index_with(&files_opts.index_id, &crawl_options(opts)) {
+        Ok(files) => {
+            println!("{}", files.cdx_cluster);
+            println!("{}", files.metadata);
+            for file in &files.cdx_files {
+                println!("{}", file);
+            }
+            exit(ExitStatus::Success);
+        }
+        Err(e) => {
+            eprintln!("cannot list the files of {}: {}", files_opts.index_id, e);
+            exit(ExitStatus::InvalidIndex);
+        }
+    }
+}
+
+/// Print the per-TLD report of a mapping file and exit.
+fn print_tld_report(stats_opts: &StatsOpts) -> ! {
+    let pfx2as = stats_opts.pfx2as.as_deref().map(load_pfx2as);
+    let report = read_mapping_file(&stats_opts.input)
+        .and_then(|mappings| tld_report_with(mappings, pfx2as.as_ref(), stats_opts.top_ases));
+    let report = unwrap_report(report, &stats_opts.input);
+    let lines = match pfx2as {
+        Some(_) => {
+            let mut lines = vec![TldStats::CSV_HEADER_WITH_ASES.to_owned()];
+            lines.extend(report.iter().map(TldStats::to_csv_with_ases));
+            lines
+        }
+        None => {
+            let mut lines = vec![TldStats::CSV_HEADER.to_owned()];
+            lines.extend(report.iter().map(TldStats::to_csv));
+            lines
+        }
+    };
+    write_report(stats_opts.output.as_deref(), stats_opts.format, &report, lines);
+}
+
+/// Print the concentration report of a mapping file and exit.
+fn print_concentration_report(concentration_opts: &ConcentrationOpts) -> ! {
+    let report = read_mapping_file(&concentration_opts.input)
+        .and_then(|mappings| concentration_report(mappings, concentration_opts.top));
+    let report = unwrap_report(report, &concentration_opts.input);
+    let mut lines = vec![ConcentrationReport::CSV_HEADER.to_owned()];
+    lines.extend(report.to_csv());
+    write_report(
+        concentration_opts.output.as_deref(),
+        concentration_opts.format,
+        &report,
+        lines,
+    );
+}
+
+/// Print the hosts of a mapping file seen on several IPs and exit.
+fn print_multi_ip_report(multi_ip_opts: &MultiIpOpts) -> ! {
+    let report = read_mapping_file(&multi_ip_opts.input).and_then(|mappings| {
+        multi_ip_hosts(mappings, multi_ip_opts.threshold, PrefixLengths::default())
+    });
+    let report = unwrap_report(report, &multi_ip_opts.input);
+    let mut lines = vec![HostSpread::CSV_HEADER.to_owned()];
+    lines.extend(report.iter().map(HostSpread::to_csv));
+    write_report(multi_ip_opts.output.as_deref(), multi_ip_opts.format, &report, lines);
+}
+
+/// Print the churn report of the mapping files of several crawls and exit.
+fn print_churn_report(churn_opts: &ChurnOpts) -> ! {
+    let mut crawls = vec![];
+    for input in &churn_opts.inputs {
+        crawls.push(unwrap_report(read_mapping_file(input), input));
+    }
+    let pfx2as = churn_opts.pfx2as.as_deref().map(load_pfx2as);
+    let report = churn_report_with(crawls, pfx2as.as_ref());
+    let report = unwrap_report(report, &churn_opts.inputs.join(", "));
+    let mut lines = vec![ChurnReport::CSV_HEADER.to_owned()];
+    lines.extend(report.to_csv());
+    write_report(churn_opts.output.as_deref(), churn_opts.format, &report, lines);
+}
+
+/// Export the host-IP graph of a mapping file and exit.
+fn export_graph(graph_opts: &GraphOpts) -> ! {
+    let graph = read_mapping_file(&graph_opts.input).and_then(HostIpGraph::from_mappings);
+    let graph = unwrap_report(graph, &graph_opts.input);
+    let mut writer: Box<dyn Write> = match &graph_opts.output {
+        Some(output) => get_writer(output),
+        None => Box::new(std::io::stdout()),
+    };
+    let written = graph.write(&mut writer, graph_opts.format, graph_opts.weights);
+    if let Err(e) = written.and_then(|_| writer.flush()) {
+        eprintln!("cannot write the graph: {}", e);
+        exit(ExitStatus::OutputError);
+    }
+    exit(ExitStatus::Success);
+}
+
+/// Settings of the external sort of --sort-output and merge.
+fn sort_options(opts: &Opts) -> SortOptions {
+    SortOptions {
+        temp_dir: opts.sort_temp_dir.clone(),
+        memory_budget: opts.sort_memory as usize,
+    }
+}
+
+/// Sort, deduplicate and merge mapping files into one.
+fn merge(opts: &Opts, merge_opts: &MergeOpts) -> ! {
+    let inputs: Vec<&str> = merge_opts.inputs.iter().map(String::as_str).collect();
+    match merge_files(&inputs, &merge_opts.output, &sort_options(opts)) {
+        Ok(count) => println!("{} unique mappings written to {}", count, merge_opts.output),
+        Err(e) => {
+            eprintln!("cannot merge into {}: {}", merge_opts.output, e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+    exit(ExitStatus::Success);
+}
+
+/// Probe the endpoints a crawl depends on and exit with
+/// [ExitStatus::RateLimited] if Common Crawl throttles us, or
+/// [ExitStatus::NetworkUnreachable] if any other probe failed.
+fn run_healthcheck(opts: &Opts) -> ! {
+    let report = healthcheck(opts.index_id.as_deref(), &client_options(opts));
+    println!("{}", report);
+    if report.is_healthy() {
+        exit(ExitStatus::Success);
+    } else if report.is_throttled() {
+        exit(ExitStatus::RateLimited);
+    }
+    exit(ExitStatus::NetworkUnreachable);
+}
+
+/// Print the rows of a host or IP from a mapping file.
+fn lookup(lookup_opts: &LookupOpts) -> ! {
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let found = lookup_rows(&lookup_opts.input, &lookup_opts.query, &mut out);
+    unwrap_report(found.and_then(|found| out.flush().map(|_| found)), &lookup_opts.input);
+    exit(ExitStatus::Success);
+}
+
+/// Load the pfx2as file of a report, or exit.
+fn load_pfx2as(path: &Path) -> Pfx2As {
+    match Pfx2As::load(path) {
+        Ok(pfx2as) => pfx2as,
+        Err(e) => {
+            eprintln!("cannot load pfx2as: {}", e);
+            exit(ExitStatus::DatasetError);
+        }
+    }
+}
+
+/// Exit if the mapping file of a report could not be read.
+fn unwrap_report<T>(report: std::io::Result<T>, input: &str) -> T {
+    match report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("cannot read mappings from {}: {}", input, e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+}
+
+/// Write a report as JSON, or as the given CSV lines, to a file or stdout
+/// and exit.
+fn write_report<T: serde::Serialize>(
+    output: Option<&str>,
+    format: ReportFormat,
+    report: &T,
+    csv_lines: Vec<String>,
+) -> ! {
+    let mut writer: Box<dyn Write> = match output {
+        Some(output) => get_writer(output),
+        None => Box::new(std::io::stdout()),
+    };
+    let written = match format {
+        ReportFormat::Csv => csv_lines
+            .iter()
+            .try_for_each(|line| writeln!(writer, "{}", line)),
+        ReportFormat::Json => serde_json::to_writer_pretty(&mut writer, report)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(writer)),
+    };
+    if let Err(e) = written.and_then(|_| writer.flush()) {
+        eprintln!("cannot write the report: {}", e);
+        exit(ExitStatus::OutputError);
+    }
+    exit(ExitStatus::Success);
+}
+
+/// Client of the object store the output is uploaded to, if it is an
+/// s3:// or gs:// URL.
+fn object_store(opts: &Opts, options: &CrawlOptions) -> Option<Arc<ObjectStore>> {
+    if !opts.output.as_deref().is_some_and(is_object_url) {
+        return None;
+    }
+    let credentials = match Credentials::from_env() {
+        Some(credentials) => credentials,
+        None => {
+            eprintln!("set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to upload to S3 or GCS");
+            exit(ExitStatus::OutputError);
+        }
+    };
+    let mut store = ObjectStore::from_options(options, credentials)
+        .with_part_size(opts.upload_part_size as usize)
+        .with_checksums(opts.checksums);
+    if let Some(spill_dir) = &opts.spill_dir {
+        store = store.with_spill_dir(spill_dir);
+    }
+    Some(Arc::new(store))
+}
+
+/// Write the checksum sidecars of the local outputs and reports, and add
+/// the digests of all outputs, including the uploaded ones, to the stats.
+fn record_checksums(
+    opts: &Opts,
+    output_file_name: &str,
+    options: &CrawlOptions,
+    stats: &mut CrawlStats,
+) {
+    let mut outputs = vec![];
+    if !is_object_url(output_file_name) {
+        outputs.push(output_file_name);
+    }
+    #[cfg(feature = "geoip")]
+    outputs.extend(opts.country_report.as_deref());
+    outputs.extend(opts.as_report.as_deref());
+    for output in outputs {
+        match write_checksums(Path::new(output)) {
+            Ok(checksums) => {
+                for (path, digest) in checksums {
+                    stats.output_checksums.insert(path.display().to_string(), digest);
+                }
+            }
+            Err(e) => {
+                eprintln!("cannot write checksum of {}: {}", output, e);
+                exit(ExitStatus::OutputError);
+            }
+        }
+    }
+    if let Some(store) = &options.object_store {
+        for (url, digest) in store.written() {
+            stats.output_checksums.insert(url.to_string(), digest);
+        }
+    }
+}
+
+/// Bloom filter of --dedup-bloom, loaded from --dedup-state if it exists.
+fn bloom_dedup(opts: &Opts, expected: u64) -> BloomDedup {
+    match &opts.dedup_state {
+        Some(path) if path.exists() => match BloomDedup::load(path) {
+            Ok(bloom) => bloom,
+            Err(e) => {
+                eprintln!("cannot load dedup state {}: {}", path.display(), e);
+                exit(ExitStatus::OutputError);
+            }
+        },
+        _ => BloomDedup::new(expected, opts.dedup_fpr),
+    }
+}
+
+/// Save the Bloom filter of --dedup-bloom to --dedup-state.
+fn save_dedup_state(opts: &Opts, options: &CrawlOptions) {
+    if let (Some(path), Some(bloom)) = (&opts.dedup_state, &options.dedup_bloom) {
+        if let Err(e) = bloom.lock().unwrap().save(path) {
+            eprintln!("cannot save dedup state {}: {}", path.display(), e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+}
+
+/// Exit with an explanation if the output cannot be written, the disk is
+/// too small or the index cannot be fetched.
+fn run_preflight(index_id: &str, output: &str, options: &CrawlOptions) {
+    match preflight(index_id, output, options) {
+        Ok(report) => {
+            print!(
+                "preflight: about {} host pointers, output about {}MB",
+                report.pointers,
+                report.output_bytes / 1_000_000
+            );
+            match report.available_bytes {
+                Some(available) => println!(", {}MB free", available / 1_000_000),
+                None => println!(),
+            }
+        }
+        Err(e) => {
+            eprintln!("preflight failed: {}", e);
+            exit(match &e {
+                PreflightError::NotWritable { .. } | PreflightError::DiskSpace { .. } => {
+                    ExitStatus::OutputError
+                }
+                PreflightError::Index(CrawlError::Status { status: 404, .. }) => {
+                    ExitStatus::InvalidIndex
+                }
+                PreflightError::Index(CrawlError::Status {
+                    status: 403 | 429 | 503,
+                    ..
+                }) => ExitStatus::RateLimited,
+                PreflightError::Index(_) => ExitStatus::NetworkUnreachable,
+            });
+        }
+    }
+}
+
+/// Checkpoint of --checkpoint for crawling `index_id` into `output`.
+/// Resuming writes the remaining pointers into a new file, so that the
+/// output of the interrupted crawl is not overwritten.
+fn open_checkpoint(opts: &Opts, index_id: &str, output: &str) -> Option<Arc<Checkpoint>> {
+    let path = opts.checkpoint.as_ref()?;
+    if is_object_url(output) {
+        eprintln!("--checkpoint only applies to local output files");
+        exit(ExitStatus::OutputError);
+    }
+    let checkpoint = match Checkpoint::open(path, index_id) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            eprintln!("cannot load checkpoint {}: {}", path.display(), e);
+            exit(ExitStatus::OutputError);
+        }
+    };
+    if !checkpoint.is_empty() {
+        if Path::new(output).exists() {
+            eprintln!(
+                "{} exists, resume from checkpoint {} into another output file",
+                output,
+                path.display()
+            );
+            exit(ExitStatus::OutputError);
+        }
+        println!("resuming: skipping {} completed host pointers", checkpoint.len());
+    }
+    Some(Arc::new(checkpoint))
+}
+
+/// Reports the checkpoints saved while crawling.
+struct CheckpointReporter;
+
+impl ProgressObserver for CheckpointReporter {
+    fn on_checkpoint(&self, saved: &std::io::Result<usize>) {
+        match saved {
+            Ok(pointers) => eprintln!("checkpoint of {} completed pointers saved", pointers),
+            Err(e) => eprintln!("cannot save checkpoint: {}", e),
+        }
+    }
+}
+
+/// Build the crawl configuration from the command-line options.
+fn crawl_options(opts: &Opts) -> CrawlOptions {
+    let mut options = client_options(opts);
+    options.fetcher = build_fetcher(opts, &options);
+    options.enricher = load_enricher(opts, &options);
+    options.object_store = object_store(opts, &options);
+    options.dedup_bloom = opts
+        .dedup_bloom
+        .map(|expected| Arc::new(Mutex::new(bloom_dedup(opts, expected))));
+    options
+}
+
+/// Options for --dry-run: the connection and pointer selection settings of
+/// [crawl_options], without loading datasets, connecting to an object store
+/// or opening a checkpoint.
+fn estimate_options(opts: &Opts) -> CrawlOptions {
+    let mut options = client_options(opts);
+    options.fetcher = build_fetcher(opts, &options);
+    options
+}
+
+/// Download or refresh the datasets listed with --fetch-datasets into
+/// --dataset-dir.
+fn fetch_datasets(opts: &Opts, options: &CrawlOptions) -> Vec<(Dataset, PathBuf)> {
+    let names = split_list(opts.fetch_datasets.as_deref());
+    if names.is_empty() {
+        return vec![];
+    }
+    let fetcher = match ReqwestFetcher::from_options(options) {
+        Ok(fetcher) => fetcher,
+        Err(e) => {
+            eprintln!("cannot create the HTTP client: {}", e);
+            exit(ExitStatus::NetworkUnreachable);
+        }
+    };
+    let max_age = Duration::from_secs(opts.dataset_max_age * 24 * 3600);
+    let manager = DatasetManager::new(&opts.dataset_dir, Arc::new(fetcher), max_age);
+    let mut paths = vec![];
+    for name in names {
+        let datasets: &[Dataset] = match name.as_str() {
+            "pfx2as" => &[Dataset::Pfx2AsV4, Dataset::Pfx2AsV6],
+            "as2org" => &[Dataset::As2Org],
+            _ => {
+                eprintln!("unknown dataset {}, expected pfx2as or as2org", name);
+                exit(ExitStatus::DatasetError);
+            }
+        };
+        for dataset in datasets {
+            match manager.path(*dataset) {
+                Ok(path) => paths.push((*dataset, path)),
+                Err(e) => {
+                    eprintln!("cannot download {}: {}", dataset, e);
+                    exit(ExitStatus::DatasetError);
+                }
+            }
+        }
+    }
+    paths
+}
+
+/// Load the enrichment datasets given with --pfx2as, --rpki-vrps,
+/// --as2org and --provider-ranges, or downloaded with --fetch-datasets, if
+/// any.
+fn load_enricher(opts: &Opts, options: &CrawlOptions) -> Option<Arc<Enricher>> {
+    fn load<T>(path: &Option<PathBuf>, loader: fn(&Path) -> std::io::Result<T>) -> Option<T> {
+        let path = path.as_ref()?;
+        match loader(path) {
+            Ok(dataset) => Some(dataset),
+            Err(e) => {
+                eprintln!("cannot load {}: {}", path.display(), e);
+                exit(ExitStatus::DatasetError);
+            }
+        }
+    }
+    let provider_ranges = split_list(opts.provider_ranges.as_deref());
+    let mut providers = ProviderRanges::new();
+    for ranges in &provider_ranges {
+        let (name, path) = match ranges.split_once('=') {
+            Some((name, path)) => (name.to_owned(), Path::new(path)),
+            None => {
+                let path = Path::new(ranges);
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                (name.into_owned(), path)
+            }
+        };
+        if let Err(e) = providers.load(&name, path) {
+            eprintln!("cannot load {}: {}", path.display(), e);
+            exit(ExitStatus::DatasetError);
+        }
+    }
+    let fetched = fetch_datasets(opts, options);
+    let fetched_paths = |wanted: &[Dataset]| -> Vec<PathBuf> {
+        let fetched = fetched.iter().filter(|(dataset, _)| wanted.contains(dataset));
+        fetched.map(|(_, path)| path.clone()).collect()
+    };
+    let pfx2as_paths = match &opts.pfx2as {
+        Some(path) => vec![path.clone()],
+        None => fetched_paths(&[Dataset::Pfx2AsV4, Dataset::Pfx2AsV6]),
+    };
+    let as2org_path = opts.as2org.clone().or_else(|| fetched_paths(&[Dataset::As2Org]).pop());
+    let needs_pfx2as =
+        opts.rpki_vrps.is_some() || as2org_path.is_some() || opts.as_report.is_some();
+    if pfx2as_paths.is_empty() && needs_pfx2as {
+        eprintln!("--rpki-vrps, --as2org and --as-report need --pfx2as or --fetch-datasets pfx2as");
+        exit(ExitStatus::DatasetError);
+    }
+    let pfx2as = match pfx2as_paths.is_empty() {
+        true => None,
+        false => match Pfx2As::load_all(&pfx2as_paths) {
+            Ok(pfx2as) => Some(pfx2as),
+            Err(e) => {
+                eprintln!("cannot load pfx2as: {}", e);
+                exit(ExitStatus::DatasetError);
+            }
+        },
+    };
+    let enricher = Enricher {
+        pfx2as,
+        vrps: load(&opts.rpki_vrps, VrpTable::load),
+        providers: Some(providers).filter(|_| !provider_ranges.is_empty()),
+        as2org: load(&as2org_path, As2Org::load),
+        #[cfg(feature = "geoip")]
+        geoip: load(&opts.geoip, GeoIp::load),
+    };
+    #[cfg(feature = "geoip")]
+    let geoip = enricher.geoip.is_some();
+    #[cfg(not(feature = "geoip"))]
+    let geoip = false;
+    match enricher.pfx2as.is_some() || enricher.providers.is_some() || geoip {
+        true => Some(Arc::new(enricher)),
+        false => None,
+    }
+}
+
+/// Fetcher selected with --local-dir, --record or --replay, if any.
+fn build_fetcher(opts: &Opts, options: &CrawlOptions) -> Option<Arc<dyn Fetcher>> {
+    if let Some(dir) = &opts.replay {
+        return match ReplayFetcher::open(dir) {
+            Ok(fetcher) => Some(Arc::new(fetcher)),
+            Err(e) => {
+                eprintln!("cannot read recorded requests from {}: {}", dir, e);
+                exit(ExitStatus::InvalidIndex);
+            }
+        };
+    }
+    let fetcher: Option<Arc<dyn Fetcher>> = opts
+        .local_dir
+        .as_ref()
+        .map(|dir| Arc::new(LocalFetcher::new(dir)) as Arc<dyn Fetcher>);
+    let dir = match &opts.record {
+        Some(dir) => dir,
+        None => return fetcher,
+    };
+    let inner = match fetcher {
+        Some(fetcher) => fetcher,
+        None => match ReqwestFetcher::from_options(options) {
+            Ok(fetcher) => Arc::new(fetcher),
+            Err(e) => {
+                eprintln!("failed to build HTTP client: {}", e);
+                exit(ExitStatus::NetworkUnreachable);
+            }
+        },
+    };
+    match RecordingFetcher::create(dir, inner) {
+        Ok(fetcher) => Some(Arc::new(fetcher)),
+        Err(e) => {
+            eprintln!("cannot record requests to {}: {}", dir, e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+}
+
+/// Crawl configuration from the command-line options, except for the
+/// fetcher, enough to build an HTTP client.
+fn client_options(opts: &Opts) -> CrawlOptions {
+    let host_filter = HostFilter {
+        include: opts.include_regex.clone(),
+        exclude: opts.exclude_regex.clone(),
+    };
+
+    let record_filter = RecordFilter {
+        mime_include: split_list(opts.mime.as_deref()),
+        mime_exclude: split_list(opts.exclude_mime.as_deref()),
+        // validated while parsing the command line
+        ports: opts.port.as_deref().and_then(|ports| parse_ports(ports).ok()).unwrap_or_default(),
+        robots: if opts.exclude_robots {
+            RobotsMode::Exclude
+        } else if opts.only_robots {
+            RobotsMode::Only
+        } else {
+            RobotsMode::Include
+        },
+        from: opts.from,
+        to: opts.to,
+        max_captures: opts.max_captures_per_host,
+        capture_selection: opts.capture_selection,
+    };
+
+    let ip_family = if opts.only_ipv4 {
+        Some(IpFamily::V4)
+    } else if opts.only_ipv6 {
+        Some(IpFamily::V6)
+    } else {
+        None
+    };
+
+    #[allow(unused_mut)]
+    let mut progress = if opts.quiet {
+        ProgressMode::Hidden
+    } else {
+        ProgressMode::Bar
+    };
+    #[cfg(feature = "tui")]
+    if opts.tui {
+        progress = ProgressMode::Tui;
+    }
+
+    CrawlOptions {
+        num_threads: opts.threads,
+        idx_offset: opts.idx_offset,
+        idx_count: opts.idx_count,
+        sample: opts.sample,
+        seed: opts.seed,
+        shuffle: opts.shuffle,
+        limit: opts.limit,
+        host_filter,
+        record_filter,
+        ip_family,
+        progress,
+        retry: RetryPolicy {
+            max_retries: opts.max_retries,
+            ..Default::default()
+        },
+        max_requests_per_sec: opts.max_requests_per_sec,
+        ramp_up: Some(RampUp {
+            initial_rate: opts.ramp_up_rate,
+            duration: Duration::from_secs(opts.ramp_up),
+        })
+        .filter(|_| opts.ramp_up > 0),
+        request_delay: Duration::from_millis(opts.delay_ms),
+        request_jitter: Duration::from_millis(opts.jitter_ms),
+        max_bytes_per_sec: opts.max_bandwidth,
+        max_requests_per_file: opts.max_requests_per_file,
+        breaker: BreakerPolicy {
+            consecutive_failures: opts.breaker_failures,
+            cooldown: Duration::from_secs(opts.breaker_cooldown),
+            block_failures: opts.block_failures,
+            block_cooldown: Some(Duration::from_secs(opts.block_cooldown * 60))
+                .filter(|_| opts.block_cooldown > 0),
+            ..Default::default()
+        },
+        user_agent: opts.user_agent.clone(),
+        proxy: opts.proxy.clone(),
+        base_url: opts.base_url.clone(),
+        index_server_url: opts.index_server_url.clone(),
+        warc_read_bytes: Some(opts.warc_read_bytes),
+        cdx_api_fallback: opts.cdx_api_fallback,
+        bonus_hosts: opts.bonus_hosts,
+        capture_server: opts.capture_server,
+        capture_protocol: opts.capture_protocol,
+        with_ports: opts.with_ports,
+        with_url: opts.with_url,
+        with_status: opts.with_status,
+        with_record_fields: opts.with_record_fields,
+        idn: opts.idn,
+        host_normalization: HostNormalization {
+            lowercase: opts.normalize_hosts,
+            strip_trailing_dot: opts.normalize_hosts,
+            fold_www: opts.fold_www,
+            reject_invalid: opts.reject_invalid_hosts,
+        },
+        dedup_window: Some(opts.dedup_window).filter(|_| opts.dedup),
+        dedup_bloom: None,
+        checkpoint: None,
+        prefixes: opts.prefixes,
+        prefix_lengths: PrefixLengths {
+            v4: opts.prefix_v4_length,
+            v6: opts.prefix_v6_length,
+        },
+        enricher: None,
+        drop_bogons: opts.drop_bogons,
+        #[cfg(feature = "geoip")]
+        country_report: opts.country_report.clone(),
+        #[cfg(not(feature = "geoip"))]
+        country_report: None,
+        as_report: opts.as_report.clone(),
+        as_report_top: opts.as_report_top,
+        output_format: opts.format,
+        object_store: None,
+        upload_chunk_mappings: opts.upload_chunk_mappings,
+        partition_by: split_list(opts.partition_by.as_deref())
+            .iter()
+            .map(|key| match key.parse() {
+                Ok(key) => key,
+                Err(e) => {
+                    eprintln!("invalid --partition-by: {}", e);
+                    exit(ExitStatus::OutputError);
+                }
+            })
+            .collect(),
+        cache_dir: opts.cache_dir.as_ref().map(PathBuf::from),
+        cache_max_bytes: Some(opts.cache_size),
+        channel_capacity: Some(opts.channel_capacity),
+        connect_timeout: Some(Duration::from_secs(opts.connect_timeout)),
+        request_timeout: Some(Duration::from_secs(opts.request_timeout)),
+        slow_request: Some(Duration::from_secs(opts.slow_request))
+            .filter(|_| opts.slow_request > 0),
+        cancel: CancelToken::new(),
+        observer: None,
+        fetcher: None,
+    }
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
-    let mut index_list: Vec<Index> = retrieve_indices();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if opts.verbose {
+        logger.filter_module("cc_host_mapper", log::LevelFilter::Debug);
+    }
+    logger.init();
+
+    if let Some(Command::Watch(watch_opts)) = &opts.command {
+        run_watch(&opts, watch_opts);
+    }
+    if let Some(Command::Files(files_opts)) = &opts.command {
+        print_index_files(&opts, files_opts);
+    }
+    if let Some(Command::Stats(stats_opts)) = &opts.command {
+        print_tld_report(stats_opts);
+    }
+    if let Some(Command::Concentration(concentration_opts)) = &opts.command {
+        print_concentration_report(concentration_opts);
+    }
+    if let Some(Command::MultiIp(multi_ip_opts)) = &opts.command {
+        print_multi_ip_report(multi_ip_opts);
+    }
+    if let Some(Command::Churn(churn_opts)) = &opts.command {
+        print_churn_report(churn_opts);
+    }
+    if let Some(Command::Graph(graph_opts)) = &opts.command {
+        export_graph(graph_opts);
+    }
+    if let Some(Command::Lookup(lookup_opts)) = &opts.command {
+        lookup(lookup_opts);
+    }
+    if let Some(Command::Merge(merge_opts)) = &opts.command {
+        merge(&opts, merge_opts);
+    }
+    if let Some(Command::Healthcheck) = &opts.command {
+        run_healthcheck(&opts);
+    }
+
+    let index_list: Vec<Index> = match list_indices(&opts) {
+        Ok(lst) => lst,
+        Err(e) => {
+            eprintln!("cannot retrieve the list of indices: {}", e);
+            exit(ExitStatus::NetworkUnreachable);
+        }
+    };
     let ids = &index_list.iter().cloned().map(|x| x.id).collect::<Vec<String>>();
-    let ids_str = ids.join(",");
 
     let mut selected_index;
 
-    match opts.index_id {
+    match &opts.index_id {
         Some(index_id) => selected_index =
-            match ids.iter().position(|x| x == &index_id) {
+            match ids.iter().position(|x| x == index_id) {
                 Some(index) => index_list[index].clone(),
-                None => panic!("index id {} not found", index_id)
+                None => {
+                    eprintln!("index id {} not found", index_id);
+                    exit(ExitStatus::InvalidIndex);
+                }
             },
 
+        None if opts.latest => {
+            selected_index = newest_or_exit(&index_list);
+        }
+
         None => {
-            index_list.sort();
-            selected_index = index_list[0].to_owned();
+            selected_index = newest_or_exit(&index_list);
 
             if !Confirm::new()
                 .with_prompt(format!("Do you want to crawl index {}?", selected_index.id))
@@ -86,7 +1693,7 @@ fn main() {
             {
                 // we don't want to go with the most recent
                 if !Confirm::new()
-                    .with_prompt(format!("Do you want to crawl another index?"))
+                    .with_prompt("Do you want to crawl another index?")
                     .default(false)
                     .interact()
                     .unwrap(){
@@ -94,12 +1701,23 @@ fn main() {
                     println!("nevermind then :)");
                     return;
                 } else {
-                    // select one index from list
-                    let input: String = Input::new()
-                        .with_prompt(format!("Select from the following index IDs:\n{}", ids_str.as_str()))
-                        .interact_text().unwrap();
+                    // select one index from a fuzzy-searchable list
+                    let items = index_list
+                        .iter()
+                        .map(|x| {
+                            let date = x.crawl_date().map(|date| date.to_string());
+                            format!("{:<18} {:<10} {}", x.id, date.unwrap_or_default(), x.name)
+                        })
+                        .collect::<Vec<String>>();
+                    let picked = FuzzySelect::new()
+                        .with_prompt("Select an index (type to search)")
+                        .items(&items)
+                        .default(0)
+                        .max_length(15)
+                        .interact_opt()
+                        .unwrap();
 
-                    match ids.iter().position(|x| x == &input) {
+                    match picked {
                         Some(index) => selected_index = index_list[index].clone(),
                         None => return
                     }
@@ -108,16 +1726,56 @@ fn main() {
         }
     }
 
-    let output_file_name = match opts.output {
+    if opts.dry_run {
+        println!("estimating crawl of {}", selected_index.id);
+        println!("{}", estimate_crawl(&selected_index.id, &estimate_options(&opts)));
+        return;
+    }
+
+    let output_file_name = match opts.output.clone() {
         Some(output) => output,
+        None if opts.partition_by.is_some() => "mappings".to_owned(),
         None => {
-            format!("mapping-{}.csv.gz", selected_index.id.as_str().to_lowercase())
+            format!(
+                "mapping-{}.{}",
+                selected_index.id.as_str().to_lowercase(),
+                opts.format.extension()
+            )
         }
     };
 
+    if opts.sort_output && is_object_url(&output_file_name) {
+        eprintln!("--sort-output only applies to local output files");
+        exit(ExitStatus::OutputError);
+    }
+    if opts.sort_output && opts.format != OutputFormat::Csv {
+        eprintln!("--sort-output only applies to csv output");
+        exit(ExitStatus::OutputError);
+    }
+    let parquet = match opts.format {
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => true,
+        _ => false,
+    };
+    if opts.partition_by.is_some() && !parquet {
+        eprintln!("--partition-by only applies to parquet output");
+        exit(ExitStatus::OutputError);
+    }
+    #[cfg(feature = "kv")]
+    if opts.format == OutputFormat::Kv && is_object_url(&output_file_name) {
+        eprintln!("kv output cannot be uploaded to S3 or GCS");
+        exit(ExitStatus::OutputError);
+    }
+
+    let mut options = crawl_options(&opts);
+    options.checkpoint = open_checkpoint(&opts, &selected_index.id, &output_file_name);
+    if options.checkpoint.is_some() {
+        options.observer = Some(Arc::new(CheckpointReporter));
+    }
+
     if opts.dump_cluster_idx {
         println!("dumping cluster.idx to csv file");
-        let host_pointers = read_cluster_idx(&selected_index.id.to_owned());
+        let host_pointers = read_cluster_idx_with(&selected_index.id, &options);
         let mut writer = get_writer(&format!("cluster-idx-{}.csv.gz", selected_index.id.as_str().to_lowercase()));
         for item in host_pointers {
             writeln!(writer, "{}", item.to_csv()).unwrap();
@@ -125,10 +1783,77 @@ fn main() {
         return
     }
 
+    if !opts.no_preflight && opts.replay.is_none() {
+        run_preflight(&selected_index.id, &output_file_name, &options);
+    }
+
+    #[cfg(feature = "otel")]
+    let otlp = opts.otlp_endpoint.as_ref().map(|endpoint| match install_otlp_exporter(endpoint) {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("cannot export to --otlp-endpoint {}: {}", endpoint, e);
+            exit(ExitStatus::OutputError);
+        }
+    });
+
     println!("Will start crawling {} now...", selected_index.id);
-    crawl_host_ip_mapping(
+    let started_at = Utc::now();
+    let mut stats = crawl_with_options(
         selected_index.id.to_owned(),
         output_file_name.to_owned(),
-        opts.threads,
+        &options,
     );
+    #[cfg(feature = "otel")]
+    if let Err(e) = otlp.map_or(Ok(()), OtlpExporter::shutdown) {
+        eprintln!("cannot export the traces and metrics: {}", e);
+    }
+    println!("{}", stats);
+    save_dedup_state(&opts, &options);
+
+    if stats.output_error.is_none() && opts.sort_output {
+        println!("sorting and deduplicating {}", output_file_name);
+        match sort_dedup_file_with(&output_file_name, &sort_options(&opts)) {
+            Ok(count) => println!("{} unique mappings written", count),
+            Err(e) => {
+                eprintln!("failed to sort {}: {}", output_file_name, e);
+                exit(ExitStatus::OutputError);
+            }
+        }
+    }
+
+    if stats.output_error.is_none() && opts.checksums {
+        record_checksums(&opts, &output_file_name, &options, &mut stats);
+    }
+
+    if let Some(summary_file) = &opts.summary_json {
+        let written = std::fs::File::create(summary_file)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(file, &stats).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("cannot write summary to {}: {}", summary_file, e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+
+    if let Some(metrics_file) = &opts.metrics {
+        let written = write_prometheus_metrics(Path::new(metrics_file), &stats, &selected_index.id);
+        if let Err(e) = written {
+            eprintln!("cannot write metrics to {}: {}", metrics_file, e);
+            exit(ExitStatus::OutputError);
+        }
+    }
+
+    if stats.output_error.is_none() && opts.manifest {
+        let manifest =
+            Manifest::new(&selected_index.id, &output_file_name, &options, started_at, &stats);
+        match manifest.write(&options) {
+            Ok(path) => println!("manifest written to {}", path),
+            Err(e) => {
+                eprintln!("cannot write manifest: {}", e);
+                exit(ExitStatus::OutputError);
+            }
+        }
+    }
+
+    exit(ExitStatus::from_stats(&stats, opts.max_failure_rate));
 }
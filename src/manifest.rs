@@ -0,0 +1,208 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Manifest of a finished crawl, written next to its outputs so that they
+//! can be published and cited along with how they were made: version,
+//! command line, index, filters, shard, times and statistics.
+use crate::{
+    is_object_url, CaptureSelection, CrawlOptions, CrawlStats, IpFamily, ObjectUrl, RobotsMode,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest written next to the outputs.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Provenance of the outputs of a crawl.
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    /// Version of cc-host-mapper the crawl ran with
+    pub version: String,
+    /// Command line of the crawl
+    pub command_line: Vec<String>,
+    /// CommonCrawl index crawled
+    pub index_id: String,
+    /// Output file, directory or object URL
+    pub output: String,
+    /// Data endpoint the index was read from
+    pub base_url: String,
+    /// Start of the crawl, in RFC 3339 UTC
+    pub started_at: String,
+    /// End of the crawl, in RFC 3339 UTC
+    pub finished_at: String,
+    /// Hosts and captures the mappings were restricted to
+    pub filters: ManifestFilters,
+    /// Part of the index crawled
+    pub shard: ManifestShard,
+    /// Statistics of the crawl, with the checksums of the outputs if they
+    /// were requested
+    pub stats: CrawlStats,
+}
+
+/// Filters of a crawl, see [crate::HostFilter] and [crate::RecordFilter].
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestFilters {
+    /// Regex hosts had to match
+    pub include_regex: Option<String>,
+    /// Regex of the hosts skipped
+    pub exclude_regex: Option<String>,
+    /// MIME types captures had to have, any if empty
+    pub mime_include: Vec<String>,
+    /// MIME types of the captures skipped
+    pub mime_exclude: Vec<String>,
+    /// Ports captures had to be on, any if empty
+    pub ports: Vec<u16>,
+    /// Treatment of robots.txt captures
+    pub robots: RobotsMode,
+    /// First capture date kept, as YYYY-MM-DD
+    pub from: Option<String>,
+    /// Last capture date kept, as YYYY-MM-DD
+    pub to: Option<String>,
+    /// Maximum number of captures resolved per host
+    pub max_captures: Option<usize>,
+    /// Captures resolved for hosts with more than `max_captures`
+    pub capture_selection: CaptureSelection,
+    /// IP address family of the mappings, both if `None`
+    pub ip_family: Option<IpFamily>,
+    /// Whether mappings to bogon IPs were dropped
+    pub drop_bogons: bool,
+    /// Window of the deduplication of the mappings, if enabled
+    pub dedup_window: Option<usize>,
+}
+
+/// Slice of the cluster.idx of the index that was crawled.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestShard {
+    /// First cluster.idx entry crawled
+    pub idx_offset: usize,
+    /// Number of cluster.idx entries crawled, all the rest if `None`
+    pub idx_count: Option<usize>,
+    /// Number of host pointers sampled
+    pub sample: Option<usize>,
+    /// Seed of the sample and shuffle
+    pub seed: Option<u64>,
+    /// Maximum number of host pointers crawled
+    pub limit: Option<usize>,
+    /// Whether the pointers were crawled in random order
+    pub shuffle: bool,
+}
+
+impl Manifest {
+    /// Manifest of a crawl of `index_id` into `output` that started at
+    /// `started_at` and just finished, run by the command line of the
+    /// current process.
+    pub fn new(
+        index_id: &str,
+        output: &str,
+        options: &CrawlOptions,
+        started_at: DateTime<Utc>,
+        stats: &CrawlStats,
+    ) -> Manifest {
+        let host_filter = &options.host_filter;
+        let record_filter = &options.record_filter;
+        Manifest {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            command_line: std::env::args().collect(),
+            index_id: index_id.to_owned(),
+            output: output.to_owned(),
+            base_url: options.base_url().to_owned(),
+            started_at: started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            finished_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            filters: ManifestFilters {
+                include_regex: host_filter.include.as_ref().map(|r| r.as_str().to_owned()),
+                exclude_regex: host_filter.exclude.as_ref().map(|r| r.as_str().to_owned()),
+                mime_include: record_filter.mime_include.clone(),
+                mime_exclude: record_filter.mime_exclude.clone(),
+                ports: record_filter.ports.clone(),
+                robots: record_filter.robots,
+                from: record_filter.from.map(|date| date.to_string()),
+                to: record_filter.to.map(|date| date.to_string()),
+                max_captures: record_filter.max_captures,
+                capture_selection: record_filter.capture_selection,
+                ip_family: options.ip_family,
+                drop_bogons: options.drop_bogons,
+                dedup_window: options.dedup_window,
+            },
+            shard: ManifestShard {
+                idx_offset: options.idx_offset,
+                idx_count: options.idx_count,
+                sample: options.sample,
+                seed: options.seed,
+                limit: options.limit,
+                shuffle: options.shuffle,
+            },
+            stats: stats.clone(),
+        }
+    }
+
+    /// Write the manifest next to the output: in the directory of an output
+    /// file, inside an output directory, or next to an uploaded object with
+    /// the object store of the `options`. Returns where it was written.
+    pub fn write(&self, options: &CrawlOptions) -> io::Result<String> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        if is_object_url(&self.output) {
+            let store = options.object_store.as_ref().ok_or_else(|| {
+                io::Error::other("no object store to upload the manifest to")
+            })?;
+            let url = self
+                .output
+                .parse::<ObjectUrl>()
+                .map_err(io::Error::other)?
+                .sibling(MANIFEST_NAME);
+            let mut writer = store.create(&url)?;
+            writer.write_all(&json)?;
+            writer.finish()?;
+            return Ok(url.to_string());
+        }
+        let path = manifest_path(Path::new(&self.output));
+        fs::write(&path, json)?;
+        Ok(path.display().to_string())
+    }
+}
+
+/// Path of the manifest of a local output file or directory.
+fn manifest_path(output: &Path) -> PathBuf {
+    if output.is_dir() {
+        return output.join(MANIFEST_NAME);
+    }
+    match output.parent() {
+        Some(dir) => dir.join(MANIFEST_NAME),
+        None => PathBuf::from(MANIFEST_NAME),
+    }
+}
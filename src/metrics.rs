@@ -0,0 +1,228 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Statistics of a finished crawl in the Prometheus text exposition format,
+//! e.g. for the textfile collector of node_exporter.
+use crate::{CrawlStats, Endpoint};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Prefix of the metric names.
+const PREFIX: &str = "cc_host_mapper";
+
+/// Value of the `endpoint` label.
+pub(crate) fn endpoint_label(endpoint: Endpoint) -> &'static str {
+    match endpoint {
+        Endpoint::ClusterIdx => "cluster_idx",
+        Endpoint::Cdx => "cdx",
+        Endpoint::CdxApi => "cdx_api",
+        Endpoint::Warc => "warc",
+    }
+}
+
+/// Label value with backslashes, quotes and newlines escaped.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Append the `# HELP` and `# TYPE` lines of a metric and its samples,
+/// each with its labels besides the index.
+fn metric(
+    out: &mut String,
+    index: &str,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(String, f64)],
+) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}_{}{{index=\"{}\"{}}} {}", PREFIX, name, index, labels, value);
+    }
+}
+
+/// Render the statistics of a crawl of an index as Prometheus metrics,
+/// with the request latencies by endpoint as summaries of their p50, p90
+/// and p99.
+pub fn prometheus_metrics(stats: &CrawlStats, index_id: &str) -> String {
+    let index = escape_label(index_id);
+    let mut out = String::new();
+    let single = |value: u64| vec![(String::new(), value as f64)];
+    metric(
+        &mut out,
+        &index,
+        "hosts_attempted_total",
+        "counter",
+        "Host pointers processed",
+        &single(stats.hosts_attempted),
+    );
+    metric(
+        &mut out,
+        &index,
+        "mappings_written_total",
+        "counter",
+        "Mapping rows written to the output",
+        &single(stats.mappings_written),
+    );
+    metric(
+        &mut out,
+        &index,
+        "unique_hosts",
+        "gauge",
+        "Distinct hosts in the output",
+        &single(stats.unique_hosts),
+    );
+    metric(
+        &mut out,
+        &index,
+        "unique_ips",
+        "gauge",
+        "Distinct IP addresses in the output",
+        &single(stats.unique_ips),
+    );
+    metric(
+        &mut out,
+        &index,
+        "retries_total",
+        "counter",
+        "Requests retried after a failure",
+        &single(stats.retries),
+    );
+    metric(
+        &mut out,
+        &index,
+        "bytes_downloaded_total",
+        "counter",
+        "Response body bytes downloaded",
+        &single(stats.bytes_downloaded),
+    );
+
+    let mut responses = vec![];
+    let mut failures = vec![];
+    let mut latencies = vec![];
+    for (endpoint, endpoint_stats) in &stats.endpoints {
+        let endpoint = endpoint_label(*endpoint);
+        for (status, count) in &endpoint_stats.statuses {
+            let labels = format!(",endpoint=\"{}\",status=\"{}\"", endpoint, status);
+            responses.push((labels, *count as f64));
+        }
+        let kinds = [
+            ("timeout", endpoint_stats.timeouts),
+            ("connect", endpoint_stats.connect_errors),
+            ("truncated", endpoint_stats.truncated),
+            ("gzip", endpoint_stats.gzip_errors),
+            ("parse", endpoint_stats.parse_errors),
+        ];
+        for (kind, count) in kinds.iter() {
+            let labels = format!(",endpoint=\"{}\",kind=\"{}\"", endpoint, kind);
+            failures.push((labels, *count as f64));
+        }
+        if let Some(latency) = &endpoint_stats.latency {
+            let quantiles = [
+                ("0.5", latency.p50_secs),
+                ("0.9", latency.p90_secs),
+                ("0.99", latency.p99_secs),
+            ];
+            for (quantile, secs) in quantiles.iter() {
+                let labels = format!(",endpoint=\"{}\",quantile=\"{}\"", endpoint, quantile);
+                latencies.push((labels, *secs));
+            }
+        }
+    }
+    metric(
+        &mut out,
+        &index,
+        "responses_total",
+        "counter",
+        "Responses by endpoint and HTTP status",
+        &responses,
+    );
+    metric(
+        &mut out,
+        &index,
+        "request_failures_total",
+        "counter",
+        "Failed requests and unusable responses by endpoint and kind",
+        &failures,
+    );
+    metric(
+        &mut out,
+        &index,
+        "request_duration_seconds",
+        "summary",
+        "Latency of the requests that got a response, by endpoint",
+        &latencies,
+    );
+    for (endpoint, endpoint_stats) in &stats.endpoints {
+        if let Some(latency) = &endpoint_stats.latency {
+            let endpoint = endpoint_label(*endpoint);
+            let _ = writeln!(
+                out,
+                "{}_request_duration_seconds_sum{{index=\"{}\",endpoint=\"{}\"}} {}",
+                PREFIX, index, endpoint, latency.sum_secs
+            );
+            let _ = writeln!(
+                out,
+                "{}_request_duration_seconds_count{{index=\"{}\",endpoint=\"{}\"}} {}",
+                PREFIX, index, endpoint, latency.count
+            );
+        }
+    }
+    metric(
+        &mut out,
+        &index,
+        "crawl_duration_seconds",
+        "gauge",
+        "Wall-clock duration of the crawl",
+        &[(String::new(), stats.elapsed_secs)],
+    );
+    out
+}
+
+/// Write the [prometheus_metrics] of a crawl to a file, through a temporary
+/// file renamed into place so that a collector never reads a partial file.
+pub fn write_prometheus_metrics(path: &Path, stats: &CrawlStats, index_id: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, prometheus_metrics(stats, index_id))?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,398 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Async variants of the library functions, enabled with the `async` feature,
+//! for embedding the crawler in tokio applications without spawning blocking
+//! threads.
+//!
+//! Requests are retried and rate limited like in the blocking API, but the
+//! circuit breaker, bandwidth limit, per-file request cap, disk cache and CDX
+//! API fallback are only available in the blocking API.
+use crate::http::{expected_range_len, pace_delay, Attempts};
+use crate::{
+    cluster_idx_url, keep_ip, mapping_entry, narrow_pointers, next_warc_window, parse_cluster_idx,
+    pointer_hosts, select_records, warc_range, CrawlCounters, CrawlError, CrawlOptions, CrawlStats,
+    Endpoint, FailureKind, Index, IndexHostPointer, IndexRecord, MappingEntry, MappingSink,
+    ObserverHook, OutputTally, RateLimiter, WarcCapture, WarcWindow, DEFAULT_USER_AGENT,
+    INDEX_SERVER_URL,
+};
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::FutureExt;
+use reqwest::header::{HeaderValue, RANGE};
+use reqwest::{Client, ClientBuilder, Proxy};
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// Number of host pointers crawled concurrently if
+/// [CrawlOptions::num_threads] is not set.
+pub const DEFAULT_CONCURRENCY: usize = 64;
+
+/// Async client builder with the user agent, proxy and connect timeout of
+/// the [CrawlOptions].
+fn client_builder(options: &CrawlOptions) -> reqwest::Result<ClientBuilder> {
+    let user_agent = options.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+    let mut builder = Client::builder().user_agent(user_agent);
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    Ok(builder)
+}
+
+/// Build an async HTTP client configured with the connection settings of the
+/// [CrawlOptions], like [crate::build_client].
+pub fn build_client(options: &CrawlOptions) -> reqwest::Result<Client> {
+    let mut builder = client_builder(options)?;
+    if let Some(timeout) = options.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder.build()
+}
+
+/// Async variant of [crate::try_retrieve_indices].
+pub async fn retrieve_indices(client: &Client) -> reqwest::Result<Vec<Index>> {
+    client
+        .get(&format!("{}/collinfo.json", INDEX_SERVER_URL))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<Index>>()
+        .await
+}
+
+/// Async variant of [crate::read_cluster_idx], applying the host filter of
+/// the [CrawlOptions]. The cluster.idx is large, so the client should not
+/// have an overall request timeout.
+pub async fn read_cluster_idx(
+    client: &Client,
+    index_id: &str,
+    options: &CrawlOptions,
+) -> reqwest::Result<Vec<IndexHostPointer>> {
+    Ok(fetch_cluster_idx(client, index_id, options).await?.0)
+}
+
+/// Download and parse cluster.idx, returning its pointers and the number of
+/// malformed lines skipped.
+async fn fetch_cluster_idx(
+    client: &Client,
+    index_id: &str,
+    options: &CrawlOptions,
+) -> reqwest::Result<(Vec<IndexHostPointer>, u64)> {
+    let bytes = client
+        .get(&cluster_idx_url(index_id, options))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(parse_cluster_idx(&bytes, index_id, options))
+}
+
+/// Async variant of [crate::query_host_with_options]. Returns an error if
+/// the index records of the host cannot be fetched.
+pub async fn query_host(
+    client: &Client,
+    pointer: IndexHostPointer,
+    options: &CrawlOptions,
+) -> Result<Vec<MappingEntry>, CrawlError> {
+    let counters = CrawlCounters::default();
+    let limiter = RateLimiter::from_options(options);
+    let ctx = AsyncContext {
+        client,
+        options,
+        counters: &counters,
+        limiter: &limiter,
+        pointer_hosts: None,
+    };
+    query_host_in(pointer, &ctx).await
+}
+
+/// Async variant of [crate::crawl_to_sink]. Up to `options.num_threads`
+/// host pointers, or [DEFAULT_CONCURRENCY], are crawled concurrently.
+pub async fn crawl(
+    index_id: &str,
+    sink: &mut (dyn MappingSink + Send),
+    options: &CrawlOptions,
+) -> CrawlStats {
+    let start_time = Instant::now();
+    let clients = client_builder(options)
+        .and_then(ClientBuilder::build)
+        .and_then(|bulk| Ok((bulk, build_client(options)?)));
+    let (bulk_client, client) = match clients {
+        Ok(clients) => clients,
+        Err(e) => {
+            return CrawlStats {
+                index_error: Some(format!("cannot create the HTTP client: {}", e)),
+                ..Default::default()
+            }
+        }
+    };
+    let cluster_idx = fetch_cluster_idx(&bulk_client, index_id, options).await;
+    let (host_pointers, malformed) = match cluster_idx {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let url = cluster_idx_url(index_id, options);
+            let error = match e.status() {
+                Some(status) => CrawlError::Status {
+                    url,
+                    status: status.as_u16(),
+                },
+                None => CrawlError::Request {
+                    url,
+                    reason: e.to_string(),
+                },
+            };
+            return CrawlStats {
+                index_error: Some(error.to_string()),
+                index_error_retryable: error.is_retryable(),
+                ..Default::default()
+            };
+        }
+    };
+    let pointer_hosts = pointer_hosts(&host_pointers, options);
+    let (host_pointers, available_hosts) = narrow_pointers(host_pointers, options);
+
+    let counters = CrawlCounters::default();
+    counters.malformed_lines.fetch_add(malformed, Ordering::Relaxed);
+    let limiter = RateLimiter::from_options(options);
+    let ctx = AsyncContext {
+        client: &client,
+        options,
+        counters: &counters,
+        limiter: &limiter,
+        pointer_hosts: pointer_hosts.as_ref(),
+    };
+    let concurrency = options.num_threads.unwrap_or(DEFAULT_CONCURRENCY);
+    let total_hosts = host_pointers.len() as u64;
+    let mut results = stream::iter(host_pointers)
+        .take_while(|_| future::ready(!options.cancel.is_cancelled()))
+        .map(|pointer| async {
+            let host = pointer.host.clone();
+            (host, query_host_in(pointer, &ctx).await)
+        })
+        .buffer_unordered(concurrency);
+
+    let hook = ObserverHook::new(options.observer.as_deref());
+    let mut output = OutputTally::new(options);
+    while let Some((host, result)) = results.next().await {
+        counters.hosts_attempted.fetch_add(1, Ordering::Relaxed);
+        let result = result.map(|mappings| mappings.into_iter().map(Some).collect::<Vec<_>>());
+        hook.pointer_done(&host, &result, &counters);
+        for mapping in result.unwrap_or_default().into_iter().flatten() {
+            output.write(sink, mapping);
+        }
+    }
+    let output = output.finish(sink);
+
+    let mut stats = counters.to_stats();
+    output.fill_stats(&mut stats);
+    stats.cancelled = options.cancel.is_cancelled() && stats.hosts_attempted < total_hosts;
+    stats.hosts_available = available_hosts as u64;
+    stats.elapsed_secs = start_time.elapsed().as_secs_f64();
+    hook.finish(&stats);
+    stats
+}
+
+/// Shared state of an async crawl.
+struct AsyncContext<'a> {
+    client: &'a Client,
+    options: &'a CrawlOptions,
+    counters: &'a CrawlCounters,
+    limiter: &'a RateLimiter,
+    /// Hosts of the pointers of the crawl, see [crate::pointer_hosts]
+    pointer_hosts: Option<&'a HashSet<String>>,
+}
+
+async fn query_host_in(
+    pointer: IndexHostPointer,
+    ctx: &AsyncContext<'_>,
+) -> Result<Vec<MappingEntry>, CrawlError> {
+    #[cfg(feature = "otel")]
+    let cx = crate::otel::pointer_context(&pointer);
+    let query = query_pointer(pointer, ctx);
+    #[cfg(feature = "otel")]
+    let query = query.with_context(cx.clone());
+    let result = query.await;
+    #[cfg(feature = "otel")]
+    crate::otel::finish_pointer(&cx, &result);
+    result
+}
+
+async fn query_pointer(
+    pointer: IndexHostPointer,
+    ctx: &AsyncContext<'_>,
+) -> Result<Vec<MappingEntry>, CrawlError> {
+    let start = pointer.range_start;
+    let end = start + pointer.range_length - 1;
+    let lines = fetch_range(ctx, &pointer.index_file_name, start, end)
+        .await
+        .and_then(|bytes| {
+            let mut decoded = vec![];
+            match flate2::read::MultiGzDecoder::new(&*bytes).read_to_end(&mut decoded) {
+                Ok(_) => Ok(String::from_utf8_lossy(&decoded).lines().map(str::to_owned).collect()),
+                Err(_) => {
+                    ctx.counters.record_failure(Endpoint::Cdx, FailureKind::Gzip);
+                    Err(CrawlError::Truncated {
+                        url: pointer.index_file_name.clone(),
+                    })
+                }
+            }
+        });
+    let lines: Vec<String> = match lines {
+        Ok(lines) => lines,
+        Err(cause) => {
+            ctx.counters.record_failed_host(&pointer.host, &cause);
+            return Err(CrawlError::HostFailed {
+                host: pointer.host,
+                cause: Box::new(cause),
+            });
+        }
+    };
+
+    let mut mappings = vec![];
+    for line in select_records(&pointer.host, lines, ctx.options, ctx.counters, ctx.pointer_hosts) {
+        if let Some(capture) = retrieve_ip(&line.record, ctx).await {
+            mappings.push(mapping_entry(line, capture, ctx.options));
+        }
+    }
+    Ok(mappings)
+}
+
+async fn retrieve_ip(record: &IndexRecord, ctx: &AsyncContext<'_>) -> Option<WarcCapture> {
+    let (url, offset, length) = match warc_range(record, ctx.options) {
+        Ok(range) => range,
+        Err(_) => {
+            ctx.counters.malformed_lines.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+    };
+    let mut window = ctx.options.warc_read_bytes();
+    let capture = loop {
+        let end = offset + window.min(length) - 1;
+        let bytes = fetch_range(ctx, &url, offset, end).await.ok()?;
+        // records that cannot be parsed are counted by next_warc_window
+        match next_warc_window(&bytes, window, length, ctx.options, ctx.counters).ok()? {
+            WarcWindow::Capture(capture) => break capture,
+            WarcWindow::Widen(next) => {
+                ctx.counters.warc_refetches.fetch_add(1, Ordering::Relaxed);
+                window = next;
+            }
+        }
+    };
+    match capture {
+        Some(capture) if keep_ip(capture.ip, ctx.options, ctx.counters) => Some(capture),
+        Some(_) => None,
+        None => {
+            ctx.counters.missing_ip.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Fetch the byte range `start..=end` of `url`, retrying failed requests and
+/// truncated responses according to the [crate::RetryPolicy].
+async fn fetch_range(
+    ctx: &AsyncContext<'_>,
+    url: &str,
+    start: i64,
+    end: i64,
+) -> Result<Vec<u8>, CrawlError> {
+    let id = ctx.counters.next_fetch_id();
+    let attempts = fetch_attempts(ctx, id, url, start, end);
+    #[cfg(feature = "otel")]
+    let cx = crate::otel::fetch_context(id, url, Some((start, end)));
+    #[cfg(feature = "otel")]
+    let attempts = attempts.with_context(cx.clone());
+    let result = attempts.await;
+    #[cfg(feature = "otel")]
+    crate::otel::finish(&cx, &result);
+    result
+}
+
+/// The attempts of [fetch_range] for the fetch with the given ID.
+async fn fetch_attempts(
+    ctx: &AsyncContext<'_>,
+    id: u64,
+    url: &str,
+    start: i64,
+    end: i64,
+) -> Result<Vec<u8>, CrawlError> {
+    let range = HeaderValue::from_str(&format!("bytes={}-{}", start, end)).unwrap();
+    let mut attempts = Attempts::new(url, ctx.options, ctx.counters, ctx.limiter);
+    loop {
+        let wait = pace_delay(ctx.options) + ctx.limiter.reserve();
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+        let sent = attempts.send();
+        let error = match ctx.client.get(url).header(RANGE, range.clone()).send().await {
+            Ok(rsp) => {
+                let status = rsp.status();
+                if status.is_success() {
+                    let expected = expected_range_len(status, rsp.headers(), start, end);
+                    let body = rsp.bytes().await;
+                    attempts.response(status, sent);
+                    attempts.log_slow(id, Some((start, end)), sent, &status);
+                    if let Ok(bytes) = body {
+                        attempts.downloaded(bytes.len());
+                        if expected == Some(bytes.len() as i64) {
+                            return Ok(bytes.to_vec());
+                        }
+                    }
+                    attempts.truncated()
+                } else {
+                    attempts.response(status, sent);
+                    attempts.log_slow(id, Some((start, end)), sent, &status);
+                    attempts.status_error(status, rsp.headers())
+                }
+            }
+            Err(e) => {
+                attempts.log_slow(id, Some((start, end)), sent, &e);
+                let kind = match e.is_timeout() {
+                    true => FailureKind::Timeout,
+                    false => FailureKind::Connect,
+                };
+                attempts.request_error(e.to_string(), kind)
+            }
+        };
+        tokio::time::sleep(attempts.backoff(error)?).await;
+    }
+}
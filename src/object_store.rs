@@ -0,0 +1,831 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Output to Amazon S3 and Google Cloud Storage objects, uploaded in parts
+//! while it is written, so that no local copy of the whole output is
+//! needed. Requests are signed with AWS Signature Version 4, which GCS also
+//! accepts with the HMAC keys of a service account.
+use crate::checksum::{checksum_line, CHECKSUM_EXTENSION};
+use crate::datasets::sha256_hex;
+use crate::http::{bulk_client, is_retryable};
+use crate::{CrawlOptions, MappingSink, MappingEntry, RetryPolicy};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::blocking::{Body, Client};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Default size of the parts of an upload.
+pub const DEFAULT_PART_SIZE: usize = 64 * 1024 * 1024;
+
+/// Smallest part S3 accepts, except for the last one.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Whether an output path is the URL of an object, `s3://bucket/key` or
+/// `gs://bucket/key`.
+pub fn is_object_url(path: &str) -> bool {
+    path.starts_with("s3://") || path.starts_with("gs://")
+}
+
+/// Location of an object in S3 (`s3://bucket/key`) or GCS
+/// (`gs://bucket/key`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectUrl {
+    /// Whether the object is in Google Cloud Storage rather than S3
+    pub gcs: bool,
+    pub bucket: String,
+    pub key: String,
+}
+
+impl ObjectUrl {
+    /// The `n`th chunk of this object, numbered before the extensions of its
+    /// name, e.g. `s3://bucket/mapping-00001.csv.gz`.
+    pub fn chunk(&self, n: usize) -> ObjectUrl {
+        let (dir, name) = match self.key.rfind('/') {
+            Some(i) => self.key.split_at(i + 1),
+            None => ("", self.key.as_str()),
+        };
+        let (stem, extensions) = name.split_at(name.find('.').unwrap_or(name.len()));
+        let key = format!("{}{}-{:05}{}", dir, stem, n, extensions);
+        ObjectUrl { key, ..self.clone() }
+    }
+
+    /// The object named `name` in the same directory as this one, e.g.
+    /// `s3://bucket/prefix/manifest.json` for `s3://bucket/prefix/mapping.csv.gz`.
+    pub fn sibling(&self, name: &str) -> ObjectUrl {
+        let dir = match self.key.rfind('/') {
+            Some(i) => &self.key[..i + 1],
+            None => "",
+        };
+        ObjectUrl {
+            key: format!("{}{}", dir, name),
+            ..self.clone()
+        }
+    }
+
+    /// The object at `path` under this one taken as a directory, e.g.
+    /// `s3://bucket/prefix/date=2020-11-26/part.parquet`.
+    pub fn join(&self, path: &str) -> ObjectUrl {
+        let key = match self.key.is_empty() || self.key.ends_with('/') {
+            true => format!("{}{}", self.key, path),
+            false => format!("{}/{}", self.key, path),
+        };
+        ObjectUrl { key, ..self.clone() }
+    }
+}
+
+impl fmt::Display for ObjectUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.gcs { "gs" } else { "s3" };
+        write!(f, "{}://{}/{}", scheme, self.bucket, self.key)
+    }
+}
+
+impl std::str::FromStr for ObjectUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (gcs, rest) = match (s.strip_prefix("s3://"), s.strip_prefix("gs://")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return Err(format!("expected an s3:// or gs:// URL, found {:?}", s)),
+        };
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(format!("no bucket in {:?}", s));
+        }
+        Ok(ObjectUrl {
+            gcs,
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+/// Access keys to sign requests with; for GCS, the HMAC keys of a service
+/// account.
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Token of temporary credentials
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Read the credentials from the `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables.
+    pub fn from_env() -> Option<Credentials> {
+        Some(Credentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Response of a successful request.
+struct Reply {
+    etag: Option<String>,
+    body: String,
+}
+
+/// Client of S3, GCS or another S3-compatible store, e.g. MinIO, writing
+/// objects with [ObjectStore::create]. Requests failing with a connection
+/// error or a retryable status are retried according to a [RetryPolicy].
+pub struct ObjectStore {
+    client: Client,
+    credentials: Credentials,
+    region: String,
+    endpoint: Option<String>,
+    retry: RetryPolicy,
+    part_size: usize,
+    spill_dir: Option<PathBuf>,
+    /// Whether a checksum sidecar is uploaded with each object
+    checksums: bool,
+    /// SHA-256 digests of the objects written
+    written: Mutex<Vec<(ObjectUrl, String)>>,
+}
+
+impl fmt::Debug for ObjectStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjectStore")
+            .field("credentials", &self.credentials)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("part_size", &self.part_size)
+            .field("spill_dir", &self.spill_dir)
+            .finish()
+    }
+}
+
+impl ObjectStore {
+    /// Create a client with the region of the `AWS_REGION` or
+    /// `AWS_DEFAULT_REGION` environment variables, `us-east-1` by default,
+    /// and the S3 endpoint of `AWS_ENDPOINT_URL` if set.
+    pub fn new(client: Client, credentials: Credentials) -> ObjectStore {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_owned());
+        ObjectStore {
+            client,
+            credentials,
+            region,
+            endpoint: std::env::var("AWS_ENDPOINT_URL").ok(),
+            retry: RetryPolicy::default(),
+            part_size: DEFAULT_PART_SIZE,
+            spill_dir: None,
+            checksums: false,
+            written: Mutex::new(vec![]),
+        }
+    }
+
+    /// Create a client with the connection settings and retry policy of the
+    /// [CrawlOptions], without an overall request timeout since parts are
+    /// large.
+    pub fn from_options(options: &CrawlOptions, credentials: Credentials) -> ObjectStore {
+        ObjectStore::new(bulk_client(options), credentials).with_retry_policy(options.retry.clone())
+    }
+
+    /// Sign requests for this S3 region.
+    pub fn with_region(mut self, region: &str) -> ObjectStore {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Send S3 requests to this endpoint, e.g. `http://localhost:9000`,
+    /// instead of AWS.
+    pub fn with_endpoint(mut self, endpoint: &str) -> ObjectStore {
+        self.endpoint = Some(endpoint.trim_end_matches('/').to_owned());
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> ObjectStore {
+        self.retry = retry;
+        self
+    }
+
+    /// Upload objects in parts of this size, at least 5 MiB. Objects can
+    /// have at most 10,000 parts.
+    pub fn with_part_size(mut self, part_size: usize) -> ObjectStore {
+        self.part_size = part_size.max(MIN_PART_SIZE);
+        self
+    }
+
+    /// Buffer the part being written in a file of this directory instead of
+    /// in memory.
+    pub fn with_spill_dir(mut self, dir: impl Into<PathBuf>) -> ObjectStore {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Upload a `<key>.sha256` sidecar with the SHA-256 digest of each
+    /// object, in the format of `sha256sum`.
+    pub fn with_checksums(mut self, checksums: bool) -> ObjectStore {
+        self.checksums = checksums;
+        self
+    }
+
+    /// The objects written so far and their SHA-256 digests.
+    pub fn written(&self) -> Vec<(ObjectUrl, String)> {
+        self.written.lock().unwrap().clone()
+    }
+
+    /// Start writing an object, which only appears once the writer is
+    /// finished, see [ObjectWriter::finish].
+    pub fn create(self: &Arc<Self>, url: &ObjectUrl) -> io::Result<ObjectWriter> {
+        let buffer = match &self.spill_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                let id = rand::random::<u64>();
+                let name = format!("upload-{}-{:016x}.part", std::process::id(), id);
+                let path = dir.join(name);
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?;
+                PartBuffer::Spill { path, file, len: 0 }
+            }
+            None => PartBuffer::Memory(Vec::new()),
+        };
+        Ok(ObjectWriter {
+            store: self.clone(),
+            url: url.clone(),
+            upload_id: None,
+            etags: vec![],
+            buffer,
+            hasher: Sha256::new(),
+            finished: false,
+        })
+    }
+
+    fn endpoint(&self, url: &ObjectUrl) -> String {
+        match (url.gcs, &self.endpoint) {
+            (true, _) => "https://storage.googleapis.com".to_owned(),
+            (false, Some(endpoint)) => endpoint.clone(),
+            (false, None) => format!("https://s3.{}.amazonaws.com", self.region),
+        }
+    }
+
+    /// Headers of a request signed with Signature Version 4, and the URL to
+    /// send it to. Buckets are addressed in the path, which all stores
+    /// support.
+    fn sign(
+        &self,
+        method: &Method,
+        url: &ObjectUrl,
+        query: &[(&str, String)],
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> io::Result<(String, Vec<(&'static str, String)>)> {
+        let endpoint = self.endpoint(url);
+        let parsed = reqwest::Url::parse(&endpoint).map_err(|e| invalid_input(&endpoint, e))?;
+        let host = match (parsed.host_str(), parsed.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(invalid_input(&endpoint, "no host")),
+        };
+        let region = if url.gcs { "auto" } else { &self.region };
+        let path = format!("/{}/{}", uri_encode(&url.bucket, true), uri_encode(&url.key, false));
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.to_owned()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let secret = format!("AWS4{}", self.credentials.secret_access_key);
+        let mut key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        for part in &[region, "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature: String = hmac_sha256(&key, string_to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        // reqwest sets the host header itself
+        headers.remove(0);
+        let request_url = match query.is_empty() {
+            true => format!("{}{}", endpoint, path),
+            false => format!("{}{}?{}", endpoint, path, query),
+        };
+        Ok((request_url, headers))
+    }
+
+    /// Send a signed request, retrying failures. `body` gives the body of
+    /// each attempt and its SHA-256 digest, or `UNSIGNED-PAYLOAD`.
+    fn send(
+        &self,
+        method: Method,
+        url: &ObjectUrl,
+        query: &[(&str, String)],
+        body: &dyn Fn() -> io::Result<(Body, String)>,
+    ) -> io::Result<Reply> {
+        let mut retry = 0;
+        loop {
+            let (payload, payload_hash) = body()?;
+            let (request_url, headers) = self.sign(&method, url, query, &payload_hash, Utc::now())?;
+            let mut request = self.client.request(method.clone(), &request_url).body(payload);
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let error = match request.send() {
+                Ok(response) => {
+                    let status = response.status();
+                    let etag = response
+                        .headers()
+                        .get("etag")
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(str::to_owned);
+                    let text = response.text().unwrap_or_default();
+                    // CompleteMultipartUpload can fail with a 200 status
+                    if status.is_success() && !text.contains("<Error>") {
+                        return Ok(Reply { etag, body: text });
+                    }
+                    let error = format!("{} {}: {} {}", method, url, status, text.trim());
+                    if status.is_success() || is_retryable(status) {
+                        error
+                    } else {
+                        return Err(io::Error::other(error));
+                    }
+                }
+                Err(e) => format!("{} {}: {}", method, url, e),
+            };
+            if retry >= self.retry.max_retries {
+                return Err(io::Error::other(error));
+            }
+            thread::sleep(self.retry.backoff(retry));
+            retry += 1;
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode all but the unreserved characters, and `/` unless
+/// `encode_slash`.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn invalid_input(what: &str, e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", what, e))
+}
+
+/// The part of an object being written, in memory or in a spill file.
+enum PartBuffer {
+    Memory(Vec<u8>),
+    Spill { path: PathBuf, file: File, len: usize },
+}
+
+impl PartBuffer {
+    fn len(&self) -> usize {
+        match self {
+            PartBuffer::Memory(buffer) => buffer.len(),
+            PartBuffer::Spill { len, .. } => *len,
+        }
+    }
+
+    /// The buffered bytes as the body of a request, read again for every
+    /// attempt.
+    fn body(&self) -> io::Result<(Body, String)> {
+        match self {
+            PartBuffer::Memory(buffer) => Ok((Body::from(buffer.clone()), sha256_hex(buffer))),
+            PartBuffer::Spill { path, len, .. } => {
+                let file = File::open(path)?.take(*len as u64);
+                Ok((Body::sized(file, *len as u64), "UNSIGNED-PAYLOAD".to_owned()))
+            }
+        }
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        match self {
+            PartBuffer::Memory(buffer) => buffer.clear(),
+            PartBuffer::Spill { file, len, .. } => {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                *len = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writer of an object, see [ObjectStore::create]. Every time a part is
+/// full it is uploaded as a part of a multipart upload, and the object is
+/// assembled by [ObjectWriter::finish]. Objects smaller than a part are
+/// uploaded at once. An unfinished upload is aborted when the writer is
+/// dropped.
+pub struct ObjectWriter {
+    store: Arc<ObjectStore>,
+    url: ObjectUrl,
+    upload_id: Option<String>,
+    etags: Vec<String>,
+    buffer: PartBuffer,
+    hasher: Sha256,
+    finished: bool,
+}
+
+impl ObjectWriter {
+    /// URL of the object written.
+    pub fn url(&self) -> &ObjectUrl {
+        &self.url
+    }
+
+    /// Upload the buffered part, starting the multipart upload if needed.
+    fn upload_part(&mut self) -> io::Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let empty = || Ok((Body::from(Vec::new()), sha256_hex(b"")));
+                let query = [("uploads", String::new())];
+                let reply = self.store.send(Method::POST, &self.url, &query, &empty)?;
+                let pattern = Regex::new(r"<UploadId>([^<]+)</UploadId>").unwrap();
+                let upload_id = match pattern.captures(&reply.body) {
+                    Some(captures) => captures[1].to_owned(),
+                    None => return Err(io::Error::other(format!("{}: no upload id", self.url))),
+                };
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+        let query = [
+            ("partNumber", (self.etags.len() + 1).to_string()),
+            ("uploadId", upload_id),
+        ];
+        let buffer = &self.buffer;
+        let etag = match self.store.send(Method::PUT, &self.url, &query, &|| buffer.body()) {
+            Ok(reply) => reply.etag,
+            // the part may have been received although the response was lost
+            Err(e) => match self.uploaded_part(&query[1].1, self.etags.len() + 1) {
+                Some(etag) => Some(etag),
+                None => return Err(e),
+            },
+        };
+        let etag = etag.ok_or_else(|| io::Error::other(format!("{}: no ETag for part", self.url)))?;
+        self.etags.push(etag);
+        self.buffer.clear()
+    }
+
+    /// ETag of a part the store has received in full, found by listing the
+    /// parts of the upload.
+    fn uploaded_part(&self, upload_id: &str, number: usize) -> Option<String> {
+        let query = [
+            ("uploadId", upload_id.to_owned()),
+            ("part-number-marker", (number - 1).to_string()),
+            ("max-parts", "1".to_owned()),
+        ];
+        let empty = || Ok((Body::from(Vec::new()), sha256_hex(b"")));
+        let reply = self.store.send(Method::GET, &self.url, &query, &empty).ok()?;
+        let part = Regex::new(r"(?s)<Part>(.*?)</Part>").unwrap();
+        let field = |part: &str, name: &str| {
+            let pattern = Regex::new(&format!("<{0}>([^<]*)</{0}>", name)).unwrap();
+            pattern.captures(part).map(|captures| captures[1].to_owned())
+        };
+        let found = part
+            .captures_iter(&reply.body)
+            .map(|captures| captures[1].to_owned())
+            .find(|part| {
+                field(part, "PartNumber") == Some(number.to_string())
+                    && field(part, "Size") == Some(self.buffer.len().to_string())
+            })?;
+        field(&found, "ETag").map(|etag| etag.replace("&quot;", "\""))
+    }
+
+    /// Whether the object was assembled from this upload although the
+    /// response to the request completing it was lost: S3 then has the
+    /// object with the ETag of a multipart object of as many parts.
+    fn completed(&self) -> bool {
+        let empty = || Ok((Body::from(Vec::new()), sha256_hex(b"")));
+        match self.store.send(Method::HEAD, &self.url, &[], &empty) {
+            Ok(reply) if self.url.gcs => reply.etag.is_some(),
+            Ok(reply) => {
+                let suffix = format!("-{}", self.etags.len());
+                reply.etag.is_some_and(|etag| etag.trim_matches('"').ends_with(&suffix))
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Upload the rest of the object and assemble it, then its checksum
+    /// sidecar if enabled. The writer cannot be used afterwards.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.assemble()?;
+        self.finished = true;
+        let digest: String = self
+            .hasher
+            .clone()
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if self.store.checksums {
+            let name = self.url.key.rsplit('/').next().unwrap_or_default();
+            let line = checksum_line(&digest, name);
+            let sidecar = ObjectUrl {
+                key: format!("{}.{}", self.url.key, CHECKSUM_EXTENSION),
+                ..self.url.clone()
+            };
+            let body = || Ok((Body::from(line.clone()), sha256_hex(line.as_bytes())));
+            self.store.send(Method::PUT, &sidecar, &[], &body)?;
+        }
+        self.store.written.lock().unwrap().push((self.url.clone(), digest));
+        Ok(())
+    }
+
+    /// Upload the rest of the object and assemble it.
+    fn assemble(&mut self) -> io::Result<()> {
+        let upload_id = match &self.upload_id {
+            None => {
+                let buffer = &self.buffer;
+                self.store.send(Method::PUT, &self.url, &[], &|| buffer.body())?;
+                return Ok(());
+            }
+            Some(upload_id) => upload_id.clone(),
+        };
+        if self.buffer.len() > 0 {
+            self.upload_part()?;
+        }
+        let parts: String = self
+            .etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag)
+            })
+            .collect();
+        let complete = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+        let body = || Ok((Body::from(complete.clone()), sha256_hex(complete.as_bytes())));
+        let completed = self.store.send(Method::POST, &self.url, &[("uploadId", upload_id)], &body);
+        if let Err(e) = completed {
+            if !self.completed() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other(format!("{}: already finished", self.url)));
+        }
+        self.hasher.update(data);
+        match &mut self.buffer {
+            PartBuffer::Memory(buffer) => buffer.extend_from_slice(data),
+            PartBuffer::Spill { file, len, .. } => {
+                file.write_all(data)?;
+                *len += data.len();
+            }
+        }
+        if self.buffer.len() >= self.store.part_size {
+            self.upload_part()?;
+        }
+        Ok(data.len())
+    }
+
+    /// Parts are only uploaded once full, so flushing does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        if let (false, Some(upload_id)) = (self.finished, &self.upload_id) {
+            let empty = || Ok((Body::from(Vec::new()), sha256_hex(b"")));
+            let query = [("uploadId", upload_id.clone())];
+            let _ = self.store.send(Method::DELETE, &self.url, &query, &empty);
+        }
+        if let PartBuffer::Spill { path, .. } = &self.buffer {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Writer shared between the writers of a sink, e.g. a gzip encoder, and
+/// the [UploadSink] finishing the object.
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<ObjectWriter>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Sink writing to an object through another sink, e.g. a
+/// [crate::CsvSink], and finishing the object once that sink is flushed
+/// and dropped, so that encoders have written their trailers.
+pub(crate) struct UploadSink {
+    sink: Option<Box<dyn MappingSink>>,
+    writer: Arc<Mutex<ObjectWriter>>,
+}
+
+impl UploadSink {
+    /// Create a sink writing to `url` through the sink `make` creates over
+    /// the object writer.
+    pub(crate) fn create(
+        store: &Arc<ObjectStore>,
+        url: &ObjectUrl,
+        make: impl FnOnce(Box<dyn Write + Send>) -> io::Result<Box<dyn MappingSink>>,
+    ) -> io::Result<UploadSink> {
+        let writer = Arc::new(Mutex::new(store.create(url)?));
+        let sink = make(Box::new(SharedWriter(writer.clone())))?;
+        Ok(UploadSink {
+            sink: Some(sink),
+            writer,
+        })
+    }
+}
+
+/// Creates the sink writing to an object.
+pub(crate) type MakeSink = Box<dyn Fn(&ObjectUrl) -> io::Result<Box<dyn MappingSink>>>;
+
+/// Sink splitting the output into objects of at most a number of mappings,
+/// see [crate::CrawlOptions::upload_chunk_mappings]. Each chunk is finished
+/// as soon as it is full, so that a failure late in a crawl only loses the
+/// chunk being written.
+pub(crate) struct ChunkedSink {
+    url: ObjectUrl,
+    chunk_mappings: u64,
+    make: MakeSink,
+    chunks: usize,
+    current: Option<(Box<dyn MappingSink>, u64)>,
+}
+
+impl ChunkedSink {
+    /// Create a sink writing chunks of `url` with the sinks `make` creates.
+    pub(crate) fn new(
+        url: ObjectUrl,
+        chunk_mappings: u64,
+        make: MakeSink,
+    ) -> ChunkedSink {
+        ChunkedSink {
+            url,
+            chunk_mappings: chunk_mappings.max(1),
+            make,
+            chunks: 0,
+            current: None,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let sink = (self.make)(&self.url.chunk(self.chunks))?;
+        self.chunks += 1;
+        self.current = Some((sink, 0));
+        Ok(())
+    }
+}
+
+impl MappingSink for ChunkedSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        if self.current.is_none() {
+            self.next_chunk()?;
+        }
+        let (sink, written) = self.current.as_mut().unwrap();
+        sink.write(entry)?;
+        *written += 1;
+        if *written >= self.chunk_mappings {
+            let (mut sink, _) = self.current.take().unwrap();
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the last chunk, written even if empty when no mapping was.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunks == 0 {
+            self.next_chunk()?;
+        }
+        match self.current.take() {
+            Some((mut sink, _)) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl MappingSink for UploadSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        match &mut self.sink {
+            Some(sink) => sink.write(entry),
+            None => Err(io::Error::other("upload already finished")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(mut sink) = self.sink.take() {
+            sink.flush()?;
+            drop(sink);
+            self.writer.lock().unwrap().finish()?;
+        }
+        Ok(())
+    }
+}
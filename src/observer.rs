@@ -0,0 +1,124 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Hooks for library users to follow the progress of a crawl.
+use crate::{CrawlCounters, CrawlError, CrawlStats, MappingEntry};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often [ProgressObserver::on_stats] is called during a crawl.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Callbacks invoked while crawling, for embedders that render their own
+/// progress instead of the built-in progress bar (see
+/// [crate::ProgressMode::Hidden]). Set it in [crate::CrawlOptions::observer].
+///
+/// The callbacks are invoked from the crawling threads, so they must be
+/// cheap and thread safe. All of them default to doing nothing.
+pub trait ProgressObserver: Send + Sync {
+    /// A host pointer has been processed.
+    fn on_pointer_done(&self, _host: &str) {}
+
+    /// A mapping has been found, before it is written to the output.
+    fn on_mapping(&self, _mapping: &MappingEntry) {}
+
+    /// A host pointer could not be processed.
+    fn on_error(&self, _error: &CrawlError) {}
+
+    /// Statistics of the crawl so far, called about once per second and
+    /// once with the final statistics when the crawl ends.
+    fn on_stats(&self, _stats: &CrawlStats) {}
+
+    /// The [crate::CrawlOptions::checkpoint] was saved mid-crawl with this
+    /// many completed pointers, or could not be.
+    fn on_checkpoint(&self, _saved: &io::Result<usize>) {}
+}
+
+impl fmt::Debug for dyn ProgressObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressObserver")
+    }
+}
+
+/// Forwards the outcome of each host pointer to an optional observer.
+pub(crate) struct ObserverHook<'a> {
+    observer: Option<&'a dyn ProgressObserver>,
+    last_stats: Mutex<Instant>,
+}
+
+impl<'a> ObserverHook<'a> {
+    pub(crate) fn new(observer: Option<&'a dyn ProgressObserver>) -> ObserverHook<'a> {
+        ObserverHook {
+            observer,
+            last_stats: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Report the result of a host pointer, and the statistics if they are
+    /// due.
+    pub(crate) fn pointer_done(
+        &self,
+        host: &str,
+        result: &Result<Vec<Option<MappingEntry>>, CrawlError>,
+        counters: &CrawlCounters,
+    ) {
+        let observer = match self.observer {
+            Some(observer) => observer,
+            None => return,
+        };
+        match result {
+            Ok(mappings) => mappings.iter().flatten().for_each(|m| observer.on_mapping(m)),
+            Err(e) => observer.on_error(e),
+        }
+        observer.on_pointer_done(host);
+
+        // only one worker reports the statistics, the others move on
+        if let Ok(mut last_stats) = self.last_stats.try_lock() {
+            if last_stats.elapsed() >= STATS_INTERVAL {
+                *last_stats = Instant::now();
+                observer.on_stats(&counters.to_stats());
+            }
+        }
+    }
+
+    /// Report the final statistics.
+    pub(crate) fn finish(&self, stats: &CrawlStats) {
+        if let Some(observer) = self.observer {
+            observer.on_stats(stats);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Traces and metrics of a crawl through the OpenTelemetry API, with a span
+//! per host pointer and a child span per fetch, exported over OTLP once
+//! [install_otlp_exporter] has installed the global providers.
+use crate::metrics::endpoint_label;
+use crate::{CrawlError, Endpoint, FailureKind, IndexHostPointer};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::trace::{Span, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::io;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Name of the instrumentation scope and of the exported service.
+const SCOPE: &str = "cc-host-mapper";
+
+/// Bucket boundaries of the request latency histogram in seconds.
+const LATENCY_BOUNDARIES: [f64; 12] = [
+    0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0,
+];
+
+/// The global tracer and meter providers exporting over OTLP, which must
+/// be shut down at the end of the crawl to export the last spans and
+/// metrics.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+/// Export the traces and metrics of the crawls to an OTLP collector over
+/// HTTP, e.g. `http://localhost:4318`, in batches sent by background
+/// threads. Install it before crawling: the instruments are created on
+/// first use.
+pub fn install_otlp_exporter(endpoint: &str) -> io::Result<OtlpExporter> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder().with_service_name(SCOPE).build();
+    let spans = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint))
+        .build()
+        .map_err(io::Error::other)?;
+    let metrics = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/metrics", endpoint))
+        .build()
+        .map_err(io::Error::other)?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(spans)
+        .with_resource(resource.clone())
+        .build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_periodic_exporter(metrics)
+        .with_resource(resource)
+        .build();
+    global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+    Ok(OtlpExporter {
+        tracer_provider,
+        meter_provider,
+    })
+}
+
+impl OtlpExporter {
+    /// Export the remaining spans and metrics and stop the exporters.
+    pub fn shutdown(self) -> io::Result<()> {
+        let traces = self.tracer_provider.shutdown().map_err(io::Error::other);
+        let metrics = self.meter_provider.shutdown().map_err(io::Error::other);
+        traces.and(metrics)
+    }
+}
+
+struct Instruments {
+    pointers: Counter<u64>,
+    responses: Counter<u64>,
+    failures: Counter<u64>,
+    latency: Histogram<f64>,
+}
+
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(SCOPE);
+        Instruments {
+            pointers: meter
+                .u64_counter("cc_host_mapper.pointers")
+                .with_description("Host pointers processed, by outcome")
+                .build(),
+            responses: meter
+                .u64_counter("cc_host_mapper.responses")
+                .with_description("Responses by endpoint and HTTP status")
+                .build(),
+            failures: meter
+                .u64_counter("cc_host_mapper.request_failures")
+                .with_description("Failed requests and unusable responses by endpoint and kind")
+                .build(),
+            latency: meter
+                .f64_histogram("cc_host_mapper.request.duration")
+                .with_description("Latency of the requests that got a response, by endpoint")
+                .with_unit("s")
+                .with_boundaries(LATENCY_BOUNDARIES.to_vec())
+                .build(),
+        }
+    })
+}
+
+/// Context holding a new span for a host pointer, a child of the current
+/// span if any.
+pub(crate) fn pointer_context(pointer: &IndexHostPointer) -> Context {
+    let mut span = global::tracer(SCOPE).start("pointer");
+    span.set_attribute(KeyValue::new("host", pointer.host.clone()));
+    span.set_attribute(KeyValue::new("cdx.file", pointer.index_file_name.clone()));
+    span.set_attribute(KeyValue::new("cdx.offset", pointer.range_start));
+    span.set_attribute(KeyValue::new("cdx.length", pointer.range_length));
+    Context::current_with_span(span)
+}
+
+/// Context holding a new span for a fetch and its retries, a child of the
+/// current pointer span if any.
+pub(crate) fn fetch_context(id: u64, url: &str, range: Option<(i64, i64)>) -> Context {
+    let mut span = global::tracer(SCOPE).start("fetch");
+    span.set_attribute(KeyValue::new("fetch.id", id as i64));
+    span.set_attribute(KeyValue::new("url.full", url.to_owned()));
+    span.set_attribute(KeyValue::new("endpoint", endpoint_label(Endpoint::of(url))));
+    if let Some((start, end)) = range {
+        let range = format!("bytes={}-{}", start, end);
+        span.set_attribute(KeyValue::new("http.request.header.range", range));
+    }
+    Context::current_with_span(span)
+}
+
+/// End the span of a pointer or fetch, marked as failed with the error.
+pub(crate) fn finish<T>(cx: &Context, result: &Result<T, CrawlError>) {
+    let span = cx.span();
+    if let Err(e) = result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+}
+
+/// End the span of a pointer and count it.
+pub(crate) fn finish_pointer<T>(cx: &Context, result: &Result<T, CrawlError>) {
+    let outcome = if result.is_ok() { "ok" } else { "failed" };
+    instruments().pointers.add(1, &[KeyValue::new("outcome", outcome)]);
+    finish(cx, result);
+}
+
+/// Record a retry of the current fetch, after the error of the attempt.
+pub(crate) fn record_retry(retry: u32, error: &CrawlError) {
+    Context::current().span().add_event(
+        "retry",
+        vec![
+            KeyValue::new("retry", retry as i64),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+/// Count a response, and set its status on the current fetch span.
+pub(crate) fn record_response(endpoint: Endpoint, status: u16) {
+    let attributes = [
+        KeyValue::new("endpoint", endpoint_label(endpoint)),
+        KeyValue::new("status", status as i64),
+    ];
+    instruments().responses.add(1, &attributes);
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("http.response.status_code", status as i64));
+}
+
+/// Count a failed request or unusable response.
+pub(crate) fn record_failure(endpoint: Endpoint, kind: FailureKind) {
+    let kind = match kind {
+        FailureKind::Timeout => "timeout",
+        FailureKind::Connect => "connect",
+        FailureKind::Truncated => "truncated",
+        FailureKind::Gzip => "gzip",
+        FailureKind::Parse => "parse",
+    };
+    let attributes = [
+        KeyValue::new("endpoint", endpoint_label(endpoint)),
+        KeyValue::new("kind", kind),
+    ];
+    instruments().failures.add(1, &attributes);
+}
+
+/// Record the latency of a request that got a response.
+pub(crate) fn record_latency(endpoint: Endpoint, latency: Duration) {
+    let attributes = [KeyValue::new("endpoint", endpoint_label(endpoint))];
+    instruments().latency.record(latency.as_secs_f64(), &attributes);
+}
@@ -0,0 +1,383 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Parsers for the lines of cluster.idx and cdx files and for WARC headers.
+//! They never panic: malformed lines are reported as a [ParseError], so that
+//! the crawl can skip and count them.
+use crate::{IndexHostPointer, IndexRecord, ParseError};
+use chrono::NaiveDate;
+use flate2::read::MultiGzDecoder;
+use std::io::Read;
+use std::net::IpAddr;
+
+/// A parsed line of a cdx file: `SURT TIMESTAMP JSON`.
+#[derive(Debug, Clone)]
+pub struct CdxLine {
+    /// Hostname of the capture, without port
+    pub host: String,
+    /// Port given in the SURT, `None` for the default port of the scheme
+    pub surt_port: Option<u16>,
+    /// Capture time as `YYYYMMDDhhmmss`
+    pub timestamp: String,
+    /// The JSON part of the line
+    pub record: IndexRecord,
+}
+
+impl CdxLine {
+    /// Port the capture was fetched from: the one in the SURT, or else the
+    /// default port of the URL scheme.
+    pub fn port(&self) -> Option<u16> {
+        self.surt_port.or_else(|| {
+            let scheme = self.record.url.split_once("://")?.0;
+            match scheme.to_ascii_lowercase().as_str() {
+                "http" => Some(80),
+                "https" => Some(443),
+                _ => None,
+            }
+        })
+    }
+
+    /// Day of the capture as `YYYY-MM-DD`.
+    pub fn day(&self) -> String {
+        // checked when parsing
+        format!(
+            "{}-{}-{}",
+            &self.timestamp[0..4],
+            &self.timestamp[4..6],
+            &self.timestamp[6..8]
+        )
+    }
+}
+
+/// Hostname of a SURT URL key, e.g. `example.com` for
+/// `com,example:8080)/index.html`.
+pub fn parse_surt_host(surt: &str) -> Result<String, ParseError> {
+    parse_surt_authority(surt).map(|(host, _)| host)
+}
+
+/// Hostname and explicit port of a SURT URL key, e.g. `example.com` and
+/// `Some(8080)` for `com,example:8080)/index.html`. The port is `None` if
+/// the URL uses the default port of its scheme.
+pub fn parse_surt_authority(surt: &str) -> Result<(String, Option<u16>), ParseError> {
+    let (authority, _) = surt
+        .split_once(')')
+        .ok_or_else(|| ParseError::new(format!("no ')' in SURT {:?}", surt)))?;
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| ParseError::new(format!("invalid port in SURT {:?}", surt)))?;
+            (host, Some(port))
+        }
+        None => (authority, None),
+    };
+    if host.is_empty() {
+        return Err(ParseError::new(format!("empty host in SURT {:?}", surt)));
+    }
+    let mut labels: Vec<&str> = host.split(',').collect();
+    labels.reverse();
+    Ok((labels.join("."), port))
+}
+
+/// Check that a capture timestamp starts with a valid `YYYYMMDD` date.
+fn check_timestamp(timestamp: &str) -> Result<(), ParseError> {
+    let invalid = || ParseError::new(format!("invalid timestamp {:?}", timestamp));
+    if timestamp.len() < 8 || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let year = timestamp[0..4].parse().map_err(|_| invalid())?;
+    let month = timestamp[4..6].parse().map_err(|_| invalid())?;
+    let day = timestamp[6..8].parse().map_err(|_| invalid())?;
+    NaiveDate::from_ymd_opt(year, month, day).ok_or_else(invalid)?;
+    Ok(())
+}
+
+/// Whether the host of a SURT is an IP address rather than a host name,
+/// going by its last label.
+pub(crate) fn is_ip_host(host: &str) -> bool {
+    let last_label = host.rsplit('.').next().unwrap_or_default();
+    last_label.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Parse one line of a cluster.idx file into an [IndexHostPointer] whose
+/// index file URL points to `base_url`. Returns `Ok(None)` for pointers to
+/// IP addresses rather than hostnames.
+///
+/// Example line:
+/// `0,102,126,13:7037)/robots.txt 20201126201142\tcdx-00000.gz\t0\t205505\t1`
+pub fn parse_idx_line(
+    base_url: &str,
+    index_id: &str,
+    line: &str,
+) -> Result<Option<IndexHostPointer>, ParseError> {
+    let parts: Vec<&str> = line.trim_end().split('\t').collect();
+    if parts.len() != 5 {
+        return Err(ParseError::new(format!(
+            "expected 5 tab-separated fields, found {}",
+            parts.len()
+        )));
+    }
+    let (surt, timestamp) = parts[0]
+        .split_once(' ')
+        .ok_or_else(|| ParseError::new("no timestamp after the SURT"))?;
+    let timestamp = timestamp
+        .parse::<i64>()
+        .map_err(|_| ParseError::new(format!("invalid timestamp {:?}", timestamp)))?;
+    let host = parse_surt_host(surt)?;
+    if is_ip_host(&host) {
+        return Ok(None);
+    }
+    let number = |field: &str| {
+        field
+            .parse::<i64>()
+            .ok()
+            .filter(|n| *n >= 0)
+            .ok_or_else(|| ParseError::new(format!("invalid offset or length {:?}", field)))
+    };
+    Ok(Some(IndexHostPointer {
+        host,
+        timestamp,
+        index_file_name: format!(
+            "{}/cc-index/collections/{}/indexes/{}",
+            base_url, index_id, parts[1]
+        ),
+        range_start: number(parts[2])?,
+        range_length: number(parts[3])?,
+    }))
+}
+
+/// Parse one line of a cdx file.
+///
+/// Example line:
+/// `com,example)/ 20201126201142 {"url": "http://example.com/", ...}`
+pub fn parse_cdx_line(line: &str) -> Result<CdxLine, ParseError> {
+    let (surt, rest) = line
+        .split_once(' ')
+        .ok_or_else(|| ParseError::new("no timestamp after the SURT"))?;
+    let (timestamp, json) = rest
+        .split_once(' ')
+        .ok_or_else(|| ParseError::new("no JSON after the timestamp"))?;
+    check_timestamp(timestamp)?;
+    let record = serde_json::from_str::<IndexRecord>(json)
+        .map_err(|e| ParseError::new(format!("invalid JSON: {}", e)))?;
+    let (host, surt_port) = parse_surt_authority(surt)?;
+    Ok(CdxLine {
+        host,
+        surt_port,
+        timestamp: timestamp.to_owned(),
+        record,
+    })
+}
+
+/// The header block of a WARC record.
+#[derive(Debug, Clone, Default)]
+pub struct WarcHeaders {
+    /// Version from the first line, e.g. `WARC/1.0`
+    pub version: String,
+    /// Named fields in the order of the record, with folded values joined
+    pub fields: Vec<(String, String)>,
+    /// Whether the empty line ending the header block was reached; if not,
+    /// the data was cut off and later fields may be missing
+    pub complete: bool,
+}
+
+impl WarcHeaders {
+    /// Value of the first field with the given name, compared
+    /// case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        find_field(&self.fields, name)
+    }
+
+    /// The `WARC-Type` field, e.g. `response`.
+    pub fn record_type(&self) -> Option<&str> {
+        self.get("WARC-Type")
+    }
+
+    /// The `WARC-IP-Address` field, if present and a valid address.
+    pub fn ip_address(&self) -> Option<IpAddr> {
+        self.get("WARC-IP-Address")?.parse().ok()
+    }
+}
+
+/// Decompress as much of the gzip members in `bytes` as possible. Ranges cut
+/// off inside a member yield the data up to the cut. Data that is not
+/// gzip-compressed is returned as is.
+fn decompress_prefix(bytes: &[u8]) -> Vec<u8> {
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        return bytes.to_vec();
+    }
+    let mut data = vec![];
+    // on error, the data read before it is kept
+    let _ = MultiGzDecoder::new(bytes).read_to_end(&mut data);
+    data
+}
+
+/// A parsed header block, see [parse_header_block].
+struct HeaderBlock {
+    first_line: String,
+    fields: Vec<(String, String)>,
+    complete: bool,
+    /// Bytes taken up by the block, including the empty line ending it
+    len: usize,
+}
+
+/// Parse a header block: a first line, then `Name: value` fields up to an
+/// empty line. Lines starting with a space or tab continue the value of the
+/// previous field. Data cut off inside the block gives the fields read so
+/// far, with `complete` unset.
+fn parse_header_block(data: &[u8], first_line_prefix: &str) -> Result<HeaderBlock, ParseError> {
+    let mut lines = data.split(|b| *b == b'\n').peekable();
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut consumed = 0;
+
+    let first = lines.next().unwrap_or_default();
+    consumed += first.len() + 1;
+    let at_end = lines.peek().is_none();
+    let first = String::from_utf8_lossy(first);
+    let first = first.trim_end_matches('\r');
+    if !at_end && !first.starts_with(first_line_prefix) {
+        return Err(ParseError::new(format!("expected {}: {:?}", first_line_prefix, first)));
+    }
+    let first = first.to_owned();
+
+    while let Some(line) = lines.next() {
+        // the last piece has no newline and may be cut off
+        if lines.peek().is_none() {
+            break;
+        }
+        consumed += line.len() + 1;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            return Ok(HeaderBlock {
+                first_line: first,
+                fields,
+                complete: true,
+                len: consumed,
+            });
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            match fields.last_mut() {
+                Some((_, value)) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                None => return Err(ParseError::new(format!("continuation without field: {:?}", line))),
+            }
+            continue;
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() => {
+                fields.push((name.trim().to_owned(), value.trim().to_owned()))
+            }
+            _ => return Err(ParseError::new(format!("malformed header: {:?}", line))),
+        }
+    }
+    Ok(HeaderBlock {
+        first_line: first,
+        fields,
+        complete: false,
+        len: data.len(),
+    })
+}
+
+/// Value of the first field with the given name, compared case-insensitively.
+fn find_field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Parse the header block at the (gzip-compressed) beginning of a WARC
+/// record: the version line, then `Name: value` fields up to an empty line.
+/// Lines starting with a space or tab continue the value of the previous
+/// field. Data cut off inside the block gives the fields read so far, with
+/// [WarcHeaders::complete] unset.
+pub fn parse_warc_headers(bytes: &[u8]) -> Result<WarcHeaders, ParseError> {
+    parse_warc_response(bytes).map(|(headers, _)| headers)
+}
+
+/// The status line and header of the HTTP response stored in a WARC
+/// response record.
+#[derive(Debug, Clone, Default)]
+pub struct HttpHeaders {
+    /// First line, e.g. `HTTP/1.1 200 OK`
+    pub status_line: String,
+    /// Named fields in the order of the response, with folded values joined
+    pub fields: Vec<(String, String)>,
+    /// Whether the empty line ending the header was reached
+    pub complete: bool,
+}
+
+impl HttpHeaders {
+    /// Value of the first field with the given name, compared
+    /// case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        find_field(&self.fields, name)
+    }
+}
+
+/// Parse the WARC header block at the (gzip-compressed) beginning of a WARC
+/// record and, for response records holding an HTTP response, the HTTP
+/// header following it, as far as the data reaches.
+pub fn parse_warc_response(bytes: &[u8]) -> Result<(WarcHeaders, Option<HttpHeaders>), ParseError> {
+    let data = decompress_prefix(bytes);
+    let warc = parse_header_block(&data, "WARC/")?;
+    let block = &data[warc.len.min(data.len())..];
+    let headers = WarcHeaders {
+        version: warc.first_line,
+        fields: warc.fields,
+        complete: warc.complete,
+    };
+    if !headers.complete || headers.record_type() != Some("response") || !block.starts_with(b"HTTP/") {
+        return Ok((headers, None));
+    }
+    // a broken HTTP header leaves the WARC headers usable
+    let http = parse_header_block(block, "HTTP/")
+        .ok()
+        .map(|http| HttpHeaders {
+            status_line: http.first_line,
+            fields: http.fields,
+            complete: http.complete,
+        });
+    Ok((headers, http))
+}
+
+/// Extract the `WARC-IP-Address` header from the (gzip-compressed) beginning
+/// of a WARC record.
+pub fn parse_warc_ip(bytes: &[u8]) -> Option<IpAddr> {
+    parse_warc_headers(bytes).ok()?.ip_address()
+}
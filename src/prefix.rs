@@ -0,0 +1,168 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Aggregation of IPs into the prefixes that contain them.
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// An IP prefix in CIDR notation, e.g. `192.0.2.0/24`. The host bits of
+/// `network` are always zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct IpPrefix {
+    pub network: IpAddr,
+    pub length: u8,
+}
+
+impl IpPrefix {
+    /// The prefix of the given length containing an IP. Lengths beyond the
+    /// address size are capped to it.
+    pub fn of(ip: IpAddr, length: u8) -> IpPrefix {
+        match ip {
+            IpAddr::V4(v4) => {
+                let length = length.min(32);
+                let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+                IpPrefix {
+                    network: IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask)),
+                    length,
+                }
+            }
+            IpAddr::V6(v6) => {
+                let length = length.min(128);
+                let mask = u128::MAX.checked_shl(128 - length as u32).unwrap_or(0);
+                IpPrefix {
+                    network: IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask)),
+                    length,
+                }
+            }
+        }
+    }
+
+    /// Check whether the prefix contains an IP.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.network.is_ipv4() && IpPrefix::of(ip, self.length) == *self
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.length)
+    }
+}
+
+impl std::str::FromStr for IpPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, length) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected a prefix such as 192.0.2.0/24, found {:?}", s))?;
+        let network: IpAddr = network.parse().map_err(|e| format!("{}: {}", s, e))?;
+        let length: u8 = length.parse().map_err(|e| format!("{}: {}", s, e))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        if length > max {
+            return Err(format!("{}: prefix length above {}", s, max));
+        }
+        Ok(IpPrefix::of(network, length))
+    }
+}
+
+impl From<IpPrefix> for String {
+    fn from(prefix: IpPrefix) -> String {
+        prefix.to_string()
+    }
+}
+
+impl TryFrom<String> for IpPrefix {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Prefix lengths IPs are aggregated to, see [CrawlOptions::prefixes].
+///
+/// [CrawlOptions::prefixes]: crate::CrawlOptions::prefixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixLengths {
+    /// Length of IPv4 prefixes, 24 by default
+    pub v4: u8,
+    /// Length of IPv6 prefixes, 48 by default
+    pub v6: u8,
+}
+
+impl Default for PrefixLengths {
+    fn default() -> Self {
+        PrefixLengths { v4: 24, v6: 48 }
+    }
+}
+
+impl PrefixLengths {
+    /// The prefix containing an IP.
+    pub fn prefix_of(&self, ip: IpAddr) -> IpPrefix {
+        match ip {
+            IpAddr::V4(_) => IpPrefix::of(ip, self.v4),
+            IpAddr::V6(_) => IpPrefix::of(ip, self.v6),
+        }
+    }
+}
+
+/// Whether the output has the prefixes of the IPs, see [PrefixLengths].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrefixOutput {
+    /// Only the IPs
+    #[default]
+    Off,
+    /// The prefix in an extra `PREFIX` column
+    Alongside,
+    /// The prefix in the `IP` column, in place of the IP
+    Instead,
+}
+
+impl std::str::FromStr for PrefixOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(PrefixOutput::Off),
+            "alongside" => Ok(PrefixOutput::Alongside),
+            "instead" => Ok(PrefixOutput::Instead),
+            _ => Err(format!("expected off, alongside or instead, found {:?}", s)),
+        }
+    }
+}
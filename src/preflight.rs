@@ -0,0 +1,272 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Checks run before a crawl, so that problems show up in seconds rather
+//! than hours in: an output that cannot be written, a disk too small for
+//! the output, and an index that cannot be fetched.
+//!
+//! The output size is estimated without downloading cluster.idx: a single
+//! byte of it is requested, which also checks connectivity, and the number
+//! of pointers is derived from its total size.
+use crate::estimate::WARC_FETCHES_PER_POINTER;
+use crate::{
+    cluster_idx_url, is_object_url, CrawlError, CrawlOptions, FetchError, Fetcher, OutputFormat,
+    ReqwestFetcher,
+};
+use reqwest::header::CONTENT_RANGE;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Assumed average size of a cluster.idx line, i.e. of one host pointer.
+pub const IDX_BYTES_PER_POINTER: u64 = 64;
+/// Assumed average size of an uncompressed CSV mapping row.
+pub const OUTPUT_BYTES_PER_MAPPING: u64 = 48;
+/// Assumed compression ratio of compressed and columnar output.
+const COMPRESSION_RATIO: u64 = 4;
+
+/// Outcome of a successful [preflight].
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    /// Estimated host pointers to crawl
+    pub pointers: u64,
+    /// Estimated size of the output in bytes
+    pub output_bytes: u64,
+    /// Free space on the output's file system, `None` for object store
+    /// output
+    pub available_bytes: Option<u64>,
+}
+
+/// Problem found by [preflight].
+#[derive(Debug)]
+pub enum PreflightError {
+    /// The output cannot be created in its directory, or an existing
+    /// output cannot be written
+    NotWritable { path: PathBuf, cause: io::Error },
+    /// The file system of the output has less free space than the output
+    /// is estimated to take
+    DiskSpace {
+        path: PathBuf,
+        available: u64,
+        needed: u64,
+    },
+    /// The sample request to the index failed
+    Index(CrawlError),
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightError::NotWritable { path, cause } => write!(
+                f,
+                "cannot write the output to {}: {}; check the path and its permissions",
+                path.display(),
+                cause
+            ),
+            PreflightError::DiskSpace {
+                path,
+                available,
+                needed,
+            } => write!(
+                f,
+                "{} has {}MB free but the output is estimated at {}MB; free up space, \
+                 write compressed output, or crawl a slice with --idx-count",
+                path.display(),
+                available / 1_000_000,
+                needed / 1_000_000
+            ),
+            PreflightError::Index(e) => {
+                write!(f, "{}", e)?;
+                match e {
+                    CrawlError::Status { status: 404, .. } => {
+                        write!(f, "; check the index id and --base-url")
+                    }
+                    CrawlError::Status {
+                        status: 403 | 429 | 503,
+                        ..
+                    } => write!(f, "; Common Crawl is throttling requests, retry later"),
+                    CrawlError::Request { .. } => {
+                        write!(f, "; check the network connection and proxy settings")
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Error for PreflightError {}
+
+/// Check that the output `output` of crawling `index_id` can be written,
+/// that its file system has room for the estimated output, and that the
+/// cluster.idx of the index can be fetched, with the fetcher and
+/// connection settings of the [CrawlOptions].
+pub fn preflight(
+    index_id: &str,
+    output: &str,
+    options: &CrawlOptions,
+) -> Result<PreflightReport, PreflightError> {
+    let idx_bytes = cluster_idx_size(index_id, options).map_err(PreflightError::Index)?;
+    let pointers = estimate_pointers(idx_bytes, options);
+    let mappings = (pointers as f64 * WARC_FETCHES_PER_POINTER).round() as u64;
+    let mut output_bytes = mappings * OUTPUT_BYTES_PER_MAPPING;
+    if is_compressed(output, options) {
+        output_bytes /= COMPRESSION_RATIO;
+    }
+    let mut report = PreflightReport {
+        pointers,
+        output_bytes,
+        available_bytes: None,
+    };
+    if is_object_url(output) {
+        return Ok(report);
+    }
+
+    let path = Path::new(output);
+    let not_writable = |cause| PreflightError::NotWritable {
+        path: path.to_owned(),
+        cause,
+    };
+    let dir = output_dir(path, options).map_err(not_writable)?;
+    check_writable(path, &dir).map_err(not_writable)?;
+    let available = fs2::available_space(&dir)
+        .map_err(|cause| PreflightError::NotWritable { path: dir.clone(), cause })?;
+    report.available_bytes = Some(available);
+    if available < output_bytes {
+        return Err(PreflightError::DiskSpace {
+            path: dir,
+            available,
+            needed: output_bytes,
+        });
+    }
+    Ok(report)
+}
+
+/// Size of the cluster.idx of the index, from the `Content-Range` of a
+/// request for its first byte.
+fn cluster_idx_size(index_id: &str, options: &CrawlOptions) -> Result<u64, CrawlError> {
+    let fetcher: Arc<dyn Fetcher> = match &options.fetcher {
+        Some(fetcher) => fetcher.clone(),
+        None => Arc::new(ReqwestFetcher::from_options(options).map_err(|e| {
+            CrawlError::Request {
+                url: String::new(),
+                reason: e.to_string(),
+            }
+        })?),
+    };
+    let url = cluster_idx_url(index_id, options);
+    let rsp = fetcher.get_range(&url, 0, 0).map_err(|e| match e {
+        FetchError::Connect(reason) | FetchError::Timeout(reason) => CrawlError::Request {
+            url: url.clone(),
+            reason,
+        },
+        FetchError::Body(_) => CrawlError::Truncated { url: url.clone() },
+    })?;
+    if !rsp.status.is_success() {
+        return Err(CrawlError::Status {
+            url,
+            status: rsp.status.as_u16(),
+        });
+    }
+    // a server ignoring the range sends the whole file
+    Ok(rsp
+        .headers
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|total| total.parse().ok())
+        .unwrap_or(rsp.body.len() as u64))
+}
+
+/// Number of pointers left after the slice, sample and limit of the
+/// [CrawlOptions], out of the pointers of a cluster.idx of `idx_bytes`.
+fn estimate_pointers(idx_bytes: u64, options: &CrawlOptions) -> u64 {
+    let mut pointers = (idx_bytes / IDX_BYTES_PER_POINTER)
+        .saturating_sub(options.idx_offset as u64);
+    for cap in [options.idx_count, options.sample, options.limit].iter().flatten() {
+        pointers = pointers.min(*cap as u64);
+    }
+    pointers
+}
+
+/// Whether the output is compressed, or in a compressed format.
+fn is_compressed(output: &str, options: &CrawlOptions) -> bool {
+    let extension = Path::new(output).extension().and_then(|extension| extension.to_str());
+    options.output_format != OutputFormat::Csv || matches!(extension, Some("gz" | "bgz" | "zst"))
+}
+
+/// The directory the output is written to. Partitioned and kv outputs
+/// create their directories, so this is the closest existing one; other
+/// outputs are single files, whose directory must exist.
+fn output_dir(path: &Path, options: &CrawlOptions) -> io::Result<PathBuf> {
+    let creates_dirs = match options.output_format {
+        #[cfg(feature = "kv")]
+        OutputFormat::Kv => true,
+        _ => !options.partition_by.is_empty(),
+    };
+    let mut dir = path.to_owned();
+    if !creates_dirs && dir.is_dir() {
+        return Err(io::Error::other("is a directory"));
+    }
+    if !creates_dirs || !dir.is_dir() {
+        dir.pop();
+    }
+    while !dir.as_os_str().is_empty() && !dir.is_dir() {
+        if !creates_dirs || !dir.pop() {
+            let message = format!("directory {} does not exist", dir.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, message));
+        }
+    }
+    if dir.as_os_str().is_empty() {
+        dir = PathBuf::from(".");
+    }
+    Ok(dir)
+}
+
+/// Open an existing output file for writing without truncating it, or
+/// create and remove a file next to where the output will be.
+fn check_writable(path: &Path, dir: &Path) -> io::Result<()> {
+    if path.is_file() {
+        return OpenOptions::new().append(true).open(path).map(|_| ());
+    }
+    let name = path.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    let probe = dir.join(format!(".{}.preflight", name));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
@@ -0,0 +1,115 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Display of the crawl progress.
+use crate::{CancelToken, CrawlCounters};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the progress bar message is refreshed.
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How the progress of a crawl is shown to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProgressMode {
+    /// Single-line progress bar
+    #[default]
+    Bar,
+    /// No progress display, e.g. when output is redirected to a log file
+    Hidden,
+    /// Full-screen dashboard with per-worker activity
+    #[cfg(feature = "tui")]
+    Tui,
+}
+
+impl ProgressMode {
+    /// Whether the display shows the host each worker is crawling, which the
+    /// workers then record, see [crate::CrawlCounters::set_activity].
+    pub(crate) fn shows_activity(self) -> bool {
+        #[cfg(feature = "tui")]
+        return self == ProgressMode::Tui;
+        #[cfg(not(feature = "tui"))]
+        false
+    }
+}
+
+/// Spawn the thread displaying progress. The thread consumes one host name
+/// per finished pointer from `receiver` and returns once all senders are
+/// dropped.
+#[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+pub(crate) fn spawn_progress(
+    mode: ProgressMode,
+    counters: Arc<CrawlCounters>,
+    total_hosts: u64,
+    receiver: Receiver<String>,
+    output_file_name: String,
+    cancel: CancelToken,
+) -> JoinHandle<()> {
+    match mode {
+        ProgressMode::Bar => thread::spawn(move || run_bar(&counters, total_hosts, receiver)),
+        ProgressMode::Hidden => thread::spawn(move || for _ in receiver.iter() {}),
+        #[cfg(feature = "tui")]
+        ProgressMode::Tui => thread::spawn(move || {
+            crate::tui::run_dashboard(&counters, total_hosts, receiver, &output_file_name, &cancel)
+        }),
+    }
+}
+
+fn run_bar(counters: &CrawlCounters, total_hosts: u64, receiver: Receiver<String>) {
+    let sty = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {per_sec} ETA {eta} {msg}")
+        .progress_chars("##-");
+    let pb = ProgressBar::new(total_hosts);
+    pb.set_style(sty);
+    // refreshing the message on every host causes lock contention on
+    // the bar with hundreds of threads, so only do it periodically
+    let mut last_update = Instant::now();
+    for host in receiver.iter() {
+        pb.inc(1);
+        if last_update.elapsed() >= PROGRESS_UPDATE_INTERVAL {
+            pb.set_message(&format!(
+                "errors {:.1}% | retries {} | {}",
+                counters.error_rate() * 100.0,
+                counters.retries.load(Ordering::Relaxed),
+                host
+            ));
+            last_update = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,619 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Aggregate reports over finished mapping files.
+use crate::sink::{csv_field, split_csv_line};
+use crate::{get_reader, IpPrefix, MappingEntry, Pfx2As, PrefixLengths};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+
+/// Read the mappings of an output file, gzip-compressed if its name ends
+/// with `.gz`. Only the `HOST,DATE,IP` columns are read, whatever optional
+/// columns the file has; a line without them is an
+/// [io::ErrorKind::InvalidData] error. Prefixes written in place of the IPs
+/// are read as their network address, see [crate::PrefixOutput::Instead].
+pub fn read_mapping_file(
+    filename: &str,
+) -> io::Result<impl Iterator<Item = io::Result<MappingEntry>>> {
+    let lines = get_reader(filename)?.lines();
+    Ok(lines.enumerate().map(|(number, line)| {
+        let line = line?;
+        parse_mapping_columns(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: not a HOST,DATE,IP mapping: {}", number + 1, line),
+            )
+        })
+    }))
+}
+
+fn parse_mapping_columns(line: &str) -> Option<MappingEntry> {
+    let mut parts = split_csv_line(line.trim_end())?.into_iter();
+    let host = parts.next()?;
+    let timestr = parts.next()?;
+    let ip = parts.next()?;
+    let (ip, prefix) = match ip.parse() {
+        Ok(ip) => (ip, None),
+        Err(_) => {
+            let prefix: IpPrefix = ip.parse().ok()?;
+            (prefix.network, Some(prefix))
+        }
+    };
+    Some(MappingEntry {
+        host,
+        timestr,
+        ip,
+        prefix,
+        server: None,
+        protocol: None,
+        port: None,
+        url: None,
+        status: None,
+        record: None,
+        host_unicode: None,
+        asn: None,
+        rpki: None,
+        provider: None,
+        org: None,
+        country: None,
+    })
+}
+
+/// Top-level domain of a hostname, lowercased, e.g. `org` for
+/// `www.example.org.`.
+pub fn host_tld(host: &str) -> String {
+    let host = host.trim_end_matches('.');
+    host.rsplit('.').next().unwrap_or(host).to_ascii_lowercase()
+}
+
+/// Mapping counts of one top-level domain, see [tld_report].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TldStats {
+    /// The top-level domain, e.g. `com`
+    pub tld: String,
+    /// Distinct hosts under the TLD
+    pub hosts: u64,
+    /// Distinct IPs the hosts mapped to
+    pub ips: u64,
+    /// Distinct IPv4 addresses among `ips`
+    pub ipv4: u64,
+    /// Distinct IPv6 addresses among `ips`
+    pub ipv6: u64,
+    /// The origin ASes of the most hosts of the TLD, most first, with a
+    /// pfx2as table, see [tld_report_with]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_ases: Vec<AsHosts>,
+}
+
+/// Hosts routed by an origin AS, see [TldStats::top_ases].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AsHosts {
+    pub asn: u32,
+    /// Distinct hosts with an IP routed by the AS
+    pub hosts: u64,
+}
+
+impl TldStats {
+    /// Header of the CSV form of the report.
+    pub const CSV_HEADER: &'static str = "TLD,HOSTS,IPS,IPV4,IPV6";
+
+    /// Header of the CSV form of the report with the top ASes.
+    pub const CSV_HEADER_WITH_ASES: &'static str = "TLD,HOSTS,IPS,IPV4,IPV6,TOP_ASES";
+
+    /// Format as a line of the CSV form of the report.
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{}", self.tld, self.hosts, self.ips, self.ipv4, self.ipv6)
+    }
+
+    /// Format as a line of the CSV form of the report with the top ASes, as
+    /// `ASN:HOSTS` pairs separated by `;`.
+    pub fn to_csv_with_ases(&self) -> String {
+        let ases: Vec<String> = self
+            .top_ases
+            .iter()
+            .map(|asn| format!("{}:{}", asn.asn, asn.hosts))
+            .collect();
+        format!("{},{}", self.to_csv(), ases.join(";"))
+    }
+}
+
+/// Hosts, IPs and hosts per origin AS of a TLD, see [tld_report_with].
+#[derive(Default)]
+struct TldCounts {
+    hosts: HashSet<String>,
+    ips: HashSet<IpAddr>,
+    ases: HashMap<u32, HashSet<String>>,
+}
+
+/// Aggregate mappings by the TLD of their host, see [host_tld]. The hosts
+/// and IPs of each TLD are held in memory. Returns the TLDs by decreasing
+/// number of hosts.
+pub fn tld_report<I>(mappings: I) -> io::Result<Vec<TldStats>>
+where
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    tld_report_with(mappings, None, 0)
+}
+
+/// Same as [tld_report], also listing the `top` origin ASes of the most
+/// hosts of each TLD when given a pfx2as table. The hosts of each AS are
+/// then held in memory too.
+pub fn tld_report_with<I>(
+    mappings: I,
+    pfx2as: Option<&Pfx2As>,
+    top: usize,
+) -> io::Result<Vec<TldStats>>
+where
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    let mut tlds: BTreeMap<String, TldCounts> = BTreeMap::new();
+    for mapping in mappings {
+        let mapping = mapping?;
+        let counts = tlds.entry(host_tld(&mapping.host)).or_default();
+        if let Some((_, origins)) = pfx2as.and_then(|pfx2as| pfx2as.lookup(mapping.ip)) {
+            for asn in origins {
+                let hosts = counts.ases.entry(*asn).or_default();
+                if !hosts.contains(&mapping.host) {
+                    hosts.insert(mapping.host.clone());
+                }
+            }
+        }
+        counts.hosts.insert(mapping.host);
+        counts.ips.insert(mapping.ip);
+    }
+    let mut report: Vec<TldStats> = tlds
+        .into_iter()
+        .map(|(tld, counts)| {
+            let ipv4 = counts.ips.iter().filter(|ip| ip.is_ipv4()).count() as u64;
+            let mut top_ases: Vec<AsHosts> = counts
+                .ases
+                .iter()
+                .map(|(asn, hosts)| AsHosts { asn: *asn, hosts: hosts.len() as u64 })
+                .collect();
+            top_ases.sort_by(|a, b| b.hosts.cmp(&a.hosts).then(a.asn.cmp(&b.asn)));
+            top_ases.truncate(top);
+            TldStats {
+                tld,
+                hosts: counts.hosts.len() as u64,
+                ips: counts.ips.len() as u64,
+                ipv4,
+                ipv6: counts.ips.len() as u64 - ipv4,
+                top_ases,
+            }
+        })
+        .collect();
+    report.sort_by_key(|tld| std::cmp::Reverse(tld.hosts));
+    Ok(report)
+}
+
+/// Number of values within a range of a histogram, see
+/// [ConcentrationReport].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistogramBucket {
+    /// Smallest value counted in the bucket
+    pub min: u64,
+    /// Largest value counted in the bucket
+    pub max: u64,
+    /// Number of values within `min..=max`
+    pub count: u64,
+}
+
+/// An IP shared by many hosts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SharedIp {
+    pub ip: IpAddr,
+    /// Distinct hosts mapped to the IP
+    pub hosts: u64,
+}
+
+/// How concentrated the hosting of a mapping is, see
+/// [concentration_report].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConcentrationReport {
+    /// Distinct hosts
+    pub hosts: u64,
+    /// Distinct IPs
+    pub ips: u64,
+    /// Distribution of the number of hosts per IP, in power-of-two buckets
+    pub hosts_per_ip: Vec<HistogramBucket>,
+    /// Distribution of the number of IPs per host, in power-of-two buckets
+    pub ips_per_host: Vec<HistogramBucket>,
+    /// The IPs shared by the most hosts, most shared first
+    pub top_shared_ips: Vec<SharedIp>,
+}
+
+impl ConcentrationReport {
+    /// Header of the CSV form of the report.
+    pub const CSV_HEADER: &'static str = "KIND,KEY,COUNT";
+
+    /// Format as the lines of the CSV form of the report: `hosts_per_ip`
+    /// and `ips_per_host` lines keyed by their bucket, e.g. `4-7`, and
+    /// `shared_ip` lines keyed by the IP.
+    pub fn to_csv(&self) -> Vec<String> {
+        let buckets = |kind: &str, histogram: &[HistogramBucket]| -> Vec<String> {
+            histogram
+                .iter()
+                .map(|bucket| match bucket.min == bucket.max {
+                    true => format!("{},{},{}", kind, bucket.min, bucket.count),
+                    false => format!("{},{}-{},{}", kind, bucket.min, bucket.max, bucket.count),
+                })
+                .collect()
+        };
+        let mut lines = buckets("hosts_per_ip", &self.hosts_per_ip);
+        lines.extend(buckets("ips_per_host", &self.ips_per_host));
+        lines.extend(
+            self.top_shared_ips
+                .iter()
+                .map(|shared| format!("shared_ip,{},{}", shared.ip, shared.hosts)),
+        );
+        lines
+    }
+}
+
+/// Histogram of counts in the buckets `1`, `2-3`, `4-7`, ..., leaving out
+/// empty buckets.
+fn power_of_two_histogram(counts: impl Iterator<Item = u64>) -> Vec<HistogramBucket> {
+    let mut buckets: BTreeMap<u32, u64> = BTreeMap::new();
+    for count in counts.filter(|&count| count > 0) {
+        *buckets.entry(63 - count.leading_zeros()).or_default() += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(exponent, count)| HistogramBucket {
+            min: 1 << exponent,
+            max: u64::MAX >> (63 - exponent),
+            count,
+        })
+        .collect()
+}
+
+/// Compute the distribution of hosts per IP and IPs per host of mappings,
+/// and the `top` IPs shared by the most hosts. The distinct host-IP pairs
+/// are held in memory.
+pub fn concentration_report<I>(mappings: I, top: usize) -> io::Result<ConcentrationReport>
+where
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    let mut pairs: HashSet<(String, IpAddr)> = HashSet::new();
+    for mapping in mappings {
+        let mapping = mapping?;
+        pairs.insert((mapping.host, mapping.ip));
+    }
+    let mut hosts_per_ip: HashMap<IpAddr, u64> = HashMap::new();
+    let mut ips_per_host: HashMap<&str, u64> = HashMap::new();
+    for (host, ip) in &pairs {
+        *hosts_per_ip.entry(*ip).or_default() += 1;
+        *ips_per_host.entry(host).or_default() += 1;
+    }
+
+    let mut shared: Vec<SharedIp> = hosts_per_ip
+        .iter()
+        .map(|(ip, hosts)| SharedIp { ip: *ip, hosts: *hosts })
+        .collect();
+    shared.sort_by(|a, b| b.hosts.cmp(&a.hosts).then(a.ip.cmp(&b.ip)));
+    shared.truncate(top);
+
+    Ok(ConcentrationReport {
+        hosts: ips_per_host.len() as u64,
+        ips: hosts_per_ip.len() as u64,
+        hosts_per_ip: power_of_two_histogram(hosts_per_ip.values().copied()),
+        ips_per_host: power_of_two_histogram(ips_per_host.values().copied()),
+        top_shared_ips: shared,
+    })
+}
+
+/// A host seen on several IPs or prefixes, see [multi_ip_hosts].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostSpread {
+    pub host: String,
+    /// Distinct IPs the host mapped to
+    pub ips: u64,
+    /// Distinct prefixes containing those IPs
+    pub prefixes: u64,
+}
+
+impl HostSpread {
+    /// Header of the CSV form of the report.
+    pub const CSV_HEADER: &'static str = "HOST,IPS,PREFIXES";
+
+    /// Format as a line of the CSV form of the report.
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{}", csv_field(&self.host), self.ips, self.prefixes)
+    }
+}
+
+/// Find the hosts mapped to more than `threshold` distinct IPs, or to IPs
+/// in more than `threshold` distinct prefixes of the given lengths, a hint
+/// of anycast or CDN hosting. The distinct IPs of each host are held in
+/// memory. Returns the hosts by decreasing number of IPs.
+pub fn multi_ip_hosts<I>(
+    mappings: I,
+    threshold: u64,
+    lengths: PrefixLengths,
+) -> io::Result<Vec<HostSpread>>
+where
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    let mut hosts: HashMap<String, HashSet<IpAddr>> = HashMap::new();
+    for mapping in mappings {
+        let mapping = mapping?;
+        hosts.entry(mapping.host).or_default().insert(mapping.ip);
+    }
+    let mut report: Vec<HostSpread> = hosts
+        .into_iter()
+        .map(|(host, ips)| HostSpread {
+            host,
+            ips: ips.len() as u64,
+            prefixes: ips
+                .iter()
+                .map(|ip| lengths.prefix_of(*ip))
+                .collect::<HashSet<_>>()
+                .len() as u64,
+        })
+        .filter(|spread| spread.ips > threshold || spread.prefixes > threshold)
+        .collect();
+    report.sort_by(|a, b| b.ips.cmp(&a.ips).then_with(|| a.host.cmp(&b.host)));
+    Ok(report)
+}
+
+/// How the IPs of hosts changed over consecutive crawls, see
+/// [churn_report].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ChurnReport {
+    /// Number of crawls compared
+    pub crawls: u64,
+    /// Distinct hosts over all crawls
+    pub hosts: u64,
+    /// Hosts found in every crawl
+    pub hosts_in_all: u64,
+    /// Hosts found in at least two crawls, whose IPs could be compared
+    pub hosts_compared: u64,
+    /// Compared hosts whose IPs changed at least once
+    pub hosts_changed: u64,
+    /// Changes over all hosts
+    pub ip_changes: u64,
+    /// Number of compared hosts by number of changes, starting at zero
+    pub changes_per_host: Vec<u64>,
+    /// Compared hosts whose origin ASes changed at least once, with a
+    /// pfx2as table, see [churn_report_with]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hosts_changed_as: Option<u64>,
+    /// Origin AS changes over all hosts, with a pfx2as table
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_changes: Option<u64>,
+}
+
+impl ChurnReport {
+    /// Header of the CSV form of the report.
+    pub const CSV_HEADER: &'static str = "KIND,KEY,COUNT";
+
+    /// Format as the lines of the CSV form of the report: `summary` lines
+    /// keyed by the field name, and `changes_per_host` lines keyed by the
+    /// number of changes.
+    pub fn to_csv(&self) -> Vec<String> {
+        let mut summary = vec![
+            ("crawls", self.crawls),
+            ("hosts", self.hosts),
+            ("hosts_in_all", self.hosts_in_all),
+            ("hosts_compared", self.hosts_compared),
+            ("hosts_changed", self.hosts_changed),
+            ("ip_changes", self.ip_changes),
+        ];
+        if let (Some(hosts_changed_as), Some(as_changes)) = (self.hosts_changed_as, self.as_changes)
+        {
+            summary.push(("hosts_changed_as", hosts_changed_as));
+            summary.push(("as_changes", as_changes));
+        }
+        let mut lines: Vec<String> = summary
+            .iter()
+            .map(|(key, count)| format!("summary,{},{}", key, count))
+            .collect();
+        lines.extend(
+            self.changes_per_host
+                .iter()
+                .enumerate()
+                .map(|(changes, hosts)| format!("changes_per_host,{},{}", changes, hosts)),
+        );
+        lines
+    }
+}
+
+/// Churn of a host while reading the crawls in order.
+#[derive(Default)]
+struct HostChurn {
+    /// IPs of the last crawl the host was found in
+    ips: BTreeSet<IpAddr>,
+    /// Origin ASes of those IPs, with a pfx2as table
+    asns: BTreeSet<u32>,
+    crawls: u64,
+    changes: u64,
+    as_changes: u64,
+}
+
+/// Compare the mappings of consecutive crawls, oldest first. A host
+/// changes when the set of IPs it mapped to in a crawl differs from the
+/// one of the previous crawl it was found in. Every host seen so far is
+/// held in memory with the IPs of its last crawl, and the hosts of the
+/// current crawl with their IPs.
+pub fn churn_report<C, I>(crawls: C) -> io::Result<ChurnReport>
+where
+    C: IntoIterator<Item = I>,
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    churn_report_with(crawls, None)
+}
+
+/// Same as [churn_report], also counting the changes of the origin ASes of
+/// the IPs of hosts, looked up in a pfx2as table, when given one. IPs
+/// without a route have no origin AS.
+pub fn churn_report_with<C, I>(crawls: C, pfx2as: Option<&Pfx2As>) -> io::Result<ChurnReport>
+where
+    C: IntoIterator<Item = I>,
+    I: IntoIterator<Item = io::Result<MappingEntry>>,
+{
+    let mut report = ChurnReport::default();
+    let mut hosts: HashMap<String, HostChurn> = HashMap::new();
+    for crawl in crawls {
+        report.crawls += 1;
+        let mut current: HashMap<String, BTreeSet<IpAddr>> = HashMap::new();
+        for mapping in crawl {
+            let mapping = mapping?;
+            current.entry(mapping.host).or_default().insert(mapping.ip);
+        }
+        for (host, ips) in current {
+            let asns: BTreeSet<u32> = match pfx2as {
+                Some(pfx2as) => ips
+                    .iter()
+                    .filter_map(|ip| pfx2as.lookup(*ip))
+                    .flat_map(|(_, origins)| origins.iter().copied())
+                    .collect(),
+                None => BTreeSet::new(),
+            };
+            let churn = hosts.entry(host).or_default();
+            if churn.crawls > 0 && churn.ips != ips {
+                churn.changes += 1;
+            }
+            if churn.crawls > 0 && churn.asns != asns {
+                churn.as_changes += 1;
+            }
+            churn.crawls += 1;
+            churn.ips = ips;
+            churn.asns = asns;
+        }
+    }
+
+    report.hosts = hosts.len() as u64;
+    if pfx2as.is_some() {
+        report.hosts_changed_as = Some(0);
+        report.as_changes = Some(0);
+    }
+    for churn in hosts.values() {
+        if churn.crawls == report.crawls {
+            report.hosts_in_all += 1;
+        }
+        if churn.crawls < 2 {
+            continue;
+        }
+        report.hosts_compared += 1;
+        report.hosts_changed += (churn.changes > 0) as u64;
+        report.ip_changes += churn.changes;
+        if let (Some(hosts_changed_as), Some(as_changes)) =
+            (&mut report.hosts_changed_as, &mut report.as_changes)
+        {
+            *hosts_changed_as += (churn.as_changes > 0) as u64;
+            *as_changes += churn.as_changes;
+        }
+        let changes = churn.changes as usize;
+        if report.changes_per_host.len() <= changes {
+            report.changes_per_host.resize(changes + 1, 0);
+        }
+        report.changes_per_host[changes] += 1;
+    }
+    Ok(report)
+}
+
+/// Hosts and IPs of one group of mappings, see [GroupCounter].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupStats {
+    /// The key of the group, e.g. a country code
+    pub key: String,
+    /// Distinct hosts of the group
+    pub hosts: u64,
+    /// Distinct IPs of the group
+    pub ips: u64,
+}
+
+/// Distinct hosts and IPs of mappings grouped by a key, e.g. their country,
+/// counted while the mappings are written so that the report is ready when
+/// the crawl ends.
+#[derive(Debug, Default)]
+pub struct GroupCounter {
+    groups: HashMap<String, (HashSet<String>, HashSet<IpAddr>)>,
+}
+
+impl GroupCounter {
+    pub fn new() -> GroupCounter {
+        GroupCounter::default()
+    }
+
+    /// Count a mapping in a group.
+    pub fn add(&mut self, key: &str, host: &str, ip: IpAddr) {
+        let (hosts, ips) = match self.groups.get_mut(key) {
+            Some(group) => group,
+            None => self.groups.entry(key.to_owned()).or_default(),
+        };
+        if !hosts.contains(host) {
+            hosts.insert(host.to_owned());
+        }
+        ips.insert(ip);
+    }
+
+    /// The groups by decreasing number of hosts.
+    pub fn stats(&self) -> Vec<GroupStats> {
+        let mut stats: Vec<GroupStats> = self
+            .groups
+            .iter()
+            .map(|(key, (hosts, ips))| GroupStats {
+                key: key.clone(),
+                hosts: hosts.len() as u64,
+                ips: ips.len() as u64,
+            })
+            .collect();
+        stats.sort_by(|a, b| b.hosts.cmp(&a.hosts).then_with(|| a.key.cmp(&b.key)));
+        stats
+    }
+}
+
+/// Write grouped counts to a file, as JSON if its name ends with `.json`
+/// or `.json.gz`, and else as CSV with a `KEY_HEADER,HOSTS,IPS` header.
+pub fn write_group_report(
+    filename: &str,
+    key_header: &str,
+    groups: &[GroupStats],
+) -> io::Result<()> {
+    let mut writer = crate::try_get_writer(filename)?;
+    if filename.trim_end_matches(".gz").ends_with(".json") {
+        serde_json::to_writer_pretty(&mut writer, groups).map_err(io::Error::from)?;
+        writeln!(writer)?;
+    } else {
+        writeln!(writer, "{},HOSTS,IPS", key_header)?;
+        for group in groups {
+            writeln!(writer, "{},{},{}", csv_field(&group.key), group.hosts, group.ips)?;
+        }
+    }
+    writer.flush()
+}
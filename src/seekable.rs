@@ -0,0 +1,279 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+
+//! Seekable compressed output: BGZF (`.bgz`) or seekable zstd (`.zst`, with
+//! the zstd feature), compressed in independent blocks of whole lines, with
+//! a `<file>.idx` companion listing the blocks holding the rows of each
+//! host, so that one host can be read without decompressing the whole file.
+//!
+//! The index has one `HOST<TAB>OFFSET<TAB>LENGTH` line per host and block,
+//! in the order of the blocks, with the byte range of the compressed block.
+use flate2::read::MultiGzDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Extension of the host index of a seekable file.
+pub const INDEX_EXTENSION: &str = "idx";
+
+/// Uncompressed size of the BGZF blocks, the one of bgzip.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Uncompressed size of the seekable zstd frames.
+#[cfg(feature = "zstd")]
+const ZSTD_FRAME_SIZE: usize = 256 * 1024;
+
+/// Empty block marking the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, 0x42, 0x43, 0x02, 0, 0x1b, 0, 0x03, 0,
+    0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Compressed format whose blocks can be decompressed on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekableFormat {
+    /// Blocked gzip of samtools and tabix, readable by any gzip reader
+    Bgzf,
+    /// Zstandard seekable format, readable by any zstd reader
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl SeekableFormat {
+    /// Seekable format of a file, from its extension: `.bgz`, or `.zst`
+    /// with the zstd feature.
+    pub fn of(filename: &str) -> Option<SeekableFormat> {
+        match Path::new(filename).extension()?.to_str()? {
+            "bgz" => Some(SeekableFormat::Bgzf),
+            #[cfg(feature = "zstd")]
+            "zst" => Some(SeekableFormat::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Maximum uncompressed size of a block.
+    fn block_size(&self) -> usize {
+        match self {
+            SeekableFormat::Bgzf => BGZF_BLOCK_SIZE,
+            #[cfg(feature = "zstd")]
+            SeekableFormat::Zstd => ZSTD_FRAME_SIZE,
+        }
+    }
+
+    /// Compress one block.
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SeekableFormat::Bgzf => bgzf_block(data),
+            #[cfg(feature = "zstd")]
+            SeekableFormat::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+}
+
+impl SeekableFormat {
+    /// Decompress one block, as listed in the host index.
+    pub(crate) fn decompress(&self, block: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SeekableFormat::Bgzf => {
+                let mut data = vec![];
+                MultiGzDecoder::new(block).read_to_end(&mut data)?;
+                Ok(data)
+            }
+            #[cfg(feature = "zstd")]
+            SeekableFormat::Zstd => zstd::stream::decode_all(block),
+        }
+    }
+}
+
+/// Compress a BGZF block: a gzip member whose `BC` extra field holds the
+/// size of the block.
+fn bgzf_block(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    let deflated = encoder.finish()?;
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    let block_size = 18 + deflated.len() + 8;
+    let bsize = u16::try_from(block_size - 1)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "BGZF block too large"))?;
+    let mut block = Vec::with_capacity(block_size);
+    block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0]);
+    block.extend_from_slice(&[0x42, 0x43, 0x02, 0]);
+    block.extend_from_slice(&bsize.to_le_bytes());
+    block.extend_from_slice(&deflated);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+/// Host of an output line: its first CSV field.
+fn line_host(line: &[u8]) -> &[u8] {
+    let end = line.iter().position(|byte| *byte == b',').unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Writer compressing lines into a [SeekableFormat], cutting the blocks
+/// between lines, and writing the host index of the blocks if given one.
+/// The file is finished when the writer is dropped, or with
+/// [SeekableWriter::finish] to get the errors.
+pub struct SeekableWriter<W: Write> {
+    inner: W,
+    format: SeekableFormat,
+    index: Option<Box<dyn Write + Send>>,
+    buffer: Vec<u8>,
+    offset: u64,
+    /// Compressed and uncompressed size of the blocks, for the seek table
+    /// of zstd
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+    blocks: Vec<(u32, u32)>,
+    finished: bool,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    /// Compress to `inner`, writing the host index to `index` if any.
+    pub fn new(
+        inner: W,
+        format: SeekableFormat,
+        index: Option<Box<dyn Write + Send>>,
+    ) -> SeekableWriter<W> {
+        SeekableWriter {
+            inner,
+            format,
+            index,
+            buffer: Vec::with_capacity(format.block_size()),
+            offset: 0,
+            blocks: vec![],
+            finished: false,
+        }
+    }
+
+    /// Compress and write the first `len` buffered bytes as one block.
+    fn write_block(&mut self, len: usize) -> io::Result<()> {
+        let data: Vec<u8> = self.buffer.drain(..len).collect();
+        let compressed = self.format.compress(&data)?;
+        self.inner.write_all(&compressed)?;
+        if let Some(index) = &mut self.index {
+            let hosts: BTreeSet<&[u8]> = data
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(line_host)
+                .collect();
+            for host in hosts {
+                index.write_all(host)?;
+                writeln!(index, "\t{}\t{}", self.offset, compressed.len())?;
+            }
+        }
+        self.offset += compressed.len() as u64;
+        self.blocks.push((compressed.len() as u32, data.len() as u32));
+        Ok(())
+    }
+
+    /// Write the last block and the end of the file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if !self.buffer.is_empty() {
+            self.write_block(self.buffer.len())?;
+        }
+        match self.format {
+            SeekableFormat::Bgzf => self.inner.write_all(&BGZF_EOF)?,
+            #[cfg(feature = "zstd")]
+            SeekableFormat::Zstd => self.write_seek_table()?,
+        }
+        self.inner.flush()?;
+        if let Some(index) = &mut self.index {
+            index.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the seek table of the zstd seekable format, in a skippable
+    /// frame that other readers ignore.
+    #[cfg(feature = "zstd")]
+    fn write_seek_table(&mut self) -> io::Result<()> {
+        let mut table = Vec::with_capacity(8 + self.blocks.len() * 8 + 9);
+        table.extend_from_slice(&0x184d_2a5e_u32.to_le_bytes());
+        table.extend_from_slice(&((self.blocks.len() * 8 + 9) as u32).to_le_bytes());
+        for (compressed, uncompressed) in &self.blocks {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&uncompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        // no checksums
+        table.push(0);
+        table.extend_from_slice(&0x8f92_eab1_u32.to_le_bytes());
+        self.inner.write_all(&table)
+    }
+}
+
+impl<W: Write> Write for SeekableWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        let block_size = self.format.block_size();
+        while self.buffer.len() >= block_size {
+            // cut after the last complete line, or mid-line if it is longer
+            // than a whole block
+            let len = self.buffer[..block_size]
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(block_size, |newline| newline + 1);
+            self.write_block(len)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Write the complete lines buffered as a short block, so that they
+    /// are on disk.
+    fn flush(&mut self) -> io::Result<()> {
+        let complete = self.buffer.iter().rposition(|byte| *byte == b'\n');
+        if let (false, Some(newline)) = (self.finished, complete) {
+            self.write_block(newline + 1)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for SeekableWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Selection of the host pointers to crawl out of a full cluster.idx listing.
+use crate::IndexHostPointer;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/// Keep only the slice of pointers starting at position `offset`, at most
+/// `count` of them (all remaining ones if `None`).
+///
+/// Positions are counted over host pointers, i.e. cluster.idx lines for IP
+/// addresses are not counted. This allows splitting a crawl into manual
+/// chunks, e.g. `offset=0,count=1000` followed by `offset=1000,count=1000`.
+pub fn slice_pointers(
+    pointers: Vec<IndexHostPointer>,
+    offset: usize,
+    count: Option<usize>,
+) -> Vec<IndexHostPointer> {
+    pointers
+        .into_iter()
+        .skip(offset)
+        .take(count.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Draw a uniform random sample of `n` pointers without replacement.
+///
+/// The sampled pointers keep their original cluster.idx order. Passing the
+/// same `seed` over the same pointer list always yields the same sample; if
+/// `seed` is `None`, the generator is seeded from system entropy. If `n` is
+/// not smaller than the number of pointers, all pointers are returned.
+pub fn sample_pointers(
+    pointers: Vec<IndexHostPointer>,
+    n: usize,
+    seed: Option<u64>,
+) -> Vec<IndexHostPointer> {
+    if n >= pointers.len() {
+        return pointers;
+    }
+    let mut rng = match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    let mut picked = rand::seq::index::sample(&mut rng, pointers.len(), n).into_vec();
+    picked.sort_unstable();
+
+    let mut picked = picked.into_iter().peekable();
+    pointers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, p)| match picked.peek() {
+            Some(&next) if next == i => {
+                picked.next();
+                Some(p)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Shuffle the pointers, so that concurrent requests are spread over all
+/// the cdx files of the index instead of a few neighboring ones.
+///
+/// Passing the same `seed` always yields the same order; if `seed` is
+/// `None`, the generator is seeded from system entropy.
+pub fn shuffle_pointers(
+    mut pointers: Vec<IndexHostPointer>,
+    seed: Option<u64>,
+) -> Vec<IndexHostPointer> {
+    let mut rng = match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    pointers.shuffle(&mut rng);
+    pointers
+}
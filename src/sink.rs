@@ -0,0 +1,365 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Destinations for the mappings found by a crawl.
+use crate::object_store::{ChunkedSink, UploadSink};
+use crate::{
+    encoded_writer, host_tld, is_object_url, try_get_writer, CrawlOptions, CsvColumns,
+    MappingEntry, ObjectUrl,
+};
+use std::io::{self, Write};
+
+/// File format of the output of [crate::crawl_with_options].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// CSV lines, see [CsvSink]
+    #[default]
+    Csv,
+    /// Parquet, see [crate::ParquetSink] (`parquet` feature)
+    #[cfg(feature = "parquet")]
+    Parquet,
+    /// Host -> dates and IPs in an embedded store, see [crate::KvSink]
+    /// (`kv` feature)
+    #[cfg(feature = "kv")]
+    Kv,
+}
+
+impl OutputFormat {
+    /// Extension of the default output file name, e.g. `csv.gz`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv.gz",
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => "parquet",
+            #[cfg(feature = "kv")]
+            OutputFormat::Kv => "kv",
+        }
+    }
+
+    /// Create a sink writing the mappings of a crawl of `index_id` to
+    /// `filename` in this format, with the optional columns of the options,
+    /// or to partitions under the `filename` directory, see
+    /// [CrawlOptions::partition_by]. Files are uploaded to the
+    /// [CrawlOptions::object_store] if `filename` is an object URL.
+    #[cfg_attr(not(feature = "parquet"), allow(unused_variables))]
+    pub(crate) fn create_sink(
+        self,
+        filename: &str,
+        index_id: &str,
+        options: &CrawlOptions,
+    ) -> io::Result<Box<dyn MappingSink>> {
+        let columns = CsvColumns::of(options);
+        if is_object_url(filename) {
+            let store = options.object_store.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no object store to upload to")
+            })?;
+            let url: ObjectUrl = filename
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let chunked = options.upload_chunk_mappings.filter(|_| options.partition_by.is_empty());
+            if let Some(chunk_mappings) = chunked {
+                // each chunk is written like a whole output
+                let index_id = index_id.to_owned();
+                let options = CrawlOptions {
+                    upload_chunk_mappings: None,
+                    ..options.clone()
+                };
+                let make = move |chunk: &ObjectUrl| {
+                    self.create_sink(&chunk.to_string(), &index_id, &options)
+                };
+                return Ok(Box::new(ChunkedSink::new(url, chunk_mappings, Box::new(make))));
+            }
+            return Ok(match self {
+                OutputFormat::Csv => Box::new(UploadSink::create(store, &url, |writer| {
+                    let writer = encoded_writer(filename, writer);
+                    Ok(Box::new(CsvSink::new(writer).with_columns(columns)))
+                })?),
+                #[cfg(feature = "parquet")]
+                OutputFormat::Parquet if !options.partition_by.is_empty() => {
+                    Box::new(crate::PartitionedParquetSink::in_object_store(
+                        store.clone(),
+                        url,
+                        index_id,
+                        options.partition_by.clone(),
+                        columns,
+                    ))
+                }
+                #[cfg(feature = "parquet")]
+                OutputFormat::Parquet => Box::new(UploadSink::create(store, &url, |writer| {
+                    Ok(Box::new(crate::ParquetSink::new(writer, columns)?))
+                })?),
+                #[cfg(feature = "kv")]
+                OutputFormat::Kv => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "key-value store output cannot be uploaded",
+                    ))
+                }
+            });
+        }
+        Ok(match self {
+            OutputFormat::Csv => Box::new(CsvSink::create(filename)?.with_columns(columns)),
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet if !options.partition_by.is_empty() => {
+                Box::new(crate::PartitionedParquetSink::create(
+                    filename,
+                    index_id,
+                    options.partition_by.clone(),
+                    columns,
+                )?)
+            }
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => Box::new(crate::ParquetSink::create(filename, columns)?),
+            #[cfg(feature = "kv")]
+            OutputFormat::Kv => Box::new(crate::KvSink::create(filename)?),
+        })
+    }
+}
+
+/// Key of the directories the mappings are partitioned into, see
+/// [CrawlOptions::partition_by].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKey {
+    /// Id of the crawled index, e.g. `index_id=CC-MAIN-2020-50`
+    IndexId,
+    /// Capture date, e.g. `date=2020-11-26`
+    Date,
+    /// Capture month, e.g. `month=2020-11`
+    Month,
+    /// Top-level domain of the host, see [host_tld]
+    Tld,
+    /// Country of the IP, see [MappingEntry::country]
+    Country,
+}
+
+impl PartitionKey {
+    /// Name of the key in the directory names.
+    pub fn name(self) -> &'static str {
+        match self {
+            PartitionKey::IndexId => "index_id",
+            PartitionKey::Date => "date",
+            PartitionKey::Month => "month",
+            PartitionKey::Tld => "tld",
+            PartitionKey::Country => "country",
+        }
+    }
+
+    /// Value of the key for a mapping of a crawl of `index_id`.
+    pub fn value(self, entry: &MappingEntry, index_id: &str) -> String {
+        match self {
+            PartitionKey::IndexId => index_id.to_owned(),
+            PartitionKey::Date => entry.timestr.clone(),
+            PartitionKey::Month => entry.timestr.chars().take(7).collect(),
+            PartitionKey::Tld => host_tld(&entry.host),
+            PartitionKey::Country => entry.country.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Directory of a mapping for the given keys, e.g.
+    /// `index_id=CC-MAIN-2020-50/date=2020-11-26`. Values are escaped like
+    /// Hive does, and empty ones written as `__HIVE_DEFAULT_PARTITION__`.
+    pub fn directory(keys: &[PartitionKey], entry: &MappingEntry, index_id: &str) -> String {
+        let parts: Vec<String> = keys
+            .iter()
+            .map(|key| {
+                let value = key.value(entry, index_id);
+                let value = match value.is_empty() {
+                    true => "__HIVE_DEFAULT_PARTITION__".to_owned(),
+                    false => value
+                        .chars()
+                        .map(|c| match c {
+                            '/' | '\\' | '=' | '%' | ':' | '#' | '?' | '"' | '\'' => {
+                                format!("%{:02X}", c as u32)
+                            }
+                            c if c.is_control() => format!("%{:02X}", c as u32),
+                            c => c.to_string(),
+                        })
+                        .collect(),
+                };
+                format!("{}={}", key.name(), value)
+            })
+            .collect();
+        parts.join("/")
+    }
+}
+
+impl std::str::FromStr for PartitionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "index_id" => Ok(PartitionKey::IndexId),
+            "date" => Ok(PartitionKey::Date),
+            "month" => Ok(PartitionKey::Month),
+            "tld" => Ok(PartitionKey::Tld),
+            "country" => Ok(PartitionKey::Country),
+            _ => Err(format!(
+                "expected index_id, date, month, tld or country, found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => Err("parquet output needs the parquet feature".to_owned()),
+            #[cfg(feature = "kv")]
+            "kv" => Ok(OutputFormat::Kv),
+            #[cfg(not(feature = "kv"))]
+            "kv" => Err("key-value store output needs the kv feature".to_owned()),
+            _ => Err(format!("expected csv, parquet or kv, found {:?}", s)),
+        }
+    }
+}
+
+/// Destination of the mappings found by a crawl, see
+/// [crate::crawl_to_sink]. Implement it to stream results into a database or
+/// an in-memory structure instead of a file.
+///
+/// All mappings are written from a single thread, so implementations need no
+/// synchronization.
+pub trait MappingSink {
+    /// Write one mapping. After an error, the crawl stops writing to the
+    /// sink and reports the error in [crate::CrawlStats::output_error].
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()>;
+
+    /// Flush buffered mappings, called once after the crawl has finished.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write the mappings so far through to storage mid-crawl, before a
+    /// [crate::Checkpoint] is saved. Returns `false` if the sink cannot,
+    /// e.g. because its format needs a footer, in which case the
+    /// checkpoint is only saved at the end of the crawl.
+    fn sync(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Quote a CSV field if it contains a comma, quote or line break.
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Split a CSV line into its fields, unquoting quoted ones. Returns `None`
+/// for an unterminated quote.
+pub(crate) fn split_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+/// Sink writing `HOST,DATE,IP` lines, the format of the command line tool,
+/// see [MappingEntry::to_csv_with].
+pub struct CsvSink {
+    writer: Box<dyn Write>,
+    columns: CsvColumns,
+}
+
+impl CsvSink {
+    /// Create a sink writing to `filename`, gzip-compressed if the file name
+    /// ends with `.gz`.
+    pub fn create(filename: &str) -> io::Result<CsvSink> {
+        Ok(CsvSink::new(try_get_writer(filename)?))
+    }
+
+    /// Create a sink writing to any writer.
+    pub fn new(writer: Box<dyn Write>) -> CsvSink {
+        CsvSink {
+            writer,
+            columns: CsvColumns::default(),
+        }
+    }
+
+    /// Write the given optional columns after `HOST,DATE,IP`.
+    pub fn with_columns(mut self, columns: CsvColumns) -> CsvSink {
+        self.columns = columns;
+        self
+    }
+}
+
+impl MappingSink for CsvSink {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        writeln!(self.writer, "{}", entry.to_csv_with(self.columns))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<bool> {
+        self.writer.flush()?;
+        Ok(true)
+    }
+}
+
+/// Collects the mappings in memory.
+impl MappingSink for Vec<MappingEntry> {
+    fn write(&mut self, entry: MappingEntry) -> io::Result<()> {
+        self.push(entry);
+        Ok(())
+    }
+}
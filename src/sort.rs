@@ -0,0 +1,268 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Post-crawl finalization pass that sorts and deduplicates a mapping file.
+//!
+//! Crawling workers write results concurrently, so the order of the output
+//! lines is nondeterministic and retried pointers may produce duplicate rows.
+//! [sort_dedup_file] rewrites a finished output file in lexicographic order
+//! with duplicate lines removed, and [merge_files] merges several files into
+//! one the same way.
+//!
+//! Files that do not fit in memory are handled with an external merge sort:
+//! the input is split into sorted and deduplicated runs that are spilled to
+//! temporary files, and the runs are then k-way merged into the final output.
+use crate::{get_reader, FileWriter, SeekableFormat, INDEX_EXTENSION};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+
+/// Default memory taken by the lines of a sorted run before it is spilled
+/// to disk.
+pub const DEFAULT_SORT_MEMORY: usize = 256 * 1024 * 1024;
+
+/// Estimated memory of a buffered line besides its bytes.
+const LINE_OVERHEAD: usize = 32;
+
+/// Most runs merged at once, to stay under the limit of open files; more
+/// runs are first merged into larger ones.
+const MERGE_FAN_IN: usize = 128;
+
+/// Settings of the external merge sort.
+#[derive(Debug, Clone)]
+pub struct SortOptions {
+    /// Directory the sorted runs are spilled to, the one of the output if
+    /// `None`
+    pub temp_dir: Option<PathBuf>,
+    /// Approximate memory taken by the lines of a run before it is spilled,
+    /// see [DEFAULT_SORT_MEMORY]
+    pub memory_budget: usize,
+}
+
+impl Default for SortOptions {
+    fn default() -> SortOptions {
+        SortOptions {
+            temp_dir: None,
+            memory_budget: DEFAULT_SORT_MEMORY,
+        }
+    }
+}
+
+/// Spilled runs, removed when dropped, whether the sort succeeded or not.
+struct Runs {
+    dir: PathBuf,
+    name: String,
+    paths: Vec<PathBuf>,
+    /// Number of runs created so far, to name the next one
+    created: usize,
+}
+
+impl Runs {
+    /// Path of a new run.
+    fn next_path(&mut self) -> PathBuf {
+        let run_path = self.dir.join(format!(
+            ".run{}-{}.{}",
+            self.created,
+            std::process::id(),
+            self.name
+        ));
+        self.created += 1;
+        self.paths.push(run_path.clone());
+        run_path
+    }
+
+    /// Sort the buffered lines and spill them to a new run.
+    fn spill(&mut self, buffer: &mut Vec<String>) -> io::Result<()> {
+        let run_path = self.next_path();
+        write_run(&run_path, buffer)
+    }
+
+    /// Merge the first [MERGE_FAN_IN] runs into a new one.
+    fn reduce(&mut self) -> io::Result<()> {
+        let merged: Vec<PathBuf> = self.paths.drain(..MERGE_FAN_IN).collect();
+        let run_path = self.next_path();
+        let mut writer = BufWriter::with_capacity(128 * 1024, File::create(&run_path)?);
+        let result = merge_runs(&merged, &mut writer).and_then(|_| writer.flush());
+        for run in &merged {
+            let _ = fs::remove_file(run);
+        }
+        result
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for run in &self.paths {
+            let _ = fs::remove_file(run);
+        }
+    }
+}
+
+/// Sort and deduplicate the lines of `filename` in place.
+///
+/// Gzip-compressed files (`.gz` extension) are read and written compressed,
+/// seekable files (see [SeekableFormat]) with a new host index.
+/// Returns the number of lines in the finalized file.
+pub fn sort_dedup_file(filename: &str) -> io::Result<u64> {
+    sort_dedup_file_with(filename, &SortOptions::default())
+}
+
+/// Same as [sort_dedup_file], with the given temporary directory and memory
+/// budget.
+pub fn sort_dedup_file_with(filename: &str, options: &SortOptions) -> io::Result<u64> {
+    merge_files(&[filename], filename, options)
+}
+
+/// Sort, deduplicate and merge the lines of several files into `output`,
+/// which may be one of the inputs. The files are compressed according to
+/// their extensions like with [get_reader] and [crate::get_writer]. Returns
+/// the number of lines written.
+pub fn merge_files(inputs: &[&str], output: &str, options: &SortOptions) -> io::Result<u64> {
+    let path = Path::new(output);
+    let tmp_output = sibling_path(path, "sorting");
+    let dir = match &options.temp_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => path.parent().unwrap_or_else(|| Path::new("")).to_owned(),
+    };
+    let mut runs = Runs {
+        dir,
+        name: path.file_name().unwrap().to_string_lossy().into_owned(),
+        paths: vec![],
+        created: 0,
+    };
+
+    let mut buffer: Vec<String> = Vec::new();
+    let mut buffered = 0;
+    for input in inputs {
+        for line in get_reader(input)?.lines() {
+            let line = line?;
+            buffered += line.len() + LINE_OVERHEAD;
+            buffer.push(line);
+            if buffered >= options.memory_budget {
+                runs.spill(&mut buffer)?;
+                buffered = 0;
+            }
+        }
+    }
+
+    let written = if runs.paths.is_empty() {
+        // everything fits in memory, no need to spill
+        sort_and_dedup(&mut buffer);
+        let mut writer = FileWriter::create(tmp_output.to_str().unwrap())?;
+        for line in &buffer {
+            writeln!(writer, "{}", line)?;
+        }
+        writer.finish()?;
+        buffer.len() as u64
+    } else {
+        if !buffer.is_empty() {
+            runs.spill(&mut buffer)?;
+        }
+        while runs.paths.len() > MERGE_FAN_IN {
+            runs.reduce()?;
+        }
+        let mut writer = FileWriter::create(tmp_output.to_str().unwrap())?;
+        let written = merge_runs(&runs.paths, &mut writer)?;
+        writer.finish()?;
+        written
+    };
+
+    fs::rename(&tmp_output, path)?;
+    if SeekableFormat::of(output).is_some() {
+        // the host index of the sorted file replaces the one of the input
+        let index = |path: &Path| format!("{}.{}", path.display(), INDEX_EXTENSION);
+        fs::rename(index(&tmp_output), index(path))?;
+    }
+    Ok(written)
+}
+
+/// Build a hidden temporary path next to `path` that keeps the original
+/// extension, so that [FileWriter::create] picks the same compression.
+fn sibling_path(path: &Path, tag: &str) -> PathBuf {
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    path.with_file_name(format!(".{}-{}.{}", tag, std::process::id(), file_name))
+}
+
+fn sort_and_dedup(lines: &mut Vec<String>) {
+    lines.sort_unstable();
+    lines.dedup();
+}
+
+/// Sort the buffered lines and spill them to an uncompressed run file.
+fn write_run(run_path: &Path, buffer: &mut Vec<String>) -> io::Result<()> {
+    sort_and_dedup(buffer);
+    let mut writer = BufWriter::with_capacity(128 * 1024, File::create(run_path)?);
+    for line in buffer.drain(..) {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()
+}
+
+/// K-way merge of sorted run files into `writer`, dropping duplicate lines
+/// that appear across runs.
+fn merge_runs(runs: &[PathBuf], writer: &mut dyn Write) -> io::Result<u64> {
+    let mut readers: Vec<Lines<BufReader<File>>> = vec![];
+    for run in runs {
+        readers.push(BufReader::with_capacity(128 * 1024, File::open(run)?).lines());
+    }
+
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(line) = reader.next() {
+            heap.push(Reverse((line?, i)));
+        }
+    }
+
+    let mut last: Option<String> = None;
+    let mut written = 0;
+    while let Some(Reverse((line, i))) = heap.pop() {
+        if let Some(next) = readers[i].next() {
+            heap.push(Reverse((next?, i)));
+        }
+        if last.as_ref() == Some(&line) {
+            continue;
+        }
+        writeln!(writer, "{}", line)?;
+        written += 1;
+        last = Some(line);
+    }
+    writer.flush()?;
+    Ok(written)
+}
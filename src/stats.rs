@@ -0,0 +1,722 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Counters and statistics collected while crawling.
+use crate::{CrawlError, GroupStats, INDEX_SERVER_URL};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Live counters shared between the crawling workers and the progress
+/// display. All counters are updated with relaxed atomics.
+#[derive(Debug, Default)]
+pub struct CrawlCounters {
+    /// Host pointers processed by the workers
+    pub hosts_attempted: AtomicU64,
+    /// HTTP requests issued to cdx index files and WARC files, including
+    /// retries
+    pub requests: AtomicU64,
+    /// Requests that were retried after a failure
+    pub retries: AtomicU64,
+    /// Response body bytes downloaded
+    pub bytes_downloaded: AtomicU64,
+    /// Requests that failed before getting a response, after all retries
+    pub connect_errors: AtomicU64,
+    /// Requests answered with a non-success HTTP status, after all retries
+    pub status_errors: AtomicU64,
+    /// Requests whose response body could not be read or was truncated,
+    /// after all retries
+    pub body_errors: AtomicU64,
+    /// WARC records fetched successfully but without a usable IP address
+    pub missing_ip: AtomicU64,
+    /// Hosts looked up through the CDX API after their cdx block failed
+    pub cdx_api_fallbacks: AtomicU64,
+    /// Hosts mapped from the cdx block of another host's pointer, see
+    /// [crate::CrawlOptions::bonus_hosts]
+    pub bonus_hosts: AtomicU64,
+    /// Malformed cluster.idx and cdx lines that were skipped
+    pub malformed_lines: AtomicU64,
+    /// WARC records read again with a larger window because their header
+    /// block did not fit
+    pub warc_refetches: AtomicU64,
+    /// Host pointers whose hostname was changed by the
+    /// [crate::HostNormalization]
+    pub normalized_hosts: AtomicU64,
+    /// Host pointers skipped because their hostname is invalid
+    pub rejected_hosts: AtomicU64,
+    /// Captures skipped because their host reached
+    /// [crate::RecordFilter::max_captures]
+    pub capped_captures: AtomicU64,
+    /// Captures whose IP is a bogon, see [crate::is_bogon]
+    pub bogon_ips: AtomicU64,
+    /// Ranged requests answered from the disk cache
+    pub cache_hits: AtomicU64,
+    /// Hosts that failed with a retryable error, e.g. a timeout
+    pub retryable_hosts: AtomicU64,
+    /// Hosts that failed with a fatal error, e.g. a missing cdx file
+    pub fatal_hosts: AtomicU64,
+    status_histogram: Mutex<BTreeMap<u16, u64>>,
+    endpoints: Mutex<BTreeMap<Endpoint, EndpointStats>>,
+    latencies: Mutex<BTreeMap<Endpoint, LatencyHistogram>>,
+    fetch_ids: AtomicU64,
+    activity: Mutex<BTreeMap<usize, String>>,
+    recent_failures: Mutex<VecDeque<String>>,
+}
+
+/// Number of recently failed hosts kept by [CrawlCounters].
+const RECENT_FAILURES: usize = 20;
+
+/// Kind of resource a request is sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// The cluster.idx of an index
+    ClusterIdx,
+    /// A block of a cdx index file
+    Cdx,
+    /// The CDX API of the index server, see
+    /// [crate::CrawlOptions::cdx_api_fallback]
+    CdxApi,
+    /// A record of a WARC file
+    Warc,
+}
+
+impl Endpoint {
+    /// The endpoint a URL belongs to, from the layout of the Common Crawl
+    /// buckets, which mirrors keep.
+    pub fn of(url: &str) -> Endpoint {
+        if url.ends_with("/cluster.idx") {
+            Endpoint::ClusterIdx
+        } else if url.starts_with(INDEX_SERVER_URL) || url.contains("-index?") {
+            Endpoint::CdxApi
+        } else if url.contains("/cc-index/collections/") {
+            Endpoint::Cdx
+        } else {
+            Endpoint::Warc
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Endpoint::ClusterIdx => "cluster.idx",
+            Endpoint::Cdx => "cdx",
+            Endpoint::CdxApi => "cdx api",
+            Endpoint::Warc => "warc",
+        })
+    }
+}
+
+/// Category of a failed request or unusable response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The request or the response body timed out
+    Timeout,
+    /// No response was received for another reason
+    Connect,
+    /// The body was incomplete or did not cover the requested range
+    Truncated,
+    /// The body was not a complete gzip member
+    Gzip,
+    /// The data could not be parsed, e.g. a malformed cdx line or WARC
+    /// record
+    Parse,
+}
+
+/// Buckets of a [LatencyHistogram] per doubling of the latency, which
+/// bounds the error of its quantiles to about 2%.
+const LATENCY_BUCKETS_PER_DOUBLING: f64 = 16.0;
+
+/// Histogram of request latencies in logarithmic buckets, to estimate
+/// their quantiles in constant memory however many requests are sent.
+#[derive(Debug, Clone, Default)]
+struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().max(1) as f64;
+        let bucket = (micros.log2() * LATENCY_BUCKETS_PER_DOUBLING) as usize;
+        if bucket >= self.buckets.len() {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Latency below which the fraction `q` of the requests completed,
+    /// estimated by the geometric middle of its bucket.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let micros = 2f64.powf((bucket as f64 + 0.5) / LATENCY_BUCKETS_PER_DOUBLING);
+                return Duration::from_secs_f64(micros / 1e6).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn to_stats(&self) -> LatencyStats {
+        LatencyStats {
+            count: self.count,
+            sum_secs: self.sum.as_secs_f64(),
+            p50_secs: self.quantile(0.5).as_secs_f64(),
+            p90_secs: self.quantile(0.9).as_secs_f64(),
+            p99_secs: self.quantile(0.99).as_secs_f64(),
+            max_secs: self.max.as_secs_f64(),
+        }
+    }
+}
+
+/// Bits of the hash of a value selecting its register in a
+/// [DistinctCounter].
+const DISTINCT_PRECISION: u32 = 14;
+
+/// HyperLogLog estimate of the number of distinct values, e.g. the hosts
+/// of the output, in 16kB however many values are added. The standard
+/// error of the estimate is about 0.8%, small counts are nearly exact.
+#[derive(Debug, Clone)]
+pub(crate) struct DistinctCounter {
+    registers: Vec<u8>,
+}
+
+impl DistinctCounter {
+    pub(crate) fn new() -> DistinctCounter {
+        DistinctCounter {
+            registers: vec![0; 1 << DISTINCT_PRECISION],
+        }
+    }
+
+    pub(crate) fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - DISTINCT_PRECISION)) as usize;
+        // first set bit of the other bits, bounded by a sentinel bit
+        let rest = (hash << DISTINCT_PRECISION) | (1 << (DISTINCT_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    /// Estimated number of distinct values added, by linear counting while
+    /// some registers are still empty and the count is small.
+    pub(crate) fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        let estimate = match raw <= 2.5 * m && empty > 0 {
+            true => m * (m / empty as f64).ln(),
+            false => raw,
+        };
+        estimate.round() as u64
+    }
+}
+
+/// Latency of the requests to one [Endpoint] that got a response, from
+/// sending the request to reading the whole body. The percentiles are
+/// estimated within about 2%.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencyStats {
+    /// Requests measured
+    pub count: u64,
+    /// Total latency of the requests in seconds
+    pub sum_secs: f64,
+    /// Median latency in seconds
+    pub p50_secs: f64,
+    /// 90th percentile of the latency in seconds
+    pub p90_secs: f64,
+    /// 99th percentile of the latency in seconds
+    pub p99_secs: f64,
+    /// Highest latency in seconds
+    pub max_secs: f64,
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency p50 {:.0}ms, p90 {:.0}ms, p99 {:.0}ms, max {:.0}ms",
+            self.p50_secs * 1000.0,
+            self.p90_secs * 1000.0,
+            self.p99_secs * 1000.0,
+            self.max_secs * 1000.0
+        )
+    }
+}
+
+/// Responses and failures of the requests to one [Endpoint].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EndpointStats {
+    /// Requests sent, including retries
+    pub requests: u64,
+    /// Number of responses per HTTP status code
+    pub statuses: BTreeMap<u16, u64>,
+    /// Requests that timed out
+    pub timeouts: u64,
+    /// Requests that failed without a response, other than timeouts
+    pub connect_errors: u64,
+    /// Responses that were incomplete or did not cover the requested range
+    pub truncated: u64,
+    /// Responses that were not a complete gzip member
+    pub gzip_errors: u64,
+    /// Malformed cdx lines and WARC records
+    pub parse_errors: u64,
+    /// Latency of the requests that got a response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyStats>,
+}
+
+impl fmt::Display for EndpointStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let statuses = self
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(
+            f,
+            "{} requests ({}), timeouts {}, connect {}, truncated {}, gzip {}, parse {}",
+            self.requests,
+            statuses,
+            self.timeouts,
+            self.connect_errors,
+            self.truncated,
+            self.gzip_errors,
+            self.parse_errors
+        )
+    }
+}
+
+impl CrawlCounters {
+    /// Count one response with the given HTTP status code.
+    pub fn record_status(&self, status: u16) {
+        *self.status_histogram.lock().unwrap().entry(status).or_insert(0) += 1;
+    }
+
+    /// Count one response to a request for the endpoint with the given
+    /// HTTP status code.
+    pub fn record_response(&self, endpoint: Endpoint, status: u16) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let stats = endpoints.entry(endpoint).or_default();
+        stats.requests += 1;
+        *stats.statuses.entry(status).or_insert(0) += 1;
+        #[cfg(feature = "otel")]
+        crate::otel::record_response(endpoint, status);
+    }
+
+    /// ID of a new fetch, shared by its retries, to follow it in the
+    /// debug messages. IDs start at 1 in each crawl.
+    pub fn next_fetch_id(&self) -> u64 {
+        self.fetch_ids.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Record the latency of a request for the endpoint that got a
+    /// response.
+    pub fn record_latency(&self, endpoint: Endpoint, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.entry(endpoint).or_default().record(latency);
+        #[cfg(feature = "otel")]
+        crate::otel::record_latency(endpoint, latency);
+    }
+
+    /// Count one request to the endpoint that got no response, see
+    /// [CrawlCounters::record_response].
+    pub fn record_no_response(&self, endpoint: Endpoint) {
+        self.endpoints.lock().unwrap().entry(endpoint).or_default().requests += 1;
+    }
+
+    /// Count a failure of a request for the endpoint, once it failed for
+    /// good. The request itself is counted by
+    /// [CrawlCounters::record_response] or
+    /// [CrawlCounters::record_no_response].
+    pub fn record_failure(&self, endpoint: Endpoint, kind: FailureKind) {
+        #[cfg(feature = "otel")]
+        crate::otel::record_failure(endpoint, kind);
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let stats = endpoints.entry(endpoint).or_default();
+        match kind {
+            FailureKind::Timeout => stats.timeouts += 1,
+            FailureKind::Connect => stats.connect_errors += 1,
+            FailureKind::Truncated => stats.truncated += 1,
+            FailureKind::Gzip => stats.gzip_errors += 1,
+            FailureKind::Parse => stats.parse_errors += 1,
+        }
+    }
+
+    /// Record the host the calling worker thread is currently crawling.
+    pub fn set_activity(&self, host: &str) {
+        let worker = rayon::current_thread_index().unwrap_or(0);
+        self.activity.lock().unwrap().insert(worker, host.to_owned());
+    }
+
+    /// Host currently being crawled by each worker thread, by worker index.
+    pub fn activity(&self) -> BTreeMap<usize, String> {
+        self.activity.lock().unwrap().clone()
+    }
+
+    /// Remember a host whose index range could not be fetched, and count it
+    /// by the class of the error.
+    pub fn record_failed_host(&self, host: &str, error: &CrawlError) {
+        if error.is_retryable() {
+            self.retryable_hosts.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.fatal_hosts.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut failures = self.recent_failures.lock().unwrap();
+        if failures.len() == RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(host.to_owned());
+    }
+
+    /// The most recently failed hosts, oldest first.
+    pub fn recent_failures(&self) -> Vec<String> {
+        self.recent_failures.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Fraction of requests that failed so far after all their retries,
+    /// between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let requests = requests.saturating_sub(self.retries.load(Ordering::Relaxed));
+        if requests == 0 {
+            return 0.0;
+        }
+        let errors = self.connect_errors.load(Ordering::Relaxed)
+            + self.status_errors.load(Ordering::Relaxed);
+        errors as f64 / requests as f64
+    }
+
+    /// The responses, failures and latencies by endpoint.
+    fn endpoint_stats(&self) -> BTreeMap<Endpoint, EndpointStats> {
+        let mut endpoints = self.endpoints.lock().unwrap().clone();
+        for (endpoint, latencies) in self.latencies.lock().unwrap().iter() {
+            endpoints.entry(*endpoint).or_default().latency = Some(latencies.to_stats());
+        }
+        endpoints
+    }
+
+    /// Snapshot the counters into a [CrawlStats]. Fields that are not
+    /// tracked by the counters, such as the output statistics, are left
+    /// at zero.
+    pub fn to_stats(&self) -> CrawlStats {
+        CrawlStats {
+            hosts_attempted: self.hosts_attempted.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            cdx_api_fallbacks: self.cdx_api_fallbacks.load(Ordering::Relaxed),
+            bonus_hosts: self.bonus_hosts.load(Ordering::Relaxed),
+            malformed_lines: self.malformed_lines.load(Ordering::Relaxed),
+            warc_refetches: self.warc_refetches.load(Ordering::Relaxed),
+            normalized_hosts: self.normalized_hosts.load(Ordering::Relaxed),
+            rejected_hosts: self.rejected_hosts.load(Ordering::Relaxed),
+            capped_captures: self.capped_captures.load(Ordering::Relaxed),
+            bogon_ips: self.bogon_ips.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            failures: FailureCounts {
+                connect: self.connect_errors.load(Ordering::Relaxed),
+                http_status: self.status_errors.load(Ordering::Relaxed),
+                body: self.body_errors.load(Ordering::Relaxed),
+                missing_ip: self.missing_ip.load(Ordering::Relaxed),
+                retryable_hosts: self.retryable_hosts.load(Ordering::Relaxed),
+                fatal_hosts: self.fatal_hosts.load(Ordering::Relaxed),
+            },
+            status_histogram: self.status_histogram.lock().unwrap().clone(),
+            endpoints: self.endpoint_stats(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Number of failures by category. A request is counted once, by the
+/// outcome of its last attempt, when all its retries failed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FailureCounts {
+    /// Requests that failed before getting a response
+    pub connect: u64,
+    /// Responses with a non-success HTTP status
+    pub http_status: u64,
+    /// Responses whose body could not be read or was truncated
+    pub body: u64,
+    /// WARC records without a usable IP address
+    pub missing_ip: u64,
+    /// Hosts that failed with a retryable error, which a re-run may fix
+    pub retryable_hosts: u64,
+    /// Hosts that failed with a fatal error, which a re-run will not fix
+    pub fatal_hosts: u64,
+}
+
+/// Summary of a finished crawl, returned by [crate::crawl_with_options].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CrawlStats {
+    /// Host pointers selected before `limit` was applied
+    pub hosts_available: u64,
+    /// Host pointers processed
+    pub hosts_attempted: u64,
+    /// Host pointers skipped because the
+    /// [crate::CrawlOptions::checkpoint] lists them as completed
+    pub pointers_resumed: u64,
+    /// Mapping rows written to the output
+    pub mappings_written: u64,
+    /// Distinct hosts in the output, estimated with a standard error of
+    /// about 0.8%
+    pub unique_hosts: u64,
+    /// Distinct IP addresses in the output, estimated likewise
+    pub unique_ips: u64,
+    /// HTTP requests issued, including retries
+    pub requests: u64,
+    /// Requests retried after a failure
+    pub retries: u64,
+    /// Response body bytes downloaded
+    pub bytes_downloaded: u64,
+    /// Hosts looked up through the CDX API after their cdx block failed
+    pub cdx_api_fallbacks: u64,
+    /// Hosts mapped from the cdx block of another host's pointer, see
+    /// [crate::CrawlOptions::bonus_hosts]
+    pub bonus_hosts: u64,
+    /// Malformed cluster.idx and cdx lines that were skipped
+    pub malformed_lines: u64,
+    /// WARC records read again with a larger window because their header
+    /// block did not fit
+    pub warc_refetches: u64,
+    /// Host pointers whose hostname was changed by the
+    /// [crate::HostNormalization]
+    pub normalized_hosts: u64,
+    /// Host pointers skipped because their hostname is invalid
+    pub rejected_hosts: u64,
+    /// Captures skipped because their host reached
+    /// [crate::RecordFilter::max_captures]
+    pub capped_captures: u64,
+    /// Captures whose IP is a bogon, see [crate::is_bogon], dropped with
+    /// [crate::CrawlOptions::drop_bogons]
+    pub bogon_ips: u64,
+    /// Duplicate mappings dropped before writing, see
+    /// [crate::CrawlOptions::dedup_window]
+    pub duplicates_dropped: u64,
+    /// Ranged requests answered from the disk cache
+    pub cache_hits: u64,
+    /// Failures by category
+    pub failures: FailureCounts,
+    /// Number of responses per HTTP status code
+    pub status_histogram: BTreeMap<u16, u64>,
+    /// Responses and failures by endpoint
+    pub endpoints: BTreeMap<Endpoint, EndpointStats>,
+    /// Origin ASes with the most hosts, see
+    /// [crate::CrawlOptions::as_report_top]
+    pub top_ases: Vec<GroupStats>,
+    /// Wall-clock duration of the crawl in seconds
+    pub elapsed_secs: f64,
+    /// Files, directories and object URLs written: the output, or the
+    /// objects it was split into, then the reports
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub output_files: Vec<String>,
+    /// Error that stopped writing the output file, if any
+    pub output_error: Option<String>,
+    /// SHA-256 digests of the output files, by path or URL, when checksums
+    /// were requested
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub output_checksums: BTreeMap<String, String>,
+    /// Reason the crawl was aborted before processing all pointers, if any
+    pub aborted: Option<String>,
+    /// Why the cluster.idx of the index could not be read, in which case
+    /// nothing was crawled
+    pub index_error: Option<String>,
+    /// Whether the `index_error` is retryable, see
+    /// [crate::CrawlError::is_retryable]
+    pub index_error_retryable: bool,
+    /// Whether the crawl was stopped through its [crate::CancelToken] before
+    /// processing all pointers
+    pub cancelled: bool,
+}
+
+impl CrawlStats {
+    /// Fraction of requests that failed after all their retries, between 0
+    /// and 1.
+    pub fn failure_rate(&self) -> f64 {
+        let requests = self.requests_without_retries();
+        if requests == 0 {
+            return 0.0;
+        }
+        (self.failures.connect + self.failures.http_status) as f64 / requests as f64
+    }
+
+    /// Requests sent, not counting their retries.
+    pub fn requests_without_retries(&self) -> u64 {
+        self.requests.saturating_sub(self.retries)
+    }
+
+    /// Number of responses with a status commonly used for throttling:
+    /// 403, 429 and 503.
+    pub fn throttled_responses(&self) -> u64 {
+        [403, 429, 503]
+            .iter()
+            .filter_map(|status| self.status_histogram.get(status))
+            .sum()
+    }
+
+    /// Whether the crawl stopped before processing all selected pointers.
+    pub fn truncated(&self) -> bool {
+        self.hosts_attempted + self.pointers_resumed < self.hosts_available
+    }
+}
+
+impl fmt::Display for CrawlStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "hosts attempted:   {}", self.hosts_attempted)?;
+        if self.pointers_resumed > 0 {
+            writeln!(f, "pointers resumed:  {}", self.pointers_resumed)?;
+        }
+        if self.truncated() {
+            writeln!(
+                f,
+                "                   (truncated by limit, {} hosts available)",
+                self.hosts_available
+            )?;
+        }
+        writeln!(f, "mappings written:  {}", self.mappings_written)?;
+        writeln!(f, "unique hosts:      {}", self.unique_hosts)?;
+        writeln!(f, "unique IPs:        {}", self.unique_ips)?;
+        writeln!(f, "requests:          {} ({} retries)", self.requests, self.retries)?;
+        writeln!(f, "bytes downloaded:  {}", self.bytes_downloaded)?;
+        if self.cache_hits > 0 {
+            writeln!(f, "cache hits:        {}", self.cache_hits)?;
+        }
+        if self.cdx_api_fallbacks > 0 {
+            writeln!(f, "cdx api fallbacks: {}", self.cdx_api_fallbacks)?;
+        }
+        if self.bonus_hosts > 0 {
+            writeln!(f, "bonus hosts:       {}", self.bonus_hosts)?;
+        }
+        if self.malformed_lines > 0 {
+            writeln!(f, "malformed lines:   {}", self.malformed_lines)?;
+        }
+        if self.warc_refetches > 0 {
+            writeln!(f, "warc refetches:    {}", self.warc_refetches)?;
+        }
+        if self.capped_captures > 0 {
+            writeln!(f, "capped captures:   {}", self.capped_captures)?;
+        }
+        if self.bogon_ips > 0 {
+            writeln!(f, "bogon IPs:         {}", self.bogon_ips)?;
+        }
+        if self.duplicates_dropped > 0 {
+            writeln!(f, "duplicates:        {} dropped", self.duplicates_dropped)?;
+        }
+        if self.normalized_hosts + self.rejected_hosts > 0 {
+            writeln!(
+                f,
+                "hostnames:         {} normalized, {} rejected",
+                self.normalized_hosts, self.rejected_hosts
+            )?;
+        }
+        writeln!(
+            f,
+            "failures:          connect {}, http status {}, body {}, missing ip {}",
+            self.failures.connect,
+            self.failures.http_status,
+            self.failures.body,
+            self.failures.missing_ip
+        )?;
+        if self.failures.retryable_hosts + self.failures.fatal_hosts > 0 {
+            writeln!(
+                f,
+                "failed hosts:      {} retryable, {} fatal",
+                self.failures.retryable_hosts, self.failures.fatal_hosts
+            )?;
+        }
+        let statuses = self
+            .status_histogram
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "http statuses:     {}", statuses)?;
+        for (endpoint, stats) in &self.endpoints {
+            writeln!(f, "{:<19}{}", format!("  {}:", endpoint), stats)?;
+            if let Some(latency) = &stats.latency {
+                writeln!(f, "                   {}", latency)?;
+            }
+        }
+        for (i, group) in self.top_ases.iter().enumerate() {
+            let asn = match group.key.as_str() {
+                "" => "unrouted".to_owned(),
+                asn => format!("AS{}", asn),
+            };
+            writeln!(
+                f,
+                "{}{}: {} hosts, {} IPs",
+                if i == 0 { "top ASes:          " } else { "                   " },
+                asn,
+                group.hosts,
+                group.ips
+            )?;
+        }
+        if let Some(e) = &self.index_error {
+            writeln!(f, "index error:       {}", e)?;
+        }
+        if let Some(reason) = &self.aborted {
+            writeln!(f, "aborted:           {}", reason)?;
+        }
+        if self.cancelled {
+            writeln!(f, "cancelled:         yes")?;
+        }
+        for (i, output) in self.output_files.iter().enumerate() {
+            let label = if i == 0 { "output files:" } else { "" };
+            writeln!(f, "{:<19}{}", label, output)?;
+        }
+        if let Some(e) = &self.output_error {
+            writeln!(f, "output error:      {}", e)?;
+        }
+        write!(f, "elapsed:           {:.1}s", self.elapsed_secs)
+    }
+}
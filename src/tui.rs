@@ -0,0 +1,248 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Full-screen terminal dashboard for interactive long-running crawls,
+//! enabled with the `tui` cargo feature.
+use crate::{CancelToken, CrawlCounters};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, Paragraph};
+use ratatui::Frame;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How often the dashboard is redrawn.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// Length of the window over which request and error rates are computed.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Counter values sampled at one point in time, used for rolling rates.
+struct Sample {
+    at: Instant,
+    requests: u64,
+    errors: u64,
+}
+
+/// Run the dashboard until all workers are done, i.e. until the progress
+/// channel is disconnected. Pressing `q` or Ctrl-C cancels the crawl, letting
+/// in-flight requests finish and the output be flushed; pressing it again
+/// restores the terminal and aborts the process.
+pub(crate) fn run_dashboard(
+    counters: &CrawlCounters,
+    total_hosts: u64,
+    receiver: Receiver<String>,
+    output_file_name: &str,
+    cancel: &CancelToken,
+) {
+    let mut terminal = ratatui::init();
+    let start = Instant::now();
+    let mut done: u64 = 0;
+    let mut samples: VecDeque<Sample> = VecDeque::new();
+
+    loop {
+        let deadline = Instant::now() + REFRESH_INTERVAL;
+        let mut finished = false;
+        // drain finished hosts until the next redraw is due
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(_) => done += 1,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if finished {
+            break;
+        }
+
+        while event::poll(Duration::from_millis(0)).unwrap_or(false) {
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c = key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Char('q') || ctrl_c {
+                    if cancel.is_cancelled() {
+                        ratatui::restore();
+                        eprintln!("crawl aborted by user");
+                        std::process::exit(130);
+                    }
+                    cancel.cancel();
+                }
+            }
+        }
+
+        let now = Instant::now();
+        samples.push_back(Sample {
+            at: now,
+            requests: counters.requests.load(Ordering::Relaxed),
+            errors: counters.connect_errors.load(Ordering::Relaxed)
+                + counters.status_errors.load(Ordering::Relaxed),
+        });
+        while samples.len() > 2 && now.duration_since(samples[0].at) > RATE_WINDOW {
+            samples.pop_front();
+        }
+
+        let output_size = std::fs::metadata(output_file_name)
+            .map(|m| m.len())
+            .unwrap_or(0);
+        let view = View {
+            counters,
+            total_hosts,
+            done,
+            elapsed: start.elapsed(),
+            samples: &samples,
+            output_file_name,
+            output_size,
+            cancelling: cancel.is_cancelled(),
+        };
+        let _ = terminal.draw(|frame| view.render(frame));
+    }
+
+    ratatui::restore();
+}
+
+/// Everything shown on one frame of the dashboard.
+struct View<'a> {
+    counters: &'a CrawlCounters,
+    total_hosts: u64,
+    done: u64,
+    elapsed: Duration,
+    samples: &'a VecDeque<Sample>,
+    output_file_name: &'a str,
+    output_size: u64,
+    cancelling: bool,
+}
+
+impl View<'_> {
+    /// Requests per second and error fraction over the rate window.
+    fn rolling_rates(&self) -> (f64, f64) {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) if last.at > first.at => {
+                let secs = last.at.duration_since(first.at).as_secs_f64();
+                let requests = last.requests - first.requests;
+                let errors = last.errors - first.errors;
+                let error_rate = if requests == 0 {
+                    0.0
+                } else {
+                    errors as f64 / requests as f64
+                };
+                (requests as f64 / secs, error_rate)
+            }
+            _ => (0.0, 0.0),
+        }
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Length(7),
+                Constraint::Min(5),
+            ])
+            .split(frame.area());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(rows[2]);
+
+        let ratio = if self.total_hosts == 0 {
+            1.0
+        } else {
+            (self.done as f64 / self.total_hosts as f64).min(1.0)
+        };
+        let title = if self.cancelling {
+            "Progress (cancelling, press q again to abort)"
+        } else {
+            "Progress"
+        };
+        let gauge = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(format!("{}/{} hosts", self.done, self.total_hosts));
+        frame.render_widget(gauge, rows[0]);
+
+        let (request_rate, error_rate) = self.rolling_rates();
+        let summary = vec![
+            Line::from(format!("elapsed:        {}s", self.elapsed.as_secs())),
+            Line::from(format!("request rate:   {:.1}/s", request_rate)),
+            Line::from(format!(
+                "error rate:     {:.1}% (overall {:.1}%)",
+                error_rate * 100.0,
+                self.counters.error_rate() * 100.0
+            )),
+            Line::from(format!(
+                "downloaded:     {:.1} MB",
+                self.counters.bytes_downloaded.load(Ordering::Relaxed) as f64 / 1e6
+            )),
+            Line::from(format!(
+                "output:         {} ({:.1} MB)",
+                self.output_file_name,
+                self.output_size as f64 / 1e6
+            )),
+        ];
+        let summary = Paragraph::new(summary)
+            .block(Block::default().borders(Borders::ALL).title("Rates"));
+        frame.render_widget(summary, rows[1]);
+
+        let workers: Vec<ListItem> = self
+            .counters
+            .activity()
+            .into_iter()
+            .map(|(worker, host)| ListItem::new(format!("#{:<4} {}", worker, host)))
+            .collect();
+        let workers =
+            List::new(workers).block(Block::default().borders(Borders::ALL).title("Workers"));
+        frame.render_widget(workers, columns[0]);
+
+        let failures: Vec<ListItem> = self
+            .counters
+            .recent_failures()
+            .into_iter()
+            .rev()
+            .map(ListItem::new)
+            .collect();
+        let failures = List::new(failures)
+            .block(Block::default().borders(Borders::ALL).title("Recently failed hosts"));
+        frame.render_widget(failures, columns[1]);
+    }
+}
@@ -0,0 +1,91 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Bookkeeping for the `watch` mode, which crawls newly published indices.
+use crate::Index;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Set of index ids that have already been crawled, persisted as JSON.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WatchState {
+    crawled: BTreeSet<String>,
+    #[serde(skip)]
+    new: bool,
+}
+
+impl WatchState {
+    /// Load the state from `path`. A missing file yields an empty state for
+    /// which [WatchState::is_new] returns true.
+    pub fn load(path: &str) -> io::Result<WatchState> {
+        if !Path::new(path).exists() {
+            return Ok(WatchState {
+                new: true,
+                ..Default::default()
+            });
+        }
+        serde_json::from_reader(File::open(path)?).map_err(io::Error::from)
+    }
+
+    /// Write the state to `path`, replacing the file atomically.
+    pub fn save(&mut self, path: &str) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        serde_json::to_writer_pretty(File::create(&tmp_path)?, self)?;
+        fs::rename(&tmp_path, path)?;
+        self.new = false;
+        Ok(())
+    }
+
+    /// Whether the state has never been loaded from or saved to a file.
+    pub fn is_new(&self) -> bool {
+        self.new
+    }
+
+    /// Record an index as crawled.
+    pub fn mark_crawled(&mut self, index_id: &str) {
+        self.crawled.insert(index_id.to_owned());
+    }
+
+    /// The indices of the list that have not been crawled yet.
+    pub fn uncrawled<'a>(&self, indices: &'a [Index]) -> Vec<&'a Index> {
+        indices
+            .iter()
+            .filter(|index| !self.crawled.contains(&index.id))
+            .collect()
+    }
+}
@@ -0,0 +1,873 @@
+/*
+ * This software is Copyright (c) 2021 The Regents of the University of
+ * California. All Rights Reserved. Permission to copy, modify, and distribute this
+ * software and its documentation for academic research and education purposes,
+ * without fee, and without a written agreement is hereby granted, provided that
+ * the above copyright notice, this paragraph and the following three paragraphs
+ * appear in all copies. Permission to make use of this software for other than
+ * academic research and education purposes may be obtained by contacting:
+ *
+ * Office of Innovation and Commercialization
+ * 9500 Gilman Drive, Mail Code 0910
+ * University of California
+ * La Jolla, CA 92093-0910
+ * (858) 534-5815
+ * invent@ucsd.edu
+ *
+ * This software program and documentation are copyrighted by The Regents of the
+ * University of California. The software program and documentation are supplied
+ * "as is", without any accompanying services from The Regents. The Regents does
+ * not warrant that the operation of the program will be uninterrupted or
+ * error-free. The end-user understands that the program was developed for research
+ * purposes and is advised not to rely exclusively on the program for any reason.
+ *
+ * IN NO EVENT SHALL THE UNIVERSITY OF CALIFORNIA BE LIABLE TO ANY PARTY FOR
+ * DIRECT, INDIRECT, SPECIAL, INCIDENTAL, OR CONSEQUENTIAL DAMAGES, INCLUDING LOST
+ * PROFITS, ARISING OUT OF THE USE OF THIS SOFTWARE AND ITS DOCUMENTATION, EVEN IF
+ * THE UNIVERSITY OF CALIFORNIA HAS BEEN ADVISED OF THE POSSIBILITY OF SUCH
+ * DAMAGE. THE UNIVERSITY OF CALIFORNIA SPECIFICALLY DISCLAIMS ANY WARRANTIES,
+ * INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND
+ * FITNESS FOR A PARTICULAR PURPOSE. THE SOFTWARE PROVIDED HEREUNDER IS ON AN "AS
+ * IS" BASIS, AND THE UNIVERSITY OF CALIFORNIA HAS NO OBLIGATIONS TO PROVIDE
+ * MAINTENANCE, SUPPORT, UPDATES, ENHANCEMENTS, OR MODIFICATIONS.
+ *
+ */
+
+//! Runs the whole crawl pipeline against a local mock server serving a
+//! small index: cluster.idx, one cdx file, and one WARC file.
+use cc_host_mapper::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use httpmock::prelude::*;
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const INDEX_ID: &str = "CC-MAIN-2020-50";
+const WARC_PATH: &str = "crawl-data/CC-MAIN-2020-50/segments/1/warc/test.warc.gz";
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Gzip `data` as `members` concatenated gzip members of about equal size,
+/// like files compressed in blocks.
+fn gzip_members(data: &[u8], members: usize) -> Vec<u8> {
+    let size = data.len().div_ceil(members).max(1);
+    data.chunks(size).flat_map(gzip).collect()
+}
+
+fn indexes_path(file: &str) -> String {
+    format!("/cc-index/collections/{}/indexes/{}", INDEX_ID, file)
+}
+
+/// The fixture files of one index.
+struct Fixture {
+    cluster_idx: String,
+    cdx: Vec<u8>,
+    warc: Vec<u8>,
+}
+
+/// An index with captures of `example.com` on two days and of `example.org`
+/// on one day, all recorded in one WARC file.
+fn fixture() -> Fixture {
+    fixture_with_members(1)
+}
+
+/// Same as [fixture], but with the cdx file and each WARC record compressed
+/// as several gzip members.
+fn fixture_with_members(members: usize) -> Fixture {
+    let mut warc = vec![];
+    let mut cdx_lines = vec![];
+    for (surt, timestamp, ip) in &[
+        ("com,example)/", "20201126201142", "93.184.216.34"),
+        ("com,example)/about", "20201126211142", "93.184.216.35"),
+        ("com,example)/", "20201127101142", "2606:2800:220:1::248"),
+        ("org,example)/", "20201128101142", "93.184.216.36"),
+    ] {
+        let record = gzip_members(
+            format!(
+                "WARC/1.0\r\nWARC-Type: response\r\nWARC-IP-Address: {}\r\n\r\n",
+                ip
+            )
+            .as_bytes(),
+            members,
+        );
+        let json = serde_json::json!({
+            "url": "http://example.com/",
+            "mime": "text/html",
+            "status": "200",
+            "filename": WARC_PATH,
+            "offset": warc.len().to_string(),
+            "length": record.len().to_string(),
+            "charset": "UTF-8",
+            "languages": "eng,deu",
+        });
+        cdx_lines.push(format!("{} {} {}\n", surt, timestamp, json));
+        warc.extend(record);
+    }
+    let cdx = gzip_members(cdx_lines.concat().as_bytes(), members);
+    let cluster_idx = format!(
+        "com,example)/ 20201126201142\tcdx-00000.gz\t0\t{}\t1\n\
+         0,102,126,13:7037)/robots.txt 20201126201142\tcdx-00000.gz\t0\t{}\t1\n\
+         org,example)/ 20201128101142\tcdx-00000.gz\t0\t{}\t2\n",
+        cdx.len(),
+        cdx.len(),
+        cdx.len()
+    );
+    Fixture {
+        cluster_idx,
+        cdx,
+        warc,
+    }
+}
+
+/// Serve the byte ranges of `data` that the crawler requests, like a
+/// server honouring `Range` headers.
+fn serve_ranges(server: &MockServer, path: &str, data: &[u8], ranges: &[(usize, usize)]) {
+    for &(start, end) in ranges {
+        let last = end.min(data.len() - 1);
+        server.mock(|when, then| {
+            when.method(GET)
+                .path(path)
+                .header("range", format!("bytes={}-{}", start, end));
+            then.status(206)
+                .header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", start, last, data.len()),
+                )
+                .body(&data[start..=last]);
+        });
+    }
+}
+
+/// Start a server for the fixture. Every WARC record is served starting at
+/// its offset, the cdx file in one block.
+fn serve(fixture: &Fixture) -> MockServer {
+    serve_cdx_file(fixture, &fixture.cdx)
+}
+
+/// Same as [serve], but with `cdx_file` as the cdx file, of which the cdx of
+/// the fixture is the first block.
+fn serve_cdx_file(fixture: &Fixture, cdx_file: &[u8]) -> MockServer {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path(indexes_path("cluster.idx"));
+        then.status(200).body(&fixture.cluster_idx);
+    });
+    serve_ranges(
+        &server,
+        &indexes_path("cdx-00000.gz"),
+        cdx_file,
+        &[(0, fixture.cdx.len() - 1)],
+    );
+    let records = cdx_records(&fixture.cdx);
+    let ranges: Vec<(usize, usize)> = records
+        .iter()
+        .map(|(offset, length)| (*offset, offset + length.min(&901) - 1))
+        .collect();
+    serve_ranges(&server, &format!("/{}", WARC_PATH), &fixture.warc, &ranges);
+    server
+}
+
+/// Offset and length of the WARC records listed in a cdx file.
+fn cdx_records(cdx: &[u8]) -> Vec<(usize, usize)> {
+    let mut text = String::new();
+    let mut decoder = flate2::read::MultiGzDecoder::new(cdx);
+    std::io::Read::read_to_string(&mut decoder, &mut text).unwrap();
+    text.lines()
+        .map(|line| {
+            let record = parse_cdx_line(line).unwrap().record;
+            (record.offset.parse().unwrap(), record.length.parse().unwrap())
+        })
+        .collect()
+}
+
+fn options(server: &MockServer) -> CrawlOptions {
+    CrawlOptions {
+        base_url: Some(server.base_url()),
+        progress: ProgressMode::Hidden,
+        num_threads: Some(2),
+        retry: RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        },
+        ..Default::default()
+    }
+}
+
+fn sorted_csv(mappings: &[MappingEntry]) -> Vec<String> {
+    let mut lines: Vec<String> = mappings.iter().map(MappingEntry::to_csv).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn crawls_one_mapping_per_host_and_day() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+            "example.org,2020-11-28,93.184.216.36",
+        ]
+    );
+    // the pointer to an IP address is skipped
+    assert_eq!(stats.hosts_available, 2);
+    assert_eq!(stats.hosts_attempted, 2);
+    assert_eq!(stats.mappings_written, 3);
+    assert_eq!(stats.unique_hosts, 2);
+    assert_eq!(stats.failures.connect + stats.failures.http_status, 0);
+}
+
+#[test]
+fn reads_cdx_blocks_followed_by_other_blocks() {
+    let fixture = fixture();
+    let mut cdx_file = fixture.cdx.clone();
+    cdx_file.extend(gzip(b"org,example)/next 20201129101142 {}\n"));
+    let server = serve_cdx_file(&fixture, &cdx_file);
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+            "example.org,2020-11-28,93.184.216.36",
+        ]
+    );
+    assert_eq!(stats.failures.body + stats.failures.retryable_hosts, 0);
+}
+
+#[test]
+fn unparsable_warc_records_are_not_missing_ips() {
+    let mut fixture = fixture();
+    let (offset, _) = *cdx_records(&fixture.cdx).last().unwrap();
+    fixture.warc[offset..].fill(b'\n');
+    let server = serve(&fixture);
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert_eq!(mappings.len(), 2);
+    assert_eq!(stats.failures.missing_ip, 0);
+    assert_eq!(stats.endpoints[&Endpoint::Warc].parse_errors, 1);
+}
+
+#[test]
+fn applies_host_filter_and_ip_family() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+
+    let options = CrawlOptions {
+        host_filter: HostFilter {
+            include: Some(regex::Regex::new(r"\.com$").unwrap()),
+            exclude: None,
+        },
+        ip_family: Some(IpFamily::V6),
+        ..options(&server)
+    };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    crawl_to_sink(INDEX_ID, &mut mappings, &options);
+
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec!["example.com,2020-11-27,2606:2800:220:1::248"]
+    );
+}
+
+#[test]
+fn bonus_hosts_are_mapped_from_fetched_blocks() {
+    let mut fixture = fixture();
+    // Only `example.com` has a pointer; `example.org` shares its cdx block.
+    fixture.cluster_idx = fixture.cluster_idx.lines().next().unwrap().to_owned() + "\n";
+    let server = serve(&fixture);
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+    assert_eq!(mappings.len(), 2);
+    assert_eq!(stats.bonus_hosts, 0);
+
+    let options = CrawlOptions { bonus_hosts: true, ..options(&server) };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options);
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+            "example.org,2020-11-28,93.184.216.36",
+        ]
+    );
+    assert_eq!(stats.bonus_hosts, 1);
+}
+
+#[test]
+fn writes_index_record_fields() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+
+    let options = CrawlOptions { with_record_fields: true, ..options(&server) };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    crawl_to_sink(INDEX_ID, &mut mappings, &options);
+
+    assert_eq!(
+        sorted_csv(&mappings)[0],
+        "example.com,2020-11-26,93.184.216.34,eng;deu,UTF-8,,"
+    );
+    let columns = CsvColumns::of(&options);
+    let line = mappings[0].to_csv_with(columns);
+    let parsed = MappingEntry::from_csv_with(&line, columns).unwrap();
+    assert_eq!(parsed.record, mappings[0].record);
+}
+
+#[test]
+fn falls_back_to_cdx_api_once_per_host() {
+    let mut fixture = fixture();
+    // blocks the server does not have, two of them for example.com
+    let second = "com,example)/about 20201126211142\tcdx-00000.gz\t0\t10\t1\n";
+    fixture.cluster_idx = (fixture.cluster_idx + second).replace("cdx-00000.gz", "cdx-00001.gz");
+    let server = serve(&fixture);
+    let mut text = String::new();
+    let mut decoder = flate2::read::MultiGzDecoder::new(&*fixture.cdx);
+    std::io::Read::read_to_string(&mut decoder, &mut text).unwrap();
+    let captures: Vec<String> = text
+        .lines()
+        .filter(|line| line.starts_with("com,example)"))
+        .map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let (urlkey, timestamp) = (fields.next().unwrap(), fields.next().unwrap());
+            let mut capture: serde_json::Value =
+                serde_json::from_str(fields.next().unwrap()).unwrap();
+            capture["urlkey"] = urlkey.into();
+            capture["timestamp"] = timestamp.into();
+            capture.to_string()
+        })
+        .collect();
+    let api_path = format!("/{}-index", INDEX_ID);
+    let num_pages = server.mock(|when, then| {
+        when.method(GET).path(&api_path).query_param("showNumPages", "true");
+        then.status(200).body(r#"{"pages": 2, "pageSize": 5, "blocks": 2}"#);
+    });
+    let pages: Vec<_> = [&captures[..1], &captures[1..]]
+        .iter()
+        .enumerate()
+        .map(|(page, captures)| {
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path(&api_path)
+                    .query_param("url", "example.com")
+                    .query_param("page", page.to_string());
+                then.status(200).body(captures.join("\n"));
+            })
+        })
+        .collect();
+
+    let options = CrawlOptions {
+        cdx_api_fallback: true,
+        index_server_url: Some(server.base_url()),
+        host_filter: HostFilter {
+            include: Some(regex::Regex::new(r"\.com$").unwrap()),
+            exclude: None,
+        },
+        ..options(&server)
+    };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options);
+
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+        ]
+    );
+    num_pages.assert_hits(1);
+    pages.iter().for_each(|page| page.assert_hits(1));
+    assert_eq!(stats.cdx_api_fallbacks, 1);
+}
+
+#[test]
+fn hosts_fail_when_the_cdx_api_fails() {
+    let mut fixture = fixture();
+    // blocks the server does not have, two of them for example.com
+    let second = "com,example)/about 20201126211142\tcdx-00000.gz\t0\t10\t1\n";
+    fixture.cluster_idx = (fixture.cluster_idx + second).replace("cdx-00000.gz", "cdx-00001.gz");
+    let server = serve(&fixture);
+    let num_pages = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{}-index", INDEX_ID))
+            .query_param("showNumPages", "true");
+        then.status(404);
+    });
+
+    let options = CrawlOptions {
+        cdx_api_fallback: true,
+        index_server_url: Some(server.base_url()),
+        host_filter: HostFilter {
+            include: Some(regex::Regex::new(r"\.com$").unwrap()),
+            exclude: None,
+        },
+        ..options(&server)
+    };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options);
+
+    assert!(mappings.is_empty());
+    // queried once, and both pointers of the host are failures
+    num_pages.assert_hits(1);
+    assert_eq!(stats.failures.retryable_hosts + stats.failures.fatal_hosts, 2);
+    assert_eq!(stats.cdx_api_fallbacks, 0);
+}
+
+#[test]
+fn writes_csv_output() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("mapping.csv");
+
+    let stats = crawl_with_options(
+        INDEX_ID.to_owned(),
+        output.to_string_lossy().to_string(),
+        &options(&server),
+    );
+
+    assert!(stats.output_error.is_none());
+    assert_eq!(stats.output_files, vec![output.to_string_lossy().to_string()]);
+    let mut lines: Vec<String> = std::fs::read_to_string(&output)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    lines.sort();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        MappingEntry::from_csv(&lines[0]).unwrap().ip.to_string(),
+        "93.184.216.34"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mapping_entries_round_trip_through_every_csv_column() {
+    let entry = MappingEntry {
+        host: "example.com".to_owned(),
+        timestr: "2020-11-26".to_owned(),
+        ip: "93.184.216.34".parse().unwrap(),
+        server: Some(ServerInfo {
+            server: Some("ECS (dcb/7F84)".to_owned()),
+            powered_by: Some("PHP/7.4".to_owned()),
+        }),
+        protocol: Some(ProtocolInfo {
+            protocols: vec!["h2".to_owned(), "http/1.1".to_owned()],
+            cipher_suite: Some("TLS_AES_128_GCM_SHA256".to_owned()),
+        }),
+        port: Some(8443),
+        url: Some("https://example.com/a,b?c=\"d\"".to_owned()),
+        status: Some(200),
+        record: Some(RecordInfo {
+            languages: vec!["eng".to_owned(), "fra".to_owned()],
+            charset: Some("UTF-8".to_owned()),
+            truncated: Some("length".to_owned()),
+            redirect: Some("https://www.example.com/".to_owned()),
+        }),
+        host_unicode: Some("example.com".to_owned()),
+        prefix: Some("93.184.216.0/24".parse().unwrap()),
+        asn: Some(vec![15133, 15134]),
+        rpki: Some(RpkiValidity::Valid),
+        provider: Some("Edgecast".to_owned()),
+        org: Some(vec!["Edgecast Inc.".to_owned(), "Verizon".to_owned()]),
+        country: Some("US".to_owned()),
+    };
+    let none = CsvColumns::default();
+    let all = CsvColumns {
+        server: true,
+        protocol: true,
+        port: true,
+        url: true,
+        status: true,
+        record: true,
+        host_unicode: true,
+        prefix: PrefixOutput::Alongside,
+        asn: true,
+        rpki: true,
+        provider: true,
+        org: true,
+        country: true,
+    };
+    let layouts = vec![
+        none,
+        all,
+        CsvColumns { server: true, ..none },
+        CsvColumns { protocol: true, ..none },
+        CsvColumns { port: true, ..none },
+        CsvColumns { url: true, ..none },
+        CsvColumns { status: true, ..none },
+        CsvColumns { record: true, ..none },
+        CsvColumns { host_unicode: true, ..none },
+        CsvColumns { prefix: PrefixOutput::Alongside, ..none },
+        CsvColumns { prefix: PrefixOutput::Instead, ..none },
+        CsvColumns { asn: true, ..none },
+        CsvColumns { rpki: true, ..none },
+        CsvColumns { provider: true, ..none },
+        CsvColumns { org: true, ..none },
+        CsvColumns { country: true, ..none },
+    ];
+    for columns in layouts {
+        let line = entry.to_csv_with(columns);
+        let parsed = MappingEntry::from_csv_with(&line, columns)
+            .unwrap_or_else(|| panic!("cannot parse {:?} with {:?}", line, columns));
+        assert_eq!(parsed.to_csv_with(columns), line);
+        if columns.prefix != PrefixOutput::Instead {
+            // only the columns of the layout are set
+            assert_eq!(parsed.to_csv(), line);
+        }
+        // optional columns cannot be guessed from a plain line
+        assert_eq!(MappingEntry::from_csv(&line).is_some(), columns == none);
+    }
+}
+
+#[test]
+fn churn_counts_origin_as_changes() {
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-churn-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("pfx2as.txt");
+    std::fs::write(&path, "192.0.2.0\t24\t64500\n198.51.100.0\t24\t64501\n").unwrap();
+    let pfx2as = Pfx2As::load(&path).unwrap();
+    let crawl = |lines: &[&str]| -> Vec<std::io::Result<MappingEntry>> {
+        lines.iter().map(|line| Ok(MappingEntry::from_csv(line).unwrap())).collect()
+    };
+    let crawls = || {
+        vec![
+            crawl(&["a.example,2020-11-26,192.0.2.1", "b.example,2020-11-26,192.0.2.2"]),
+            // a moves within its AS, b to another AS
+            crawl(&["a.example,2020-12-01,192.0.2.3", "b.example,2020-12-01,198.51.100.2"]),
+        ]
+    };
+
+    let report = churn_report_with(crawls(), Some(&pfx2as)).unwrap();
+    assert_eq!(report.hosts_changed, 2);
+    assert_eq!(report.ip_changes, 2);
+    assert_eq!(report.hosts_changed_as, Some(1));
+    assert_eq!(report.as_changes, Some(1));
+    assert!(report.to_csv().contains(&"summary,as_changes,1".to_owned()));
+
+    let report = churn_report(crawls()).unwrap();
+    assert_eq!(report.as_changes, None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tld_report_lists_top_origin_ases() {
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-tld-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("pfx2as.txt");
+    std::fs::write(&path, "192.0.2.0\t24\t64500\n198.51.100.0\t24\t64501_64502\n").unwrap();
+    let pfx2as = Pfx2As::load(&path).unwrap();
+    let mappings: Vec<std::io::Result<MappingEntry>> = [
+        "a.example.org,2020-11-26,192.0.2.1",
+        "a.example.org,2020-11-27,192.0.2.2",
+        "b.example.org,2020-11-26,192.0.2.3",
+        "c.example.org,2020-11-26,198.51.100.1",
+        "d.example.org,2020-11-26,203.0.113.1",
+    ]
+    .iter()
+    .map(|line| Ok(MappingEntry::from_csv(line).unwrap()))
+    .collect();
+
+    let report = tld_report_with(mappings, Some(&pfx2as), 2).unwrap();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].hosts, 4);
+    assert_eq!(
+        report[0].top_ases,
+        vec![AsHosts { asn: 64500, hosts: 2 }, AsHosts { asn: 64501, hosts: 1 }]
+    );
+    assert_eq!(report[0].to_csv_with_ases(), "org,4,5,5,0,64500:2;64501:1");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_index_is_a_fatal_error() {
+    let server = MockServer::start();
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert!(mappings.is_empty());
+    assert!(stats.index_error.is_some());
+    assert!(!stats.index_error_retryable);
+}
+
+#[test]
+fn server_errors_are_retried_and_counted() {
+    let fixture = fixture();
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path(indexes_path("cluster.idx"));
+        then.status(200).body(&fixture.cluster_idx);
+    });
+    let cdx = server.mock(|when, then| {
+        when.method(GET).path(indexes_path("cdx-00000.gz"));
+        then.status(503);
+    });
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert!(mappings.is_empty());
+    // one retry for each of the two hosts
+    cdx.assert_hits(4);
+    assert_eq!(stats.retries, 2);
+    assert_eq!(stats.failures.http_status, 2);
+    assert_eq!(stats.failures.retryable_hosts, 2);
+    assert_eq!(stats.status_histogram.get(&503), Some(&4));
+}
+
+/// Fetcher answering the first request for a cdx range with a 503.
+struct FlakyFetcher {
+    inner: ReqwestFetcher,
+    failed: AtomicBool,
+}
+
+impl Fetcher for FlakyFetcher {
+    fn get(&self, url: &str) -> Result<FetchResponse, FetchError> {
+        self.inner.get(url)
+    }
+
+    fn get_range(&self, url: &str, start: i64, end: i64) -> Result<FetchResponse, FetchError> {
+        if url.ends_with(".gz") && !self.failed.swap(true, AtomicOrdering::Relaxed) {
+            return Ok(FetchResponse {
+                status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                headers: Default::default(),
+                body: vec![],
+            });
+        }
+        self.inner.get_range(url, start, end)
+    }
+}
+
+#[test]
+fn recovered_requests_are_not_failures() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+    let options = options(&server);
+    let fetcher = FlakyFetcher {
+        inner: ReqwestFetcher::from_options(&options).unwrap(),
+        failed: AtomicBool::new(false),
+    };
+    let options = CrawlOptions {
+        fetcher: Some(Arc::new(fetcher)),
+        ..options
+    };
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options);
+
+    assert_eq!(mappings.len(), 3);
+    assert_eq!(stats.retries, 1);
+    assert_eq!(stats.failures.http_status, 0);
+    assert_eq!(stats.failure_rate(), 0.0);
+    assert_eq!(stats.status_histogram.get(&503), Some(&1));
+}
+
+#[test]
+fn ignored_ranges_count_as_truncated() {
+    let fixture = fixture();
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(GET).path(indexes_path("cluster.idx"));
+        then.status(200).body(&fixture.cluster_idx);
+    });
+    // a server that ignores the Range header and sends the whole file
+    server.mock(|when, then| {
+        when.method(GET).path(indexes_path("cdx-00000.gz"));
+        then.status(200).body(&fixture.cdx);
+    });
+
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options(&server));
+
+    assert!(mappings.is_empty());
+    assert_eq!(stats.failures.body, 2);
+    assert_eq!(stats.failures.retryable_hosts, 2);
+}
+
+#[test]
+fn checkpoint_skips_completed_pointers() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-ckpt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("crawl.checkpoint");
+    let header = format!("# index {}\n", INDEX_ID);
+    std::fs::write(&path, header + "cdx-00000.gz 0\n").unwrap();
+
+    let checkpoint = Checkpoint::open(&path, INDEX_ID).unwrap();
+    let pointers = read_cluster_idx_with(INDEX_ID, &options(&server));
+    assert!(checkpoint.contains(&pointers[0]));
+    // another block of the same host is still to be crawled
+    let next = IndexHostPointer { range_start: 1024, ..pointers[0].clone() };
+    assert!(!checkpoint.contains(&next));
+
+    let options = CrawlOptions {
+        checkpoint: Some(Arc::new(checkpoint)),
+        ..options(&server)
+    };
+    let mut mappings: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut mappings, &options);
+    assert!(mappings.is_empty());
+    assert_eq!(stats.pointers_resumed, 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sorts_and_dedups_runs_larger_than_memory() {
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-sort-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("mapping.csv.gz");
+    // every line is spilled to its own run, so the runs are reduced before
+    // the final merge
+    let lines: Vec<String> = (0..600)
+        .map(|i| format!("host{}.example.com,2020-11-26,192.0.2.{}", (i * 7919) % 300, i % 3))
+        .collect();
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    for line in &lines {
+        writeln!(encoder, "{}", line).unwrap();
+    }
+    std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+    let sort_options = SortOptions {
+        temp_dir: None,
+        memory_budget: 1,
+    };
+    let written = sort_dedup_file_with(path.to_str().unwrap(), &sort_options).unwrap();
+
+    let mut expected = lines.clone();
+    expected.sort();
+    expected.dedup();
+    let sorted: Vec<String> = get_reader(path.to_str().unwrap())
+        .unwrap()
+        .lines()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(written, expected.len() as u64);
+    assert_eq!(sorted, expected);
+    // the spilled runs are removed
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn disk_cache_checks_the_key_of_entries() {
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-cache-{}", std::process::id()));
+    let cache = DiskCache::open(dir.clone(), DEFAULT_CACHE_SIZE).unwrap();
+    let url = "https://data.commoncrawl.org/cdx-00000.gz";
+    cache.put(url, Some((0, 9)), b"first body");
+    cache.put(url, Some((0, 9)), b"other body");
+    assert_eq!(cache.get(url, Some((0, 9))).as_deref(), Some(&b"other body"[..]));
+    assert_eq!(cache.get(url, Some((10, 19))), None);
+
+    // an entry whose file holds another key, as after a hash collision
+    let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+    assert_eq!(entries.len(), 1);
+    std::fs::write(&entries[0], format!("{} 10-19\nother body", url)).unwrap();
+    assert_eq!(cache.get(url, Some((0, 9))), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn resolves_single_pointer() {
+    let fixture = fixture();
+    let server = serve(&fixture);
+    let options = options(&server);
+
+    let pointers = read_cluster_idx_with(INDEX_ID, &options);
+    assert_eq!(pointers.len(), 2);
+    let mappings = resolve_pointer_with(&pointers[0], &options).unwrap();
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+        ]
+    );
+}
+
+#[test]
+fn replays_recorded_crawl() {
+    let fixture = fixture();
+    let dir = std::env::temp_dir().join(format!("cc-host-mapper-cassette-{}", std::process::id()));
+    let recorded = {
+        let server = serve(&fixture);
+        let options = options(&server);
+        let inner = Arc::new(ReqwestFetcher::from_options(&options).unwrap());
+        let options = CrawlOptions {
+            fetcher: Some(Arc::new(RecordingFetcher::create(&dir, inner).unwrap())),
+            ..options
+        };
+        let mut mappings: Vec<MappingEntry> = vec![];
+        crawl_to_sink(INDEX_ID, &mut mappings, &options);
+        mappings
+    };
+
+    // the server is gone, all responses come from the cassette
+    let options = CrawlOptions {
+        fetcher: Some(Arc::new(ReplayFetcher::open(&dir).unwrap())),
+        progress: ProgressMode::Hidden,
+        ..Default::default()
+    };
+    let mut replayed: Vec<MappingEntry> = vec![];
+    let stats = crawl_to_sink(INDEX_ID, &mut replayed, &options);
+
+    assert_eq!(sorted_csv(&replayed), sorted_csv(&recorded));
+    assert_eq!(stats.mappings_written, 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn queries_host_across_gzip_members() {
+    let fixture = fixture_with_members(3);
+    let server = serve(&fixture);
+    let options = options(&server);
+
+    let pointers = read_cluster_idx_with(INDEX_ID, &options);
+    let mappings: Vec<MappingEntry> = query_host_with_options(pointers[0].clone(), &options)
+        .into_iter()
+        .flatten()
+        .collect();
+    assert_eq!(
+        sorted_csv(&mappings),
+        vec![
+            "example.com,2020-11-26,93.184.216.34",
+            "example.com,2020-11-27,2606:2800:220:1::248",
+        ]
+    );
+}
+
+#[test]
+fn resolves_record_across_gzip_members() {
+    let fixture = fixture_with_members(3);
+    let server = serve(&fixture);
+    let options = options(&server);
+
+    let mut text = String::new();
+    let mut decoder = flate2::read::MultiGzDecoder::new(&*fixture.cdx);
+    std::io::Read::read_to_string(&mut decoder, &mut text).unwrap();
+    let record = parse_cdx_line(text.lines().last().unwrap()).unwrap().record;
+    let ip = resolve_record_with(&record, &options).unwrap();
+    assert_eq!(ip.map(|ip| ip.to_string()).as_deref(), Some("93.184.216.36"));
+}